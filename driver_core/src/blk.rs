@@ -0,0 +1,773 @@
+//! virtio-blk request format (virtio 1.x, section 5.2)
+//!
+//! A request is a descriptor chain: the header the device reads, the data
+//! (one buffer, or several segments within the device's size_max/seg_max
+//! limits), and a status byte the device writes. Flush has no data; get-id
+//! has 20 bytes the device fills in; discard and write-zeroes carry a list
+//! of sector ranges.
+//!
+//! With several requests outstanding on a queue, `Requests` gives each its
+//! own header and status byte and matches the device's completions, which
+//! may come in any order, back to them.
+
+use core::ptr::read_volatile;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::mmio::Mmio;
+use crate::virtqueue::Buffer;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Request types
+pub const VIRTIO_BLK_T_IN: u32 = 0; // Read
+pub const VIRTIO_BLK_T_OUT: u32 = 1; // Write
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Request status
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Feature bits
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_GEOMETRY: u64 = 1 << 4;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+pub const VIRTIO_BLK_F_MQ: u64 = 1 << 12;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+/// Length of the serial number T_GET_ID returns (NUL padded)
+pub const ID_BYTES: usize = 20;
+
+/// Write-zeroes segment flag: the device may deallocate the sectors
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// Device config: capacity in 512-byte sectors (le64)
+pub const CONFIG_CAPACITY: u64 = 0x00;
+/// Device config: longest data segment in bytes (le32, VIRTIO_BLK_F_SIZE_MAX)
+pub const CONFIG_SIZE_MAX: u64 = 0x08;
+/// Device config: most data segments per request (le32, VIRTIO_BLK_F_SEG_MAX)
+pub const CONFIG_SEG_MAX: u64 = 0x0C;
+/// Device config: cylinders (le16), heads (u8), sectors (u8)
+pub const CONFIG_GEOMETRY: u64 = 0x10;
+/// Device config: logical block size in bytes (le32, VIRTIO_BLK_F_BLK_SIZE)
+pub const CONFIG_BLK_SIZE: u64 = 0x14;
+/// Device config: physical_block_exp (u8), alignment_offset (u8),
+/// min_io_size (le16), opt_io_size (le32)
+pub const CONFIG_TOPOLOGY: u64 = 0x18;
+/// Device config: request queues (le16, VIRTIO_BLK_F_MQ)
+pub const CONFIG_NUM_QUEUES: u64 = 0x22;
+// Device config, VIRTIO_BLK_F_DISCARD (le32 each)
+pub const CONFIG_MAX_DISCARD_SECTORS: u64 = 0x24;
+pub const CONFIG_MAX_DISCARD_SEG: u64 = 0x28;
+pub const CONFIG_DISCARD_SECTOR_ALIGNMENT: u64 = 0x2C;
+// Device config, VIRTIO_BLK_F_WRITE_ZEROES
+pub const CONFIG_MAX_WRITE_ZEROES_SECTORS: u64 = 0x30;
+pub const CONFIG_MAX_WRITE_ZEROES_SEG: u64 = 0x34;
+/// Device config: write-zeroes may unmap (u8)
+pub const CONFIG_WRITE_ZEROES_MAY_UNMAP: u64 = 0x38;
+
+/// Disk geometry (VIRTIO_BLK_F_GEOMETRY)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+/// How the disk likes to be accessed (VIRTIO_BLK_F_TOPOLOGY)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Topology {
+    /// log2 of logical blocks per physical block
+    pub physical_block_exp: u8,
+    /// Logical blocks before the first physical block boundary
+    pub alignment_offset: u8,
+    /// Suggested minimum I/O size, in logical blocks
+    pub min_io_size: u16,
+    /// Optimal I/O size, in logical blocks
+    pub opt_io_size: u32,
+}
+
+/// The device configuration (struct virtio_blk_config), with the fields
+/// the negotiated features leave undefined as None or their defaults
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Size in 512-byte sectors, whatever the block size
+    pub capacity: u64,
+    pub size_max: Option<u32>,
+    pub seg_max: Option<u32>,
+    pub geometry: Option<Geometry>,
+    /// Logical block size in bytes: a power of two, 512 by default
+    pub blk_size: u32,
+    pub topology: Option<Topology>,
+    /// Request queues, 1 without VIRTIO_BLK_F_MQ
+    pub num_queues: u16,
+    pub discard: Option<RangeLimits>,
+    pub write_zeroes: Option<RangeLimits>,
+    /// Write-zeroes may deallocate the sectors it zeroes
+    pub write_zeroes_may_unmap: bool,
+}
+
+impl Config {
+    /// Read the config at `base` given the `features` negotiated
+    pub fn read(mmio: &impl Mmio, base: u64, features: u64) -> Self {
+        let has = |feature| features & feature != 0;
+        let field32 = |offset, feature| has(feature).then(|| mmio.read32(base + offset));
+
+        let blk_size = field32(CONFIG_BLK_SIZE, VIRTIO_BLK_F_BLK_SIZE)
+            .filter(|&n| n.is_power_of_two() && n as usize >= SECTOR_SIZE)
+            .unwrap_or(SECTOR_SIZE as u32);
+        let geometry = has(VIRTIO_BLK_F_GEOMETRY).then(|| Geometry {
+            cylinders: mmio.read16(base + CONFIG_GEOMETRY),
+            heads: mmio.read8(base + CONFIG_GEOMETRY + 2),
+            sectors: mmio.read8(base + CONFIG_GEOMETRY + 3),
+        });
+        let topology = has(VIRTIO_BLK_F_TOPOLOGY).then(|| Topology {
+            physical_block_exp: mmio.read8(base + CONFIG_TOPOLOGY),
+            alignment_offset: mmio.read8(base + CONFIG_TOPOLOGY + 1),
+            min_io_size: mmio.read16(base + CONFIG_TOPOLOGY + 2),
+            opt_io_size: mmio.read32(base + CONFIG_TOPOLOGY + 4),
+        });
+        let num_queues = if has(VIRTIO_BLK_F_MQ) { mmio.read16(base + CONFIG_NUM_QUEUES).max(1) } else { 1 };
+        let discard = field32(CONFIG_MAX_DISCARD_SECTORS, VIRTIO_BLK_F_DISCARD).map(|max_sectors| {
+            RangeLimits::new(
+                max_sectors,
+                mmio.read32(base + CONFIG_MAX_DISCARD_SEG),
+                mmio.read32(base + CONFIG_DISCARD_SECTOR_ALIGNMENT),
+            )
+        });
+        let write_zeroes = field32(CONFIG_MAX_WRITE_ZEROES_SECTORS, VIRTIO_BLK_F_WRITE_ZEROES)
+            .map(|max_sectors| RangeLimits::new(max_sectors, mmio.read32(base + CONFIG_MAX_WRITE_ZEROES_SEG), 1));
+
+        Config {
+            capacity: mmio.read64(base + CONFIG_CAPACITY),
+            size_max: field32(CONFIG_SIZE_MAX, VIRTIO_BLK_F_SIZE_MAX),
+            seg_max: field32(CONFIG_SEG_MAX, VIRTIO_BLK_F_SEG_MAX),
+            geometry,
+            blk_size,
+            topology,
+            num_queues,
+            discard,
+            write_zeroes,
+            write_zeroes_may_unmap: write_zeroes.is_some() && mmio.read8(base + CONFIG_WRITE_ZEROES_MAY_UNMAP) != 0,
+        }
+    }
+
+    /// 512-byte sectors per logical block
+    pub fn sectors_per_block(&self) -> u64 {
+        (self.blk_size as usize / SECTOR_SIZE) as u64
+    }
+
+    /// Size in logical blocks
+    pub fn blocks(&self) -> u64 {
+        self.capacity / self.sectors_per_block()
+    }
+}
+
+/// What a request's status byte means
+pub fn check_status(status: u8) -> Result<(), &'static str> {
+    match status {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_IOERR => Err("I/O error"),
+        VIRTIO_BLK_S_UNSUPP => Err("request not supported by the device"),
+        _ => Err("device did not complete the request"),
+    }
+}
+
+/// How much data one request may carry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest data segment in bytes
+    pub size_max: u32,
+    /// Most data segments per request
+    pub seg_max: u32,
+    /// Requests move whole logical blocks of this many bytes
+    pub block_size: u32,
+}
+
+impl Limits {
+    /// Limits for a queue of `queue_size` descriptors, given the device's
+    /// size_max and seg_max where those features were negotiated
+    pub fn new(queue_size: u16, size_max: Option<u32>, seg_max: Option<u32>) -> Self {
+        // The header and status take a descriptor each
+        let descs = (queue_size as u32).saturating_sub(2).max(1);
+        Limits {
+            size_max: size_max.filter(|&n| n > 0).unwrap_or(u32::MAX),
+            seg_max: seg_max.filter(|&n| n > 0).map_or(descs, |n| n.min(descs)),
+            block_size: SECTOR_SIZE as u32,
+        }
+    }
+
+    /// Limits for a queue of `queue_size` descriptors on a device with
+    /// `config`
+    ///
+    /// Fails if one request cannot carry a whole block: reads and writes
+    /// would then send no data and never finish.
+    pub fn for_config(queue_size: u16, config: &Config) -> Result<Self, &'static str> {
+        let limits = Limits { block_size: config.blk_size, ..Self::new(queue_size, config.size_max, config.seg_max) };
+        if (limits.size_max as u64) * (limits.seg_max as u64) < limits.block_size as u64 {
+            return Err("device segments cannot hold one block");
+        }
+        Ok(limits)
+    }
+
+    /// Most data bytes in one request: whole blocks, and at least one
+    /// (the used length the device reports is a u32, so never more than that)
+    pub fn max_request(&self) -> usize {
+        let block = self.block_size as usize;
+        let bytes = (self.size_max as u64 * self.seg_max as u64).min(u32::MAX as u64 - 1);
+        (bytes as usize / block * block).max(block)
+    }
+}
+
+/// Limits on discard or write-zeroes requests, from the device config
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RangeLimits {
+    /// Longest range in one segment, in sectors
+    pub max_sectors: u32,
+    /// Most segments per request
+    pub max_seg: u32,
+    /// Segments should start and end on multiples of this many sectors
+    pub alignment: u32,
+}
+
+impl RangeLimits {
+    /// Limits as read from config; zeroes (which a device should not
+    /// report) are taken as 1
+    pub fn new(max_sectors: u32, max_seg: u32, alignment: u32) -> Self {
+        RangeLimits { max_sectors: max_sectors.max(1), max_seg: max_seg.max(1), alignment: alignment.max(1) }
+    }
+
+    /// Most sectors one segment may cover, a multiple of the alignment
+    /// where that is possible
+    fn segment_sectors(&self) -> u64 {
+        let aligned = self.max_sectors / self.alignment * self.alignment;
+        if aligned == 0 { self.max_sectors as u64 } else { aligned as u64 }
+    }
+}
+
+/// One range of a discard or write-zeroes request
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct RangeSegment {
+    pub sector: u64,
+    pub num_sectors: u32,
+    pub flags: u32,
+}
+
+/// Cut `count` sectors at `sector` into segments within `limits`, filling
+/// at most `max_seg` entries of `out`
+/// Returns the number of segments and the sectors they cover; the rest
+/// takes further requests.
+pub fn range_segments(sector: u64, count: u64, flags: u32, limits: &RangeLimits, out: &mut [RangeSegment]) -> (usize, u64) {
+    let max = out.len().min(limits.max_seg as usize);
+    let mut done = 0;
+    let mut n = 0;
+    while done < count && n < max {
+        let len = (count - done).min(limits.segment_sectors());
+        out[n] = RangeSegment { sector: sector + done, num_sectors: len as u32, flags };
+        n += 1;
+        done += len;
+    }
+    (n, done)
+}
+
+/// Block device request header
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ReqHeader {
+    pub req_type: u32,
+    pub reserved: u32,
+    pub sector: u64,
+}
+
+impl ReqHeader {
+    pub const fn new(req_type: u32, sector: u64) -> Self {
+        ReqHeader { req_type, reserved: 0, sector }
+    }
+
+    /// True if the device writes the data buffer
+    pub fn device_writes_data(&self) -> bool {
+        matches!(self.req_type, VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID)
+    }
+
+    /// True if the data is sectors of the disk (rather than a command's
+    /// arguments or result)
+    pub fn data_is_sectors(&self) -> bool {
+        matches!(self.req_type, VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT)
+    }
+}
+
+/// A request's data, borrowed as the device uses it
+///
+/// The device writes the data of reads and get-id requests in place, so
+/// those must come as `In`: behind a shared borrow, the compiler may
+/// assume the bytes never change.
+#[derive(Debug)]
+pub enum Data<'a> {
+    /// Read by the device: blocks to write, or a command's arguments
+    Out(&'a [u8]),
+    /// Written by the device: blocks read, or the serial number
+    In(&'a mut [u8]),
+}
+
+impl Data<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            Data::Out(data) => data.len(),
+            Data::In(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes from `offset` on, borrowed again
+    pub fn tail(&mut self, offset: usize) -> Data<'_> {
+        match self {
+            Data::Out(data) => Data::Out(&data[offset..]),
+            Data::In(data) => Data::In(&mut data[offset..]),
+        }
+    }
+
+    /// Address of the first byte
+    fn addr(&mut self) -> u64 {
+        match self {
+            Data::Out(data) => data.as_ptr() as u64,
+            Data::In(data) => data.as_mut_ptr() as u64,
+        }
+    }
+}
+
+/// Descriptor chain for one request
+/// The status byte should be preset to something other than VIRTIO_BLK_S_OK
+pub fn request_chain(header: &ReqHeader, mut data: Data<'_>, status: &mut u8) -> [Buffer; 3] {
+    debug_assert!(!header.device_writes_data() || matches!(data, Data::In(_)), "device-written data passed as Data::Out");
+    [
+        Buffer::readable(header),
+        Buffer { addr: data.addr(), len: data.len() as u32, device_writes: header.device_writes_data() },
+        Buffer::writable(status),
+    ]
+}
+
+/// Descriptor chain for a request carrying as much of `data` as `limits`
+/// and `out` allow: the header, data segments of at most size_max bytes,
+/// then the status byte
+///
+/// Returns how many entries of `out` the chain uses and how many bytes of
+/// `data` it covers: whole blocks for reads and writes, everything for
+/// other commands. With no data the chain is just the header and status,
+/// as flush needs. `out` needs room for seg_max + 2.
+pub fn request_chain_sg(header: &ReqHeader, mut data: Data<'_>, status: &mut u8, limits: &Limits, out: &mut [Buffer]) -> (usize, usize) {
+    debug_assert!(!header.device_writes_data() || matches!(data, Data::In(_)), "device-written data passed as Data::Out");
+    if out.len() < 2 {
+        return (0, 0);
+    }
+    let segs = (limits.seg_max as usize).min(out.len() - 2);
+    let len = if header.data_is_sectors() {
+        let len = data.len().min(limits.max_request()).min(segs.saturating_mul(limits.size_max as usize));
+        len / limits.block_size as usize * limits.block_size as usize
+    } else {
+        data.len()
+    };
+
+    out[0] = Buffer::readable(header);
+    let addr = data.addr();
+    let mut n = 1;
+    let mut offset = 0;
+    while offset < len {
+        let seg = (len - offset).min(limits.size_max as usize);
+        out[n] = Buffer { addr: addr + offset as u64, len: seg as u32, device_writes: header.device_writes_data() };
+        n += 1;
+        offset += seg;
+    }
+    out[n] = Buffer::writable(status);
+    (n + 1, len)
+}
+
+/// Where a request slot is in its life
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    /// Header set up, not on the ring yet
+    Claimed,
+    /// On the ring, under this head descriptor
+    Submitted(u16),
+    /// Returned by the device; the status is final
+    Done,
+    /// Given up on while the device still has it; freed when it returns
+    Abandoned(u16),
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    header: ReqHeader,
+    status: u8,
+    state: SlotState,
+}
+
+/// Headers and status bytes for up to `N` outstanding requests on one
+/// queue, each known by a tag
+///
+/// The device returns a chain by its head descriptor; complete() maps that
+/// back to the tag, so requests can finish in any order. The table must
+/// stay put while requests are outstanding, since the device reads the
+/// headers and writes the status bytes in place.
+pub struct Requests<const N: usize> {
+    slots: [Slot; N],
+}
+
+impl<const N: usize> Requests<N> {
+    pub const fn new() -> Self {
+        Requests { slots: [Slot { header: ReqHeader::new(0, 0), status: 0xFF, state: SlotState::Free }; N] }
+    }
+
+    /// Take a free slot for a request; its tag, or None if all `N` are
+    /// outstanding
+    pub fn claim(&mut self, req_type: u32, sector: u64) -> Option<usize> {
+        let tag = self.slots.iter().position(|s| s.state == SlotState::Free)?;
+        self.slots[tag] = Slot { header: ReqHeader::new(req_type, sector), status: 0xFF, state: SlotState::Claimed };
+        Some(tag)
+    }
+
+    /// Header and status byte of a claimed request, to build its chain
+    pub fn parts(&mut self, tag: usize) -> (&ReqHeader, &mut u8) {
+        let slot = &mut self.slots[tag];
+        (&slot.header, &mut slot.status)
+    }
+
+    /// The claimed request `tag` is on the ring under `head`
+    pub fn submitted(&mut self, tag: usize, head: u16) {
+        self.slots[tag].state = SlotState::Submitted(head);
+    }
+
+    /// Free a claimed request that never reached the ring
+    pub fn cancel(&mut self, tag: usize) {
+        if self.slots[tag].state == SlotState::Claimed {
+            self.slots[tag].state = SlotState::Free;
+        }
+    }
+
+    /// The device returned the chain with head descriptor `head`
+    /// Returns the request's tag; None if no request is waiting on it.
+    pub fn complete(&mut self, head: u16) -> Option<usize> {
+        let tag = self.slots.iter().position(|s| matches!(s.state, SlotState::Submitted(h) | SlotState::Abandoned(h) if h == head))?;
+        let slot = &mut self.slots[tag];
+        if slot.state == SlotState::Abandoned(head) {
+            slot.state = SlotState::Free;
+            return None;
+        }
+        slot.state = SlotState::Done;
+        Some(tag)
+    }
+
+    /// How request `tag` went, freeing its slot; None while the device
+    /// still has it
+    pub fn take(&mut self, tag: usize) -> Option<Result<(), &'static str>> {
+        let slot = &mut self.slots[tag];
+        if slot.state != SlotState::Done {
+            return None;
+        }
+        slot.state = SlotState::Free;
+        // The data the device wrote is in place once its status is
+        fence(Ordering::SeqCst);
+        Some(check_status(unsafe { read_volatile(&slot.status) }))
+    }
+
+    /// Stop waiting for request `tag`; its slot is reused only once the
+    /// device has given the request back
+    pub fn abandon(&mut self, tag: usize) {
+        let slot = &mut self.slots[tag];
+        slot.state = match slot.state {
+            SlotState::Submitted(head) => SlotState::Abandoned(head),
+            SlotState::Abandoned(head) => SlotState::Abandoned(head),
+            _ => SlotState::Free,
+        };
+    }
+
+    /// Requests claimed and not yet taken (or abandoned and not yet returned)
+    pub fn outstanding(&self) -> usize {
+        self.slots.iter().filter(|s| s.state != SlotState::Free).count()
+    }
+}
+
+impl<const N: usize> Default for Requests<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_is_16_bytes_little_endian() {
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_OUT, 0x0102_0304_0506_0708);
+        assert_eq!(core::mem::size_of::<ReqHeader>(), 16);
+        let bytes = unsafe { core::slice::from_raw_parts(&hdr as *const ReqHeader as *const u8, 16) };
+        assert_eq!(bytes, [1, 0, 0, 0, 0, 0, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn read_chain_lets_device_write_data() {
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_IN, 7);
+        let mut data = [0u8; SECTOR_SIZE];
+        let mut status = 0xFF;
+        let chain = request_chain(&hdr, Data::In(&mut data), &mut status);
+
+        assert_eq!(chain[0], Buffer { addr: &hdr as *const ReqHeader as u64, len: 16, device_writes: false });
+        assert_eq!(chain[1], Buffer { addr: data.as_ptr() as u64, len: 512, device_writes: true });
+        assert_eq!(chain[2], Buffer { addr: &status as *const u8 as u64, len: 1, device_writes: true });
+    }
+
+    #[test]
+    fn write_chain_lets_device_read_data() {
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_OUT, 7);
+        let data = [0u8; 2 * SECTOR_SIZE];
+        let mut status = 0xFF;
+        let chain = request_chain(&hdr, Data::Out(&data), &mut status);
+        assert!(!chain[1].device_writes);
+        assert_eq!(chain[1].len, 1024);
+        assert!(chain[2].device_writes);
+    }
+
+    #[test]
+    fn limits_default_to_the_queue_size() {
+        let limits = Limits::new(8, None, None);
+        assert_eq!(limits, Limits { size_max: u32::MAX, seg_max: 6, block_size: 512 });
+        assert_eq!(limits.max_request(), (u32::MAX as usize - 1) / SECTOR_SIZE * SECTOR_SIZE);
+
+        // The device's seg_max cannot exceed what the queue holds
+        assert_eq!(Limits::new(8, Some(4096), Some(126)).seg_max, 6);
+        assert_eq!(Limits::new(8, Some(4096), Some(2)).max_request(), 8192);
+        // Odd segment sizes still make whole-sector requests
+        assert_eq!(Limits::new(8, Some(1000), Some(1)).max_request(), 512);
+        assert_eq!(Limits::new(8, Some(100), Some(1)).max_request(), 512);
+    }
+
+    #[test]
+    fn sg_chain_splits_data_into_segments() {
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_IN, 0);
+        let mut data = vec![0u8; 8 * SECTOR_SIZE];
+        let mut status = 0xFF;
+        let limits = Limits::new(8, Some(1536), Some(2));
+        let mut out = [Buffer { addr: 0, len: 0, device_writes: false }; 8];
+
+        // Two 1536-byte segments: six of the eight sectors
+        let (n, len) = request_chain_sg(&hdr, Data::In(&mut data), &mut status, &limits, &mut out);
+        assert_eq!((n, len), (4, 6 * SECTOR_SIZE));
+        assert_eq!(out[0], Buffer::readable(&hdr));
+        assert_eq!(out[1], Buffer { addr: data.as_ptr() as u64, len: 1536, device_writes: true });
+        assert_eq!(out[2], Buffer { addr: data.as_ptr() as u64 + 1536, len: 1536, device_writes: true });
+        assert_eq!(out[3], Buffer::writable(&mut status));
+
+        // The rest fits in one request, with a short last segment
+        let (n, len) = request_chain_sg(&hdr, Data::In(&mut data[len..]), &mut status, &limits, &mut out);
+        assert_eq!((n, len), (3, 2 * SECTOR_SIZE));
+        assert_eq!(out[1].len, 1024);
+    }
+
+    #[test]
+    fn sg_chain_respects_room_in_out() {
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_OUT, 0);
+        let data = vec![0u8; 8 * SECTOR_SIZE];
+        let mut status = 0xFF;
+        let limits = Limits::new(64, Some(700), None);
+        let mut out = [Buffer { addr: 0, len: 0, device_writes: false }; 5];
+
+        // Three 700-byte segments hold four whole sectors, the last one cut short
+        let (n, len) = request_chain_sg(&hdr, Data::Out(&data), &mut status, &limits, &mut out);
+        assert_eq!((n, len), (5, 4 * SECTOR_SIZE));
+        assert_eq!(out[1..4].iter().map(|b| b.len).collect::<Vec<_>>(), [700, 700, 648]);
+        assert!(out[1..4].iter().all(|b| !b.device_writes));
+
+        // No data: header and status only
+        assert_eq!(request_chain_sg(&hdr, Data::Out(&[]), &mut status, &limits, &mut out), (2, 0));
+        assert_eq!(out[1], Buffer::writable(&mut status));
+    }
+
+    #[test]
+    fn command_chains_carry_their_data_whole() {
+        let mut status = 0xFF;
+        let limits = Limits::new(8, Some(4096), None);
+        let mut out = [Buffer { addr: 0, len: 0, device_writes: false }; 8];
+
+        let mut id = [0u8; ID_BYTES];
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_GET_ID, 0);
+        assert_eq!(request_chain_sg(&hdr, Data::In(&mut id), &mut status, &limits, &mut out), (3, ID_BYTES));
+        assert_eq!(out[1], Buffer::writable(&mut id));
+
+        let segments = [RangeSegment { sector: 8, num_sectors: 4, flags: 0 }];
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_DISCARD, 0);
+        assert_eq!(core::mem::size_of::<RangeSegment>(), 16);
+        let bytes = unsafe { core::slice::from_raw_parts(segments.as_ptr() as *const u8, 16) };
+        assert_eq!(request_chain_sg(&hdr, Data::Out(bytes), &mut status, &limits, &mut out), (3, 16));
+        assert!(!out[1].device_writes);
+
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_FLUSH, 0);
+        assert_eq!(request_chain_sg(&hdr, Data::Out(&[]), &mut status, &limits, &mut out), (2, 0));
+    }
+
+    #[test]
+    fn ranges_split_within_limits() {
+        let mut out = [RangeSegment::default(); 4];
+
+        // 10 sectors, 4 per segment, 2 segments per request
+        let limits = RangeLimits::new(4, 2, 1);
+        assert_eq!(range_segments(100, 10, 0, &limits, &mut out), (2, 8));
+        assert_eq!(out[0], RangeSegment { sector: 100, num_sectors: 4, flags: 0 });
+        assert_eq!(out[1], RangeSegment { sector: 104, num_sectors: 4, flags: 0 });
+        assert_eq!(range_segments(108, 2, 1, &limits, &mut out), (1, 2));
+        assert_eq!(out[0], RangeSegment { sector: 108, num_sectors: 2, flags: 1 });
+
+        // Segment length kept a multiple of the alignment
+        let limits = RangeLimits::new(10, 4, 8);
+        assert_eq!(range_segments(0, 20, 0, &limits, &mut out), (3, 20));
+        assert_eq!(out[..3].iter().map(|s| s.num_sectors).collect::<Vec<_>>(), [8, 8, 4]);
+
+        // Room in `out` bounds it too, and zero limits are taken as 1
+        assert_eq!(range_segments(0, 100, 0, &RangeLimits::new(0, 0, 0), &mut out), (1, 1));
+        assert_eq!(range_segments(0, 100, 0, &RangeLimits::new(1, 8, 1), &mut out), (4, 4));
+    }
+
+    #[test]
+    fn status_bytes_map_to_errors() {
+        assert_eq!(check_status(VIRTIO_BLK_S_OK), Ok(()));
+        assert_eq!(check_status(VIRTIO_BLK_S_IOERR), Err("I/O error"));
+        assert!(check_status(VIRTIO_BLK_S_UNSUPP).is_err());
+        assert!(check_status(0xFF).is_err());
+    }
+
+    #[test]
+    fn config_fields_follow_features() {
+        use crate::mmio::FakeMmio;
+
+        let mmio = FakeMmio::new();
+        let base = 0x2000;
+        mmio.poke(base, &(1u64 << 20).to_le_bytes());
+        mmio.poke32(base + CONFIG_SIZE_MAX, 65536);
+        mmio.poke32(base + CONFIG_SEG_MAX, 254);
+        mmio.poke(base + CONFIG_GEOMETRY, &[0x10, 0x02, 16, 63]);
+        mmio.poke32(base + CONFIG_BLK_SIZE, 4096);
+        mmio.poke(base + CONFIG_TOPOLOGY, &[1, 0, 1, 0, 32, 0, 0, 0]);
+        mmio.poke16(base + CONFIG_NUM_QUEUES, 4);
+        mmio.poke32(base + CONFIG_MAX_DISCARD_SECTORS, 1 << 22);
+        mmio.poke32(base + CONFIG_MAX_DISCARD_SEG, 1);
+        mmio.poke32(base + CONFIG_DISCARD_SECTOR_ALIGNMENT, 8);
+
+        // Nothing negotiated: capacity and defaults only
+        let config = Config::read(&mmio, base, 0);
+        assert_eq!((config.capacity, config.blk_size, config.num_queues), (1 << 20, 512, 1));
+        assert_eq!((config.size_max, config.geometry, config.topology, config.discard), (None, None, None, None));
+        assert_eq!(config.blocks(), 1 << 20);
+
+        let all = VIRTIO_BLK_F_SIZE_MAX
+            | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_GEOMETRY
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_TOPOLOGY
+            | VIRTIO_BLK_F_MQ
+            | VIRTIO_BLK_F_DISCARD;
+        let config = Config::read(&mmio, base, all);
+        assert_eq!((config.size_max, config.seg_max), (Some(65536), Some(254)));
+        assert_eq!(config.geometry, Some(Geometry { cylinders: 0x210, heads: 16, sectors: 63 }));
+        assert_eq!((config.blk_size, config.sectors_per_block(), config.blocks()), (4096, 8, 1 << 17));
+        assert_eq!(config.topology, Some(Topology { physical_block_exp: 1, alignment_offset: 0, min_io_size: 1, opt_io_size: 32 }));
+        assert_eq!(config.num_queues, 4);
+        assert_eq!(config.discard, Some(RangeLimits::new(1 << 22, 1, 8)));
+        assert_eq!((config.write_zeroes, config.write_zeroes_may_unmap), (None, false));
+
+        // A block size that is no use is ignored
+        mmio.poke32(base + CONFIG_BLK_SIZE, 1000);
+        assert_eq!(Config::read(&mmio, base, all).blk_size, 512);
+    }
+
+    #[test]
+    fn requests_move_whole_blocks() {
+        let config = Config {
+            capacity: 64,
+            size_max: Some(6144),
+            seg_max: None,
+            geometry: None,
+            blk_size: 4096,
+            topology: None,
+            num_queues: 1,
+            discard: None,
+            write_zeroes: None,
+            write_zeroes_may_unmap: false,
+        };
+        let limits = Limits::for_config(4, &config).unwrap();
+        assert_eq!((limits.seg_max, limits.max_request()), (2, 12288));
+
+        let hdr = ReqHeader::new(VIRTIO_BLK_T_IN, 0);
+        let mut data = vec![0u8; 4 * 4096];
+        let mut status = 0xFF;
+        let mut out = [Buffer { addr: 0, len: 0, device_writes: false }; 4];
+        assert_eq!(request_chain_sg(&hdr, Data::In(&mut data), &mut status, &limits, &mut out), (4, 12288));
+        // Short of a block: nothing to send
+        assert_eq!(request_chain_sg(&hdr, Data::In(&mut data[..4000]), &mut status, &limits, &mut out).1, 0);
+    }
+
+    #[test]
+    fn segments_too_small_for_a_block_are_refused() {
+        let config = Config {
+            capacity: 64,
+            size_max: Some(256),
+            seg_max: Some(1),
+            geometry: None,
+            blk_size: 512,
+            topology: None,
+            num_queues: 1,
+            discard: None,
+            write_zeroes: None,
+            write_zeroes_may_unmap: false,
+        };
+        assert_eq!(Limits::for_config(8, &config), Err("device segments cannot hold one block"));
+        // Two segments are enough
+        assert_eq!(Limits::for_config(8, &Config { seg_max: Some(2), ..config }).unwrap().max_request(), 512);
+        // And so is a deeper queue holding more of them, when seg_max allows
+        assert!(Limits::for_config(4, &Config { seg_max: None, ..config }).is_ok());
+        assert!(Limits::for_config(3, &Config { seg_max: None, ..config }).is_err());
+    }
+
+    #[test]
+    fn tagged_requests_complete_in_any_order() {
+        let mut reqs = Requests::<3>::new();
+        let tags: Vec<usize> = (0..3).map(|i| reqs.claim(VIRTIO_BLK_T_IN, i * 8).unwrap()).collect();
+        assert_eq!(reqs.claim(VIRTIO_BLK_T_IN, 0), None);
+        assert_eq!(reqs.parts(tags[2]).0.sector, 16);
+        for (&tag, head) in tags.iter().zip([0, 3, 6]) {
+            reqs.submitted(tag, head);
+        }
+        assert_eq!(reqs.take(tags[0]), None);
+
+        // The device answers the last first, and fails the middle one
+        *reqs.parts(tags[2]).1 = VIRTIO_BLK_S_OK;
+        *reqs.parts(tags[1]).1 = VIRTIO_BLK_S_IOERR;
+        assert_eq!(reqs.complete(6), Some(tags[2]));
+        assert_eq!(reqs.complete(3), Some(tags[1]));
+        assert_eq!(reqs.complete(9), None);
+        assert_eq!(reqs.take(tags[1]), Some(Err("I/O error")));
+        assert_eq!(reqs.take(tags[2]), Some(Ok(())));
+        assert_eq!(reqs.take(tags[2]), None);
+        assert_eq!(reqs.outstanding(), 1);
+
+        // An abandoned request holds its slot until the device gives it back
+        reqs.abandon(tags[0]);
+        let fresh = [reqs.claim(VIRTIO_BLK_T_OUT, 0).unwrap(), reqs.claim(VIRTIO_BLK_T_OUT, 0).unwrap()];
+        assert!(!fresh.contains(&tags[0]));
+        assert_eq!(reqs.claim(VIRTIO_BLK_T_OUT, 0), None);
+        assert_eq!(reqs.complete(0), None);
+        reqs.cancel(fresh[0]);
+        assert_eq!(reqs.outstanding(), 1);
+    }
+}
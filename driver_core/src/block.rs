@@ -0,0 +1,414 @@
+//! Block devices, whatever backs them
+//!
+//! `BlockDevice` is what filesystems see: fixed-size blocks to read, write
+//! and flush. The kernel implements it for virtio-blk; here are a RAM disk,
+//! a partition (a window onto blocks of another device) and `BlockCache`,
+//! an LRU write-back cache that is a BlockDevice itself, so layers stack.
+//!
+//! Methods take `&self`, like virtio-blk's, so several partitions can
+//! share the disk under them; the RAM disk and the cache keep their state
+//! in RefCells. No heap: the cache's blocks live inline in it.
+
+use core::cell::{Cell, RefCell};
+
+/// Storage in fixed-size blocks
+pub trait BlockDevice {
+    /// Bytes per block
+    fn block_size(&self) -> usize;
+
+    /// Size in blocks
+    fn block_count(&self) -> u64;
+
+    /// Read `buf.len() / block_size()` blocks starting at block `lba`
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Write `buf` (whole blocks) starting at block `lba`
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str>;
+
+    /// Make completed writes durable
+    fn flush(&self) -> Result<(), &'static str>;
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        (**self).read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        (**self).write_blocks(lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        (**self).flush()
+    }
+}
+
+/// Check `len` bytes at block `lba` are whole blocks on `dev`; how many
+pub fn check_range(dev: &(impl BlockDevice + ?Sized), lba: u64, len: usize) -> Result<u64, &'static str> {
+    if !len.is_multiple_of(dev.block_size()) {
+        return Err("not a whole number of blocks");
+    }
+    let count = (len / dev.block_size()) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= dev.block_count() => Ok(count),
+        _ => Err("past the end of the disk"),
+    }
+}
+
+/// A disk in memory
+pub struct RamDisk<'a> {
+    data: RefCell<&'a mut [u8]>,
+    block_size: usize,
+}
+
+impl<'a> RamDisk<'a> {
+    /// Disk of `block_size`-byte blocks over `data`; a partial block at the
+    /// end is not used. None if `block_size` is 0.
+    pub fn new(data: &'a mut [u8], block_size: usize) -> Option<Self> {
+        (block_size > 0).then(|| RamDisk { data: RefCell::new(data), block_size })
+    }
+
+    fn offset(&self, lba: u64) -> usize {
+        lba as usize * self.block_size
+    }
+}
+
+impl BlockDevice for RamDisk<'_> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.borrow().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let start = self.offset(lba);
+        buf.copy_from_slice(&self.data.borrow()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let start = self.offset(lba);
+        self.data.borrow_mut()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// Blocks [start, start + count) of `dev`, as a device of their own
+pub struct Partition<D> {
+    dev: D,
+    start: u64,
+    count: u64,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// None if the blocks are not all on `dev`
+    pub fn new(dev: D, start: u64, count: u64) -> Option<Self> {
+        (start.checked_add(count)? <= dev.block_count()).then_some(Partition { dev, start, count })
+    }
+
+    /// First block on the device underneath
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        self.dev.read_blocks(self.start + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        self.dev.write_blocks(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.dev.flush()
+    }
+}
+
+/// One cached block
+struct Line<const B: usize> {
+    /// Block held, if any
+    lba: Option<u64>,
+    /// Changed since it was read or last written back
+    dirty: bool,
+    /// Cache clock at the last access, for LRU eviction
+    last_used: u64,
+    data: [u8; B],
+}
+
+/// Up to `N` blocks of `B` bytes of `dev`, written back lazily
+///
+/// Reads are served from the cache where possible. Writes only change the
+/// cache; a dirty block reaches the device when it is evicted (least
+/// recently used first) or at sync(), which is also what flush() does.
+/// Dropping the cache loses whatever sync() has not written back.
+pub struct BlockCache<D, const N: usize, const B: usize> {
+    dev: D,
+    lines: RefCell<[Line<B>; N]>,
+    clock: Cell<u64>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<D: BlockDevice, const N: usize, const B: usize> BlockCache<D, N, B> {
+    /// None unless `dev` has `B`-byte blocks (and `N` is not 0)
+    pub fn new(dev: D) -> Option<Self> {
+        if dev.block_size() != B || N == 0 {
+            return None;
+        }
+        Some(BlockCache {
+            dev,
+            lines: RefCell::new(core::array::from_fn(|_| Line { lba: None, dirty: false, last_used: 0, data: [0; B] })),
+            clock: Cell::new(0),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        })
+    }
+
+    /// The device underneath
+    pub fn device(&self) -> &D {
+        &self.dev
+    }
+
+    /// Blocks changed in the cache and not yet written back
+    pub fn dirty(&self) -> usize {
+        self.lines.borrow().iter().filter(|l| l.dirty).count()
+    }
+
+    /// Accesses served from the cache, and those that went to the device
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.get(), self.misses.get())
+    }
+
+    /// Write every dirty block back, in block order, then flush the device
+    pub fn sync(&self) -> Result<(), &'static str> {
+        let mut lines = self.lines.borrow_mut();
+        while let Some(line) = lines.iter_mut().filter(|l| l.dirty).min_by_key(|l| l.lba) {
+            Self::write_back(&self.dev, line)?;
+        }
+        self.dev.flush()
+    }
+
+    fn write_back(dev: &D, line: &mut Line<B>) -> Result<(), &'static str> {
+        if let (true, Some(lba)) = (line.dirty, line.lba) {
+            dev.write_blocks(lba, &line.data)?;
+        }
+        line.dirty = false;
+        Ok(())
+    }
+
+    /// The line holding block `lba`, reading it in if `load` (a caller
+    /// about to overwrite the whole block need not)
+    fn line<'l>(&self, lines: &'l mut [Line<B>; N], lba: u64, load: bool) -> Result<&'l mut Line<B>, &'static str> {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+
+        let index = match lines.iter().position(|l| l.lba == Some(lba)) {
+            Some(index) => {
+                self.hits.set(self.hits.get() + 1);
+                index
+            }
+            None => {
+                self.misses.set(self.misses.get() + 1);
+                // An empty line, or else the least recently used
+                let index = match lines.iter().position(|l| l.lba.is_none()) {
+                    Some(index) => index,
+                    None => (0..N).min_by_key(|&i| lines[i].last_used).unwrap_or(0),
+                };
+                let line = &mut lines[index];
+                Self::write_back(&self.dev, line)?;
+                line.lba = None;
+                if load {
+                    self.dev.read_blocks(lba, &mut line.data)?;
+                }
+                line.lba = Some(lba);
+                index
+            }
+        };
+        let line = &mut lines[index];
+        line.last_used = now;
+        Ok(line)
+    }
+}
+
+impl<D: BlockDevice, const N: usize, const B: usize> BlockDevice for BlockCache<D, N, B> {
+    fn block_size(&self) -> usize {
+        B
+    }
+
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let mut lines = self.lines.borrow_mut();
+        for (block, chunk) in (lba..).zip(buf.chunks_exact_mut(B)) {
+            chunk.copy_from_slice(&self.line(&mut lines, block, true)?.data);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let mut lines = self.lines.borrow_mut();
+        for (block, chunk) in (lba..).zip(buf.chunks_exact(B)) {
+            let line = self.line(&mut lines, block, false)?;
+            line.data.copy_from_slice(chunk);
+            line.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the writes that reach the device underneath
+    struct Counting<'a> {
+        disk: RamDisk<'a>,
+        writes: Cell<usize>,
+        flushes: Cell<usize>,
+    }
+
+    impl BlockDevice for Counting<'_> {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+
+        fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+            self.disk.read_blocks(lba, buf)
+        }
+
+        fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+            self.writes.set(self.writes.get() + buf.len() / self.block_size());
+            self.disk.write_blocks(lba, buf)
+        }
+
+        fn flush(&self) -> Result<(), &'static str> {
+            self.flushes.set(self.flushes.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ram_disk_reads_back_whole_blocks() {
+        let mut data = vec![0u8; 4 * 512 + 100];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        assert_eq!(disk.block_count(), 4);
+
+        disk.write_blocks(1, &[7; 1024]).unwrap();
+        let mut buf = [0; 1536];
+        disk.read_blocks(0, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0) && buf[512..].iter().all(|&b| b == 7));
+
+        assert_eq!(disk.read_blocks(0, &mut buf[..100]), Err("not a whole number of blocks"));
+        assert_eq!(disk.write_blocks(3, &[0; 1024]), Err("past the end of the disk"));
+        assert!(RamDisk::new(&mut [], 0).is_none());
+    }
+
+    #[test]
+    fn partition_is_a_window_onto_the_disk() {
+        let mut data: Vec<u8> = (0..8 * 512).map(|i| (i / 512) as u8).collect();
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        assert!(Partition::new(&disk, 6, 3).is_none());
+        let part = Partition::new(&disk, 2, 4).unwrap();
+        assert_eq!((part.start(), part.block_count(), part.block_size()), (2, 4, 512));
+
+        let mut buf = [0; 512];
+        part.read_blocks(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 3));
+        part.write_blocks(3, &[0xee; 512]).unwrap();
+        assert_eq!(part.write_blocks(4, &[0; 512]), Err("past the end of the disk"));
+
+        disk.read_blocks(5, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xee));
+        disk.read_blocks(6, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 6));
+    }
+
+    #[test]
+    fn cache_writes_back_on_sync_and_eviction() {
+        let mut data = vec![0u8; 16 * 512];
+        let dev = Counting { disk: RamDisk::new(&mut data, 512).unwrap(), writes: Cell::new(0), flushes: Cell::new(0) };
+        assert!(BlockCache::<_, 2, 1024>::new(&dev).is_none());
+        let cache = BlockCache::<_, 2, 512>::new(&dev).unwrap();
+
+        // Two blocks fit: nothing reaches the disk until sync
+        cache.write_blocks(0, &[1; 1024]).unwrap();
+        assert_eq!((cache.dirty(), dev.writes.get()), (2, 0));
+        let mut buf = [0; 512];
+        cache.read_blocks(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+        dev.disk.read_blocks(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(cache.stats(), (1, 2));
+
+        // Block 0 is the least recently used, so block 5 evicts it
+        cache.read_blocks(5, &mut buf).unwrap();
+        assert_eq!((cache.dirty(), dev.writes.get()), (1, 1));
+        dev.disk.read_blocks(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+
+        cache.flush().unwrap();
+        assert_eq!((cache.dirty(), dev.writes.get(), dev.flushes.get()), (0, 2, 1));
+        dev.disk.read_blocks(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+
+        // Clean blocks are dropped without a write
+        cache.read_blocks(6, &mut [0; 1024]).unwrap();
+        assert_eq!(dev.writes.get(), 2);
+        assert_eq!(cache.read_blocks(15, &mut [0; 1024]), Err("past the end of the disk"));
+    }
+
+    #[test]
+    fn layers_stack() {
+        let mut data = vec![0u8; 8 * 512];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        let cache = BlockCache::<_, 4, 512>::new(Partition::new(&disk, 4, 4).unwrap()).unwrap();
+        let layers: &dyn BlockDevice = &cache;
+        layers.write_blocks(0, &[9; 512]).unwrap();
+        layers.flush().unwrap();
+
+        let mut buf = [0; 512];
+        disk.read_blocks(4, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 9));
+        assert_eq!(cache.device().start(), 4);
+    }
+}
@@ -0,0 +1,258 @@
+//! CPIO "newc" archives, the initramfs format
+//!
+//! Each member is a 110-byte ASCII header ("070701" and thirteen 8-digit hex
+//! fields), the NUL-terminated path, then the data; the header plus path and
+//! the data are each padded to 4 bytes. A member named "TRAILER!!!" ends the
+//! archive. Like Linux, several archives may be concatenated, with NUL
+//! padding in between; later members replace earlier ones of the same path.
+//!
+//! Read-only: members are borrowed straight out of the archive.
+
+/// newc magic
+pub const MAGIC: &[u8; 6] = b"070701";
+/// newc with a checksum in the `check` field (which is not verified here)
+pub const MAGIC_CRC: &[u8; 6] = b"070702";
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+// Header fields, in order after the magic
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+// File type bits of `mode`
+pub const S_IFMT: u32 = 0o170_000;
+pub const S_IFDIR: u32 = 0o040_000;
+pub const S_IFREG: u32 = 0o100_000;
+pub const S_IFLNK: u32 = 0o120_000;
+
+const fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// One archive member
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Path without any leading "/" or "./" ("" for the root directory)
+    pub path: &'a str,
+    pub mode: u32,
+    /// File contents, or the target of a symlink
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// Strip the prefixes archivers put on paths ("./etc/", "/etc" -> "etc")
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_end_matches('/');
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if path == "." {
+            return "";
+        } else {
+            return path;
+        }
+    }
+}
+
+/// A newc archive in memory
+#[derive(Clone, Copy, Debug)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// None unless `data` starts with a newc header
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let magic = data.get(..MAGIC.len())?;
+        (magic == MAGIC || magic == MAGIC_CRC).then_some(Archive { data })
+    }
+
+    /// Members in archive order
+    pub fn entries(&self) -> Entries<'a> {
+        Entries { data: self.data, offset: 0, start: 0 }
+    }
+
+    /// Member whose header is `offset` bytes in, as from Entries::start()
+    pub fn entry_at(&self, offset: usize) -> Option<Entry<'a>> {
+        Entries { data: self.data, offset, start: offset }.next()
+    }
+
+    /// Member at `path` (leading "/" or "./" optional)
+    pub fn find(&self, path: &str) -> Option<Entry<'a>> {
+        let path = normalize(path);
+        self.entries().filter(|e| e.path == path).last()
+    }
+
+    /// Contents of the regular file at `path`
+    pub fn file(&self, path: &str) -> Option<&'a [u8]> {
+        self.find(path).filter(Entry::is_file).map(|e| e.data)
+    }
+}
+
+/// Iterator over an archive's members
+///
+/// Stops at the last trailer, or early at the first malformed header.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    /// Header of the member last returned
+    start: usize,
+}
+
+impl Entries<'_> {
+    /// Where the member last returned starts, for Archive::entry_at()
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    fn field(&self, index: usize) -> Option<u32> {
+        let start = self.offset + MAGIC.len() + index * 8;
+        let digits = core::str::from_utf8(self.data.get(start..start + 8)?).ok()?;
+        u32::from_str_radix(digits, 16).ok()
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        loop {
+            self.start = self.offset;
+            let magic = self.data.get(self.offset..self.offset + MAGIC.len())?;
+            if magic != MAGIC && magic != MAGIC_CRC {
+                self.offset = self.data.len();
+                return None;
+            }
+            let mode = self.field(FIELD_MODE)?;
+            let filesize = self.field(FIELD_FILESIZE)? as usize;
+            let namesize = self.field(FIELD_NAMESIZE)? as usize;
+
+            // Name includes its NUL terminator
+            let name_start = self.offset + HEADER_SIZE;
+            let name = self.data.get(name_start..(name_start + namesize).checked_sub(1)?)?;
+            let data_start = align4(name_start + namesize);
+            let data = self.data.get(data_start..data_start + filesize)?;
+            self.offset = align4(data_start + filesize);
+
+            if name == TRAILER {
+                // Skip the padding before a concatenated archive, if any
+                match self.data[self.offset.min(self.data.len())..].iter().position(|&b| b != 0) {
+                    Some(skip) => self.offset += skip,
+                    None => return None,
+                }
+                continue;
+            }
+            let path = normalize(core::str::from_utf8(name).ok()?);
+            return Some(Entry { path, mode, data });
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// newc member as `cpio -H newc` writes it
+    pub(crate) fn member(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(MAGIC);
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08X}").as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    pub(crate) fn initramfs() -> Vec<u8> {
+        let mut archive = Vec::new();
+        member(&mut archive, ".", S_IFDIR | 0o755, b"");
+        member(&mut archive, "./etc", S_IFDIR | 0o755, b"");
+        member(&mut archive, "./etc/hostname", S_IFREG | 0o644, b"unikernel\n");
+        member(&mut archive, "./init", S_IFLNK | 0o777, b"bin/app");
+        member(&mut archive, "./bin/app", S_IFREG | 0o755, b"\x7fELF");
+        member(&mut archive, "TRAILER!!!", 0, b"");
+        // cpio pads the whole archive to a 512-byte block
+        archive.resize(archive.len().next_multiple_of(512), 0);
+        archive
+    }
+
+    #[test]
+    fn finds_files_by_path() {
+        let data = initramfs();
+        let archive = Archive::new(&data).unwrap();
+        assert_eq!(archive.file("etc/hostname"), Some(&b"unikernel\n"[..]));
+        assert_eq!(archive.file("/etc/hostname"), archive.file("./etc/hostname"));
+        assert_eq!(archive.file("/bin/app"), Some(&b"\x7fELF"[..]));
+        // Directories and symlinks are not files
+        assert_eq!(archive.file("etc"), None);
+        assert_eq!(archive.file("init"), None);
+        assert!(archive.find("/etc/").unwrap().is_dir());
+        assert_eq!(archive.find("init").unwrap().data, b"bin/app");
+        assert_eq!(archive.find("etc/passwd"), None);
+    }
+
+    #[test]
+    fn lists_entries_up_to_the_trailer() {
+        let data = initramfs();
+        let archive = Archive::new(&data).unwrap();
+        let paths: Vec<_> = archive.entries().map(|e| e.path).collect();
+        assert_eq!(paths, ["", "etc", "etc/hostname", "init", "bin/app"]);
+
+        // Members can be found again by where they start
+        let mut entries = archive.entries();
+        let hostname = entries.nth(2).unwrap();
+        assert_eq!(archive.entry_at(entries.start()), Some(hostname));
+        assert_eq!(archive.entry_at(entries.start() + 1), None);
+    }
+
+    #[test]
+    fn later_archives_override_earlier_ones() {
+        let mut data = initramfs();
+        let mut overlay = Vec::new();
+        member(&mut overlay, "etc/hostname", S_IFREG | 0o644, b"test\n");
+        member(&mut overlay, "TRAILER!!!", 0, b"");
+        data.extend(overlay);
+
+        let archive = Archive::new(&data).unwrap();
+        assert_eq!(archive.entries().count(), 6);
+        assert_eq!(archive.file("etc/hostname"), Some(&b"test\n"[..]));
+    }
+
+    #[test]
+    fn malformed_data_ends_the_walk() {
+        assert!(Archive::new(b"070707").is_none());
+        assert!(Archive::new(b"0707").is_none());
+
+        let data = initramfs();
+        // Truncated inside the third member's data
+        let cut = data.windows(10).position(|w| w == b"unikernel\n").unwrap() + 4;
+        let archive = Archive::new(&data[..cut]).unwrap();
+        assert_eq!(archive.entries().count(), 2);
+        assert_eq!(archive.file("etc/hostname"), None);
+
+        // Bad hex in a header
+        let mut data = initramfs();
+        data[6 + 8] = b'x';
+        assert_eq!(Archive::new(&data).unwrap().entries().count(), 0);
+    }
+}
@@ -0,0 +1,526 @@
+//! Flattened device tree reader
+//!
+//! Just enough of the DTB format to find what the kernel needs at boot: the
+//! PCI MMIO window and ECAM base, /chosen properties and the GIC. Works on
+//! a byte slice, so malformed blobs end the walk instead of reading past
+//! the end.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn be64(data: &[u8], off: usize) -> Option<u64> {
+    Some((be32(data, off)? as u64) << 32 | be32(data, off + 4)? as u64)
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Interrupt controller found in the DTB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GicInfo {
+    /// 2 or 3
+    pub version: u8,
+    /// Distributor base
+    pub dist_base: u64,
+    /// GICv2 CPU interface or GICv3 redistributor region
+    pub cpu_base: u64,
+}
+
+/// A validated device tree blob
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+enum Token<'a> {
+    BeginNode(&'a [u8]),
+    EndNode,
+    Prop(&'a [u8], &'a [u8]),
+}
+
+struct Tokens<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let token = be32(self.structs, self.pos)?;
+            self.pos += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let rest = self.structs.get(self.pos..)?;
+                    let len = rest.iter().position(|&b| b == 0)?;
+                    self.pos += align4(len + 1);
+                    return Some(Token::BeginNode(&rest[..len]));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = be32(self.structs, self.pos)? as usize;
+                    let nameoff = be32(self.structs, self.pos + 4)? as usize;
+                    let value = self.structs.get(self.pos + 8..self.pos + 8 + len)?;
+                    self.pos += 8 + align4(len);
+
+                    let name = self.strings.get(nameoff..)?;
+                    let name = &name[..name.iter().position(|&b| b == 0)?];
+                    return Some(Token::Prop(name, value));
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                // Unknown token: the rest cannot be parsed
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl<'a> Fdt<'a> {
+    /// Check the header and locate the structure and strings blocks
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = be32(data, 4)? as usize;
+        let data = data.get(..total_size)?;
+        if total_size < FDT_HEADER_SIZE {
+            return None;
+        }
+
+        let off_struct = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let size_strings = be32(data, 32)? as usize;
+        let size_struct = be32(data, 36)? as usize;
+
+        Some(Fdt {
+            structs: data.get(off_struct..off_struct + size_struct)?,
+            strings: data.get(off_strings..off_strings + size_strings)?,
+        })
+    }
+
+    /// # Safety
+    /// `ptr` must point to a DTB that stays mapped and unmodified for `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Option<Self> {
+        if ptr.is_null() {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(ptr, 8);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = be32(header, 4)? as usize;
+        Self::new(core::slice::from_raw_parts(ptr, total_size))
+    }
+
+    fn tokens(&self) -> Tokens<'a> {
+        Tokens { structs: self.structs, strings: self.strings, pos: 0 }
+    }
+
+    /// Find the PCI 32-bit MMIO window from the host bridge's `ranges`
+    /// Returns (base_address, size) if found
+    pub fn pci_mmio_window(&self) -> Option<(u64, u64)> {
+        let mut depth = 0u32;
+        let mut pci_depth: Option<u32> = None;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    if pci_depth.is_none() && name.starts_with(b"pci") {
+                        pci_depth = Some(depth);
+                    }
+                }
+                Token::EndNode => {
+                    if pci_depth == Some(depth) {
+                        pci_depth = None;
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                Token::Prop(b"ranges", value) if pci_depth.is_some() => {
+                    // <child_hi child_mid child_lo parent_hi parent_lo size_hi size_lo>
+                    // child_hi bits 24-25 give the space: 2 = 32-bit MMIO, 3 = 64-bit
+                    for entry in value.chunks_exact(28) {
+                        let child_hi = be32(entry, 0)?;
+                        if child_hi & 0x0300_0000 != 0x0200_0000 {
+                            continue;
+                        }
+                        let base = be64(entry, 12)?;
+                        let size = be64(entry, 20)?;
+                        if base != 0 && size != 0 {
+                            return Some((base, size));
+                        }
+                    }
+                }
+                Token::Prop(..) => {}
+            }
+        }
+        None
+    }
+
+    /// ECAM base from the host bridge's `reg`
+    pub fn ecam_base(&self) -> Option<u64> {
+        let mut depth = 0u32;
+        let mut pci_depth: Option<u32> = None;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    if pci_depth.is_none() && name.starts_with(b"pci") {
+                        pci_depth = Some(depth);
+                    }
+                }
+                Token::EndNode => {
+                    if pci_depth == Some(depth) {
+                        pci_depth = None;
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                Token::Prop(b"reg", value) if pci_depth == Some(depth) && value.len() >= 16 => {
+                    let base = be64(value, 0)?;
+                    if base != 0 {
+                        return Some(base);
+                    }
+                }
+                Token::Prop(..) => {}
+            }
+        }
+        None
+    }
+
+    /// Value of a property of the /chosen node
+    pub fn chosen_property(&self, prop: &str) -> Option<&'a [u8]> {
+        let mut depth = 0u32;
+        let mut in_chosen = false;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    // /chosen is a direct child of the root node (depth 1)
+                    if depth == 2 {
+                        in_chosen = name == b"chosen";
+                    }
+                }
+                Token::EndNode => {
+                    if depth == 2 {
+                        in_chosen = false;
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                Token::Prop(name, value) => {
+                    if in_chosen && depth == 2 && name == prop.as_bytes() {
+                        return Some(value);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The kernel command line (/chosen/bootargs)
+    pub fn bootargs(&self) -> Option<&'a str> {
+        let value = self.chosen_property("bootargs")?;
+        // Property value is NUL-terminated; drop the terminator
+        let len = value.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        core::str::from_utf8(&value[..len]).ok()
+    }
+
+    /// Initrd the loader placed in RAM (/chosen linux,initrd-start/end)
+    /// Returns (start, end); each value may be one cell or two
+    pub fn initrd(&self) -> Option<(u64, u64)> {
+        let addr = |prop| {
+            let value = self.chosen_property(prop)?;
+            match value.len() {
+                4 => be32(value, 0).map(u64::from),
+                8 => be64(value, 0),
+                _ => None,
+            }
+        };
+        let (start, end) = (addr("linux,initrd-start")?, addr("linux,initrd-end")?);
+        (start <= end).then_some((start, end))
+    }
+
+    /// First RAM bank from the /memory node's `reg`
+    /// Returns (base_address, size); assumes #address-cells = #size-cells = 2
+    pub fn memory(&self) -> Option<(u64, u64)> {
+        let mut depth = 0u32;
+        let mut memory_depth: Option<u32> = None;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    if depth == 2 && (name == b"memory" || name.starts_with(b"memory@")) {
+                        memory_depth = Some(depth);
+                    }
+                }
+                Token::EndNode => {
+                    if memory_depth == Some(depth) {
+                        memory_depth = None;
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                Token::Prop(b"reg", value) if memory_depth == Some(depth) && value.len() >= 16 => {
+                    return Some((be64(value, 0)?, be64(value, 8)?));
+                }
+                Token::Prop(..) => {}
+            }
+        }
+        None
+    }
+
+    /// Find the GIC node (arm,gic-v3 or a GICv2 compatible)
+    /// Assumes #address-cells = #size-cells = 2, as on QEMU virt and VZ
+    pub fn gic(&self) -> Option<GicInfo> {
+        // Properties of the node being walked; they precede its children
+        let mut version: Option<u8> = None;
+        let mut regs: Option<(u64, u64)> = None;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(_) | Token::EndNode => {
+                    if let (Some(version), Some((dist_base, cpu_base))) = (version, regs) {
+                        return Some(GicInfo { version, dist_base, cpu_base });
+                    }
+                    version = None;
+                    regs = None;
+                }
+                Token::Prop(b"compatible", value) => {
+                    // NUL-separated list of strings
+                    for compat in value.split(|&b| b == 0) {
+                        match compat {
+                            b"arm,gic-v3" => version = Some(3),
+                            b"arm,cortex-a15-gic" | b"arm,gic-400" => version = Some(2),
+                            _ => {}
+                        }
+                    }
+                }
+                Token::Prop(b"reg", value) if value.len() >= 32 => {
+                    // <dist_base dist_size cpu_base cpu_size ...>
+                    regs = Some((be64(value, 0)?, be64(value, 16)?));
+                }
+                Token::Prop(..) => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal DTB writer for building test trees
+    #[derive(Default)]
+    struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.structs.extend(FDT_BEGIN_NODE.to_be_bytes());
+            self.structs.extend(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.structs.extend(FDT_END_NODE.to_be_bytes());
+            self
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let nameoff = self.strings.len() as u32;
+            self.strings.extend(name.as_bytes());
+            self.strings.push(0);
+            self.structs.extend(FDT_PROP.to_be_bytes());
+            self.structs.extend((value.len() as u32).to_be_bytes());
+            self.structs.extend(nameoff.to_be_bytes());
+            self.structs.extend(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn pad(&mut self) {
+            while !self.structs.len().is_multiple_of(4) {
+                self.structs.push(0);
+            }
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.structs.extend(FDT_END.to_be_bytes());
+            let off_struct = FDT_HEADER_SIZE + 16; // header + empty reservation map
+            let off_strings = off_struct + self.structs.len();
+            let total = off_strings + self.strings.len();
+
+            let header = [
+                FDT_MAGIC,
+                total as u32,
+                off_struct as u32,
+                off_strings as u32,
+                FDT_HEADER_SIZE as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+            blob.extend([0u8; 16]);
+            blob.extend(&self.structs);
+            blob.extend(&self.strings);
+            blob
+        }
+    }
+
+    /// Roughly what QEMU's virt machine generates
+    fn virt_dtb() -> Vec<u8> {
+        let mut b = Builder::default();
+        b.begin("")
+            .cells("#address-cells", &[2])
+            .begin("chosen")
+            .prop("bootargs", b"console=hvc0 tests=all\0")
+            .cells("linux,initrd-start", &[0, 0x4800_0000])
+            .end()
+            .begin("memory@40000000")
+            .prop("device_type", b"memory\0")
+            .cells("reg", &[0, 0x4000_0000, 0, 0x8000_0000])
+            .end()
+            .begin("intc@8000000")
+            .prop("compatible", b"arm,cortex-a15-gic\0")
+            .cells("reg", &[0, 0x0800_0000, 0, 0x1_0000, 0, 0x0801_0000, 0, 0x1_0000])
+            .begin("v2m@8020000")
+            .cells("reg", &[0, 0x0802_0000, 0, 0x1000])
+            .end()
+            .end()
+            .begin("pcie@10000000")
+            .prop("compatible", b"pci-host-ecam-generic\0")
+            .cells("reg", &[0x40, 0x1000_0000, 0, 0x1000_0000])
+            .cells(
+                "ranges",
+                &[
+                    0x0100_0000, 0, 0, 0, 0x3eff_0000, 0, 0x1_0000, // I/O
+                    0x0200_0000, 0, 0x1000_0000, 0, 0x1000_0000, 0, 0x2eff_0000, // 32-bit
+                    0x0300_0000, 0x80, 0, 0x80, 0, 0x80, 0, // 64-bit
+                ],
+            )
+            .end()
+            .end()
+            .finish()
+    }
+
+    #[test]
+    fn finds_pci_window_and_ecam() {
+        let blob = virt_dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.pci_mmio_window(), Some((0x1000_0000, 0x2eff_0000)));
+        assert_eq!(fdt.ecam_base(), Some(0x40_1000_0000));
+    }
+
+    #[test]
+    fn finds_first_memory_bank() {
+        let blob = virt_dtb();
+        assert_eq!(Fdt::new(&blob).unwrap().memory(), Some((0x4000_0000, 0x8000_0000)));
+    }
+
+    #[test]
+    fn finds_gic_on_its_own_node() {
+        let blob = virt_dtb();
+        let gic = Fdt::new(&blob).unwrap().gic().unwrap();
+        assert_eq!(gic, GicInfo { version: 2, dist_base: 0x0800_0000, cpu_base: 0x0801_0000 });
+    }
+
+    #[test]
+    fn reads_chosen_properties() {
+        let blob = virt_dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.bootargs(), Some("console=hvc0 tests=all"));
+        assert_eq!(fdt.chosen_property("linux,initrd-start"), Some(&[0, 0, 0, 0, 0x48, 0, 0, 0][..]));
+        assert_eq!(fdt.chosen_property("linux,initrd-end"), None);
+        // Same-named property elsewhere in the tree is not /chosen's
+        assert_eq!(fdt.chosen_property("compatible"), None);
+    }
+
+    #[test]
+    fn missing_nodes_give_none() {
+        let blob = Builder::default().begin("").end().finish();
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.pci_mmio_window(), None);
+        assert_eq!(fdt.ecam_base(), None);
+        assert_eq!(fdt.bootargs(), None);
+        assert_eq!(fdt.gic(), None);
+        assert_eq!(fdt.memory(), None);
+        assert_eq!(fdt.initrd(), None);
+    }
+
+    #[test]
+    fn reads_initrd_range_of_either_width() {
+        let blob = Builder::default()
+            .begin("")
+            .begin("chosen")
+            .cells("linux,initrd-start", &[0x4800_0000])
+            .cells("linux,initrd-end", &[0, 0x4810_0000])
+            .end()
+            .end()
+            .finish();
+        assert_eq!(Fdt::new(&blob).unwrap().initrd(), Some((0x4800_0000, 0x4810_0000)));
+
+        // Only the start, as in virt_dtb()
+        let blob = virt_dtb();
+        assert_eq!(Fdt::new(&blob).unwrap().initrd(), None);
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut blob = virt_dtb();
+        assert!(Fdt::new(&blob[..20]).is_none());
+        assert!(Fdt::new(&blob[..blob.len() - 1]).is_none());
+        blob[0] = 0;
+        assert!(Fdt::new(&blob).is_none());
+    }
+
+    #[test]
+    fn truncated_property_ends_walk() {
+        let mut blob = virt_dtb();
+        // Make the first property claim far more data than the block holds
+        let off_struct = FDT_HEADER_SIZE + 16;
+        let prop_len = off_struct + 8 + 4;
+        blob[prop_len..prop_len + 4].copy_from_slice(&0x10_0000u32.to_be_bytes());
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.bootargs(), None);
+        assert_eq!(fdt.pci_mmio_window(), None);
+    }
+
+    #[test]
+    fn from_ptr_reads_total_size_from_header() {
+        let blob = virt_dtb();
+        let fdt = unsafe { Fdt::from_ptr(blob.as_ptr()) }.unwrap();
+        assert_eq!(fdt.bootargs(), Some("console=hvc0 tests=all"));
+        assert!(unsafe { Fdt::from_ptr(core::ptr::null()) }.is_none());
+    }
+}
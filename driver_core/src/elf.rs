@@ -0,0 +1,340 @@
+//! ELF64 executables for AArch64, and the initial stack they start on
+//!
+//! Enough to load a static binary: the header, its PT_LOAD segments and
+//! where the program headers land in memory (for AT_PHDR). The stack is laid
+//! out the way Linux does it and libc start-up code expects: argc, argv[],
+//! NULL, envp[], NULL, then auxv pairs ending in AT_NULL, with the strings
+//! and AT_RANDOM's bytes above them.
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_AARCH64: u16 = 183;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+// Segment flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// Auxiliary vector keys
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+fn u16_at(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?))
+}
+
+/// One program header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl Segment {
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A little-endian ELF64 AArch64 executable
+#[derive(Clone, Copy, Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    /// ET_EXEC, or ET_DYN for a position-independent one
+    pub kind: u16,
+    pub entry: u64,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.get(..6) != Some(b"\x7fELF\x02\x01") {
+            return Err("not a little-endian ELF64 file");
+        }
+        if data.len() < EHDR_SIZE || u16_at(data, 0x12) != Some(EM_AARCH64) {
+            return Err("not an AArch64 ELF file");
+        }
+        let kind = u16_at(data, 0x10).unwrap_or(0);
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err("not an executable");
+        }
+        let elf = Elf {
+            data,
+            kind,
+            entry: u64_at(data, 0x18).unwrap_or(0),
+            phoff: u64_at(data, 0x20).unwrap_or(0) as usize,
+            phentsize: u16_at(data, 0x36).unwrap_or(0) as usize,
+            phnum: u16_at(data, 0x38).unwrap_or(0) as usize,
+        };
+        let table_end = elf.phentsize.checked_mul(elf.phnum).and_then(|n| n.checked_add(elf.phoff));
+        if elf.phentsize < PHDR_SIZE || table_end.is_none_or(|end| end > data.len()) {
+            return Err("program headers are malformed");
+        }
+        Ok(elf)
+    }
+
+    /// All program headers
+    pub fn program_headers(&self) -> impl Iterator<Item = Segment> + 'a {
+        let Elf { data, phoff, phentsize, .. } = *self;
+        (0..self.phnum).filter_map(move |i| {
+            let ph = phoff + i * phentsize;
+            Some(Segment {
+                kind: u32_at(data, ph)?,
+                flags: u32_at(data, ph + 0x04)?,
+                offset: u64_at(data, ph + 0x08)?,
+                vaddr: u64_at(data, ph + 0x10)?,
+                filesz: u64_at(data, ph + 0x20)?,
+                memsz: u64_at(data, ph + 0x28)?,
+            })
+        })
+    }
+
+    /// PT_LOAD segments
+    pub fn load_segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        self.program_headers().filter(|s| s.kind == PT_LOAD)
+    }
+
+    /// Dynamically linked (asks for an interpreter)
+    pub fn is_dynamic(&self) -> bool {
+        self.program_headers().any(|s| s.kind == PT_INTERP)
+    }
+
+    /// Bytes of a segment stored in the file
+    pub fn file_data(&self, s: &Segment) -> Option<&'a [u8]> {
+        self.data.get(s.offset as usize..s.offset.checked_add(s.filesz)? as usize)
+    }
+
+    /// Where the program headers are once loaded (before any load bias)
+    pub fn phdr_vaddr(&self) -> Option<u64> {
+        if let Some(phdr) = self.program_headers().find(|s| s.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        let phoff = self.phoff as u64;
+        self.load_segments()
+            .find(|s| s.offset <= phoff && phoff + (self.phnum * self.phentsize) as u64 <= s.offset + s.filesz)
+            .map(|s| s.vaddr + (phoff - s.offset))
+    }
+
+    pub fn phentsize(&self) -> u64 {
+        self.phentsize as u64
+    }
+
+    pub fn phnum(&self) -> u64 {
+        self.phnum as u64
+    }
+}
+
+/// Lay out a program's initial stack at the top of `stack`, which the
+/// program sees at [top - stack.len(), top)
+///
+/// `auxv` should not include AT_RANDOM, AT_EXECFN or AT_NULL; they are
+/// added here, pointing into the stack. Returns the stack pointer to start
+/// the program with, or None if everything does not fit.
+pub fn build_stack(
+    stack: &mut [u8],
+    top: u64,
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+    auxv: &[(u64, u64)],
+    random: &[u8; 16],
+) -> Option<u64> {
+    let bottom = top.checked_sub(stack.len() as u64)?;
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+
+    // Strings at the very top, then the random bytes, both 16-byte aligned
+    let strings = top.checked_sub(strings_size as u64)? & !15;
+    let random_at = strings.checked_sub(16)?;
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 3);
+    let sp = random_at.checked_sub(8 * words as u64)? & !15;
+    if sp < bottom {
+        return None;
+    }
+
+    let at = |va: u64| (va - bottom) as usize;
+    let mut string_va = strings;
+    for s in argv.iter().chain(envp) {
+        let off = at(string_va);
+        stack[off..off + s.len()].copy_from_slice(s);
+        stack[off + s.len()] = 0;
+        string_va += s.len() as u64 + 1;
+    }
+    stack[at(random_at)..at(random_at) + 16].copy_from_slice(random);
+
+    let mut word = sp;
+    let mut push = |value: u64| {
+        stack[at(word)..at(word) + 8].copy_from_slice(&value.to_le_bytes());
+        word += 8;
+    };
+    push(argv.len() as u64);
+    let mut string_va = strings;
+    for list in [argv, envp] {
+        for s in list {
+            push(string_va);
+            string_va += s.len() as u64 + 1;
+        }
+        push(0);
+    }
+    for &(key, value) in auxv {
+        push(key);
+        push(value);
+    }
+    push(AT_RANDOM);
+    push(random_at);
+    // The path the program was started as is argv[0]
+    push(AT_EXECFN);
+    push(if argv.is_empty() { 0 } else { strings });
+    push(AT_NULL);
+    push(0);
+    Some(sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header plus program headers for `segments`, then `payload`
+    fn elf(kind: u16, entry: u64, segments: &[Segment], payload: &[u8]) -> Vec<u8> {
+        let mut elf = vec![0; EHDR_SIZE];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x10..0x12].copy_from_slice(&kind.to_le_bytes());
+        elf[0x12..0x14].copy_from_slice(&EM_AARCH64.to_le_bytes());
+        elf[0x18..0x20].copy_from_slice(&entry.to_le_bytes());
+        elf[0x20..0x28].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for s in segments {
+            let mut ph = vec![0; PHDR_SIZE];
+            ph[0..4].copy_from_slice(&s.kind.to_le_bytes());
+            ph[4..8].copy_from_slice(&s.flags.to_le_bytes());
+            ph[0x08..0x10].copy_from_slice(&s.offset.to_le_bytes());
+            ph[0x10..0x18].copy_from_slice(&s.vaddr.to_le_bytes());
+            ph[0x20..0x28].copy_from_slice(&s.filesz.to_le_bytes());
+            ph[0x28..0x30].copy_from_slice(&s.memsz.to_le_bytes());
+            elf.extend(ph);
+        }
+        elf.extend_from_slice(payload);
+        elf
+    }
+
+    fn word(stack: &[u8], bottom: u64, va: u64) -> u64 {
+        let off = (va - bottom) as usize;
+        u64::from_le_bytes(stack[off..off + 8].try_into().unwrap())
+    }
+
+    fn c_string(stack: &[u8], bottom: u64, va: u64) -> &[u8] {
+        let rest = &stack[(va - bottom) as usize..];
+        &rest[..rest.iter().position(|&b| b == 0).unwrap()]
+    }
+
+    #[test]
+    fn reads_segments_of_a_static_executable() {
+        // Text from file offset 0 (headers included), data with BSS after it
+        let text = Segment { kind: PT_LOAD, flags: PF_R | PF_X, offset: 0, vaddr: 0x40_0000, filesz: 0xb0, memsz: 0xb0 };
+        let data = Segment { kind: PT_LOAD, flags: PF_R | PF_W, offset: 0xb0, vaddr: 0x41_00b0, filesz: 8, memsz: 0x100 };
+        let file = elf(ET_EXEC, 0x40_00b0, &[text, data], &[0xaa; 8]);
+        let elf = Elf::parse(&file).unwrap();
+
+        assert_eq!(elf.entry, 0x40_00b0);
+        assert_eq!(elf.load_segments().collect::<Vec<_>>(), [text, data]);
+        assert_eq!(elf.file_data(&data), Some(&[0xaa; 8][..]));
+        assert!(data.writable() && !data.executable() && text.executable());
+        assert!(!elf.is_dynamic());
+        // Program headers are inside the text segment
+        assert_eq!(elf.phdr_vaddr(), Some(0x40_0040));
+        assert_eq!((elf.phnum(), elf.phentsize()), (2, 56));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_run() {
+        let seg = Segment { kind: PT_LOAD, flags: PF_R, offset: 0, vaddr: 0, filesz: 0, memsz: 0 };
+        assert!(Elf::parse(b"\x7fELF\x01\x01").is_err());
+        assert!(Elf::parse(&elf(1, 0, &[seg], &[])).is_err());
+
+        let mut file = elf(ET_EXEC, 0, &[seg], &[]);
+        file[0x12] = 62; // x86-64
+        assert!(Elf::parse(&file).is_err());
+
+        // Program header table runs off the end
+        let file = elf(ET_EXEC, 0, &[seg, seg], &[]);
+        assert!(Elf::parse(&file[..file.len() - 1]).is_err());
+
+        let interp = Segment { kind: PT_INTERP, ..seg };
+        assert!(Elf::parse(&elf(ET_DYN, 0, &[seg, interp], &[])).unwrap().is_dynamic());
+    }
+
+    #[test]
+    fn stack_has_argv_envp_and_auxv_in_linux_order() {
+        let top = 0x8000_0000_0000;
+        let bottom = top - 4096;
+        let mut stack = vec![0; 4096];
+        let random = [7; 16];
+        let sp = build_stack(&mut stack, top, &[b"/bin/app", b"-v"], &[b"HOME=/"], &[(AT_PAGESZ, 4096)], &random).unwrap();
+
+        assert_eq!(sp % 16, 0);
+        assert_eq!(word(&stack, bottom, sp), 2);
+        assert_eq!(c_string(&stack, bottom, word(&stack, bottom, sp + 8)), b"/bin/app");
+        assert_eq!(c_string(&stack, bottom, word(&stack, bottom, sp + 16)), b"-v");
+        assert_eq!(word(&stack, bottom, sp + 24), 0);
+        assert_eq!(c_string(&stack, bottom, word(&stack, bottom, sp + 32)), b"HOME=/");
+        assert_eq!(word(&stack, bottom, sp + 40), 0);
+
+        let auxv: Vec<(u64, u64)> = (0..4)
+            .map(|i| (word(&stack, bottom, sp + 48 + 16 * i), word(&stack, bottom, sp + 56 + 16 * i)))
+            .collect();
+        assert_eq!(auxv[0], (AT_PAGESZ, 4096));
+        assert_eq!(auxv[1].0, AT_RANDOM);
+        let at = (auxv[1].1 - bottom) as usize;
+        assert_eq!(stack[at..at + 16], random);
+        assert_eq!(auxv[2], (AT_EXECFN, word(&stack, bottom, sp + 8)));
+        assert_eq!(auxv[3], (AT_NULL, 0));
+    }
+
+    #[test]
+    fn stack_that_is_too_small_is_refused() {
+        let mut stack = vec![0; 64];
+        assert_eq!(build_stack(&mut stack, 0x1000, &[b"app"], &[], &[], &[0; 16]), None);
+        let long = [b'x'; 200];
+        let mut stack = vec![0; 256];
+        assert_eq!(build_stack(&mut stack, 0x1000, &[&long[..]], &[], &[], &[0; 16]), None);
+    }
+}
@@ -0,0 +1,736 @@
+//! ext2, ext3 and ext4 filesystems, read-only
+//!
+//! The superblock (1024 bytes in) gives the block size and how blocks and
+//! inodes are split into groups; each group's descriptor says where its
+//! inode table is. An inode maps its data either through an extent tree
+//! (ext4) or through 12 direct and three indirect block pointers (ext2
+//! and ext3). Directories are read linearly, which also covers hashed
+//! (dir_index) ones, since their index blocks look like empty entries.
+//!
+//! Nothing is written, so the journal is ignored, and checksums are not
+//! verified. Volumes with features that change how data is found
+//! (inline_data, meta_bg, encryption, case-folding, compression) or with
+//! a journal that still needs replaying are refused. Blocks of 1, 2 or 4
+//! KiB; a device block must not be larger than a filesystem block.
+
+use crate::block::BlockDevice;
+use crate::vfs::Error;
+use crate::cpio::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+
+/// Largest filesystem block handled
+pub const MAX_BLOCK: usize = 4096;
+
+/// Longest symlink target lookup() follows
+pub const MAX_LINK: usize = 256;
+
+/// Symlinks lookup() follows in a row before giving up
+pub const MAX_LINK_DEPTH: usize = 8;
+
+/// Longest name in a directory
+pub const MAX_NAME: usize = 255;
+
+/// Inode of the root directory
+pub const ROOT_INODE: u32 = 2;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+
+// Incompatible features: what a reader must understand
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_EXTENTS: u32 = 0x0040;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_MMP: u32 = 0x0100;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_EA_INODE: u32 = 0x0400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+
+// Inode flags
+const FLAG_EXTENTS: u32 = 0x0008_0000;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+/// Deepest extent tree ext4 makes
+const EXTENT_MAX_DEPTH: u16 = 5;
+/// Extent lengths above this are preallocated, unwritten blocks
+const EXTENT_INIT_MAX: u16 = 32768;
+
+/// Bytes of block pointers (or extent tree root, or fast symlink) in an
+/// inode
+const I_BLOCK: usize = 60;
+const DIRECT_BLOCKS: u64 = 12;
+
+// File types in directory entries
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_SYMLINK: u8 = 7;
+
+fn u16_at(data: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([data[off], data[off + 1]])
+}
+
+fn u32_at(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+}
+
+/// An inode, as read from the inode table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Inode {
+    number: u32,
+    mode: u16,
+    size: u64,
+    links: u16,
+    mtime: u32,
+    flags: u32,
+    block: [u8; I_BLOCK],
+}
+
+impl Inode {
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Type and permission bits, as in cpio's S_IF*
+    pub fn mode(&self) -> u32 {
+        self.mode as u32
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn links(&self) -> u16 {
+        self.links
+    }
+
+    /// Last modified, in seconds since 1970
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode() & S_IFMT == S_IFREG
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode() & S_IFMT == S_IFLNK
+    }
+
+    /// Symlink whose target is kept in the inode itself
+    fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.size < I_BLOCK as u64 && self.flags & FLAG_EXTENTS == 0
+    }
+}
+
+/// One entry of a directory, "." and ".." included
+#[derive(Clone, Copy, Debug)]
+pub struct DirEntry {
+    inode: u32,
+    file_type: u8,
+    name: [u8; MAX_NAME],
+    name_len: usize,
+}
+
+impl DirEntry {
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+
+    /// FT_*; FT_UNKNOWN on volumes without the filetype feature
+    pub fn file_type(&self) -> u8 {
+        self.file_type
+    }
+
+    /// Names are bytes; Linux tools write UTF-8
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    /// "." or ".."
+    pub fn is_dot(&self) -> bool {
+        matches!(self.name(), b"." | b"..")
+    }
+}
+
+/// A mounted ext2/3/4 volume on `D`
+pub struct ExtFs<D> {
+    dev: D,
+    block_size: usize,
+    /// Device blocks per filesystem block
+    dev_blocks: u64,
+    blocks: u64,
+    inodes: u32,
+    first_data_block: u64,
+    inodes_per_group: u32,
+    inode_size: usize,
+    desc_size: usize,
+    groups: u32,
+    filetype: bool,
+    label: [u8; 16],
+    uuid: [u8; 16],
+}
+
+impl<D: BlockDevice> ExtFs<D> {
+    /// Mount the ext2/3/4 volume on `dev`
+    pub fn new(dev: D) -> Result<Self, Error> {
+        let dev_bs = dev.block_size();
+        if dev_bs > MAX_BLOCK || !dev_bs.is_power_of_two() {
+            return Err(Error::Io("unsupported block size for ext"));
+        }
+        // The superblock is 1024 bytes at 1024, in whichever device blocks
+        // hold them
+        let mut buf = [0u8; MAX_BLOCK];
+        let mut sb = [0u8; 1024];
+        let (mut lba, mut off) = (SUPERBLOCK_OFFSET / dev_bs as u64, (SUPERBLOCK_OFFSET % dev_bs as u64) as usize);
+        let mut copied = 0;
+        while copied < sb.len() {
+            dev.read_blocks(lba, &mut buf[..dev_bs])?;
+            let n = (dev_bs - off).min(sb.len() - copied);
+            sb[copied..copied + n].copy_from_slice(&buf[off..off + n]);
+            (copied, off, lba) = (copied + n, 0, lba + 1);
+        }
+
+        if u16_at(&sb, 56) != MAGIC {
+            return Err(Error::Io("not an ext2/3/4 filesystem"));
+        }
+        let log_block_size = u32_at(&sb, 24);
+        if log_block_size > 2 {
+            return Err(Error::Io("unsupported ext block size"));
+        }
+        let block_size = 1024usize << log_block_size;
+        if dev_bs > block_size {
+            return Err(Error::Io("device blocks larger than the filesystem's"));
+        }
+        let incompat = u32_at(&sb, 96);
+        if incompat & INCOMPAT_RECOVER != 0 {
+            return Err(Error::Io("ext journal needs recovery"));
+        }
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::Io("unsupported ext feature"));
+        }
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        // Revision 0 has fixed 128-byte inodes
+        let (inode_size, rev) = (u16_at(&sb, 88) as usize, u32_at(&sb, 76));
+        let inode_size = if rev == 0 { 128 } else { inode_size };
+        let desc_size = if is_64bit { u16_at(&sb, 254) as usize } else { 32 };
+        let blocks = u32_at(&sb, 4) as u64 | if is_64bit { (u32_at(&sb, 336) as u64) << 32 } else { 0 };
+        let first_data_block = u32_at(&sb, 20) as u64;
+        let (blocks_per_group, inodes_per_group) = (u32_at(&sb, 32) as u64, u32_at(&sb, 40));
+        if !(128..=block_size).contains(&inode_size)
+            || !inode_size.is_power_of_two()
+            || !(32..=block_size).contains(&desc_size)
+            || !desc_size.is_power_of_two()
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || blocks <= first_data_block
+        {
+            return Err(Error::Io("corrupt ext superblock"));
+        }
+        let dev_blocks = (block_size / dev_bs) as u64;
+        if blocks * dev_blocks > dev.block_count() {
+            return Err(Error::Io("filesystem larger than the disk"));
+        }
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group) as u32;
+        let mut label = [0; 16];
+        label.copy_from_slice(&sb[120..136]);
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&sb[104..120]);
+
+        Ok(ExtFs {
+            dev,
+            block_size,
+            dev_blocks,
+            blocks,
+            inodes: u32_at(&sb, 0),
+            first_data_block,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            groups,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            label,
+            uuid,
+        })
+    }
+
+    pub fn device(&self) -> &D {
+        &self.dev
+    }
+
+    /// Bytes per filesystem block
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Volume label
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(self.label.len());
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    /// Read filesystem block `block` into `buf` (one block long)
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if block >= self.blocks {
+            return Err(Error::Io("ext block out of range"));
+        }
+        self.dev.read_blocks(block * self.dev_blocks, buf).map_err(Error::Io)
+    }
+
+    pub fn root(&self) -> Result<Inode, Error> {
+        self.inode(ROOT_INODE)
+    }
+
+    /// Inode number `number`
+    pub fn inode(&self, number: u32) -> Result<Inode, Error> {
+        if number == 0 || number > self.inodes {
+            return Err(Error::Io("bad inode number"));
+        }
+        let (group, index) = ((number - 1) / self.inodes_per_group, ((number - 1) % self.inodes_per_group) as usize);
+        if group >= self.groups {
+            return Err(Error::Io("bad inode number"));
+        }
+        let bs = self.block_size;
+        let mut buf = [0u8; MAX_BLOCK];
+        let buf = &mut buf[..bs];
+
+        // The group descriptors follow the superblock's block
+        let desc = group as usize * self.desc_size;
+        self.read_block(self.first_data_block + 1 + (desc / bs) as u64, buf)?;
+        let desc = &buf[desc % bs..desc % bs + self.desc_size];
+        let mut table = u32_at(desc, 8) as u64;
+        if self.desc_size >= 64 {
+            table |= (u32_at(desc, 0x28) as u64) << 32;
+        }
+
+        let offset = index * self.inode_size;
+        self.read_block(table + (offset / bs) as u64, buf)?;
+        let raw = &buf[offset % bs..offset % bs + self.inode_size];
+        let mode = u16_at(raw, 0);
+        let mut size = u32_at(raw, 4) as u64;
+        if mode as u32 & S_IFMT == S_IFREG {
+            size |= (u32_at(raw, 108) as u64) << 32;
+        }
+        Ok(Inode {
+            number,
+            mode,
+            size,
+            links: u16_at(raw, 26),
+            mtime: u32_at(raw, 16),
+            flags: u32_at(raw, 32),
+            block: raw[40..40 + I_BLOCK].try_into().unwrap(),
+        })
+    }
+
+    /// Filesystem block holding block `logical` of `inode`; None for a hole
+    fn map(&self, inode: &Inode, logical: u64) -> Result<Option<u64>, Error> {
+        if inode.flags & FLAG_EXTENTS != 0 {
+            return self.map_extent(inode, logical);
+        }
+        // Direct pointers, then single, double and triple indirect blocks
+        if logical < DIRECT_BLOCKS {
+            return Ok(Some(u32_at(&inode.block, logical as usize * 4) as u64).filter(|&b| b != 0));
+        }
+        let per_block = (self.block_size / 4) as u64;
+        let mut logical = logical - DIRECT_BLOCKS;
+        let mut span = per_block;
+        for level in 0..3 {
+            if logical < span {
+                let mut block = u32_at(&inode.block, (DIRECT_BLOCKS as usize + level) * 4) as u64;
+                for _ in 0..=level {
+                    if block == 0 {
+                        return Ok(None);
+                    }
+                    span /= per_block;
+                    block = self.pointer(block, (logical / span) as usize)? as u64;
+                    logical %= span;
+                }
+                return Ok(Some(block).filter(|&b| b != 0));
+            }
+            logical -= span;
+            span *= per_block;
+        }
+        Err(Error::FileTooLarge)
+    }
+
+    /// Entry `index` of indirect block `block`
+    fn pointer(&self, block: u64, index: usize) -> Result<u32, Error> {
+        let mut buf = [0u8; MAX_BLOCK];
+        self.read_block(block, &mut buf[..self.block_size])?;
+        Ok(u32_at(&buf, index * 4))
+    }
+
+    fn map_extent(&self, inode: &Inode, logical: u64) -> Result<Option<u64>, Error> {
+        let Ok(logical) = u32::try_from(logical) else {
+            return Ok(None);
+        };
+        let mut buf = [0u8; MAX_BLOCK];
+        buf[..I_BLOCK].copy_from_slice(&inode.block);
+        let mut len = I_BLOCK;
+        let mut depth = None;
+        loop {
+            let node = &buf[..len];
+            let (entries, node_depth) = (u16_at(node, 2) as usize, u16_at(node, 6));
+            // Each level is one shallower than the last
+            let expected = depth.map_or(node_depth, |d: u16| d.wrapping_sub(1));
+            if u16_at(node, 0) != EXTENT_MAGIC || 12 + entries * 12 > len || node_depth > EXTENT_MAX_DEPTH || node_depth != expected {
+                return Err(Error::Io("corrupt extent tree"));
+            }
+            depth = Some(node_depth);
+            let entry = |i: usize| &node[12 + i * 12..24 + i * 12];
+
+            if node_depth == 0 {
+                for i in 0..entries {
+                    let e = entry(i);
+                    let (start, raw_len) = (u32_at(e, 0), u16_at(e, 4));
+                    let (count, unwritten) =
+                        if raw_len > EXTENT_INIT_MAX { (raw_len - EXTENT_INIT_MAX, true) } else { (raw_len, false) };
+                    if (start..start.saturating_add(count as u32)).contains(&logical) {
+                        let physical = (u16_at(e, 6) as u64) << 32 | u32_at(e, 8) as u64;
+                        // Preallocated but never written: reads as zeros
+                        return Ok((!unwritten).then_some(physical + (logical - start) as u64));
+                    }
+                }
+                return Ok(None);
+            }
+
+            // The last index starting at or before `logical` covers it
+            let Some(e) = (0..entries).map(entry).take_while(|e| u32_at(e, 0) <= logical).last() else {
+                return Ok(None);
+            };
+            let child = (u16_at(e, 8) as u64) << 32 | u32_at(e, 4) as u64;
+            self.read_block(child, &mut buf[..self.block_size])?;
+            len = self.block_size;
+        }
+    }
+
+    /// Read from `offset` of `file` into `buf`; how many bytes (0 at the end)
+    pub fn read(&self, file: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if file.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        self.read_data(file, offset, buf)
+    }
+
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = inode.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let bs = self.block_size;
+        let mut block = [0u8; MAX_BLOCK];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let start = (pos % bs as u64) as usize;
+            let n = (bs - start).min(len - done);
+            let out = &mut buf[done..done + n];
+            match self.map(inode, pos / bs as u64)? {
+                None => out.fill(0),
+                Some(b) if n == bs => self.read_block(b, out)?,
+                Some(b) => {
+                    self.read_block(b, &mut block[..bs])?;
+                    out.copy_from_slice(&block[start..start + n]);
+                }
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Target of `link` into `buf`; its length
+    pub fn read_link(&self, link: &Inode, buf: &mut [u8]) -> Result<usize, Error> {
+        if !link.is_symlink() {
+            return Err(Error::NotASymlink);
+        }
+        if link.size > buf.len() as u64 {
+            return Err(Error::LinkTooLong);
+        }
+        if link.is_fast_symlink() {
+            let len = link.size as usize;
+            buf[..len].copy_from_slice(&link.block[..len]);
+            return Ok(len);
+        }
+        self.read_data(link, 0, buf)
+    }
+
+    /// Walk the entries of `dir`
+    pub fn read_dir(&self, dir: &Inode) -> Result<ReadDir<'_, D>, Error> {
+        if !dir.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        Ok(ReadDir { fs: self, dir: *dir, pos: 0, buf: [0; MAX_BLOCK], loaded: None })
+    }
+
+    /// Entry `name` in `dir`
+    pub fn find(&self, dir: &Inode, name: &[u8]) -> Result<Option<DirEntry>, Error> {
+        for entry in self.read_dir(dir)? {
+            let entry = entry?;
+            if entry.name() == name {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The inode at `path`, from the root, following symlinks
+    pub fn lookup(&self, path: &str) -> Result<Inode, Error> {
+        self.resolve(self.root()?, path.as_bytes(), true, 0)
+    }
+
+    /// The inode at `path`; a symlink at the end is not followed
+    pub fn lookup_link(&self, path: &str) -> Result<Inode, Error> {
+        self.resolve(self.root()?, path.as_bytes(), false, 0)
+    }
+
+    /// Resolve `path` from directory `dir`, `depth` symlinks in
+    fn resolve(&self, dir: Inode, path: &[u8], follow: bool, depth: usize) -> Result<Inode, Error> {
+        let mut node = if path.first() == Some(&b'/') { self.root()? } else { dir };
+        let mut parts = path.split(|&b| b == b'/').filter(|p| !p.is_empty() && *p != b".").peekable();
+        while let Some(part) = parts.next() {
+            if !node.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            let parent = node;
+            node = self.inode(self.find(&parent, part)?.ok_or(Error::NotFound)?.inode)?;
+            if node.is_symlink() && (follow || parts.peek().is_some()) {
+                if depth == MAX_LINK_DEPTH {
+                    return Err(Error::TooManyLinks);
+                }
+                let mut target = [0u8; MAX_LINK];
+                let len = self.read_link(&node, &mut target)?;
+                node = self.resolve(parent, &target[..len], true, depth + 1)?;
+            }
+        }
+        Ok(node)
+    }
+}
+
+/// Entries of a directory, from ExtFs::read_dir()
+pub struct ReadDir<'a, D> {
+    fs: &'a ExtFs<D>,
+    dir: Inode,
+    /// Byte offset of the next entry
+    pos: u64,
+    buf: [u8; MAX_BLOCK],
+    /// Block of the directory in `buf`
+    loaded: Option<u64>,
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, Error> {
+        let fs = self.fs;
+        let bs = fs.block_size;
+        while self.pos < self.dir.size {
+            let (block, offset) = (self.pos / bs as u64, (self.pos % bs as u64) as usize);
+            if self.loaded != Some(block) {
+                match fs.map(&self.dir, block)? {
+                    Some(b) => fs.read_block(b, &mut self.buf[..bs])?,
+                    // A hole: skip the block
+                    None => {
+                        self.pos = (block + 1) * bs as u64;
+                        continue;
+                    }
+                }
+                self.loaded = Some(block);
+            }
+            let raw = &self.buf[offset..bs];
+            if raw.len() < 8 {
+                return Err(Error::Io("corrupt directory"));
+            }
+            let (inode, rec_len, name_len) = (u32_at(raw, 0), u16_at(raw, 4) as usize, raw[6] as usize);
+            if rec_len < 8 || rec_len % 4 != 0 || rec_len > raw.len() || 8 + name_len > rec_len {
+                return Err(Error::Io("corrupt directory"));
+            }
+            self.pos += rec_len as u64;
+            // Unused space, or a checksum tail
+            if inode == 0 || name_len == 0 {
+                continue;
+            }
+            let mut entry = DirEntry { inode, file_type: FT_UNKNOWN, name: [0; MAX_NAME], name_len };
+            if fs.filetype {
+                entry.file_type = raw[7];
+            }
+            entry.name[..name_len].copy_from_slice(&raw[8..8 + name_len]);
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Stop at the damage rather than trip over it again
+            self.pos = self.dir.size;
+        }
+        entry.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::vec::Vec;
+
+    /// Directory tree to build images from: small and large files, a file
+    /// with holes, nested directories and symlinks of both kinds
+    fn tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(std::format!("driver_core-ext-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dir/nested")).unwrap();
+        std::fs::write(root.join("hello.txt"), b"hello from ext\n").unwrap();
+        std::fs::write(root.join("dir/nested/deep.txt"), b"deep").unwrap();
+        std::fs::write(root.join("big.bin"), big()).unwrap();
+        // Data 100 KiB apart: enough extents for a tree with an index level
+        let sparse = std::fs::File::create(root.join("sparse.bin")).unwrap();
+        for i in 0..8u64 {
+            std::os::unix::fs::FileExt::write_at(&sparse, &[b'A' + i as u8; 3000], i * 100 * 1024).unwrap();
+        }
+        for i in 0..50 {
+            std::fs::write(root.join(std::format!("dir/file-{i}")), std::format!("{i}")).unwrap();
+        }
+        symlink("hello.txt", root.join("fast-link")).unwrap();
+        let long_target = std::format!("dir/{}/../nested/deep.txt", "x".repeat(80));
+        std::fs::create_dir(root.join("dir").join("x".repeat(80))).unwrap();
+        symlink(long_target, root.join("slow-link")).unwrap();
+        symlink("/dir/nested", root.join("dir/absolute")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+        root
+    }
+
+    fn big() -> Vec<u8> {
+        (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Image made by mke2fs from `tree()`; None if mke2fs is not installed
+    fn image(name: &str, args: &[&str]) -> Option<Vec<u8>> {
+        let src = tree(name);
+        let image = src.with_extension("img");
+        let _ = std::fs::remove_file(&image);
+        let made = Command::new("mke2fs").args(["-q", "-F", "-L", "assets", "-d"]).arg(&src).args(args).arg(&image).arg("4096").output();
+        let _ = std::fs::remove_dir_all(&src);
+        let Ok(made) = made else {
+            std::eprintln!("mke2fs not installed; skipping");
+            return None;
+        };
+        assert!(made.status.success(), "{}", std::string::String::from_utf8_lossy(&made.stderr));
+        let data = std::fs::read(&image).unwrap();
+        let _ = std::fs::remove_file(&image);
+        Some(data)
+    }
+
+    fn contents<D: BlockDevice>(fs: &ExtFs<D>, path: &str) -> Vec<u8> {
+        let file = fs.lookup(path).unwrap();
+        let mut buf = std::vec![0xEE; file.size() as usize + 10];
+        let len = fs.read(&file, 0, &mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    fn check(data: &mut [u8], block_size: usize) {
+        let disk = RamDisk::new(data, block_size).unwrap();
+        let fs = ExtFs::new(&disk).unwrap();
+        assert_eq!(fs.label(), "assets");
+
+        let root = fs.root().unwrap();
+        let mut names: Vec<Vec<u8>> = fs.read_dir(&root).unwrap().map(|e| e.unwrap().name().to_vec()).collect();
+        names.sort();
+        let expected = [".", "..", "big.bin", "dir", "fast-link", "hello.txt", "loop", "lost+found", "slow-link", "sparse.bin"];
+        assert_eq!(names, expected.map(|n| n.as_bytes().to_vec()));
+
+        assert_eq!(contents(&fs, "/hello.txt"), b"hello from ext\n");
+        assert_eq!(contents(&fs, "dir/nested/deep.txt"), b"deep");
+        assert_eq!(contents(&fs, "big.bin"), big());
+        let dir = fs.lookup("dir").unwrap();
+        assert_eq!(fs.find(&dir, b"file-37").unwrap().map(|e| e.file_type()), Some(FT_REG_FILE));
+        assert_eq!(contents(&fs, "dir/file-37"), b"37");
+
+        let sparse = contents(&fs, "sparse.bin");
+        assert_eq!(sparse.len(), 7 * 100 * 1024 + 3000);
+        for i in 0..8 {
+            let at = i * 100 * 1024;
+            assert!(sparse[at..at + 3000].iter().all(|&b| b == b'A' + i as u8));
+            if i < 7 {
+                assert!(sparse[at + 3000..at + 100 * 1024].iter().all(|&b| b == 0));
+            }
+        }
+
+        // Reading at an offset, and past the end
+        let file = fs.lookup("big.bin").unwrap();
+        let mut buf = [0u8; 100];
+        assert_eq!(fs.read(&file, 12_345, &mut buf).unwrap(), 100);
+        assert_eq!(buf[..], big()[12_345..12_445]);
+        assert_eq!(fs.read(&file, 300_000, &mut buf).unwrap(), 0);
+
+        // Symlinks, in the inode and in a block
+        let link = fs.lookup_link("fast-link").unwrap();
+        assert!(link.is_symlink());
+        let mut target = [0u8; MAX_LINK];
+        let len = fs.read_link(&link, &mut target).unwrap();
+        assert_eq!(&target[..len], b"hello.txt");
+        assert_eq!(contents(&fs, "fast-link"), b"hello from ext\n");
+        assert_eq!(contents(&fs, "slow-link"), b"deep");
+        assert_eq!(contents(&fs, "dir/absolute/deep.txt"), b"deep");
+        assert_eq!(fs.lookup("dir/absolute/..").unwrap().number(), dir.number());
+        assert_eq!(fs.lookup("loop").unwrap_err(), Error::TooManyLinks);
+
+        assert_eq!(fs.lookup("dir/missing").unwrap_err(), Error::NotFound);
+        assert_eq!(fs.lookup("hello.txt/x").unwrap_err(), Error::NotADirectory);
+        assert_eq!(fs.read(&dir, 0, &mut buf).unwrap_err(), Error::IsADirectory);
+        assert_eq!(fs.lookup("/").unwrap(), root);
+        assert_eq!(fs.lookup("dir/nested/../..").unwrap(), root);
+    }
+
+    #[test]
+    fn reads_ext4() {
+        if let Some(mut data) = image("ext4", &["-t", "ext4", "-b", "1024"]) {
+            check(&mut data, 512);
+        }
+    }
+
+    #[test]
+    fn reads_ext4_with_4k_blocks() {
+        if let Some(mut data) = image("ext4-4k", &["-t", "ext4", "-b", "4096"]) {
+            // Block size 4 KiB means 16 MiB here; the device uses 4 KiB too
+            check(&mut data, 4096);
+        }
+    }
+
+    #[test]
+    fn reads_ext2_block_maps() {
+        // 1 KiB blocks: big.bin reaches the double-indirect block
+        if let Some(mut data) = image("ext2", &["-t", "ext2", "-b", "1024"]) {
+            check(&mut data, 1024);
+        }
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_read() {
+        let mut zeros = std::vec![0u8; 64 * 1024];
+        assert_eq!(ExtFs::new(RamDisk::new(&mut zeros, 512).unwrap()).err(), Some(Error::Io("not an ext2/3/4 filesystem")));
+        if let Some(mut data) = image("inline", &["-t", "ext4", "-O", "inline_data"]) {
+            assert_eq!(ExtFs::new(RamDisk::new(&mut data, 512).unwrap()).err(), Some(Error::Io("unsupported ext feature")));
+        }
+    }
+}
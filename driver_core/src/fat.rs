@@ -0,0 +1,1480 @@
+//! FAT32 filesystems, read and write
+//!
+//! The volume starts with a boot sector (the BPB) giving the geometry:
+//! reserved sectors, then the FAT copies, then clusters numbered from 2.
+//! Each file and directory is a chain of clusters linked through the FAT;
+//! a directory is a file of 32-byte entries. A long name is kept in extra
+//! entries just before its 8.3 one, 13 UTF-16 characters each, tied to it
+//! by a checksum of the short name.
+//!
+//! A volume counts as FAT32 by its BPB (no fixed root directory, FAT size
+//! in the 32-bit field), as Linux does, not by its cluster count, so small
+//! images from `mkfs.vfat -F 32` and from format() mount too. The FAT
+//! sector size must be the device's block size.
+//!
+//! No heap and no caching: every call goes to the device, so put a
+//! `block::BlockCache` underneath for speed. A `Node` is a handle on a file
+//! or directory as of when it was looked up; write() and truncate() update
+//! the one they are given and its directory entry. Names are matched
+//! without regard to case, like FAT itself. There is no clock, so new and
+//! modified entries are stamped 1980-01-01.
+
+use core::cell::Cell;
+use core::ops::Range;
+
+use crate::block::BlockDevice;
+use crate::vfs::Error;
+
+/// Largest sector handled
+pub const MAX_SECTOR: usize = 4096;
+
+/// Longest name, in UTF-16 code units
+pub const MAX_NAME: usize = 255;
+
+// Attribute bits of a directory entry
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const LFN_SLOTS: usize = MAX_NAME.div_ceil(LFN_CHARS);
+/// Where a long-name entry keeps its 13 characters
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// Reserved byte of a short entry, as Windows NT uses it: the base name or
+// extension is all lower case (so "readme.txt" needs no long name)
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FAT_EOC: u32 = 0x0FFF_FFFF;
+/// This and above end a chain
+const FAT_EOC_MIN: u32 = 0x0FFF_FFF8;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_TRAIL: u32 = 0xAA55_0000;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// 1980-01-01, the earliest date FAT can hold
+const DOS_EPOCH: u16 = (1 << 5) | 1;
+
+static ZEROS: [u8; MAX_SECTOR] = [0; MAX_SECTOR];
+
+/// Characters allowed in a short name besides letters and digits
+const SHORT_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters no name may contain
+const NAME_FORBIDDEN: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+fn u16_at(data: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([data[off], data[off + 1]])
+}
+
+fn u32_at(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+}
+
+fn put16(data: &mut [u8], off: usize, value: u16) {
+    data[off..off + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(data: &mut [u8], off: usize, value: u32) {
+    data[off..off + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Where a directory entry is on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Slot {
+    lba: u64,
+    offset: usize,
+}
+
+/// A file or directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node {
+    /// First cluster; 0 for an empty file
+    cluster: u32,
+    size: u32,
+    attributes: u8,
+    /// Its short entry; None for the root directory
+    entry: Option<Slot>,
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Size in bytes (0 for directories)
+    pub fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    /// ATTR_* bits
+    pub fn attributes(&self) -> u8 {
+        self.attributes
+    }
+
+    /// First cluster; 0 for an empty file
+    pub fn cluster(&self) -> u32 {
+        self.cluster
+    }
+
+    /// Number that names this file or directory for as long as it exists:
+    /// where its entry is (0 for the root), for FatFs::node()
+    pub fn id(&self) -> u64 {
+        self.entry.map_or(0, |slot| slot.lba << 12 | slot.offset as u64)
+    }
+}
+
+/// One entry of a directory, "." and ".." included
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    node: Node,
+    short: [u8; 11],
+    case: u8,
+    name: [u16; LFN_SLOTS * LFN_CHARS],
+    name_len: usize,
+    /// Its long-name entries, which go with it when it is removed
+    lfn: [Slot; LFN_SLOTS],
+    lfn_len: usize,
+}
+
+impl DirEntry {
+    pub fn node(&self) -> Node {
+        self.node
+    }
+
+    /// Long name if it has one, else the short name
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.name[..self.name_len].iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// 8.3 name as "NAME.EXT"
+    pub fn short_name(&self) -> impl Iterator<Item = char> + '_ {
+        short_display(&self.short, 0)
+    }
+
+    /// "." or ".."
+    pub fn is_dot(&self) -> bool {
+        self.short[0] == b'.'
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let fold = |c: char| c.to_lowercase();
+        self.name().flat_map(fold).eq(name.chars().flat_map(fold))
+            || short_display(&self.short, 0).flat_map(fold).eq(name.chars().flat_map(fold))
+    }
+}
+
+/// 8.3 name as shown: padding dropped, a dot before any extension
+fn short_display(short: &[u8; 11], case: u8) -> impl Iterator<Item = char> + '_ {
+    let base_len = short[..8].iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    let ext_len = short[8..].iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    let lower = move |b: u8, flag: u8| if case & flag != 0 { b.to_ascii_lowercase() } else { b };
+    let base = short[..base_len].iter().enumerate().map(move |(i, &b)| {
+        // 0x05 stands for a leading 0xE5, which would mark the entry free
+        lower(if i == 0 && b == 0x05 { 0xE5 } else { b }, CASE_LOWER_BASE)
+    });
+    let dot = (ext_len > 0).then_some(b'.');
+    let ext = short[8..8 + ext_len].iter().map(move |&b| lower(b, CASE_LOWER_EXT));
+    base.chain(dot).chain(ext).map(|b| b as char)
+}
+
+/// Checksum of a short name, kept in each of its long-name entries
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.ends_with(['.', ' '])
+        || name.encode_utf16().count() > MAX_NAME
+        || name.chars().any(|c| c < ' ' || NAME_FORBIDDEN.contains(&c))
+    {
+        return Err(Error::InvalidName);
+    }
+    Ok(())
+}
+
+fn short_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_SPECIAL.contains(&b)
+}
+
+/// `name` as a short name with case flags, if it is a valid 8.3 name
+/// already and so needs no long name
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    let (short_base, short_ext) = short.split_at_mut(8);
+    for (part, out, flag) in [(base, short_base, CASE_LOWER_BASE), (ext, short_ext, CASE_LOWER_EXT)] {
+        let (upper, lower) = (part.bytes().any(|b| b.is_ascii_uppercase()), part.bytes().any(|b| b.is_ascii_lowercase()));
+        if upper && lower {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+        for (o, b) in out.iter_mut().zip(part.bytes()) {
+            *o = b.to_ascii_uppercase();
+            if !short_char(*o) {
+                return None;
+            }
+        }
+    }
+    Some((short, case))
+}
+
+/// Short name to number: the long name upper-cased, without spaces or
+/// dots but the last, anything else not allowed turned into '_'; base and
+/// how much of it there is, and the extension
+fn short_basis(name: &str) -> ([u8; 8], usize, [u8; 3]) {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let clean = |c: char| match c {
+        ' ' | '.' => None,
+        c if c.is_ascii() && short_char(c.to_ascii_uppercase() as u8) => Some(c.to_ascii_uppercase() as u8),
+        _ => Some(b'_'),
+    };
+    let (mut short_base, mut short_ext) = ([b' '; 8], [b' '; 3]);
+    let mut len = 0;
+    for (o, b) in short_base.iter_mut().zip(base.chars().filter_map(clean)) {
+        *o = b;
+        len += 1;
+    }
+    for (o, b) in short_ext.iter_mut().zip(ext.chars().filter_map(clean)) {
+        *o = b;
+    }
+    if len == 0 {
+        short_base[0] = b'_';
+        len = 1;
+    }
+    (short_base, len, short_ext)
+}
+
+/// Long-name entry `seq` (from 1) of `name`
+fn lfn_entry(name: &[u16], seq: usize, last: bool, checksum: u8) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[0] = seq as u8 | if last { LFN_LAST } else { 0 };
+    raw[11] = ATTR_LONG_NAME;
+    raw[13] = checksum;
+    for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+        let k = (seq - 1) * LFN_CHARS + i;
+        // NUL after the name, then 0xFFFF padding
+        let unit = name.get(k).copied().unwrap_or(if k == name.len() { 0 } else { 0xFFFF });
+        put16(&mut raw, off, unit);
+    }
+    raw
+}
+
+fn short_entry(short: &[u8; 11], case: u8, attributes: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short);
+    raw[11] = attributes;
+    raw[12] = case;
+    for date in [16, 18, 24] {
+        put16(&mut raw, date, DOS_EPOCH);
+    }
+    put16(&mut raw, 20, (cluster >> 16) as u16);
+    put16(&mut raw, 26, cluster as u16);
+    raw
+}
+
+/// A mounted FAT32 volume on `D`
+pub struct FatFs<D> {
+    dev: D,
+    sector_size: usize,
+    cluster_sectors: u32,
+    fat_lba: u64,
+    fat_sectors: u64,
+    /// FAT copies to write: all of them, or just the active one when
+    /// mirroring is off
+    fats: Range<u32>,
+    data_lba: u64,
+    clusters: u32,
+    root: u32,
+    fsinfo: Option<u64>,
+    label: [u8; 11],
+    /// Where to look for a free cluster next
+    next_free: Cell<u32>,
+    /// Free clusters, if known
+    free: Cell<Option<u32>>,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Mount the FAT32 volume on `dev`
+    pub fn new(dev: D) -> Result<Self, Error> {
+        let bs = dev.block_size();
+        if !(512..=MAX_SECTOR).contains(&bs) {
+            return Err(Error::Io("unsupported block size for FAT"));
+        }
+        let mut sector = [0u8; MAX_SECTOR];
+        let sector = &mut sector[..bs];
+        dev.read_blocks(0, sector)?;
+        if sector[510..512] != [0x55, 0xAA] {
+            return Err(Error::Io("not a FAT filesystem"));
+        }
+        let cluster_sectors = sector[13] as u32;
+        let reserved = u16_at(sector, 14) as u64;
+        let num_fats = sector[16] as u32;
+        if u16_at(sector, 11) as usize != bs {
+            return Err(Error::Io("FAT sector size is not the block size"));
+        }
+        if !cluster_sectors.is_power_of_two() || reserved == 0 || num_fats == 0 {
+            return Err(Error::Io("not a FAT filesystem"));
+        }
+        if u16_at(sector, 17) != 0 || u16_at(sector, 22) != 0 {
+            return Err(Error::Io("not FAT32"));
+        }
+        let total = match u16_at(sector, 19) {
+            0 => u32_at(sector, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = u32_at(sector, 36) as u64;
+        let ext_flags = u16_at(sector, 40);
+        let root = u32_at(sector, 44);
+        let fsinfo = u16_at(sector, 48) as u64;
+        let mut label = [0; 11];
+        label.copy_from_slice(&sector[71..82]);
+        if total > dev.block_count() {
+            return Err(Error::Io("filesystem larger than the disk"));
+        }
+
+        let data_lba = reserved + num_fats as u64 * fat_sectors;
+        let clusters = (total.checked_sub(data_lba).ok_or(Error::Io("not a FAT filesystem"))? / cluster_sectors as u64) as u32;
+        if (clusters as u64 + 2) * 4 > fat_sectors * bs as u64 || clusters == 0 || clusters >= FAT_EOC_MIN - 2 {
+            return Err(Error::Io("FAT too small for the clusters"));
+        }
+        // Bit 7: only the FAT numbered in bits 0-3 is in use
+        let fats = match ext_flags & 0x80 {
+            0 => 0..num_fats,
+            _ => {
+                let active = (ext_flags & 0xF) as u32;
+                if active >= num_fats {
+                    return Err(Error::Io("not a FAT filesystem"));
+                }
+                active..active + 1
+            }
+        };
+
+        let mut fs = FatFs {
+            dev,
+            sector_size: bs,
+            cluster_sectors,
+            fat_lba: reserved,
+            fat_sectors,
+            fats,
+            data_lba,
+            clusters,
+            root,
+            fsinfo: None,
+            label,
+            next_free: Cell::new(2),
+            free: Cell::new(None),
+        };
+        if !fs.valid(root) {
+            return Err(Error::Io("bad root directory cluster"));
+        }
+        if fsinfo > 0 && fsinfo < reserved {
+            fs.dev.read_blocks(fsinfo, sector)?;
+            if u32_at(sector, 0) == FSINFO_LEAD && u32_at(sector, 484) == FSINFO_STRUCT && u32_at(sector, 508) == FSINFO_TRAIL {
+                fs.fsinfo = Some(fsinfo);
+                let (free, next) = (u32_at(sector, 488), u32_at(sector, 492));
+                fs.free.set((free <= clusters).then_some(free));
+                if fs.valid(next) {
+                    fs.next_free.set(next);
+                }
+            }
+        }
+        Ok(fs)
+    }
+
+    pub fn device(&self) -> &D {
+        &self.dev
+    }
+
+    /// Volume label from the boot sector
+    pub fn label(&self) -> &str {
+        core::str::from_utf8(&self.label).unwrap_or("").trim_end()
+    }
+
+    /// Bytes per cluster
+    pub fn cluster_size(&self) -> usize {
+        self.cluster_sectors as usize * self.sector_size
+    }
+
+    /// Clusters in the data area
+    pub fn clusters(&self) -> u32 {
+        self.clusters
+    }
+
+    pub fn root(&self) -> Node {
+        Node { cluster: self.root, size: 0, attributes: ATTR_DIRECTORY, entry: None }
+    }
+
+    /// The file or directory whose Node::id() is `id`, as its entry is now
+    pub fn node(&self, id: u64) -> Result<Node, Error> {
+        if id == 0 {
+            return Ok(self.root());
+        }
+        let slot = Slot { lba: id >> 12, offset: (id & 0xFFF) as usize };
+        let data_end = self.data_lba + self.clusters as u64 * self.cluster_sectors as u64;
+        if !(self.data_lba..data_end).contains(&slot.lba) || slot.offset >= self.sector_size || !slot.offset.is_multiple_of(ENTRY_SIZE) {
+            return Err(Error::Stale);
+        }
+        let raw = self.read_slot(slot)?;
+        // Long-name entries have the volume ID bit set too
+        if raw[0] == ENTRY_FREE || raw[0] == ENTRY_END || raw[11] & ATTR_VOLUME_ID != 0 {
+            return Err(Error::Stale);
+        }
+        Ok(self.entry_node(&raw, slot))
+    }
+
+    /// Node described by the short entry `raw`, found at `slot`
+    fn entry_node(&self, raw: &[u8; ENTRY_SIZE], slot: Slot) -> Node {
+        let attributes = raw[11];
+        let cluster = (u16_at(raw, 20) as u32) << 16 | u16_at(raw, 26) as u32;
+        if attributes & ATTR_DIRECTORY != 0 && cluster == 0 {
+            // ".." of a directory in the root
+            self.root()
+        } else {
+            Node { cluster, size: u32_at(raw, 28), attributes, entry: Some(slot) }
+        }
+    }
+
+    fn valid(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_lba + (cluster - 2) as u64 * self.cluster_sectors as u64
+    }
+
+    fn cluster_slots(&self) -> usize {
+        self.cluster_size() / ENTRY_SIZE
+    }
+
+    fn slot_at(&self, cluster: u32, index: usize) -> Slot {
+        let offset = index * ENTRY_SIZE;
+        Slot { lba: self.cluster_lba(cluster) + (offset / self.sector_size) as u64, offset: offset % self.sector_size }
+    }
+
+    /// Sector (from the start of a FAT) and offset of `cluster`'s entry
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let byte = cluster as usize * 4;
+        ((byte / self.sector_size) as u64, byte % self.sector_size)
+    }
+
+    fn read_fat(&self, cluster: u32) -> Result<u32, Error> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = [0u8; MAX_SECTOR];
+        let buf = &mut buf[..self.sector_size];
+        self.dev.read_blocks(self.fat_lba + self.fats.start as u64 * self.fat_sectors + sector, buf)?;
+        Ok(u32_at(buf, offset) & FAT_MASK)
+    }
+
+    /// Set `cluster`'s FAT entry in each copy, keeping the top four bits
+    fn write_fat(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = [0u8; MAX_SECTOR];
+        let buf = &mut buf[..self.sector_size];
+        for fat in self.fats.clone() {
+            let lba = self.fat_lba + fat as u64 * self.fat_sectors + sector;
+            self.dev.read_blocks(lba, buf)?;
+            put32(buf, offset, (u32_at(buf, offset) & !FAT_MASK) | value);
+            self.dev.write_blocks(lba, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Cluster after `cluster`, or None at the end of its chain
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Error> {
+        match self.read_fat(cluster)? {
+            next if next >= FAT_EOC_MIN => Ok(None),
+            next if self.valid(next) => Ok(Some(next)),
+            _ => Err(Error::Io("corrupt cluster chain")),
+        }
+    }
+
+    /// Cluster `n` (from 0) of the chain starting at `first`
+    fn nth_cluster(&self, first: u32, n: u64) -> Result<u32, Error> {
+        let mut cluster = first;
+        for _ in 0..n {
+            cluster = self.next_cluster(cluster)?.ok_or(Error::Io("cluster chain too short"))?;
+        }
+        Ok(cluster)
+    }
+
+    /// Last cluster of the chain starting at `first`, and its length
+    fn last_cluster(&self, first: u32) -> Result<(u32, u64), Error> {
+        let (mut cluster, mut len) = (first, 1);
+        while let Some(next) = self.next_cluster(cluster)? {
+            (cluster, len) = (next, len + 1);
+            if len > self.clusters as u64 {
+                return Err(Error::Io("cluster chain loops"));
+            }
+        }
+        Ok((cluster, len))
+    }
+
+    /// Take a free cluster, zero it and append it to the chain ending at
+    /// `prev`
+    fn alloc(&self, prev: Option<u32>) -> Result<u32, Error> {
+        let cluster = self.find_free()?;
+        let lba = self.cluster_lba(cluster);
+        for i in 0..self.cluster_sectors as u64 {
+            self.dev.write_blocks(lba + i, &ZEROS[..self.sector_size])?;
+        }
+        self.write_fat(cluster, FAT_EOC)?;
+        if let Some(prev) = prev {
+            self.write_fat(prev, cluster)?;
+        }
+        self.next_free.set(if cluster + 1 < self.clusters + 2 { cluster + 1 } else { 2 });
+        self.free.set(self.free.get().map(|n| n.saturating_sub(1)));
+        Ok(cluster)
+    }
+
+    /// First free cluster from the next_free hint on, wrapping around
+    fn find_free(&self) -> Result<u32, Error> {
+        let mut buf = [0u8; MAX_SECTOR];
+        let buf = &mut buf[..self.sector_size];
+        let mut loaded = None;
+        let start = self.next_free.get() - 2;
+        for i in 0..self.clusters {
+            let cluster = 2 + (start + i) % self.clusters;
+            let (sector, offset) = self.fat_position(cluster);
+            if loaded != Some(sector) {
+                self.dev.read_blocks(self.fat_lba + self.fats.start as u64 * self.fat_sectors + sector, buf)?;
+                loaded = Some(sector);
+            }
+            if u32_at(buf, offset) & FAT_MASK == 0 {
+                return Ok(cluster);
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Free the chain starting at `first`
+    fn free_chain(&self, first: u32) -> Result<(), Error> {
+        let mut cluster = Some(first);
+        let mut freed = 0;
+        while let Some(c) = cluster {
+            cluster = self.next_cluster(c)?;
+            self.write_fat(c, 0)?;
+            freed += 1;
+            if freed > self.clusters {
+                return Err(Error::Io("cluster chain loops"));
+            }
+        }
+        self.free.set(self.free.get().map(|n| n + freed));
+        Ok(())
+    }
+
+    /// Free clusters, counted from the FAT
+    pub fn free_clusters(&self) -> Result<u32, Error> {
+        let mut buf = [0u8; MAX_SECTOR];
+        let buf = &mut buf[..self.sector_size];
+        let mut loaded = None;
+        let mut free = 0;
+        for cluster in 2..self.clusters + 2 {
+            let (sector, offset) = self.fat_position(cluster);
+            if loaded != Some(sector) {
+                self.dev.read_blocks(self.fat_lba + self.fats.start as u64 * self.fat_sectors + sector, buf)?;
+                loaded = Some(sector);
+            }
+            if u32_at(buf, offset) & FAT_MASK == 0 {
+                free += 1;
+            }
+        }
+        self.free.set(Some(free));
+        Ok(free)
+    }
+
+    /// Call `f(lba, bytes within the sector, bytes within the transfer)` for
+    /// each sector holding the `len` bytes at `offset` of the chain from
+    /// `first`
+    fn for_each_sector(
+        &self,
+        first: u32,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, Range<usize>, Range<usize>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if len == 0 {
+            return Ok(());
+        }
+        if !self.valid(first) {
+            return Err(Error::Io("corrupt cluster chain"));
+        }
+        let (bs, cluster_bytes) = (self.sector_size as u64, self.cluster_size() as u64);
+        let mut cluster = self.nth_cluster(first, offset / cluster_bytes)?;
+        let (mut pos, mut done) = (offset, 0);
+        loop {
+            let within = pos % cluster_bytes;
+            let start = (within % bs) as usize;
+            let n = (self.sector_size - start).min(len - done);
+            f(self.cluster_lba(cluster) + within / bs, start..start + n, done..done + n)?;
+            done += n;
+            pos += n as u64;
+            if done == len {
+                return Ok(());
+            }
+            if pos.is_multiple_of(cluster_bytes) {
+                cluster = self.next_cluster(cluster)?.ok_or(Error::Io("cluster chain too short"))?;
+            }
+        }
+    }
+
+    /// Write `data` (None: zeros) at `offset` of the chain from `first`
+    fn write_span(&self, first: u32, offset: u64, len: usize, data: Option<&[u8]>) -> Result<(), Error> {
+        let mut buf = [0u8; MAX_SECTOR];
+        let bs = self.sector_size;
+        self.for_each_sector(first, offset, len, |lba, sector, range| {
+            let src = data.map_or(&ZEROS[..range.len()], |d| &d[range]);
+            if sector.len() == bs {
+                return self.dev.write_blocks(lba, src).map_err(Error::Io);
+            }
+            self.dev.read_blocks(lba, &mut buf[..bs])?;
+            buf[sector].copy_from_slice(src);
+            self.dev.write_blocks(lba, &buf[..bs]).map_err(Error::Io)
+        })
+    }
+
+    fn read_slot(&self, slot: Slot) -> Result<[u8; ENTRY_SIZE], Error> {
+        let mut buf = [0u8; MAX_SECTOR];
+        self.dev.read_blocks(slot.lba, &mut buf[..self.sector_size])?;
+        Ok(buf[slot.offset..slot.offset + ENTRY_SIZE].try_into().unwrap())
+    }
+
+    fn write_slot(&self, slot: Slot, raw: &[u8; ENTRY_SIZE]) -> Result<(), Error> {
+        let mut buf = [0u8; MAX_SECTOR];
+        let buf = &mut buf[..self.sector_size];
+        self.dev.read_blocks(slot.lba, buf)?;
+        buf[slot.offset..slot.offset + ENTRY_SIZE].copy_from_slice(raw);
+        self.dev.write_blocks(slot.lba, buf).map_err(Error::Io)
+    }
+
+    /// Write `node`'s first cluster and size back to its directory entry
+    fn update_entry(&self, node: &Node) -> Result<(), Error> {
+        let Some(slot) = node.entry else {
+            return Ok(());
+        };
+        let mut raw = self.read_slot(slot)?;
+        raw[11] |= ATTR_ARCHIVE;
+        put16(&mut raw, 20, (node.cluster >> 16) as u16);
+        put16(&mut raw, 24, DOS_EPOCH);
+        put16(&mut raw, 26, node.cluster as u16);
+        put32(&mut raw, 28, node.size);
+        self.write_slot(slot, &raw)
+    }
+
+    /// Walk the entries of `dir`
+    pub fn read_dir(&self, dir: &Node) -> Result<ReadDir<'_, D>, Error> {
+        if !dir.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        if !self.valid(dir.cluster) {
+            return Err(Error::Io("corrupt cluster chain"));
+        }
+        Ok(ReadDir { fs: self, cluster: dir.cluster, last: dir.cluster, slot: 0, buf: [0; MAX_SECTOR], loaded: None })
+    }
+
+    /// Entry `name` in `dir`
+    pub fn find(&self, dir: &Node, name: &str) -> Result<Option<DirEntry>, Error> {
+        for entry in self.read_dir(dir)? {
+            let entry = entry?;
+            if entry.matches(name) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The file or directory at `path`, from the root ("/" separated; "."
+    /// and ".." work as usual)
+    pub fn lookup(&self, path: &str) -> Result<Node, Error> {
+        let mut node = self.root();
+        for part in path.split('/').filter(|&p| !p.is_empty() && p != ".") {
+            if !node.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            // The root has no "..": it is its own parent
+            if part == ".." && node.cluster == self.root {
+                continue;
+            }
+            node = self.find(&node, part)?.ok_or(Error::NotFound)?.node;
+        }
+        Ok(node)
+    }
+
+    /// Read from `offset` of `file` into `buf`; how many bytes (0 at the end)
+    pub fn read(&self, file: &Node, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if file.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        let len = (file.size().saturating_sub(offset)).min(buf.len() as u64) as usize;
+        let bs = self.sector_size;
+        let mut sector_buf = [0u8; MAX_SECTOR];
+        self.for_each_sector(file.cluster, offset, len, |lba, sector, range| {
+            if sector.len() == bs {
+                return self.dev.read_blocks(lba, &mut buf[range]).map_err(Error::Io);
+            }
+            self.dev.read_blocks(lba, &mut sector_buf[..bs])?;
+            buf[range].copy_from_slice(&sector_buf[sector]);
+            Ok(())
+        })?;
+        Ok(len)
+    }
+
+    fn check_writable(file: &Node) -> Result<(), Error> {
+        if file.is_dir() {
+            Err(Error::IsADirectory)
+        } else if file.attributes & ATTR_READ_ONLY != 0 {
+            Err(Error::ReadOnlyFile)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Give `file` clusters for `len` bytes; on failure, drop any it got
+    /// beyond its size
+    fn reserve(&self, file: &mut Node, len: u64) -> Result<(), Error> {
+        let needed = len.div_ceil(self.cluster_size() as u64);
+        if needed == 0 {
+            return Ok(());
+        }
+        let grow = |file: &mut Node| -> Result<(), Error> {
+            let (mut last, mut have) = match file.cluster {
+                0 => {
+                    file.cluster = self.alloc(None)?;
+                    (file.cluster, 1)
+                }
+                first => self.last_cluster(first)?,
+            };
+            while have < needed {
+                last = self.alloc(Some(last))?;
+                have += 1;
+            }
+            Ok(())
+        };
+        let grown = grow(file);
+        if grown.is_err() {
+            let size = file.size();
+            let _ = self.release_past(file, size);
+        }
+        grown
+    }
+
+    /// Free `file`'s clusters past the first `len` bytes
+    fn release_past(&self, file: &mut Node, len: u64) -> Result<(), Error> {
+        if file.cluster == 0 {
+            return Ok(());
+        }
+        let keep = len.div_ceil(self.cluster_size() as u64);
+        if keep == 0 {
+            let first = file.cluster;
+            file.cluster = 0;
+            self.update_entry(file)?;
+            return self.free_chain(first);
+        }
+        let last = self.nth_cluster(file.cluster, keep - 1)?;
+        if let Some(next) = self.next_cluster(last)? {
+            self.write_fat(last, FAT_EOC)?;
+            self.free_chain(next)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset` of `file`, growing it as needed; a gap
+    /// past the old end reads as zeros
+    pub fn write(&self, file: &mut Node, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        Self::check_writable(file)?;
+        let end = offset.checked_add(data.len() as u64).filter(|&end| end <= u32::MAX as u64).ok_or(Error::FileTooLarge)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.reserve(file, end)?;
+        if offset > file.size() {
+            self.write_span(file.cluster, file.size(), (offset - file.size()) as usize, None)?;
+        }
+        self.write_span(file.cluster, offset, data.len(), Some(data))?;
+        file.size = file.size.max(end as u32);
+        self.update_entry(file)?;
+        Ok(data.len())
+    }
+
+    /// Cut `file` to `len` bytes, or zero-fill it out to them
+    pub fn truncate(&self, file: &mut Node, len: u64) -> Result<(), Error> {
+        Self::check_writable(file)?;
+        if len > u32::MAX as u64 {
+            return Err(Error::FileTooLarge);
+        }
+        if len > file.size() {
+            self.reserve(file, len)?;
+            self.write_span(file.cluster, file.size(), (len - file.size()) as usize, None)?;
+        } else {
+            self.release_past(file, len)?;
+        }
+        file.size = len as u32;
+        self.update_entry(file)
+    }
+
+    /// Create the empty file `name` in `dir`
+    pub fn create(&self, dir: &Node, name: &str) -> Result<Node, Error> {
+        self.add_entry(dir, name, ATTR_ARCHIVE, 0)
+    }
+
+    /// Create the directory `name` in `dir`
+    pub fn mkdir(&self, dir: &Node, name: &str) -> Result<Node, Error> {
+        if !dir.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        check_name(name)?;
+        let cluster = self.alloc(None)?;
+        // ".." of a directory in the root says 0
+        let parent = if dir.cluster == self.root { 0 } else { dir.cluster };
+        let mut dots = [b' '; 11];
+        dots[0] = b'.';
+        let made = self.write_slot(self.slot_at(cluster, 0), &short_entry(&dots, 0, ATTR_DIRECTORY, cluster)).and_then(|_| {
+            dots[1] = b'.';
+            self.write_slot(self.slot_at(cluster, 1), &short_entry(&dots, 0, ATTR_DIRECTORY, parent))
+        });
+        match made.and_then(|_| self.add_entry(dir, name, ATTR_DIRECTORY, cluster)) {
+            Ok(node) => Ok(node),
+            Err(e) => {
+                self.free_chain(cluster)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn add_entry(&self, dir: &Node, name: &str, attributes: u8, cluster: u32) -> Result<Node, Error> {
+        check_name(name)?;
+        if self.find(dir, name)?.is_some() {
+            return Err(Error::Exists);
+        }
+        let mut long = [0u16; MAX_NAME];
+        let mut long_len: usize = 0;
+        let (short, case) = match exact_short_name(name) {
+            Some(short) => short,
+            None => {
+                for (o, unit) in long.iter_mut().zip(name.encode_utf16()) {
+                    *o = unit;
+                    long_len += 1;
+                }
+                (self.unique_short_name(dir, name)?, 0)
+            }
+        };
+        let lfn_count = long_len.div_ceil(LFN_CHARS);
+        let slots = self.free_slots(dir, lfn_count + 1)?;
+        let checksum = lfn_checksum(&short);
+        for (i, &slot) in slots[..lfn_count].iter().enumerate() {
+            // The last piece of the name comes first
+            let seq = lfn_count - i;
+            self.write_slot(slot, &lfn_entry(&long[..long_len], seq, i == 0, checksum))?;
+        }
+        let entry = slots[lfn_count];
+        self.write_slot(entry, &short_entry(&short, case, attributes, cluster))?;
+        Ok(Node { cluster, size: 0, attributes, entry: Some(entry) })
+    }
+
+    /// A short name for `name` that no entry of `dir` has: its basis with
+    /// a "~N" tail
+    fn unique_short_name(&self, dir: &Node, name: &str) -> Result<[u8; 11], Error> {
+        let (base, base_len, ext) = short_basis(name);
+        for n in 1..=999_999u32 {
+            let mut tail = [b'~'; 7];
+            let digits = n.ilog10() as usize + 1;
+            let mut rest = n;
+            for d in tail[1..=digits].iter_mut().rev() {
+                *d = b'0' + (rest % 10) as u8;
+                rest /= 10;
+            }
+            let keep = base_len.min(8 - 1 - digits);
+            let mut short = [b' '; 11];
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep..keep + 1 + digits].copy_from_slice(&tail[..=digits]);
+            short[8..].copy_from_slice(&ext);
+            if !self.short_name_used(dir, &short)? {
+                return Ok(short);
+            }
+        }
+        Err(Error::Io("no short name left"))
+    }
+
+    fn short_name_used(&self, dir: &Node, short: &[u8; 11]) -> Result<bool, Error> {
+        for entry in self.read_dir(dir)? {
+            if entry?.short == *short {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// `count` consecutive free slots in `dir`, growing it if need be
+    fn free_slots(&self, dir: &Node, count: usize) -> Result<[Slot; LFN_SLOTS + 1], Error> {
+        let mut slots = [Slot::default(); LFN_SLOTS + 1];
+        let mut run = 0;
+        let mut last = {
+            let mut walk = self.read_dir(dir)?;
+            while let Some((slot, raw)) = walk.next_slot()? {
+                if raw[0] == ENTRY_FREE || raw[0] == ENTRY_END {
+                    slots[run] = slot;
+                    run += 1;
+                    if run == count {
+                        return Ok(slots);
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+            walk.last
+        };
+        while run < count {
+            last = self.alloc(Some(last))?;
+            for index in 0..self.cluster_slots().min(count - run) {
+                slots[run] = self.slot_at(last, index);
+                run += 1;
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Remove `name` from `dir`: a file, or an empty directory
+    pub fn remove(&self, dir: &Node, name: &str) -> Result<(), Error> {
+        let entry = self.find(dir, name)?.ok_or(Error::NotFound)?;
+        if entry.is_dot() {
+            return Err(Error::InvalidName);
+        }
+        if entry.node.is_dir() {
+            for child in self.read_dir(&entry.node)? {
+                if !child?.is_dot() {
+                    return Err(Error::NotEmpty);
+                }
+            }
+        }
+        // Entries first: a crash in between loses clusters, it does not
+        // leave two files sharing them
+        for &slot in entry.lfn[..entry.lfn_len].iter().chain(entry.node.entry.iter()) {
+            let mut raw = self.read_slot(slot)?;
+            raw[0] = ENTRY_FREE;
+            self.write_slot(slot, &raw)?;
+        }
+        match entry.node.cluster {
+            0 => Ok(()),
+            first => self.free_chain(first),
+        }
+    }
+
+    /// Record the free cluster count in the FSInfo sector and flush the
+    /// device
+    pub fn flush(&self) -> Result<(), Error> {
+        if let Some(lba) = self.fsinfo {
+            let mut buf = [0u8; MAX_SECTOR];
+            let buf = &mut buf[..self.sector_size];
+            self.dev.read_blocks(lba, buf)?;
+            put32(buf, 488, self.free.get().unwrap_or(FSINFO_UNKNOWN));
+            put32(buf, 492, self.next_free.get());
+            self.dev.write_blocks(lba, buf)?;
+        }
+        self.dev.flush().map_err(Error::Io)
+    }
+}
+
+/// Entries of a directory, from FatFs::read_dir()
+pub struct ReadDir<'a, D> {
+    fs: &'a FatFs<D>,
+    /// Cluster being read; 0 once past the end
+    cluster: u32,
+    /// Cluster the last slot came from
+    last: u32,
+    /// Next slot within `cluster`
+    slot: usize,
+    buf: [u8; MAX_SECTOR],
+    loaded: Option<u64>,
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    /// Next 32-byte slot, used or not
+    fn next_slot(&mut self) -> Result<Option<(Slot, [u8; ENTRY_SIZE])>, Error> {
+        if self.cluster == 0 {
+            return Ok(None);
+        }
+        let fs = self.fs;
+        let slot = fs.slot_at(self.cluster, self.slot);
+        if self.loaded != Some(slot.lba) {
+            fs.dev.read_blocks(slot.lba, &mut self.buf[..fs.sector_size])?;
+            self.loaded = Some(slot.lba);
+        }
+        let raw = self.buf[slot.offset..slot.offset + ENTRY_SIZE].try_into().unwrap();
+        self.last = self.cluster;
+        self.slot += 1;
+        if self.slot == fs.cluster_slots() {
+            self.slot = 0;
+            self.cluster = fs.next_cluster(self.cluster)?.unwrap_or(0);
+        }
+        Ok(Some((slot, raw)))
+    }
+
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, Error> {
+        let mut entry = DirEntry {
+            node: self.fs.root(),
+            short: [0; 11],
+            case: 0,
+            name: [0; LFN_SLOTS * LFN_CHARS],
+            name_len: 0,
+            lfn: [Slot::default(); LFN_SLOTS],
+            lfn_len: 0,
+        };
+        // Long name being collected: sequence number wanted next, checksum
+        let mut long: Option<(u8, u8)> = None;
+        while let Some((slot, raw)) = self.next_slot()? {
+            match raw[0] {
+                ENTRY_END => {
+                    self.cluster = 0;
+                    return Ok(None);
+                }
+                ENTRY_FREE => {
+                    long = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & 0x3F == ATTR_LONG_NAME {
+                let seq = raw[0] & 0x1F;
+                if raw[0] & LFN_LAST != 0 && (1..=LFN_SLOTS as u8).contains(&seq) {
+                    long = Some((seq, raw[13]));
+                    entry.lfn_len = 0;
+                    entry.name_len = seq as usize * LFN_CHARS;
+                } else if long != Some((seq, raw[13])) || seq == 0 {
+                    long = None;
+                    continue;
+                }
+                let base = (seq as usize - 1) * LFN_CHARS;
+                for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+                    entry.name[base + i] = u16_at(&raw, off);
+                }
+                entry.lfn[entry.lfn_len] = slot;
+                entry.lfn_len += 1;
+                long = long.map(|(_, checksum)| (seq - 1, checksum));
+                continue;
+            }
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                long = None;
+                continue;
+            }
+
+            entry.short.copy_from_slice(&raw[..11]);
+            entry.case = raw[12];
+            if long == Some((0, lfn_checksum(&entry.short))) {
+                let name = &entry.name[..entry.name_len];
+                entry.name_len = name.iter().position(|&u| u == 0).unwrap_or(name.len()).min(MAX_NAME);
+            } else {
+                entry.lfn_len = 0;
+                entry.name_len = 0;
+                for (o, c) in entry.name.iter_mut().zip(short_display(&entry.short, entry.case)) {
+                    *o = c as u16;
+                    entry.name_len += 1;
+                }
+            }
+            entry.node = self.fs.entry_node(&raw, slot);
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Stop at the damage rather than trip over it again
+            self.cluster = 0;
+        }
+        entry.transpose()
+    }
+}
+
+/// Make an empty FAT32 volume covering `dev`, laid out like `mkfs.vfat
+/// -F 32`: 32 reserved sectors with FSInfo at 1 and a backup boot sector
+/// at 6, two FATs, and the root directory in cluster 2
+pub fn format(dev: &(impl BlockDevice + ?Sized), label: &str, volume_id: u32) -> Result<(), Error> {
+    let bs = dev.block_size();
+    if !(512..=MAX_SECTOR).contains(&bs) {
+        return Err(Error::Io("unsupported block size for FAT"));
+    }
+    if label.len() > 11 || !label.bytes().all(|b| b == b' ' || short_char(b.to_ascii_uppercase())) {
+        return Err(Error::Io("invalid volume label"));
+    }
+    let total = dev.block_count().min(u32::MAX as u64);
+    // Cluster sizes as Microsoft recommends them for FAT32
+    let cluster_bytes: u64 = match total * bs as u64 {
+        n if n <= 260 << 20 => 512,
+        n if n <= 8 << 30 => 4096,
+        n if n <= 16 << 30 => 8192,
+        n if n <= 32 << 30 => 16384,
+        _ => 32768,
+    };
+    let cluster_sectors = (cluster_bytes / bs as u64).max(1);
+    let reserved = 32u64;
+    // Enough FAT for every cluster the disk could hold without it
+    let fat_sectors = (((total.saturating_sub(reserved)) / cluster_sectors + 2) * 4).div_ceil(bs as u64);
+    let data_lba = reserved + 2 * fat_sectors;
+    let clusters = total.saturating_sub(data_lba) / cluster_sectors;
+    if clusters < 2 {
+        return Err(Error::Io("disk too small for FAT32"));
+    }
+
+    let mut label_bytes = *b"NO NAME    ";
+    if !label.is_empty() {
+        label_bytes = [b' '; 11];
+        for (o, b) in label_bytes.iter_mut().zip(label.bytes()) {
+            *o = b.to_ascii_uppercase();
+        }
+    }
+
+    let mut buf = [0u8; MAX_SECTOR];
+    let buf = &mut buf[..bs];
+    buf.fill(0);
+    for lba in 0..data_lba + cluster_sectors {
+        dev.write_blocks(lba, buf)?;
+    }
+
+    // Boot sector, and its backup
+    buf[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    buf[3..11].copy_from_slice(b"mkfs.fat");
+    put16(buf, 11, bs as u16);
+    buf[13] = cluster_sectors as u8;
+    put16(buf, 14, reserved as u16);
+    buf[16] = 2;
+    buf[21] = 0xF8;
+    put16(buf, 24, 32);
+    put16(buf, 26, 64);
+    put32(buf, 32, total as u32);
+    put32(buf, 36, fat_sectors as u32);
+    put32(buf, 44, 2);
+    put16(buf, 48, 1);
+    put16(buf, 50, 6);
+    buf[64] = 0x80;
+    buf[66] = 0x29;
+    put32(buf, 67, volume_id);
+    buf[71..82].copy_from_slice(&label_bytes);
+    buf[82..90].copy_from_slice(b"FAT32   ");
+    buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+    dev.write_blocks(0, buf)?;
+    dev.write_blocks(6, buf)?;
+
+    // FSInfo: every cluster but the root's is free
+    buf.fill(0);
+    put32(buf, 0, FSINFO_LEAD);
+    put32(buf, 484, FSINFO_STRUCT);
+    put32(buf, 488, clusters as u32 - 1);
+    put32(buf, 492, 3);
+    put32(buf, 508, FSINFO_TRAIL);
+    dev.write_blocks(1, buf)?;
+    dev.write_blocks(7, buf)?;
+
+    // Media byte, a reserved end-of-chain, and the root's one cluster
+    buf.fill(0);
+    put32(buf, 0, 0x0FFF_FF00 | 0xF8);
+    put32(buf, 4, FAT_EOC);
+    put32(buf, 8, FAT_EOC);
+    dev.write_blocks(reserved, buf)?;
+    dev.write_blocks(reserved + fat_sectors, buf)?;
+
+    if !label.is_empty() {
+        buf.fill(0);
+        buf[..ENTRY_SIZE].copy_from_slice(&short_entry(&label_bytes, 0, ATTR_VOLUME_ID, 0));
+        dev.write_blocks(data_lba, buf)?;
+    }
+    dev.flush().map_err(Error::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use std::path::Path;
+    use std::process::Command;
+    use std::string::String;
+    use std::vec::Vec;
+
+    /// 4 MiB volume: 512-byte clusters
+    fn volume(data: &mut Vec<u8>) -> RamDisk<'_> {
+        data.resize(4 << 20, 0);
+        let disk = RamDisk::new(data, 512).unwrap();
+        format(&disk, "test", 0x1234_5678).unwrap();
+        disk
+    }
+
+    fn names<D: BlockDevice>(fs: &FatFs<D>, dir: &Node) -> Vec<String> {
+        fs.read_dir(dir).unwrap().map(|e| e.unwrap().name().collect()).collect()
+    }
+
+    fn contents<D: BlockDevice>(fs: &FatFs<D>, path: &str) -> Vec<u8> {
+        let file = fs.lookup(path).unwrap();
+        let mut buf = vec![0; file.size() as usize];
+        assert_eq!(fs.read(&file, 0, &mut buf).unwrap(), buf.len());
+        buf
+    }
+
+    /// Whether fsck.vfat passes `data`, saved as `image`; None if it is not
+    /// installed
+    fn fsck(data: &[u8], image: &Path) -> Option<bool> {
+        std::fs::write(image, data).unwrap();
+        let out = Command::new("fsck.vfat").arg("-n").arg(image).output().ok()?;
+        if !out.status.success() {
+            std::eprintln!("{}", String::from_utf8_lossy(&out.stdout));
+        }
+        Some(out.status.success())
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(exact_short_name("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(exact_short_name("readme.txt"), Some((*b"README  TXT", CASE_LOWER_BASE | CASE_LOWER_EXT)));
+        assert_eq!(exact_short_name("Makefile"), None);
+        assert_eq!(exact_short_name("a.tar.gz"), None);
+        assert_eq!(exact_short_name("longername.txt"), None);
+        assert_eq!(short_basis(".hidden file.tar.gz"), (*b"HIDDENFI", 8, *b"GZ "));
+        assert_eq!(short_basis("über+"), (*b"_BER_   ", 5, *b"   "));
+        assert_eq!(lfn_checksum(b"README  TXT"), 0x73);
+        assert_eq!(check_name("a/b"), Err(Error::InvalidName));
+        assert_eq!(check_name("trailing."), Err(Error::InvalidName));
+    }
+
+    #[test]
+    fn format_then_mount() {
+        let mut data = Vec::new();
+        let disk = volume(&mut data);
+        let fs = FatFs::new(&disk).unwrap();
+        assert_eq!((fs.label(), fs.cluster_size()), ("TEST", 512));
+        // 8192 sectors: 32 reserved, two FATs of 64 sectors
+        assert_eq!(fs.clusters(), 8192 - 32 - 128);
+        assert_eq!(fs.free_clusters().unwrap(), fs.clusters() - 1);
+        // The volume label entry is not listed
+        assert!(names(&fs, &fs.root()).is_empty());
+        assert_eq!(fs.lookup("/").unwrap(), fs.root());
+        assert_eq!(fs.lookup("nothing").unwrap_err(), Error::NotFound);
+
+        data[510] = 0;
+        assert_eq!(FatFs::new(RamDisk::new(&mut data, 512).unwrap()).err(), Some(Error::Io("not a FAT filesystem")));
+    }
+
+    #[test]
+    fn files_with_long_and_short_names() {
+        let mut data = Vec::new();
+        let disk = volume(&mut data);
+        let fs = FatFs::new(&disk).unwrap();
+        let root = fs.root();
+
+        let text = b"hello from the unikernel\n";
+        for name in ["HELLO.TXT", "notes.md", "A rather long name.data"] {
+            let mut file = fs.create(&root, name).unwrap();
+            assert_eq!(fs.write(&mut file, 0, text).unwrap(), text.len());
+        }
+        assert_eq!(names(&fs, &root), ["HELLO.TXT", "notes.md", "A rather long name.data"]);
+        assert_eq!(fs.create(&root, "hello.txt").unwrap_err(), Error::Exists);
+
+        // Found whatever the case, and by short name
+        assert_eq!(contents(&fs, "/a RATHER long NAME.data"), text);
+        assert_eq!(contents(&fs, "ARATHE~1.DAT"), text);
+        assert_eq!(contents(&fs, "Notes.MD"), text);
+        let entry = fs.find(&root, "a rather long name.data").unwrap().unwrap();
+        assert_eq!(entry.short_name().collect::<String>(), "ARATHE~1.DAT");
+
+        // Same basis, next number
+        fs.create(&root, "A rather long name.dat2").unwrap();
+        let entry = fs.find(&root, "A rather long name.dat2").unwrap().unwrap();
+        assert_eq!(entry.short_name().collect::<String>(), "ARATHE~2.DAT");
+
+        // A name long enough to need all twenty long-name entries
+        let long: String = "x".repeat(MAX_NAME);
+        fs.create(&root, &long).unwrap();
+        assert!(fs.lookup(&long).is_ok());
+        assert_eq!(fs.create(&root, &"y".repeat(MAX_NAME + 1)).unwrap_err(), Error::InvalidName);
+    }
+
+    #[test]
+    fn directories_nest_and_grow() {
+        let mut data = Vec::new();
+        let disk = volume(&mut data);
+        let fs = FatFs::new(&disk).unwrap();
+        let etc = fs.mkdir(&fs.root(), "etc").unwrap();
+        let conf = fs.mkdir(&etc, "conf.d").unwrap();
+        assert_eq!(names(&fs, &conf), [".", ".."]);
+        assert_eq!(fs.lookup("etc/conf.d/..").unwrap().cluster(), etc.cluster());
+        assert_eq!(fs.lookup("/etc/../etc/./conf.d").unwrap().cluster(), conf.cluster());
+        assert_eq!(fs.lookup("etc/..").unwrap(), fs.root());
+        assert_eq!(fs.lookup("/..").unwrap(), fs.root());
+
+        // 16 entries fit a cluster; 40 files with long names need several
+        for i in 0..40 {
+            let mut file = fs.create(&conf, &std::format!("setting number {i}.conf")).unwrap();
+            fs.write(&mut file, 0, std::format!("value={i}").as_bytes()).unwrap();
+        }
+        let listed = names(&fs, &conf);
+        assert_eq!(listed.len(), 42);
+        assert_eq!(listed[41], "setting number 39.conf");
+        assert_eq!(contents(&fs, "etc/conf.d/SETTING NUMBER 27.CONF"), b"value=27");
+
+        let mut file = fs.lookup("etc").unwrap();
+        assert_eq!(fs.write(&mut file, 0, b"x").unwrap_err(), Error::IsADirectory);
+        assert_eq!(fs.create(&fs.lookup("etc/conf.d/setting number 1.conf").unwrap(), "x").unwrap_err(), Error::NotADirectory);
+    }
+
+    #[test]
+    fn write_read_and_truncate_across_clusters() {
+        let mut data = Vec::new();
+        let disk = volume(&mut data);
+        let fs = FatFs::new(&disk).unwrap();
+        let free = fs.free_clusters().unwrap();
+        let mut file = fs.create(&fs.root(), "big.bin").unwrap();
+
+        let pattern: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs.write(&mut file, 100, &pattern).unwrap();
+        assert_eq!(file.size(), 5100);
+        assert_eq!(fs.free_clusters().unwrap(), free - 10);
+        let mut buf = vec![0xFF; 6000];
+        assert_eq!(fs.read(&file, 0, &mut buf).unwrap(), 5100);
+        assert!(buf[..100].iter().all(|&b| b == 0));
+        assert_eq!(buf[100..5100], pattern[..]);
+        // Reading from an odd offset, and past the end
+        assert_eq!(fs.read(&file, 1234, &mut buf[..10]).unwrap(), 10);
+        assert_eq!(buf[..10], pattern[1134..1144]);
+        assert_eq!(fs.read(&file, 5100, &mut buf).unwrap(), 0);
+
+        // Overwrite in the middle; the size stays
+        fs.write(&mut file, 600, b"middle").unwrap();
+        assert_eq!((file.size(), &contents(&fs, "big.bin")[600..606]), (5100, &b"middle"[..]));
+
+        // Shrinking frees clusters, growing again reads back zeros
+        fs.truncate(&mut file, 700).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 2);
+        fs.truncate(&mut file, 2000).unwrap();
+        let back = contents(&fs, "big.bin");
+        assert_eq!(back.len(), 2000);
+        assert_eq!(&back[600..606], b"middle");
+        assert!(back[700..].iter().all(|&b| b == 0));
+        fs.truncate(&mut file, 0).unwrap();
+        assert_eq!((file.cluster(), fs.free_clusters().unwrap()), (0, free));
+    }
+
+    #[test]
+    fn remove_files_and_directories() {
+        let mut data = Vec::new();
+        let disk = volume(&mut data);
+        let fs = FatFs::new(&disk).unwrap();
+        let root = fs.root();
+        let free = fs.free_clusters().unwrap();
+        let dir = fs.mkdir(&root, "Scratch Space").unwrap();
+        let mut file = fs.create(&dir, "temporary file.txt").unwrap();
+        fs.write(&mut file, 0, &[1; 1500]).unwrap();
+        // Ids lead back to the nodes until they are removed
+        assert_eq!(fs.node(file.id()).unwrap(), file);
+        assert_eq!(fs.node(root.id()).unwrap(), root);
+        assert_eq!(fs.node(1).unwrap_err(), Error::Stale);
+
+        assert_eq!(fs.remove(&root, "scratch space").unwrap_err(), Error::NotEmpty);
+        assert_eq!(fs.remove(&dir, "..").unwrap_err(), Error::InvalidName);
+        fs.remove(&dir, "TEMPORARY FILE.TXT").unwrap();
+        assert_eq!(names(&fs, &dir), [".", ".."]);
+        assert_eq!(fs.node(file.id()).unwrap_err(), Error::Stale);
+        fs.remove(&root, "Scratch Space").unwrap();
+        assert!(names(&fs, &root).is_empty());
+        assert_eq!(fs.free_clusters().unwrap(), free);
+        assert_eq!(fs.remove(&root, "Scratch Space").unwrap_err(), Error::NotFound);
+
+        // The freed entries are used again
+        fs.create(&root, "again").unwrap();
+        assert_eq!(names(&fs, &root), ["again"]);
+    }
+
+    #[test]
+    fn changes_survive_a_remount() {
+        let mut data = Vec::new();
+        let disk = volume(&mut data);
+        {
+            let fs = FatFs::new(&disk).unwrap();
+            let dir = fs.mkdir(&fs.root(), "data").unwrap();
+            let mut file = fs.create(&dir, "log.txt").unwrap();
+            fs.write(&mut file, 0, &[b'x'; 3000]).unwrap();
+            fs.flush().unwrap();
+        }
+        let fs = FatFs::new(&disk).unwrap();
+        assert_eq!(contents(&fs, "data/log.txt"), [b'x'; 3000]);
+        // FSInfo kept count
+        let recorded = fs.free.get();
+        assert_eq!(recorded, Some(fs.free_clusters().unwrap()));
+        assert_eq!(recorded, Some(fs.clusters() - 1 - 1 - 6));
+    }
+
+    #[test]
+    fn full_disk() {
+        let mut data = vec![0u8; 200 * 512];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        format(&disk, "", 1).unwrap();
+        let fs = FatFs::new(&disk).unwrap();
+        assert_eq!(fs.label(), "NO NAME");
+        let free = fs.free_clusters().unwrap() as usize;
+        let mut file = fs.create(&fs.root(), "fill").unwrap();
+        assert_eq!(fs.write(&mut file, 0, &vec![7; (free + 1) * 512]).unwrap_err(), Error::NoSpace);
+        // Nothing half-allocated is left behind
+        assert_eq!((file.size(), fs.free_clusters().unwrap() as usize), (0, free));
+        fs.write(&mut file, 0, &vec![7; free * 512]).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), 0);
+    }
+
+    #[test]
+    fn mkfs_vfat_images() {
+        // Checked against the real tools where they are installed
+        let image = std::env::temp_dir().join(std::format!("driver_core-fat-{}.img", std::process::id()));
+        let _ = std::fs::remove_file(&image);
+        let Ok(made) = Command::new("mkfs.vfat").args(["-F", "32", "-n", "HOSTDATA", "-C"]).arg(&image).arg("8192").output() else {
+            std::eprintln!("mkfs.vfat not installed; skipping");
+            return;
+        };
+        assert!(made.status.success());
+        let mut data = std::fs::read(&image).unwrap();
+        {
+            let disk = RamDisk::new(&mut data, 512).unwrap();
+            let fs = FatFs::new(&disk).unwrap();
+            assert_eq!(fs.label(), "HOSTDATA");
+            let dir = fs.mkdir(&fs.root(), "From the unikernel").unwrap();
+            let mut file = fs.create(&dir, "a long file name.txt").unwrap();
+            fs.write(&mut file, 0, &[b'z'; 10_000]).unwrap();
+            fs.create(&fs.root(), "short.txt").unwrap();
+            fs.flush().unwrap();
+        }
+        assert_ne!(fsck(&data, &image), Some(false));
+
+        // And what format() makes
+        let mut ours = Vec::new();
+        {
+            let disk = volume(&mut ours);
+            let fs = FatFs::new(&disk).unwrap();
+            let mut file = fs.create(&fs.mkdir(&fs.root(), "Some Directory").unwrap(), "file.bin").unwrap();
+            fs.write(&mut file, 0, &[1; 2000]).unwrap();
+            fs.flush().unwrap();
+        }
+        assert_ne!(fsck(&ours, &image), Some(false));
+        let _ = std::fs::remove_file(&image);
+    }
+}
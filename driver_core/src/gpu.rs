@@ -0,0 +1,293 @@
+//! virtio-gpu 2D control commands (virtio 1.x, section 5.7)
+//!
+//! Wire structs for the commands the display drivers send, with
+//! constructors filling in the header. Every struct is `repr(C)` without
+//! implicit padding, so `as_bytes()` is exactly what goes on the control
+//! queue (little-endian, like the guest).
+
+pub const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+pub const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+pub const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+pub const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+pub const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+pub const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+pub const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+pub const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+pub const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+
+pub const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+pub const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+pub const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+pub const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+
+/// Number of scanouts in a display info response
+pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+/// Plain-old-data command or response
+///
+/// # Safety
+/// Implementors must be `repr(C)` with no padding bytes.
+pub unsafe trait Command: Sized {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, core::mem::size_of::<Self>()) }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct CtrlHdr {
+    pub cmd_type: u32,
+    pub flags: u32,
+    pub fence_id: u64,
+    pub ctx_id: u32,
+    pub padding: u32,
+}
+
+impl CtrlHdr {
+    pub const fn new(cmd_type: u32) -> Self {
+        CtrlHdr { cmd_type, flags: 0, fence_id: 0, ctx_id: 0, padding: 0 }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Rectangle at the origin
+    pub const fn sized(width: u32, height: u32) -> Self {
+        Rect { x: 0, y: 0, width, height }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct DisplayOne {
+    pub r: Rect,
+    pub enabled: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct RespDisplayInfo {
+    pub hdr: CtrlHdr,
+    pub pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+impl RespDisplayInfo {
+    /// Decode a response buffer; None if it is short or not display info
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < core::mem::size_of::<Self>() || response_type(bytes)? != VIRTIO_GPU_RESP_OK_DISPLAY_INFO {
+            return None;
+        }
+        Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+    }
+
+    /// Preferred (width, height) of a scanout, if it is enabled
+    pub fn preferred_mode(&self, scanout: usize) -> Option<(u32, u32)> {
+        let mode = self.pmodes.get(scanout)?;
+        (mode.enabled != 0).then_some((mode.r.width, mode.r.height))
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceCreate2d {
+    pub hdr: CtrlHdr,
+    pub resource_id: u32,
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ResourceCreate2d {
+    pub const fn new(resource_id: u32, format: u32, width: u32, height: u32) -> Self {
+        ResourceCreate2d {
+            hdr: CtrlHdr::new(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+            resource_id,
+            format,
+            width,
+            height,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemEntry {
+    pub addr: u64,
+    pub length: u32,
+    pub padding: u32,
+}
+
+/// RESOURCE_ATTACH_BACKING with a single contiguous backing region
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceAttachBacking {
+    pub hdr: CtrlHdr,
+    pub resource_id: u32,
+    pub nr_entries: u32,
+    pub entry: MemEntry,
+}
+
+impl ResourceAttachBacking {
+    pub const fn new(resource_id: u32, addr: u64, length: u32) -> Self {
+        ResourceAttachBacking {
+            hdr: CtrlHdr::new(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+            resource_id,
+            nr_entries: 1,
+            entry: MemEntry { addr, length, padding: 0 },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SetScanout {
+    pub hdr: CtrlHdr,
+    pub r: Rect,
+    pub scanout_id: u32,
+    pub resource_id: u32,
+}
+
+impl SetScanout {
+    pub const fn new(scanout_id: u32, resource_id: u32, r: Rect) -> Self {
+        SetScanout { hdr: CtrlHdr::new(VIRTIO_GPU_CMD_SET_SCANOUT), r, scanout_id, resource_id }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferToHost2d {
+    pub hdr: CtrlHdr,
+    pub r: Rect,
+    pub offset: u64,
+    pub resource_id: u32,
+    pub padding: u32,
+}
+
+impl TransferToHost2d {
+    pub const fn new(resource_id: u32, r: Rect, offset: u64) -> Self {
+        TransferToHost2d {
+            hdr: CtrlHdr::new(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+            r,
+            offset,
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceFlush {
+    pub hdr: CtrlHdr,
+    pub r: Rect,
+    pub resource_id: u32,
+    pub padding: u32,
+}
+
+impl ResourceFlush {
+    pub const fn new(resource_id: u32, r: Rect) -> Self {
+        ResourceFlush { hdr: CtrlHdr::new(VIRTIO_GPU_CMD_RESOURCE_FLUSH), r, resource_id, padding: 0 }
+    }
+}
+
+unsafe impl Command for CtrlHdr {}
+unsafe impl Command for RespDisplayInfo {}
+unsafe impl Command for ResourceCreate2d {}
+unsafe impl Command for ResourceAttachBacking {}
+unsafe impl Command for SetScanout {}
+unsafe impl Command for TransferToHost2d {}
+unsafe impl Command for ResourceFlush {}
+
+/// Response type from the header at the start of a response buffer
+pub fn response_type(resp: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(resp.get(..4)?.try_into().unwrap()))
+}
+
+/// True for any VIRTIO_GPU_RESP_ERR_* type
+pub fn is_error(resp_type: u32) -> bool {
+    resp_type >= VIRTIO_GPU_RESP_ERR_UNSPEC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(bytes: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn command_sizes_match_spec() {
+        assert_eq!(core::mem::size_of::<CtrlHdr>(), 24);
+        assert_eq!(core::mem::size_of::<RespDisplayInfo>(), 24 + 16 * 24);
+        assert_eq!(core::mem::size_of::<ResourceCreate2d>(), 40);
+        assert_eq!(core::mem::size_of::<ResourceAttachBacking>(), 48);
+        assert_eq!(core::mem::size_of::<SetScanout>(), 48);
+        assert_eq!(core::mem::size_of::<TransferToHost2d>(), 56);
+        assert_eq!(core::mem::size_of::<ResourceFlush>(), 48);
+    }
+
+    #[test]
+    fn encodes_resource_create() {
+        let cmd = ResourceCreate2d::new(1, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, 1280, 720);
+        let bytes = cmd.as_bytes();
+        assert_eq!(word(bytes, 0), VIRTIO_GPU_CMD_RESOURCE_CREATE_2D);
+        assert_eq!(&bytes[4..24], &[0; 20]);
+        assert_eq!([word(bytes, 24), word(bytes, 28), word(bytes, 32), word(bytes, 36)], [1, 2, 1280, 720]);
+    }
+
+    #[test]
+    fn encodes_attach_backing_entry() {
+        let cmd = ResourceAttachBacking::new(1, 0x7200_0000, 1280 * 720 * 4);
+        let bytes = cmd.as_bytes();
+        assert_eq!(word(bytes, 0), VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING);
+        assert_eq!([word(bytes, 24), word(bytes, 28)], [1, 1]);
+        assert_eq!(u64::from_le_bytes(bytes[32..40].try_into().unwrap()), 0x7200_0000);
+        assert_eq!(word(bytes, 40), 1280 * 720 * 4);
+    }
+
+    #[test]
+    fn encodes_transfer_and_flush_rects() {
+        let r = Rect::sized(800, 600);
+        let transfer = TransferToHost2d::new(1, r, 0);
+        let bytes = transfer.as_bytes();
+        assert_eq!(word(bytes, 0), VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D);
+        assert_eq!([word(bytes, 24), word(bytes, 28), word(bytes, 32), word(bytes, 36)], [0, 0, 800, 600]);
+        assert_eq!(word(bytes, 48), 1);
+
+        let flush = ResourceFlush::new(1, r);
+        assert_eq!(word(flush.as_bytes(), 0), VIRTIO_GPU_CMD_RESOURCE_FLUSH);
+        assert_eq!(word(flush.as_bytes(), 40), 1);
+
+        let scanout = SetScanout::new(0, 1, r);
+        assert_eq!([word(scanout.as_bytes(), 40), word(scanout.as_bytes(), 44)], [0, 1]);
+    }
+
+    #[test]
+    fn parses_display_info() {
+        let mut resp = RespDisplayInfo { hdr: CtrlHdr::new(VIRTIO_GPU_RESP_OK_DISPLAY_INFO), ..Default::default() };
+        resp.pmodes[0] = DisplayOne { r: Rect::sized(1024, 768), enabled: 1, flags: 0 };
+        let bytes = resp.as_bytes().to_vec();
+
+        let info = RespDisplayInfo::parse(&bytes).unwrap();
+        assert_eq!(info.preferred_mode(0), Some((1024, 768)));
+        assert_eq!(info.preferred_mode(1), None);
+        assert_eq!(info.preferred_mode(99), None);
+
+        assert!(RespDisplayInfo::parse(&bytes[..100]).is_none());
+        let nodata = CtrlHdr::new(VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(response_type(nodata.as_bytes()), Some(VIRTIO_GPU_RESP_OK_NODATA));
+        assert!(!is_error(VIRTIO_GPU_RESP_OK_NODATA));
+        assert!(is_error(VIRTIO_GPU_RESP_ERR_UNSPEC + 3));
+    }
+}
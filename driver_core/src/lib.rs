@@ -0,0 +1,30 @@
+//! Hardware-independent core of the unikernel's drivers
+//!
+//! Everything here is plain data handling: virtqueue bookkeeping, DTB
+//! parsing, PCI resource allocation, the wire formats of the virtio-gpu,
+//! block and net requests, block devices, their cache and partition tables,
+//! FAT32 and ext2/3/4, reading the initrd's CPIO archive, the VFS that
+//! mounts them all into one tree, and the ELF
+//! parsing, initial stack and page tables behind running programs at EL0.
+//! Register access goes through the `Mmio` trait, so the kernel plugs in
+//! `mmio::Volatile` and host tests plug in a fake.
+//!
+//! `no_std` like the kernel; build with `cargo test` on the host.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod blk;
+pub mod block;
+pub mod cpio;
+pub mod dtb;
+pub mod elf;
+pub mod ext;
+pub mod fat;
+pub mod gpu;
+pub mod mmio;
+pub mod net;
+pub mod pagetable;
+pub mod partition;
+pub mod pci;
+pub mod vfs;
+pub mod virtqueue;
@@ -0,0 +1,203 @@
+//! Register access
+//!
+//! Code that talks to a device takes an `&impl Mmio` rather than
+//! dereferencing raw addresses, so the kernel can hand it real registers
+//! (`Volatile`) and host tests can hand it a `FakeMmio`.
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// Byte-addressed register space
+pub trait Mmio {
+    fn read8(&self, addr: u64) -> u8;
+    fn read16(&self, addr: u64) -> u16;
+    fn read32(&self, addr: u64) -> u32;
+    fn write8(&self, addr: u64, val: u8);
+    fn write16(&self, addr: u64, val: u16);
+    fn write32(&self, addr: u64, val: u32);
+
+    /// 64-bit field as two 32-bit accesses, low half first (as virtio allows)
+    fn read64(&self, addr: u64) -> u64 {
+        let lo = self.read32(addr) as u64;
+        let hi = self.read32(addr + 4) as u64;
+        (hi << 32) | lo
+    }
+
+    fn write64(&self, addr: u64, val: u64) {
+        self.write32(addr, val as u32);
+        self.write32(addr + 4, (val >> 32) as u32);
+    }
+}
+
+/// Volatile loads and stores at the given physical addresses
+///
+/// The kernel runs identity mapped, so an address is also a pointer.
+#[derive(Clone, Copy, Debug)]
+pub struct Volatile(());
+
+impl Volatile {
+    /// # Safety
+    /// Every address later passed to this accessor must be device memory or
+    /// RAM that may be accessed with volatile loads and stores.
+    pub const unsafe fn new() -> Self {
+        Volatile(())
+    }
+}
+
+impl Mmio for Volatile {
+    fn read8(&self, addr: u64) -> u8 {
+        unsafe { read_volatile(addr as *const u8) }
+    }
+
+    fn read16(&self, addr: u64) -> u16 {
+        unsafe { read_volatile(addr as *const u16) }
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        unsafe { read_volatile(addr as *const u32) }
+    }
+
+    fn write8(&self, addr: u64, val: u8) {
+        unsafe { write_volatile(addr as *mut u8, val) }
+    }
+
+    fn write16(&self, addr: u64, val: u16) {
+        unsafe { write_volatile(addr as *mut u16, val) }
+    }
+
+    fn write32(&self, addr: u64, val: u32) {
+        unsafe { write_volatile(addr as *mut u32, val) }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+pub use fake::FakeMmio;
+
+#[cfg(any(test, feature = "std"))]
+mod fake {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use super::Mmio;
+
+    /// Sparse little-endian register file for host tests
+    ///
+    /// Unwritten bytes read as zero. A 32-bit register can be given a
+    /// writable mask, which is how BAR sizing is faked: bits outside the mask
+    /// keep their value whatever the driver writes.
+    #[derive(Default)]
+    pub struct FakeMmio {
+        bytes: RefCell<BTreeMap<u64, u8>>,
+        masks: RefCell<BTreeMap<u64, u32>>,
+        writes: RefCell<Vec<(u64, u64)>>,
+    }
+
+    impl FakeMmio {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Set bytes without going through the write log or masks
+        pub fn poke(&self, addr: u64, data: &[u8]) {
+            let mut bytes = self.bytes.borrow_mut();
+            for (i, &b) in data.iter().enumerate() {
+                bytes.insert(addr + i as u64, b);
+            }
+        }
+
+        pub fn poke8(&self, addr: u64, val: u8) {
+            self.poke(addr, &[val]);
+        }
+
+        pub fn poke16(&self, addr: u64, val: u16) {
+            self.poke(addr, &val.to_le_bytes());
+        }
+
+        pub fn poke32(&self, addr: u64, val: u32) {
+            self.poke(addr, &val.to_le_bytes());
+        }
+
+        /// Only the bits set in `writable` change on a 32-bit write to `addr`
+        pub fn set_write_mask(&self, addr: u64, writable: u32) {
+            self.masks.borrow_mut().insert(addr, writable);
+        }
+
+        /// Every write so far as (address, value), in order
+        pub fn writes(&self) -> Vec<(u64, u64)> {
+            self.writes.borrow().clone()
+        }
+
+        fn load(&self, addr: u64, len: usize) -> u64 {
+            let bytes = self.bytes.borrow();
+            (0..len).fold(0u64, |acc, i| {
+                let b = bytes.get(&(addr + i as u64)).copied().unwrap_or(0);
+                acc | (b as u64) << (8 * i)
+            })
+        }
+
+        fn store(&self, addr: u64, len: usize, val: u64) {
+            self.writes.borrow_mut().push((addr, val));
+            let val = match self.masks.borrow().get(&addr) {
+                Some(&mask) if len == 4 => {
+                    let old = self.load(addr, 4) as u32;
+                    ((val as u32 & mask) | (old & !mask)) as u64
+                }
+                _ => val,
+            };
+            self.poke(addr, &val.to_le_bytes()[..len]);
+        }
+    }
+
+    impl Mmio for FakeMmio {
+        fn read8(&self, addr: u64) -> u8 {
+            self.load(addr, 1) as u8
+        }
+
+        fn read16(&self, addr: u64) -> u16 {
+            self.load(addr, 2) as u16
+        }
+
+        fn read32(&self, addr: u64) -> u32 {
+            self.load(addr, 4) as u32
+        }
+
+        fn write8(&self, addr: u64, val: u8) {
+            self.store(addr, 1, val as u64);
+        }
+
+        fn write16(&self, addr: u64, val: u16) {
+            self.store(addr, 2, val as u64);
+        }
+
+        fn write32(&self, addr: u64, val: u32) {
+            self.store(addr, 4, val as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fake_is_little_endian_and_zero_filled() {
+        let mmio = FakeMmio::new();
+        assert_eq!(mmio.read32(0x1000), 0);
+        mmio.write32(0x1000, 0x1122_3344);
+        assert_eq!(mmio.read8(0x1000), 0x44);
+        assert_eq!(mmio.read16(0x1002), 0x1122);
+        mmio.write64(0x2000, 0xaabb_ccdd_0011_2233);
+        assert_eq!(mmio.read64(0x2000), 0xaabb_ccdd_0011_2233);
+        assert_eq!(mmio.read32(0x2004), 0xaabb_ccdd);
+    }
+
+    #[test]
+    fn write_mask_keeps_read_only_bits() {
+        let mmio = FakeMmio::new();
+        mmio.poke32(0x10, 0x4);
+        mmio.set_write_mask(0x10, 0xffff_f000);
+        mmio.write32(0x10, 0xffff_ffff);
+        assert_eq!(mmio.read32(0x10), 0xffff_f004);
+        assert_eq!(mmio.writes(), vec![(0x10, 0xffff_ffff)]);
+    }
+}
@@ -0,0 +1,114 @@
+//! virtio-net packet format (virtio 1.x, section 5.1)
+//!
+//! Every buffer on the receive and transmit queues starts with a
+//! `NetHdr`; the Ethernet frame follows it.
+
+// Feature bits
+pub const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+
+/// Largest Ethernet frame we send or receive (no FCS)
+pub const MTU: usize = 1514;
+
+/// Device config: MAC address (6 bytes)
+pub const CONFIG_MAC: u64 = 0x00;
+
+/// VirtIO net header (prepended to every packet)
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct NetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    // num_buffers only present with VIRTIO_NET_F_MRG_RXBUF
+}
+
+pub const NET_HDR_SIZE: usize = core::mem::size_of::<NetHdr>();
+
+/// Size of a buffer that holds any packet
+pub const PACKET_BUF_SIZE: usize = NET_HDR_SIZE + MTU;
+
+/// Write a header (no offloads) and `frame` into `buf`
+/// Returns the length to hand to the device, or None if it does not fit
+pub fn encode_tx(frame: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let total = NET_HDR_SIZE + frame.len();
+    if frame.len() > MTU || buf.len() < total {
+        return None;
+    }
+    buf[..NET_HDR_SIZE].fill(0);
+    buf[NET_HDR_SIZE..total].copy_from_slice(frame);
+    Some(total)
+}
+
+/// Frame in a receive buffer the device wrote `used_len` bytes into
+/// Empty for runts shorter than the header
+pub fn rx_frame(buf: &[u8], used_len: usize) -> &[u8] {
+    let end = used_len.min(buf.len());
+    buf.get(NET_HDR_SIZE..end).unwrap_or(&[])
+}
+
+/// Broadcast ARP request asking who has `target_ip`
+pub fn arp_request(mac: [u8; 6], sender_ip: [u8; 4], target_ip: [u8; 4]) -> [u8; 42] {
+    let mut pkt = [0u8; 42];
+
+    // Ethernet header: broadcast destination, our source, EtherType ARP
+    pkt[0..6].fill(0xFF);
+    pkt[6..12].copy_from_slice(&mac);
+    pkt[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+
+    // ARP: Ethernet/IPv4, 6-byte and 4-byte addresses, opcode request
+    pkt[14..16].copy_from_slice(&1u16.to_be_bytes());
+    pkt[16..18].copy_from_slice(&0x0800u16.to_be_bytes());
+    pkt[18] = 6;
+    pkt[19] = 4;
+    pkt[20..22].copy_from_slice(&1u16.to_be_bytes());
+    pkt[22..28].copy_from_slice(&mac);
+    pkt[28..32].copy_from_slice(&sender_ip);
+    // Target MAC stays zero (unknown)
+    pkt[38..42].copy_from_slice(&target_ip);
+    pkt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_is_10_bytes() {
+        assert_eq!(NET_HDR_SIZE, 10);
+    }
+
+    #[test]
+    fn encode_prepends_zero_header() {
+        let mut buf = [0xAAu8; PACKET_BUF_SIZE];
+        assert_eq!(encode_tx(&[1, 2, 3], &mut buf), Some(13));
+        assert_eq!(&buf[..13], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(encode_tx(&[0; MTU], &mut buf), Some(PACKET_BUF_SIZE));
+        assert_eq!(encode_tx(&[0; MTU + 1], &mut buf), None);
+        assert_eq!(encode_tx(&[0; 8], &mut [0u8; 12]), None);
+    }
+
+    #[test]
+    fn rx_frame_strips_header_and_clamps() {
+        let mut buf = [0u8; 64];
+        buf[NET_HDR_SIZE..NET_HDR_SIZE + 4].copy_from_slice(&[9, 8, 7, 6]);
+        assert_eq!(rx_frame(&buf, NET_HDR_SIZE + 4), &[9, 8, 7, 6]);
+        assert_eq!(rx_frame(&buf, 4), &[] as &[u8]);
+        assert_eq!(rx_frame(&buf, 1000).len(), 64 - NET_HDR_SIZE);
+    }
+
+    #[test]
+    fn arp_request_layout() {
+        let mac = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+        let pkt = arp_request(mac, [10, 0, 0, 2], [10, 0, 0, 1]);
+        assert_eq!(&pkt[0..6], &[0xFF; 6]);
+        assert_eq!(&pkt[6..12], &mac);
+        assert_eq!(&pkt[12..22], &[0x08, 0x06, 0, 1, 0x08, 0, 6, 4, 0, 1]);
+        assert_eq!(&pkt[22..28], &mac);
+        assert_eq!(&pkt[28..32], &[10, 0, 0, 2]);
+        assert_eq!(&pkt[32..38], &[0; 6]);
+        assert_eq!(&pkt[38..42], &[10, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,467 @@
+//! AArch64 stage 1 translation tables: 4 KiB granule, 48-bit VA, levels 0-3
+//!
+//! Tables are reached by physical address through the `Frames` trait, so the
+//! kernel plugs in its page allocator (RAM is identity mapped) and host tests
+//! plug in a Vec of pages. map() uses the largest blocks the alignment
+//! allows, and mapping over (or unmapping) part of a block splits it.
+//!
+//! An address space can start as a copy of another's top-level table
+//! (`PageTable::share`): the tables below stay shared until a map() has to
+//! change one, which then gets a private copy. Tables and pages an address
+//! space allocated itself carry the software OWNED bit, and free() returns
+//! exactly those.
+
+pub const PAGE_SIZE: u64 = 4096;
+const ENTRIES: usize = 512;
+
+/// VA bits translated by TTBR0 (TCR_EL1.T0SZ = 64 - VA_BITS)
+pub const VA_BITS: u32 = 48;
+
+// Descriptor bits
+const VALID: u64 = 1 << 0;
+/// Table at levels 0-2, page at level 3 (clear for a block)
+const TABLE_OR_PAGE: u64 = 1 << 1;
+const ATTR_INDEX_SHIFT: u32 = 2;
+/// AP[1]: EL0 may access
+const AP_EL0: u64 = 1 << 6;
+/// AP[2]: read-only
+const AP_RO: u64 = 1 << 7;
+const SH_INNER: u64 = 3 << 8;
+/// Access flag; set up front, we take no access flag faults
+const AF: u64 = 1 << 10;
+const PXN: u64 = 1 << 53;
+const UXN: u64 = 1 << 54;
+/// Software bit (ignored by hardware): allocated by this address space
+const OWNED: u64 = 1 << 55;
+const ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+
+// MAIR_EL1 attribute indices
+const ATTR_DEVICE: u64 = 0;
+const ATTR_NORMAL: u64 = 1;
+/// MAIR_EL1 value for the indices above: Device-nGnRnE, Normal write-back
+pub const MAIR: u64 = 0x00 << (8 * ATTR_DEVICE) | 0xFF << (8 * ATTR_NORMAL);
+
+/// Physical pages holding tables (and mapped memory)
+pub trait Frames {
+    /// A zeroed page, or None when out of memory
+    fn alloc(&mut self) -> Option<u64>;
+    fn free(&mut self, pa: u64);
+    /// The page at `pa`, viewed as a table
+    fn table(&mut self, pa: u64) -> &mut [u64; ENTRIES];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Memory {
+    Device,
+    Normal,
+}
+
+/// What a mapping allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Attrs {
+    pub memory: Memory,
+    /// EL0 may access it (EL1 always can)
+    pub user: bool,
+    pub write: bool,
+    /// Executable at the EL that owns it (EL0 for user mappings)
+    pub exec: bool,
+}
+
+impl Attrs {
+    /// Kernel RAM: read, write and execute at EL1 only
+    pub const KERNEL: Attrs = Attrs { memory: Memory::Normal, user: false, write: true, exec: true };
+    /// MMIO at EL1 only
+    pub const DEVICE: Attrs = Attrs { memory: Memory::Device, user: false, write: true, exec: false };
+
+    /// User memory with the given permissions
+    pub const fn user(write: bool, exec: bool) -> Attrs {
+        Attrs { memory: Memory::Normal, user: true, write, exec }
+    }
+
+    fn bits(self) -> u64 {
+        let mut bits = AF;
+        bits |= match self.memory {
+            Memory::Device => (ATTR_DEVICE << ATTR_INDEX_SHIFT) | UXN | PXN,
+            Memory::Normal => (ATTR_NORMAL << ATTR_INDEX_SHIFT) | SH_INNER,
+        };
+        if self.user {
+            // The kernel never executes user pages
+            bits |= AP_EL0 | PXN;
+            if !self.exec {
+                bits |= UXN;
+            }
+        } else {
+            bits |= UXN;
+            if !self.exec {
+                bits |= PXN;
+            }
+        }
+        if !self.write {
+            bits |= AP_RO;
+        }
+        bits
+    }
+
+    fn from_bits(bits: u64) -> Attrs {
+        let user = bits & AP_EL0 != 0;
+        Attrs {
+            memory: if (bits >> ATTR_INDEX_SHIFT) & 7 == ATTR_DEVICE { Memory::Device } else { Memory::Normal },
+            user,
+            write: bits & AP_RO == 0,
+            exec: bits & if user { UXN } else { PXN } == 0,
+        }
+    }
+}
+
+/// A leaf mapping found by lookup()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// Physical address `va` translates to
+    pub pa: u64,
+    pub attrs: Attrs,
+    /// The page was mapped with `owned` and will be freed with the table
+    pub owned: bool,
+}
+
+/// Size covered by one entry at `level`
+pub const fn entry_size(level: usize) -> u64 {
+    1 << (12 + 9 * (3 - level))
+}
+
+fn index(va: u64, level: usize) -> usize {
+    ((va >> (12 + 9 * (3 - level))) & (ENTRIES as u64 - 1)) as usize
+}
+
+fn is_table(entry: u64, level: usize) -> bool {
+    level < 3 && entry & (VALID | TABLE_OR_PAGE) == VALID | TABLE_OR_PAGE
+}
+
+fn leaf(pa: u64, level: usize, attrs: Attrs, owned: bool) -> u64 {
+    let kind = if level == 3 { TABLE_OR_PAGE } else { 0 };
+    pa | attrs.bits() | VALID | kind | if owned { OWNED } else { 0 }
+}
+
+/// One translation table hierarchy (what TTBR0_EL1 points at)
+#[derive(Debug)]
+pub struct PageTable {
+    root: u64,
+}
+
+impl PageTable {
+    /// An empty address space
+    pub fn new(frames: &mut impl Frames) -> Option<Self> {
+        Some(PageTable { root: frames.alloc()? })
+    }
+
+    /// An address space that starts out with all of `other`'s mappings
+    pub fn share(frames: &mut impl Frames, other: &PageTable) -> Option<Self> {
+        let root = frames.alloc()?;
+        let entries = *frames.table(other.root);
+        let table = frames.table(root);
+        for (dst, src) in table.iter_mut().zip(entries) {
+            *dst = src & !OWNED;
+        }
+        Some(PageTable { root })
+    }
+
+    /// Physical address of the level 0 table
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Map [va, va + size) to [pa, pa + size); all page aligned
+    ///
+    /// Existing mappings in the range are replaced (an owned page mapped
+    /// again at the same address keeps its frame). With `owned`, the pages
+    /// belong to this address space and free() releases them. Returns false
+    /// if a table could not be allocated (the range may be partly mapped).
+    pub fn map(&mut self, frames: &mut impl Frames, va: u64, pa: u64, size: u64, attrs: Attrs, owned: bool) -> bool {
+        debug_assert!((va | pa | size).is_multiple_of(PAGE_SIZE) && va + size <= 1 << VA_BITS);
+        map_level(frames, self.root, 0, va, pa, size, Fill::Map { attrs, owned })
+    }
+
+    /// Remove any mapping of [va, va + size), freeing owned pages
+    /// Returns false if splitting a block needed a table that could not be
+    /// allocated.
+    pub fn unmap(&mut self, frames: &mut impl Frames, va: u64, size: u64) -> bool {
+        debug_assert!((va | size).is_multiple_of(PAGE_SIZE) && va + size <= 1 << VA_BITS);
+        map_level(frames, self.root, 0, va, va, size, Fill::Unmap)
+    }
+
+    /// The leaf mapping covering `va`
+    pub fn lookup(&self, frames: &mut impl Frames, va: u64) -> Option<Mapping> {
+        let mut table = self.root;
+        for level in 0..=3 {
+            let entry = frames.table(table)[index(va, level)];
+            if entry & VALID == 0 {
+                return None;
+            }
+            if is_table(entry, level) {
+                table = entry & ADDR_MASK;
+                continue;
+            }
+            if level == 0 {
+                // No blocks at level 0 with a 4 KiB granule
+                return None;
+            }
+            let offset = va & (entry_size(level) - 1);
+            return Some(Mapping {
+                pa: (entry & ADDR_MASK) + offset,
+                attrs: Attrs::from_bits(entry),
+                owned: entry & OWNED != 0,
+            });
+        }
+        None
+    }
+
+    /// Release every table and page this address space owns, root included
+    pub fn free(self, frames: &mut impl Frames) {
+        let entries = *frames.table(self.root);
+        for entry in entries {
+            release(frames, entry, 0);
+        }
+        frames.free(self.root);
+    }
+}
+
+/// What map_level() puts in the entries it covers
+#[derive(Clone, Copy)]
+enum Fill {
+    Map { attrs: Attrs, owned: bool },
+    Unmap,
+}
+
+fn map_level(frames: &mut impl Frames, table: u64, level: usize, mut va: u64, mut pa: u64, size: u64, fill: Fill) -> bool {
+    let end = va + size;
+    let span = entry_size(level);
+    while va < end {
+        let chunk = ((va & !(span - 1)) + span).min(end) - va;
+        let slot = index(va, level);
+        let entry = frames.table(table)[slot];
+
+        // Blocks exist at levels 1 and 2, pages at level 3
+        let whole = chunk == span && (va | pa) & (span - 1) == 0;
+        if entry & VALID == 0 && matches!(fill, Fill::Unmap) {
+            // Nothing here to remove
+        } else if level == 3 || (whole && level >= 1) {
+            let new = match fill {
+                Fill::Map { attrs, owned } => leaf(pa, level, attrs, owned),
+                Fill::Unmap => 0,
+            };
+            // Remapping an owned page onto itself only changes permissions
+            let same = matches!(fill, Fill::Map { owned: true, .. }) && !is_table(entry, level) && entry & ADDR_MASK == pa;
+            if !same {
+                release(frames, entry, level);
+            }
+            frames.table(table)[slot] = new;
+        } else {
+            let Some(next) = private_table(frames, table, slot, level) else {
+                return false;
+            };
+            if !map_level(frames, next, level + 1, va, pa, chunk, fill) {
+                return false;
+            }
+        }
+        va += chunk;
+        pa += chunk;
+    }
+    true
+}
+
+/// The table below `table[slot]`, made private to this address space:
+/// created if missing, copied if shared, expanded if it was a block
+fn private_table(frames: &mut impl Frames, table: u64, slot: usize, level: usize) -> Option<u64> {
+    let entry = frames.table(table)[slot];
+    if is_table(entry, level) && entry & OWNED != 0 {
+        return Some(entry & ADDR_MASK);
+    }
+
+    let next = frames.alloc()?;
+    if is_table(entry, level) {
+        let entries = *frames.table(entry & ADDR_MASK);
+        for (dst, src) in frames.table(next).iter_mut().zip(entries) {
+            *dst = src & !OWNED;
+        }
+    } else if entry & VALID != 0 {
+        // Same memory, in entries one level down
+        let attrs = Attrs::from_bits(entry);
+        let base = entry & ADDR_MASK;
+        let sub = entry_size(level + 1);
+        for (i, dst) in frames.table(next).iter_mut().enumerate() {
+            *dst = leaf(base + i as u64 * sub, level + 1, attrs, false);
+        }
+    }
+    frames.table(table)[slot] = next | VALID | TABLE_OR_PAGE | OWNED;
+    Some(next)
+}
+
+/// Free what an entry that is about to be overwritten owns
+fn release(frames: &mut impl Frames, entry: u64, level: usize) {
+    if entry & (VALID | OWNED) != VALID | OWNED {
+        return;
+    }
+    if is_table(entry, level) {
+        let entries = *frames.table(entry & ADDR_MASK);
+        for e in entries {
+            release(frames, e, level + 1);
+        }
+    }
+    frames.free(entry & ADDR_MASK);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pages at fake physical addresses 0x1000, 0x2000, ...
+    #[derive(Default)]
+    struct TestFrames {
+        pages: Vec<[u64; ENTRIES]>,
+        free: Vec<u64>,
+    }
+
+    impl TestFrames {
+        fn in_use(&self) -> usize {
+            self.pages.len() - self.free.len()
+        }
+    }
+
+    impl Frames for TestFrames {
+        fn alloc(&mut self) -> Option<u64> {
+            if let Some(pa) = self.free.pop() {
+                *self.table(pa) = [0; ENTRIES];
+                return Some(pa);
+            }
+            self.pages.push([0; ENTRIES]);
+            Some(self.pages.len() as u64 * PAGE_SIZE)
+        }
+
+        fn free(&mut self, pa: u64) {
+            assert!(!self.free.contains(&pa), "double free of {pa:#x}");
+            self.free.push(pa);
+        }
+
+        fn table(&mut self, pa: u64) -> &mut [u64; ENTRIES] {
+            &mut self.pages[(pa / PAGE_SIZE) as usize - 1]
+        }
+    }
+
+    const GIB: u64 = 1 << 30;
+    const MIB2: u64 = 2 << 20;
+
+    fn lookup(table: &PageTable, frames: &mut TestFrames, va: u64) -> Option<(u64, Attrs)> {
+        table.lookup(frames, va).map(|m| (m.pa, m.attrs))
+    }
+
+    #[test]
+    fn uses_the_largest_blocks_that_fit() {
+        let mut frames = TestFrames::default();
+        let mut table = PageTable::new(&mut frames).unwrap();
+        // 1 GiB + 2 MiB + 4 KiB, GiB aligned: one block of each size
+        assert!(table.map(&mut frames, GIB, GIB, GIB + MIB2 + PAGE_SIZE, Attrs::KERNEL, false));
+        // Root, one level 1, one level 2 and one level 3 table
+        assert_eq!(frames.in_use(), 4);
+
+        assert_eq!(lookup(&table, &mut frames, GIB + 0x1234), Some((GIB + 0x1234, Attrs::KERNEL)));
+        assert_eq!(lookup(&table, &mut frames, 2 * GIB + MIB2 + 8), Some((2 * GIB + MIB2 + 8, Attrs::KERNEL)));
+        assert_eq!(lookup(&table, &mut frames, 2 * GIB + MIB2 + PAGE_SIZE), None);
+        assert_eq!(lookup(&table, &mut frames, 0), None);
+    }
+
+    #[test]
+    fn descriptor_bits_round_trip() {
+        for attrs in [
+            Attrs::KERNEL,
+            Attrs::DEVICE,
+            Attrs::user(false, true),
+            Attrs::user(true, false),
+            Attrs::user(false, false),
+        ] {
+            assert_eq!(Attrs::from_bits(attrs.bits()), attrs);
+        }
+        // User code is never executable by the kernel, data by nobody
+        assert_ne!(Attrs::user(false, true).bits() & PXN, 0);
+        assert_eq!(Attrs::user(false, true).bits() & UXN, 0);
+        assert_ne!(Attrs::user(true, false).bits() & (PXN | UXN), 0);
+        assert_eq!(Attrs::KERNEL.bits() & PXN, 0);
+    }
+
+    #[test]
+    fn overlay_splits_shared_blocks_without_touching_the_original() {
+        let mut frames = TestFrames::default();
+        let mut kernel = PageTable::new(&mut frames).unwrap();
+        assert!(kernel.map(&mut frames, 0, 0, 4 * GIB, Attrs::DEVICE, false));
+        let kernel_pages = frames.in_use();
+
+        let mut user = PageTable::share(&mut frames, &kernel).unwrap();
+        let page = frames.alloc().unwrap();
+        assert!(user.map(&mut frames, 0x40_0000, page, PAGE_SIZE, Attrs::user(false, true), true));
+
+        // The user's view has the page and the rest of the block around it
+        let mapping = user.lookup(&mut frames, 0x40_0010).unwrap();
+        assert_eq!(mapping, Mapping { pa: page + 0x10, attrs: Attrs::user(false, true), owned: true });
+        assert_eq!(lookup(&user, &mut frames, 0x40_1000), Some((0x40_1000, Attrs::DEVICE)));
+        assert_eq!(lookup(&user, &mut frames, 0x900_0000), Some((0x900_0000, Attrs::DEVICE)));
+        assert_eq!(lookup(&user, &mut frames, 3 * GIB), Some((3 * GIB, Attrs::DEVICE)));
+
+        // The kernel's does not
+        assert_eq!(lookup(&kernel, &mut frames, 0x40_0010), Some((0x40_0010, Attrs::DEVICE)));
+
+        // Freeing gives back the user's tables and page, nothing of the kernel's
+        user.free(&mut frames);
+        assert_eq!(frames.in_use(), kernel_pages);
+        assert_eq!(lookup(&kernel, &mut frames, 0x40_0010), Some((0x40_0010, Attrs::DEVICE)));
+    }
+
+    #[test]
+    fn remapping_frees_owned_pages_it_replaces() {
+        let mut frames = TestFrames::default();
+        let mut table = PageTable::new(&mut frames).unwrap();
+        let base = 0x8000_0000_0000 - 4 * PAGE_SIZE;
+        for i in 0..4 {
+            let page = frames.alloc().unwrap();
+            assert!(table.map(&mut frames, base + i * PAGE_SIZE, page, PAGE_SIZE, Attrs::user(true, false), true));
+        }
+        let before = frames.in_use();
+
+        // New permissions for a page, same frame: nothing is freed
+        let page = table.lookup(&mut frames, base).unwrap().pa;
+        assert!(table.map(&mut frames, base, page, PAGE_SIZE, Attrs::user(true, true), true));
+        assert_eq!(frames.in_use(), before);
+        assert_eq!(table.lookup(&mut frames, base).unwrap().attrs, Attrs::user(true, true));
+
+        // Same range as one plain 16 KiB mapping: the four pages go back
+        assert!(table.map(&mut frames, base, 0x10_0000, 4 * PAGE_SIZE, Attrs::user(false, false), false));
+        assert_eq!(frames.in_use(), before - 4);
+        let mapping = table.lookup(&mut frames, base + PAGE_SIZE).unwrap();
+        assert_eq!(mapping, Mapping { pa: 0x10_1000, attrs: Attrs::user(false, false), owned: false });
+
+        table.free(&mut frames);
+        assert_eq!(frames.in_use(), 0);
+    }
+
+    #[test]
+    fn unmap_frees_pages_and_splits_blocks() {
+        let mut frames = TestFrames::default();
+        let mut table = PageTable::new(&mut frames).unwrap();
+        assert!(table.map(&mut frames, 0, 0, GIB, Attrs::DEVICE, false));
+        let page = frames.alloc().unwrap();
+        assert!(table.map(&mut frames, GIB, page, PAGE_SIZE, Attrs::user(true, false), true));
+        let before = frames.in_use();
+
+        assert!(table.unmap(&mut frames, GIB, PAGE_SIZE));
+        assert_eq!(table.lookup(&mut frames, GIB), None);
+        assert_eq!(frames.in_use(), before - 1);
+
+        // A hole in the middle of a 1 GiB block leaves the rest mapped
+        assert!(table.unmap(&mut frames, MIB2, PAGE_SIZE));
+        assert_eq!(lookup(&table, &mut frames, MIB2), None);
+        assert_eq!(lookup(&table, &mut frames, MIB2 - PAGE_SIZE), Some((MIB2 - PAGE_SIZE, Attrs::DEVICE)));
+        assert_eq!(lookup(&table, &mut frames, MIB2 + PAGE_SIZE), Some((MIB2 + PAGE_SIZE, Attrs::DEVICE)));
+
+        // Unmapping where nothing is mapped allocates nothing
+        let before = frames.in_use();
+        assert!(table.unmap(&mut frames, 1 << 40, GIB));
+        assert_eq!(frames.in_use(), before);
+    }
+}
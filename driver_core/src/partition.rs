@@ -0,0 +1,564 @@
+//! Partition tables: GPT, and the MBR before it
+//!
+//! read() looks for a GPT first. The primary header (block 1) and its
+//! entry array must pass their CRC32s; if either does not, the backup at
+//! the end of the disk is used instead. Without a GPT, the four primary
+//! MBR entries are used (logical partitions inside an extended partition
+//! are not followed). A disk whose MBR is only there to protect a GPT
+//! (type 0xEE) but has no intact GPT is an error rather than an MBR disk.
+//!
+//! Block numbers are in the device's logical blocks, as GPT defines them.
+//! Each partition opens as a `block::Partition`, so a filesystem on it
+//! sees a block device of its own. write_gpt() lays out a fresh GPT, for
+//! tests and for tools that make disk images.
+
+use core::fmt;
+
+use crate::block::{BlockDevice, Partition};
+
+/// Largest logical block read() handles
+pub const MAX_BLOCK: usize = 4096;
+
+/// Partitions a table holds at most
+pub const MAX_PARTITIONS: usize = 16;
+
+/// UTF-16 code units in a GPT partition name
+pub const NAME_UNITS: usize = 36;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Entries write_gpt() allocates, as most tools do
+const GPT_ENTRIES: usize = 128;
+
+const MBR_ENTRIES: usize = 446;
+const MBR_SIGNATURE: usize = 510;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// GUID as stored on disk: the first three fields little-endian
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// From the usual written form XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX,
+    /// given as its five fields
+    pub const fn new(a: u32, b: u16, c: u16, d: u16, e: u64) -> Self {
+        let (a, b, c, d, e) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes(), d.to_be_bytes(), e.to_be_bytes());
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], e[2], e[3], e[4], e[5], e[6], e[7]])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+        g[10..].iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Partition type: EFI system partition
+pub const TYPE_EFI_SYSTEM: Guid = Guid::new(0xC12A7328, 0xF81F, 0x11D2, 0xBA4B, 0x00A0C93EC93B);
+/// Partition type: Microsoft basic data (FAT and the like)
+pub const TYPE_BASIC_DATA: Guid = Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, 0x87C0, 0x68B6B72699C7);
+/// Partition type: Linux filesystem data
+pub const TYPE_LINUX_FILESYSTEM: Guid = Guid::new(0x0FC63DAF, 0x8483, 0x4772, 0x8E79, 0x3D69D8477DE4);
+
+/// Which table the partitions came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+    /// GPT, from the backup header: the primary one is damaged
+    GptBackup,
+}
+
+/// One partition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Number as in /dev/vda1: the GPT entry or MBR slot, from 1
+    pub number: usize,
+    pub first_lba: u64,
+    /// Last block, inclusive
+    pub last_lba: u64,
+    /// Zero on MBR disks
+    pub type_guid: Guid,
+    /// Zero on MBR disks
+    pub unique_guid: Guid,
+    /// MBR partition type; 0 on GPT disks
+    pub mbr_type: u8,
+    /// GPT attribute bits
+    pub attributes: u64,
+    name: [u16; NAME_UNITS],
+}
+
+impl PartitionInfo {
+    /// Size in blocks
+    pub fn blocks(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    /// GPT partition name (empty on MBR disks)
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        let len = self.name.iter().position(|&u| u == 0).unwrap_or(NAME_UNITS);
+        char::decode_utf16(self.name[..len].iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// The partition's blocks on `dev`, as a device of their own
+    pub fn open<D: BlockDevice>(&self, dev: D) -> Option<Partition<D>> {
+        Partition::new(dev, self.first_lba, self.blocks())
+    }
+}
+
+/// A disk's partitions
+#[derive(Clone, Debug)]
+pub struct Table {
+    pub scheme: Scheme,
+    /// Zero on MBR disks
+    pub disk_guid: Guid,
+    parts: [Option<PartitionInfo>; MAX_PARTITIONS],
+    count: usize,
+}
+
+impl Table {
+    fn new(scheme: Scheme, disk_guid: Guid) -> Self {
+        Table { scheme, disk_guid, parts: [None; MAX_PARTITIONS], count: 0 }
+    }
+
+    fn push(&mut self, part: PartitionInfo) -> Result<(), &'static str> {
+        let slot = self.parts.get_mut(self.count).ok_or("too many partitions")?;
+        *slot = Some(part);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Partitions in table order
+    pub fn partitions(&self) -> impl Iterator<Item = &PartitionInfo> {
+        self.parts[..self.count].iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Partition `number` (from 1, as in /dev/vda1)
+    pub fn get(&self, number: usize) -> Option<&PartitionInfo> {
+        self.partitions().find(|p| p.number == number)
+    }
+
+    /// First GPT partition called `name`
+    pub fn find(&self, name: &str) -> Option<&PartitionInfo> {
+        self.partitions().find(|p| p.name().eq(name.chars()))
+    }
+
+    /// First GPT partition of type `type_guid`
+    pub fn find_type(&self, type_guid: Guid) -> Option<&PartitionInfo> {
+        self.partitions().find(|p| p.type_guid == type_guid)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as GPT uses) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Fold `data` into a running CRC-32 (start from !0, invert at the end)
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+fn u32_at(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(data[off..off + 8].try_into().unwrap())
+}
+
+fn guid_at(data: &[u8], off: usize) -> Guid {
+    Guid(data[off..off + 16].try_into().unwrap())
+}
+
+/// Read the partition table of `dev`
+pub fn read(dev: &(impl BlockDevice + ?Sized)) -> Result<Table, &'static str> {
+    let bs = dev.block_size();
+    if !(512..=MAX_BLOCK).contains(&bs) {
+        return Err("unsupported block size for a partition table");
+    }
+    let mut block = [0u8; MAX_BLOCK];
+    let block = &mut block[..bs];
+
+    dev.read_blocks(0, block)?;
+    let mbr_valid = block[MBR_SIGNATURE..MBR_SIGNATURE + 2] == [0x55, 0xAA];
+    let mut mbr = [[0u8; 16]; 4];
+    for (i, entry) in mbr.iter_mut().enumerate() {
+        entry.copy_from_slice(&block[MBR_ENTRIES + 16 * i..MBR_ENTRIES + 16 * (i + 1)]);
+    }
+
+    let last = dev.block_count().checked_sub(1).ok_or("empty disk")?;
+    if let Some(table) = read_gpt(dev, 1, Scheme::Gpt, block)? {
+        return Ok(table);
+    }
+    if let Some(table) = read_gpt(dev, last, Scheme::GptBackup, block)? {
+        return Ok(table);
+    }
+
+    if !mbr_valid {
+        return Err("no partition table");
+    }
+    if mbr.iter().any(|e| e[4] == MBR_TYPE_PROTECTIVE) {
+        return Err("protective MBR but no intact GPT");
+    }
+    let mut table = Table::new(Scheme::Mbr, Guid::default());
+    for (i, e) in mbr.iter().enumerate() {
+        let (kind, start, count) = (e[4], u32_at(e, 8) as u64, u32_at(e, 12) as u64);
+        if kind == 0 || count == 0 || MBR_TYPES_EXTENDED.contains(&kind) {
+            continue;
+        }
+        if start == 0 || start + count > last + 1 {
+            return Err("MBR partition outside the disk");
+        }
+        table.push(PartitionInfo {
+            number: i + 1,
+            first_lba: start,
+            last_lba: start + count - 1,
+            type_guid: Guid::default(),
+            unique_guid: Guid::default(),
+            mbr_type: kind,
+            attributes: 0,
+            name: [0; NAME_UNITS],
+        })?;
+    }
+    Ok(table)
+}
+
+/// The GPT whose header is at block `lba`; None if it is missing or fails
+/// its checks
+fn read_gpt(dev: &(impl BlockDevice + ?Sized), lba: u64, scheme: Scheme, block: &mut [u8]) -> Result<Option<Table>, &'static str> {
+    let bs = block.len();
+    let last = dev.block_count() - 1;
+    if dev.read_blocks(lba, block).is_err() || &block[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = u32_at(block, 12) as usize;
+    if !(GPT_HEADER_SIZE..=bs).contains(&header_size) || u64_at(block, 24) != lba {
+        return Ok(None);
+    }
+    let crc = u32_at(block, 16);
+    block[16..20].fill(0);
+    if crc32(&block[..header_size]) != crc {
+        return Ok(None);
+    }
+
+    let (first_usable, last_usable) = (u64_at(block, 40), u64_at(block, 48));
+    let disk_guid = guid_at(block, 56);
+    let entries_lba = u64_at(block, 72);
+    let (num_entries, entry_size) = (u32_at(block, 80) as usize, u32_at(block, 84) as usize);
+    let entries_crc = u32_at(block, 88);
+    if first_usable > last_usable || last_usable > last {
+        return Ok(None);
+    }
+    if entry_size < GPT_ENTRY_SIZE || !entry_size.is_power_of_two() || entry_size > bs {
+        return Ok(None);
+    }
+    let entry_blocks = (num_entries * entry_size).div_ceil(bs) as u64;
+    match entries_lba.checked_add(entry_blocks) {
+        Some(end) if entries_lba > 0 && end <= last + 1 => {}
+        _ => return Ok(None),
+    }
+
+    // Check the array's CRC before believing any of it
+    let mut crc = !0;
+    for i in 0..entry_blocks {
+        if dev.read_blocks(entries_lba + i, block).is_err() {
+            return Ok(None);
+        }
+        let len = (num_entries * entry_size - i as usize * bs).min(bs);
+        crc = crc32_update(crc, &block[..len]);
+    }
+    if !crc != entries_crc {
+        return Ok(None);
+    }
+
+    let mut table = Table::new(scheme, disk_guid);
+    let per_block = bs / entry_size;
+    for i in 0..entry_blocks {
+        dev.read_blocks(entries_lba + i, block)?;
+        for j in 0..per_block {
+            let number = i as usize * per_block + j;
+            if number >= num_entries {
+                break;
+            }
+            let e = &block[j * entry_size..(j + 1) * entry_size];
+            let type_guid = guid_at(e, 0);
+            if type_guid.is_zero() {
+                continue;
+            }
+            let (first_lba, last_lba) = (u64_at(e, 32), u64_at(e, 40));
+            if first_lba < first_usable || last_lba > last_usable || first_lba > last_lba {
+                return Err("GPT partition outside the usable blocks");
+            }
+            let mut name = [0u16; NAME_UNITS];
+            for (k, unit) in name.iter_mut().enumerate() {
+                *unit = u16::from_le_bytes([e[56 + 2 * k], e[57 + 2 * k]]);
+            }
+            table.push(PartitionInfo {
+                number: number + 1,
+                first_lba,
+                last_lba,
+                type_guid,
+                unique_guid: guid_at(e, 16),
+                mbr_type: 0,
+                attributes: u64_at(e, 48),
+                name,
+            })?;
+        }
+    }
+    Ok(Some(table))
+}
+
+/// A partition for write_gpt() to create
+#[derive(Clone, Copy, Debug)]
+pub struct NewPartition<'a> {
+    pub name: &'a str,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    /// Size in blocks
+    pub blocks: u64,
+}
+
+/// Write a protective MBR and a GPT (primary and backup) with `parts`
+/// laid out one after another from the first usable block
+pub fn write_gpt(dev: &(impl BlockDevice + ?Sized), disk_guid: Guid, parts: &[NewPartition]) -> Result<(), &'static str> {
+    let bs = dev.block_size();
+    if !(512..=MAX_BLOCK).contains(&bs) {
+        return Err("unsupported block size for a partition table");
+    }
+    if parts.len() > GPT_ENTRIES {
+        return Err("too many partitions");
+    }
+    let count = dev.block_count();
+    let entry_blocks = (GPT_ENTRIES * GPT_ENTRY_SIZE).div_ceil(bs) as u64;
+    let first_usable = 2 + entry_blocks;
+    let last_usable = match count.checked_sub(2 + entry_blocks) {
+        Some(last) if last >= first_usable => last,
+        _ => return Err("disk too small for a GPT"),
+    };
+    let mut next = first_usable;
+    for part in parts {
+        if part.blocks == 0 || part.name.encode_utf16().count() > NAME_UNITS {
+            return Err("bad partition size or name");
+        }
+        next = next.checked_add(part.blocks).filter(|&n| n <= last_usable + 1).ok_or("partitions do not fit")?;
+    }
+
+    let mut block = [0u8; MAX_BLOCK];
+    let block = &mut block[..bs];
+
+    // Protective MBR covering the whole disk (as far as 32 bits go)
+    block.fill(0);
+    let entry = &mut block[MBR_ENTRIES..MBR_ENTRIES + 16];
+    entry[1..4].copy_from_slice(&[0, 2, 0]);
+    entry[4] = MBR_TYPE_PROTECTIVE;
+    entry[5..8].fill(0xFF);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&((count - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+    block[MBR_SIGNATURE..MBR_SIGNATURE + 2].copy_from_slice(&[0x55, 0xAA]);
+    dev.write_blocks(0, block)?;
+
+    // The entry array, at both ends
+    let backup_entries = last_usable + 1;
+    let per_block = bs / GPT_ENTRY_SIZE;
+    let mut crc = !0;
+    let mut first = first_usable;
+    for i in 0..entry_blocks {
+        block.fill(0);
+        for j in 0..per_block {
+            let Some(part) = parts.get(i as usize * per_block + j) else {
+                break;
+            };
+            let e = &mut block[j * GPT_ENTRY_SIZE..(j + 1) * GPT_ENTRY_SIZE];
+            e[0..16].copy_from_slice(&part.type_guid.0);
+            e[16..32].copy_from_slice(&part.unique_guid.0);
+            e[32..40].copy_from_slice(&first.to_le_bytes());
+            e[40..48].copy_from_slice(&(first + part.blocks - 1).to_le_bytes());
+            for (k, unit) in part.name.encode_utf16().enumerate() {
+                e[56 + 2 * k..58 + 2 * k].copy_from_slice(&unit.to_le_bytes());
+            }
+            first += part.blocks;
+        }
+        crc = crc32_update(crc, block);
+        dev.write_blocks(2 + i, block)?;
+        dev.write_blocks(backup_entries + i, block)?;
+    }
+
+    // Headers: the backup mirrors the primary from the other end
+    for (lba, alternate, entries) in [(1, count - 1, 2), (count - 1, 1, backup_entries)] {
+        block.fill(0);
+        block[..8].copy_from_slice(GPT_SIGNATURE);
+        block[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        block[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        block[24..32].copy_from_slice(&lba.to_le_bytes());
+        block[32..40].copy_from_slice(&alternate.to_le_bytes());
+        block[40..48].copy_from_slice(&first_usable.to_le_bytes());
+        block[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        block[56..72].copy_from_slice(&disk_guid.0);
+        block[72..80].copy_from_slice(&entries.to_le_bytes());
+        block[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+        block[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        block[88..92].copy_from_slice(&(!crc).to_le_bytes());
+        let header_crc = crc32(&block[..GPT_HEADER_SIZE]);
+        block[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        dev.write_blocks(lba, block)?;
+    }
+    dev.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const DISK: Guid = Guid::new(0x12345678, 0x9ABC, 0xDEF0, 0x1122, 0x334455667788);
+
+    fn two_partitions(dev: &impl BlockDevice) {
+        let parts = [
+            NewPartition { name: "data", type_guid: TYPE_LINUX_FILESYSTEM, unique_guid: Guid::new(1, 2, 3, 4, 5), blocks: 64 },
+            NewPartition { name: "config", type_guid: TYPE_BASIC_DATA, unique_guid: Guid::new(6, 7, 8, 9, 10), blocks: 16 },
+        ];
+        write_gpt(dev, DISK, &parts).unwrap();
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn guids_print_in_the_usual_form() {
+        assert_eq!(TYPE_EFI_SYSTEM.to_string(), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert_eq!(TYPE_LINUX_FILESYSTEM.0[..4], [0xAF, 0x3D, 0xC6, 0x0F]);
+    }
+
+    #[test]
+    fn gpt_round_trips() {
+        let mut data = vec![0u8; 256 * 512];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        two_partitions(&disk);
+
+        let table = read(&disk).unwrap();
+        assert_eq!((table.scheme, table.disk_guid, table.len()), (Scheme::Gpt, DISK, 2));
+        let data_part = table.find("data").unwrap();
+        assert_eq!((data_part.number, data_part.first_lba, data_part.last_lba), (1, 34, 97));
+        assert_eq!(data_part.type_guid, TYPE_LINUX_FILESYSTEM);
+        let config = table.find_type(TYPE_BASIC_DATA).unwrap();
+        assert_eq!((config.number, config.first_lba, config.blocks()), (2, 98, 16));
+        assert_eq!(config.name().collect::<String>(), "config");
+        assert_eq!(table.get(2), Some(config));
+        assert!(table.find("swap").is_none());
+
+        // Each partition is a block device of its own
+        let part = config.open(&disk).unwrap();
+        part.write_blocks(15, &[0xAB; 512]).unwrap();
+        assert_eq!(part.write_blocks(16, &[0; 512]), Err("past the end of the disk"));
+        assert!(data[113 * 512..114 * 512].iter().all(|&b| b == 0xAB));
+    }
+
+    #[test]
+    fn damaged_primary_falls_back_to_backup() {
+        let mut data = vec![0u8; 256 * 512];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        two_partitions(&disk);
+
+        // A flipped bit in the primary entries fails their CRC
+        let mut block = [0; 512];
+        disk.read_blocks(2, &mut block).unwrap();
+        block[40] ^= 1;
+        disk.write_blocks(2, &block).unwrap();
+        let table = read(&disk).unwrap();
+        assert_eq!((table.scheme, table.find("data").unwrap().last_lba), (Scheme::GptBackup, 97));
+
+        // With the backup header gone too, the protective MBR is all there is
+        disk.write_blocks(255, &[0; 512]).unwrap();
+        assert_eq!(read(&disk).unwrap_err(), "protective MBR but no intact GPT");
+        disk.write_blocks(0, &[0; 512]).unwrap();
+        assert_eq!(read(&disk).unwrap_err(), "no partition table");
+    }
+
+    #[test]
+    fn gpt_on_4k_blocks() {
+        let mut data = vec![0u8; 128 * 4096];
+        let disk = RamDisk::new(&mut data, 4096).unwrap();
+        two_partitions(&disk);
+        let table = read(&disk).unwrap();
+        // 128 entries take 4 blocks of 4 KiB
+        assert_eq!(table.find("data").unwrap().first_lba, 6);
+        assert_eq!(table.find("config").unwrap().last_lba, 6 + 64 + 16 - 1);
+    }
+
+    #[test]
+    fn mbr_primary_partitions() {
+        let mut data = vec![0u8; 64 * 512];
+        let mbr = &mut data[..512];
+        // Linux at 2 (8 blocks), an extended partition, FAT32 at 20 (30 blocks)
+        for (slot, kind, start, count) in [(0, 0x83u8, 2u32, 8u32), (1, 0x05, 10, 8), (3, 0x0C, 20, 30)] {
+            let e = &mut mbr[446 + 16 * slot..462 + 16 * slot];
+            e[4] = kind;
+            e[8..12].copy_from_slice(&start.to_le_bytes());
+            e[12..16].copy_from_slice(&count.to_le_bytes());
+        }
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+
+        let table = read(&disk).unwrap();
+        assert_eq!((table.scheme, table.len()), (Scheme::Mbr, 2));
+        let fat = table.get(4).unwrap();
+        assert_eq!((fat.mbr_type, fat.first_lba, fat.last_lba), (0x0C, 20, 49));
+        assert_eq!(fat.name().count(), 0);
+        assert_eq!(table.get(1).unwrap().open(&disk).unwrap().block_count(), 8);
+    }
+
+    #[test]
+    fn too_small_or_too_full() {
+        let mut data = vec![0u8; 60 * 512];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        assert_eq!(write_gpt(&disk, DISK, &[]), Err("disk too small for a GPT"));
+
+        let mut data = vec![0u8; 100 * 512];
+        let disk = RamDisk::new(&mut data, 512).unwrap();
+        let big = NewPartition { name: "big", type_guid: TYPE_BASIC_DATA, unique_guid: DISK, blocks: 34 };
+        assert_eq!(write_gpt(&disk, DISK, &[big]), Err("partitions do not fit"));
+        write_gpt(&disk, DISK, &[NewPartition { blocks: 33, ..big }]).unwrap();
+        assert_eq!(read(&disk).unwrap().find("big").unwrap().last_lba, 66);
+    }
+}
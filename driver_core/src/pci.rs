@@ -0,0 +1,532 @@
+//! PCI configuration space and BAR resource allocation
+//!
+//! MmioAllocator hands out BAR addresses from the window the DTB authorises,
+//! after the addresses the VMM already programmed have been reserved. The
+//! config-space helpers work on any ECAM mapping behind an `Mmio`.
+
+use core::sync::atomic::{fence, Ordering};
+
+use crate::mmio::Mmio;
+use crate::virtqueue::Virtqueue;
+
+// Virtio vendor ID
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+// PCI config space offsets
+const PCI_COMMAND: u64 = 0x04;
+const PCI_STATUS: u64 = 0x06;
+const PCI_BAR0: u64 = 0x10;
+const PCI_CAP_PTR: u64 = 0x34;
+
+const PCI_COMMAND_MEMORY: u16 = 0x02;
+const PCI_COMMAND_BUS_MASTER: u16 = 0x04;
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+/// Alignment of every allocation (BARs must be naturally aligned; 1 MiB
+/// covers every BAR the virtio devices have)
+const BAR_ALIGN: u64 = 0x10_0000;
+
+const fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Bump allocator over the PCI MMIO window
+#[derive(Clone, Copy, Debug)]
+pub struct MmioAllocator {
+    base: u64,
+    limit: u64,
+    head: u64,
+}
+
+impl MmioAllocator {
+    pub const fn new(base: u64, size: u64) -> Self {
+        MmioAllocator { base, limit: base + size, head: base }
+    }
+
+    /// Reserve a range that's already in use (by VZ-mapped devices)
+    /// This bumps the allocator past any existing device
+    pub fn reserve(&mut self, addr: u64, size: u64) {
+        let end = addr + size;
+        if end > self.head && end <= self.limit {
+            self.head = align_up(end, BAR_ALIGN);
+        }
+    }
+
+    /// Allocate a new MMIO range for a BAR
+    pub fn allocate(&mut self, size: u64) -> Option<u64> {
+        if size == 0 {
+            return None;
+        }
+        let start = align_up(self.head, BAR_ALIGN.max(size.next_power_of_two()));
+        let end = start.checked_add(size)?;
+        if end > self.limit {
+            return None;
+        }
+        self.head = end;
+        Some(start)
+    }
+
+    /// (base, head, limit) for debugging
+    pub fn state(&self) -> (u64, u64, u64) {
+        (self.base, self.head, self.limit)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciDevice {
+    pub ecam_addr: u64,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub bars: [u64; 6],
+}
+
+impl PciDevice {
+    /// Config space address of a function
+    pub const fn config_addr(ecam_base: u64, bus: u8, slot: u8, func: u8) -> u64 {
+        ecam_base + ((bus as u64) << 20) + ((slot as u64) << 15) + ((func as u64) << 12)
+    }
+
+    /// Look up a function (does NOT read BARs - call read_bars separately)
+    pub fn new(mmio: &impl Mmio, ecam_base: u64, bus: u8, slot: u8, func: u8) -> Option<Self> {
+        let addr = Self::config_addr(ecam_base, bus, slot, func);
+
+        let header = mmio.read32(addr);
+        let vendor = header as u16;
+        if vendor == 0xFFFF || vendor == 0 {
+            return None;
+        }
+
+        Some(PciDevice {
+            ecam_addr: addr,
+            vendor_id: vendor,
+            device_id: (header >> 16) as u16,
+            bars: [0; 6],
+        })
+    }
+
+    fn bar_reg(&self, idx: usize) -> u64 {
+        self.ecam_addr + PCI_BAR0 + idx as u64 * 4
+    }
+
+    /// Read a single BAR's current address (0 for I/O BARs)
+    pub fn read_bar(&self, mmio: &impl Mmio, idx: usize) -> u64 {
+        if idx >= 6 {
+            return 0;
+        }
+
+        let val = mmio.read32(self.bar_reg(idx));
+        if val & 0x1 != 0 {
+            return 0;
+        }
+
+        let mut addr = (val & 0xFFFF_FFF0) as u64;
+        if val & 0x4 != 0 && idx < 5 {
+            addr |= (mmio.read32(self.bar_reg(idx) + 4) as u64) << 32;
+        }
+        addr
+    }
+
+    /// Read all existing BAR addresses (what VZ programmed)
+    pub fn read_bars(&mut self, mmio: &impl Mmio) {
+        let mut idx = 0;
+        while idx < 6 {
+            let val = mmio.read32(self.bar_reg(idx));
+            self.bars[idx] = self.read_bar(mmio, idx);
+            // I/O BARs are skipped; a 64-bit BAR takes two slots
+            idx += if val & 0x5 == 0x4 { 2 } else { 1 };
+        }
+    }
+
+    /// Get the size of a BAR by probing (write all 1s, read back)
+    /// Returns (size, is_64bit); size 0 if the BAR is not implemented
+    pub fn get_bar_size(&self, mmio: &impl Mmio, idx: usize) -> (u64, bool) {
+        if idx >= 6 {
+            return (0, false);
+        }
+        let bar = self.bar_reg(idx);
+
+        // Disable memory decode while probing
+        let cmd = mmio.read16(self.ecam_addr + PCI_COMMAND);
+        mmio.write16(self.ecam_addr + PCI_COMMAND, cmd & !0x03);
+        fence(Ordering::SeqCst);
+
+        let orig = mmio.read32(bar);
+        mmio.write32(bar, 0xFFFF_FFFF);
+        fence(Ordering::SeqCst);
+        let mask = mmio.read32(bar);
+        mmio.write32(bar, orig);
+        fence(Ordering::SeqCst);
+
+        mmio.write16(self.ecam_addr + PCI_COMMAND, cmd);
+        fence(Ordering::SeqCst);
+
+        if mask == 0 || mask == 0xFFFF_FFFF {
+            return (0, false);
+        }
+
+        let is_64bit = orig & 0x4 != 0;
+        let size = (!(mask & 0xFFFF_FFF0)).wrapping_add(1) as u64;
+        (size, is_64bit)
+    }
+
+    /// Program a BAR with a new address; false if the device did not take it
+    /// Leaves memory decode and bus mastering enabled
+    pub fn program_bar(&mut self, mmio: &impl Mmio, idx: usize, addr: u64) -> bool {
+        if idx >= 6 {
+            return false;
+        }
+        let bar = self.bar_reg(idx);
+
+        let orig = mmio.read32(bar);
+        let is_64bit = orig & 0x4 != 0 && idx < 5;
+
+        let cmd = mmio.read16(self.ecam_addr + PCI_COMMAND);
+        mmio.write16(self.ecam_addr + PCI_COMMAND, cmd & !0x03);
+        fence(Ordering::SeqCst);
+
+        // Low 32 bits keep the type bits
+        mmio.write32(bar, addr as u32 | (orig & 0xF));
+        if is_64bit {
+            mmio.write32(bar + 4, (addr >> 32) as u32);
+        }
+        fence(Ordering::SeqCst);
+
+        let mut accepted = (mmio.read32(bar) & 0xFFFF_FFF0) == (addr as u32 & 0xFFFF_FFF0);
+        if is_64bit && accepted {
+            accepted = mmio.read32(bar + 4) as u64 == addr >> 32;
+        }
+
+        mmio.write16(self.ecam_addr + PCI_COMMAND, cmd | PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER);
+        fence(Ordering::SeqCst);
+
+        if accepted {
+            self.bars[idx] = addr;
+        }
+        accepted
+    }
+
+    /// Enable memory space and bus master
+    pub fn enable(&self, mmio: &impl Mmio) {
+        let cmd = mmio.read16(self.ecam_addr + PCI_COMMAND);
+        mmio.write16(self.ecam_addr + PCI_COMMAND, cmd | PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER);
+        fence(Ordering::SeqCst);
+    }
+}
+
+// VirtIO PCI capability types
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Common configuration: device_status and the per-queue registers
+const VIRTIO_PCI_COMMON_STATUS: u64 = 0x14;
+const VIRTIO_PCI_COMMON_Q_SELECT: u64 = 0x16;
+const VIRTIO_PCI_COMMON_Q_SIZE: u64 = 0x18;
+const VIRTIO_PCI_COMMON_Q_ENABLE: u64 = 0x1c;
+const VIRTIO_PCI_COMMON_Q_NOFF: u64 = 0x1e;
+const VIRTIO_PCI_COMMON_Q_DESC: u64 = 0x20;
+const VIRTIO_PCI_COMMON_Q_AVAIL: u64 = 0x28;
+const VIRTIO_PCI_COMMON_Q_USED: u64 = 0x30;
+
+/// VirtIO Modern device with parsed capability locations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtioModern {
+    pub common: u64,
+    pub notify: u64,
+    pub isr: u64,
+    pub device: u64,
+    pub notify_mult: u32,
+}
+
+impl VirtioModern {
+    /// Parse VirtIO capabilities from a PCI device
+    /// Returns None if the common or notify BAR is unmapped (address = 0)
+    pub fn probe(mmio: &impl Mmio, dev: &PciDevice) -> Option<Self> {
+        let mut common = 0u64;
+        let mut notify = 0u64;
+        let mut isr = 0u64;
+        let mut device = 0u64;
+        let mut notify_mult = 0u32;
+
+        if mmio.read16(dev.ecam_addr + PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+            return None;
+        }
+
+        // Walk capability list; bounded in case it loops
+        let mut cap_offset = mmio.read8(dev.ecam_addr + PCI_CAP_PTR);
+        for _ in 0..48 {
+            if cap_offset == 0 || cap_offset == 0xFF {
+                break;
+            }
+            let cap = dev.ecam_addr + cap_offset as u64;
+
+            if mmio.read8(cap) == PCI_CAP_ID_VENDOR {
+                let cfg_type = mmio.read8(cap + 3);
+                let bar = mmio.read8(cap + 4) as usize;
+                let offset = mmio.read32(cap + 8) as u64;
+
+                // Only use if BAR is actually mapped
+                if bar < 6 && dev.bars[bar] != 0 {
+                    let addr = dev.bars[bar] + offset;
+                    match cfg_type {
+                        VIRTIO_PCI_CAP_COMMON_CFG => common = addr,
+                        VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                            notify = addr;
+                            notify_mult = mmio.read32(cap + 16);
+                        }
+                        VIRTIO_PCI_CAP_ISR_CFG => isr = addr,
+                        VIRTIO_PCI_CAP_DEVICE_CFG => device = addr,
+                        _ => {}
+                    }
+                }
+            }
+
+            cap_offset = mmio.read8(cap + 1);
+        }
+
+        // Need at least common and notify to function
+        if common != 0 && notify != 0 {
+            Some(VirtioModern { common, notify, isr, device, notify_mult })
+        } else {
+            None
+        }
+    }
+
+    /// Initialize a VirtIO device (reset → ack → driver → features → OK)
+    /// accepting VIRTIO_F_VERSION_1 only
+    pub fn init_device(&self, mmio: &impl Mmio) -> bool {
+        if !self.negotiate(mmio) {
+            return false;
+        }
+        self.driver_ok(mmio);
+        true
+    }
+
+    /// Steps 1-8 of initialization, up to FEATURES_OK; the driver sets up
+    /// its queues next and then calls `driver_ok`
+    pub fn negotiate(&self, mmio: &impl Mmio) -> bool {
+        self.negotiate_features(mmio, 0).is_some()
+    }
+
+    /// negotiate(), also accepting whichever of the device-specific
+    /// features in `wanted` (bits 0-31) the device offers
+    /// Returns the features agreed on, or None if the device refused.
+    pub fn negotiate_features(&self, mmio: &impl Mmio, wanted: u64) -> Option<u64> {
+        let status = self.common + VIRTIO_PCI_COMMON_STATUS;
+
+        // 1. Reset device, wait for it to complete
+        mmio.write8(status, 0);
+        fence(Ordering::SeqCst);
+        for _ in 0..1000 {
+            if mmio.read8(status) == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        // 2-3. Acknowledge, Driver
+        mmio.write8(status, 0x01);
+        mmio.write8(status, 0x03);
+        fence(Ordering::SeqCst);
+
+        // 4-6. What we want of bank 0, VERSION_1 (bit 32) in bank 1
+        mmio.write32(self.common, 0);
+        let features0 = mmio.read32(self.common + 4) & wanted as u32;
+        mmio.write32(self.common + 8, 0);
+        mmio.write32(self.common + 12, features0);
+        mmio.write32(self.common + 8, 1);
+        mmio.write32(self.common + 12, 1);
+        fence(Ordering::SeqCst);
+
+        // 7-8. Features OK, and check the device agreed
+        mmio.write8(status, 0x0B);
+        fence(Ordering::SeqCst);
+        (mmio.read8(status) & 0x08 != 0).then_some(features0 as u64 | 1 << 32)
+    }
+
+    /// 9. Driver OK: the device may start using the queues
+    pub fn driver_ok(&self, mmio: &impl Mmio) {
+        mmio.write8(self.common + VIRTIO_PCI_COMMON_STATUS, 0x0F);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Device status register (0x40 = DEVICE_NEEDS_RESET)
+    pub fn status(&self, mmio: &impl Mmio) -> u8 {
+        mmio.read8(self.common + VIRTIO_PCI_COMMON_STATUS)
+    }
+
+    /// Largest queue the device allows at `index` (0 if there is no such
+    /// queue), to size a queue with Virtqueue::set_size() before setup
+    pub fn queue_max_size(&self, mmio: &impl Mmio, index: u16) -> u16 {
+        mmio.write16(self.common + VIRTIO_PCI_COMMON_Q_SELECT, index);
+        fence(Ordering::SeqCst);
+        mmio.read16(self.common + VIRTIO_PCI_COMMON_Q_SIZE)
+    }
+
+    /// Hand queue `index` to the device and enable it
+    /// Returns the address to write to notify the queue, or None if the
+    /// device has no such queue or it is smaller than `queue`
+    pub fn setup_queue<const N: usize>(&self, mmio: &impl Mmio, index: u16, queue: &Virtqueue<N>) -> Option<u64> {
+        if self.queue_max_size(mmio, index) < queue.size() {
+            return None;
+        }
+        mmio.write16(self.common + VIRTIO_PCI_COMMON_Q_SIZE, queue.size());
+
+        let (desc, avail, used) = queue.addrs();
+        mmio.write64(self.common + VIRTIO_PCI_COMMON_Q_DESC, desc);
+        mmio.write64(self.common + VIRTIO_PCI_COMMON_Q_AVAIL, avail);
+        mmio.write64(self.common + VIRTIO_PCI_COMMON_Q_USED, used);
+
+        let notify_off = mmio.read16(self.common + VIRTIO_PCI_COMMON_Q_NOFF) as u64;
+        mmio.write16(self.common + VIRTIO_PCI_COMMON_Q_ENABLE, 1);
+        fence(Ordering::SeqCst);
+        Some(self.notify + notify_off * self.notify_mult as u64)
+    }
+
+    /// Tell the device queue `index` has new buffers
+    pub fn notify(&self, mmio: &impl Mmio, notify_addr: u64, index: u16) {
+        fence(Ordering::SeqCst);
+        mmio.write16(notify_addr, index);
+    }
+
+    /// Read (and so clear) the ISR status: bit 0 queue, bit 1 config change
+    pub fn read_isr(&self, mmio: &impl Mmio) -> u8 {
+        mmio.read8(self.isr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::FakeMmio;
+
+    const ECAM: u64 = 0x4000_0000;
+
+    /// Virtio-net-like function at slot 1: 64-bit BAR0 of 16 KiB,
+    /// 32-bit BAR2 of 4 KiB, and the four virtio capabilities in BAR0
+    fn fake_device(mmio: &FakeMmio) -> u64 {
+        let cfg = PciDevice::config_addr(ECAM, 0, 1, 0);
+        mmio.poke32(cfg, (0x1041 << 16) | VIRTIO_VENDOR_ID as u32);
+        mmio.poke16(cfg + PCI_STATUS, PCI_STATUS_CAP_LIST);
+
+        mmio.poke32(cfg + 0x10, 0x4);
+        mmio.set_write_mask(cfg + 0x10, 0xFFFF_C000);
+        mmio.set_write_mask(cfg + 0x14, 0xFFFF_FFFF);
+        mmio.poke32(cfg + 0x18, 0x0);
+        mmio.set_write_mask(cfg + 0x18, 0xFFFF_F000);
+
+        // cap list: 0x40 common -> 0x50 notify -> 0x64 isr -> 0x74 device
+        mmio.poke8(cfg + PCI_CAP_PTR, 0x40);
+        let caps = [(0x40u64, 1u8, 0x0000u32, 0x50u8), (0x50, 2, 0x3000, 0x64), (0x64, 3, 0x1000, 0x74), (0x74, 4, 0x2000, 0)];
+        for (off, cfg_type, bar_off, next) in caps {
+            mmio.poke(cfg + off, &[PCI_CAP_ID_VENDOR, next, 16, cfg_type, 0]);
+            mmio.poke32(cfg + off + 8, bar_off);
+        }
+        mmio.poke32(cfg + 0x50 + 16, 4);
+        cfg
+    }
+
+    #[test]
+    fn allocator_reserves_and_aligns() {
+        let mut alloc = MmioAllocator::new(0x5000_0000, 0x100_0000);
+        alloc.reserve(0x5000_0000, 0x4000);
+        assert_eq!(alloc.allocate(0x4000), Some(0x5010_0000));
+        assert_eq!(alloc.allocate(0x20_0000), Some(0x5020_0000));
+        // Reservations outside the window are ignored
+        alloc.reserve(0x9000_0000, 0x1000);
+        assert_eq!(alloc.state(), (0x5000_0000, 0x5040_0000, 0x5100_0000));
+        assert_eq!(alloc.allocate(0), None);
+        assert_eq!(alloc.allocate(0x100_0000), None);
+    }
+
+    #[test]
+    fn reads_and_sizes_bars() {
+        let mmio = FakeMmio::new();
+        fake_device(&mmio);
+        let mut dev = PciDevice::new(&mmio, ECAM, 0, 1, 0).unwrap();
+        assert_eq!((dev.vendor_id, dev.device_id), (VIRTIO_VENDOR_ID, 0x1041));
+        assert!(PciDevice::new(&mmio, ECAM, 0, 2, 0).is_none());
+
+        assert_eq!(dev.get_bar_size(&mmio, 0), (0x4000, true));
+        assert_eq!(dev.get_bar_size(&mmio, 2), (0x1000, false));
+        assert_eq!(dev.get_bar_size(&mmio, 3), (0, false));
+
+        dev.read_bars(&mmio);
+        assert_eq!(dev.bars, [0; 6]);
+    }
+
+    #[test]
+    fn programs_64bit_bar_and_enables_decode() {
+        let mmio = FakeMmio::new();
+        let cfg = fake_device(&mmio);
+        let mut dev = PciDevice::new(&mmio, ECAM, 0, 1, 0).unwrap();
+
+        assert!(dev.program_bar(&mmio, 0, 0x80_5000_0000));
+        assert_eq!(dev.bars[0], 0x80_5000_0000);
+        assert_eq!(mmio.read32(cfg + 0x10), 0x5000_0004);
+        assert_eq!(mmio.read16(cfg + PCI_COMMAND) & 0x6, 0x6);
+
+        // Misaligned for the BAR's size: the device drops the low bits
+        assert!(!dev.program_bar(&mmio, 2, 0x5000_0800));
+        assert_eq!(dev.bars[2], 0);
+
+        dev.read_bars(&mmio);
+        assert_eq!(dev.bars[0], 0x80_5000_0000);
+        assert_eq!(dev.bars[1], 0);
+    }
+
+    #[test]
+    fn probes_virtio_capabilities() {
+        let mmio = FakeMmio::new();
+        fake_device(&mmio);
+        let mut dev = PciDevice::new(&mmio, ECAM, 0, 1, 0).unwrap();
+        assert_eq!(VirtioModern::probe(&mmio, &dev), None);
+
+        dev.program_bar(&mmio, 0, 0x5000_0000);
+        let modern = VirtioModern::probe(&mmio, &dev).unwrap();
+        assert_eq!(
+            modern,
+            VirtioModern { common: 0x5000_0000, notify: 0x5000_3000, isr: 0x5000_1000, device: 0x5000_2000, notify_mult: 4 }
+        );
+    }
+
+    #[test]
+    fn init_device_negotiates_version_1() {
+        let mmio = FakeMmio::new();
+        let modern = VirtioModern { common: 0x5000_0000, notify: 0x5000_3000, isr: 0, device: 0, notify_mult: 4 };
+        assert!(modern.init_device(&mmio));
+        assert_eq!(mmio.read8(0x5000_0014), 0x0F);
+        let writes = mmio.writes();
+        assert!(writes.ends_with(&[(0x5000_0008, 1), (0x5000_000c, 1), (0x5000_0014, 0x0B), (0x5000_0014, 0x0F)]));
+    }
+
+    #[test]
+    fn setup_queue_programs_rings_and_returns_notify_address() {
+        let mmio = FakeMmio::new();
+        let modern = VirtioModern { common: 0x5000_0000, notify: 0x5000_3000, isr: 0, device: 0, notify_mult: 4 };
+        let queue = Virtqueue::<8>::new();
+        assert_eq!(modern.setup_queue(&mmio, 1, &queue), None);
+
+        mmio.poke16(0x5000_0018, 256);
+        mmio.poke16(0x5000_001e, 1);
+        assert_eq!(modern.setup_queue(&mmio, 1, &queue), Some(0x5000_3004));
+        let (desc, _, used) = queue.addrs();
+        assert_eq!(mmio.read16(0x5000_0016), 1);
+        assert_eq!(mmio.read16(0x5000_0018), 8);
+        assert_eq!(mmio.read64(0x5000_0020), desc);
+        assert_eq!(mmio.read64(0x5000_0030), used);
+        assert_eq!(mmio.read16(0x5000_001c), 1);
+
+        // A device with shorter queues takes a queue cut down to fit
+        let mut queue = Virtqueue::<8>::new();
+        mmio.poke16(0x5000_0018, 4);
+        assert_eq!(modern.queue_max_size(&mmio, 2), 4);
+        assert_eq!(modern.setup_queue(&mmio, 2, &queue), None);
+        assert!(queue.set_size(4));
+        assert!(modern.setup_queue(&mmio, 2, &queue).is_some());
+        assert_eq!(mmio.read16(0x5000_0018), 4);
+    }
+}
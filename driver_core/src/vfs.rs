@@ -0,0 +1,1179 @@
+//! Virtual filesystem: one namespace over several filesystems
+//!
+//! A filesystem implements `FileSystem`, naming its files and directories
+//! with `NodeId`s of its own choosing, and is mounted at an absolute path
+//! in a `Vfs`. A path goes to the mount with the longest matching prefix
+//! and is walked from that filesystem's root one name at a time. "." and
+//! ".." are settled in the path itself before anything is looked up, as
+//! Plan 9 does, so ".." out of a mount leads back to where it is mounted;
+//! a symlink met on the way is spliced into the path, which is walked
+//! again.
+//!
+//! `File` and `Dir` are handles: a mount, a node and a position, with no
+//! borrows, so they can sit in a descriptor table (`FdTable`) and be used
+//! with the `Vfs` they came from. Unmounting a filesystem makes its
+//! handles stale rather than dangling.
+//!
+//! Adapters for the CPIO archive and ext2/3/4 (both read-only) and for
+//! FAT32 are at the end. Errors are an `Error`, shared with the
+//! filesystems, so callers that need errno values match on its variants.
+
+use crate::block::BlockDevice;
+use crate::cpio::{Archive, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use crate::ext::{self, ExtFs};
+use crate::fat::{self, FatFs};
+
+/// Filesystems mounted at once
+pub const MAX_MOUNTS: usize = 8;
+
+/// Longest path, in bytes
+pub const MAX_PATH: usize = 256;
+
+/// Longest name in a directory, in bytes
+pub const MAX_NAME: usize = 255;
+
+/// Symlinks followed while resolving one path
+pub const MAX_LINK_DEPTH: usize = 8;
+
+/// Descriptors in an FdTable
+pub const MAX_FDS: usize = 16;
+
+/// Why a filesystem call failed
+///
+/// Everything a caller may act on has a variant of its own; anything else
+/// (a device error, a corrupt filesystem) is `Io` with its message, which
+/// is what `?` makes of a block device's errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotASymlink,
+    Exists,
+    NotEmpty,
+    /// Something is mounted on or below the path
+    Busy,
+    ReadOnlyFs,
+    /// The file itself is marked read-only
+    ReadOnlyFile,
+    NameTooLong,
+    PathTooLong,
+    LinkTooLong,
+    TooManyLinks,
+    InvalidName,
+    InvalidSeek,
+    NoSpace,
+    FileTooLarge,
+    /// Devices, FIFOs and sockets are listed but not opened
+    NoDevice,
+    NotSupported,
+    NotReadable,
+    NotWritable,
+    /// The handle's filesystem was unmounted, or its file removed
+    Stale,
+    BadDescriptor,
+    TooManyOpen,
+    AlreadyMounted,
+    NotAMountPoint,
+    MountTableFull,
+    Io(&'static str),
+}
+
+impl Error {
+    pub fn message(self) -> &'static str {
+        match self {
+            Error::NotFound => "no such file or directory",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::NotASymlink => "not a symlink",
+            Error::Exists => "file exists",
+            Error::NotEmpty => "directory not empty",
+            Error::Busy => "mount point busy",
+            Error::ReadOnlyFs => "read-only filesystem",
+            Error::ReadOnlyFile => "file is read-only",
+            Error::NameTooLong => "file name too long",
+            Error::PathTooLong => "path too long",
+            Error::LinkTooLong => "symlink target too long",
+            Error::TooManyLinks => "too many levels of symbolic links",
+            Error::InvalidName => "invalid file name",
+            Error::InvalidSeek => "invalid seek",
+            Error::NoSpace => "filesystem full",
+            Error::FileTooLarge => "file too large",
+            Error::NoDevice => "no such device",
+            Error::NotSupported => "operation not supported",
+            Error::NotReadable => "file not open for reading",
+            Error::NotWritable => "file not open for writing",
+            Error::Stale => "stale file handle",
+            Error::BadDescriptor => "bad file descriptor",
+            Error::TooManyOpen => "too many open files",
+            Error::AlreadyMounted => "already mounted",
+            Error::NotAMountPoint => "not a mount point",
+            Error::MountTableFull => "mount table full",
+            Error::Io(msg) => msg,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl From<&'static str> for Error {
+    fn from(msg: &'static str) -> Self {
+        Error::Io(msg)
+    }
+}
+
+/// A file or directory, as its filesystem numbers it
+pub type NodeId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, FIFOs and sockets: listed, but not opened
+    Other,
+}
+
+impl FileType {
+    /// Type of an S_IF* mode
+    pub fn from_mode(mode: u32) -> Self {
+        match mode & S_IFMT {
+            S_IFREG => FileType::File,
+            S_IFDIR => FileType::Dir,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+}
+
+/// What stat() says about a file or directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stat {
+    pub node: NodeId,
+    pub file_type: FileType,
+    /// Type and permission bits, as cpio's S_IF*
+    pub mode: u32,
+    pub size: u64,
+    pub links: u32,
+    /// Last modified, in seconds since 1970; 0 if the filesystem does not
+    /// say
+    pub mtime: u64,
+}
+
+/// One entry of a directory
+#[derive(Clone, Copy, Debug)]
+pub struct DirEntry {
+    node: NodeId,
+    file_type: FileType,
+    name: [u8; MAX_NAME],
+    name_len: usize,
+}
+
+impl DirEntry {
+    pub fn new(node: NodeId, file_type: FileType, name: &[u8]) -> Result<Self, Error> {
+        let mut entry = DirEntry { node, file_type, name: [0; MAX_NAME], name_len: name.len() };
+        entry.name.get_mut(..name.len()).ok_or(Error::NameTooLong)?.copy_from_slice(name);
+        Ok(entry)
+    }
+
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// A filesystem that can be mounted
+///
+/// Only reading is required; the other calls refuse with "read-only
+/// filesystem" unless implemented.
+pub trait FileSystem {
+    fn root(&self) -> NodeId;
+
+    /// Entry `name` of directory `dir`
+    fn lookup(&self, dir: NodeId, name: &[u8]) -> Result<NodeId, Error>;
+
+    fn stat(&self, node: NodeId) -> Result<Stat, Error>;
+
+    /// Read from `offset` of a file into `buf`; how many bytes (0 at the end)
+    fn read(&self, node: NodeId, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Call `visit` with the entries of `dir`, "." and ".." left out, from
+    /// the `start`th on, until it returns false
+    fn read_dir(&self, dir: NodeId, start: usize, visit: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Error>;
+
+    /// Target of a symlink into `buf`; its length
+    fn read_link(&self, node: NodeId, buf: &mut [u8]) -> Result<usize, Error>;
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    /// Write `data` at `offset` of a file, growing it as needed
+    fn write(&self, _node: NodeId, _offset: u64, _data: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnlyFs)
+    }
+
+    /// Cut a file to `len` bytes, or zero-fill it out to them
+    fn truncate(&self, _node: NodeId, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnlyFs)
+    }
+
+    /// Make an empty file or directory `name` in `dir`
+    fn create(&self, _dir: NodeId, _name: &[u8], _file_type: FileType) -> Result<NodeId, Error> {
+        Err(Error::ReadOnlyFs)
+    }
+
+    /// Remove `name` from `dir`: a file, or an empty directory
+    fn remove(&self, _dir: NodeId, _name: &[u8]) -> Result<(), Error> {
+        Err(Error::ReadOnlyFs)
+    }
+
+    /// Write back anything held in memory
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// How open() opens a file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    /// Create the file if it does not exist
+    pub create: bool,
+    /// With `create`: fail if it does exist
+    pub exclusive: bool,
+    /// Empty it first (with `write`)
+    pub truncate: bool,
+    /// Every write goes to the end
+    pub append: bool,
+}
+
+impl OpenFlags {
+    /// An existing file, for reading
+    pub const READ: Self = OpenFlags { read: true, write: false, create: false, exclusive: false, truncate: false, append: false };
+    /// A new or emptied file, for writing
+    pub const WRITE: Self = OpenFlags { read: false, write: true, create: true, exclusive: false, truncate: true, append: false };
+    /// A new or existing file, for writing at the end
+    pub const APPEND: Self = OpenFlags { read: false, write: true, create: true, exclusive: false, truncate: false, append: true };
+}
+
+/// Where seek() moves to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct File {
+    mount: u32,
+    node: NodeId,
+    pos: u64,
+    flags: OpenFlags,
+}
+
+impl File {
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+}
+
+/// An open directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dir {
+    mount: u32,
+    node: NodeId,
+    /// Entries already handed out
+    index: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Mount<'a> {
+    path: [u8; MAX_PATH],
+    len: usize,
+    /// Handles name their mount by this, so that one mounted later in the
+    /// same slot does not pick them up
+    id: u32,
+    fs: &'a dyn FileSystem,
+}
+
+impl Mount<'_> {
+    fn path(&self) -> &[u8] {
+        &self.path[..self.len]
+    }
+
+    /// What is left of `path` if this mount covers it
+    fn strip<'p>(&self, path: &'p [u8]) -> Option<&'p [u8]> {
+        if self.path() == b"/" {
+            return Some(path);
+        }
+        let rest = path.strip_prefix(self.path())?;
+        (rest.is_empty() || rest[0] == b'/').then_some(rest)
+    }
+}
+
+/// How far walk() got
+enum Walk<'m, 'a> {
+    Found(&'m Mount<'a>, NodeId),
+    /// A symlink was in the way: the path to walk instead is this long
+    Link(usize),
+}
+
+/// Join `parts` with "/" and reduce the result to an absolute path with no
+/// ".", ".." or repeated slashes ("/" for the root); its length in `out`
+fn normalize(parts: &[&[u8]], out: &mut [u8; MAX_PATH]) -> Result<usize, Error> {
+    let mut len = 0;
+    for name in parts.iter().flat_map(|part| part.split(|&b| b == b'/')) {
+        match name {
+            b"" | b"." => {}
+            b".." => len = out[..len].iter().rposition(|&b| b == b'/').unwrap_or(0),
+            _ if name.len() > MAX_NAME => return Err(Error::NameTooLong),
+            _ if len + 1 + name.len() > MAX_PATH => return Err(Error::PathTooLong),
+            _ => {
+                out[len] = b'/';
+                out[len + 1..len + 1 + name.len()].copy_from_slice(name);
+                len += 1 + name.len();
+            }
+        }
+    }
+    if len == 0 {
+        out[0] = b'/';
+        len = 1;
+    }
+    Ok(len)
+}
+
+/// Filesystems mounted into one tree of paths
+pub struct Vfs<'a> {
+    mounts: [Option<Mount<'a>>; MAX_MOUNTS],
+    next_id: u32,
+}
+
+impl Default for Vfs<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Vfs<'a> {
+    pub const fn new() -> Self {
+        Vfs { mounts: [None; MAX_MOUNTS], next_id: 0 }
+    }
+
+    /// Mount `fs` at `path`, which need not exist below it
+    pub fn mount(&mut self, path: &str, fs: &'a dyn FileSystem) -> Result<(), Error> {
+        let mut buf = [0; MAX_PATH];
+        let len = normalize(&[path.as_bytes()], &mut buf)?;
+        if self.mounts.iter().flatten().any(|m| m.path() == &buf[..len]) {
+            return Err(Error::AlreadyMounted);
+        }
+        let slot = self.mounts.iter_mut().find(|m| m.is_none()).ok_or(Error::MountTableFull)?;
+        self.next_id += 1;
+        *slot = Some(Mount { path: buf, len, id: self.next_id, fs });
+        Ok(())
+    }
+
+    /// Unmount the filesystem at `path`, unless another is mounted below
+    /// it; its open handles go stale
+    pub fn unmount(&mut self, path: &str) -> Result<&'a dyn FileSystem, Error> {
+        let mut buf = [0; MAX_PATH];
+        let len = normalize(&[path.as_bytes()], &mut buf)?;
+        let path = &buf[..len];
+        let index = self.mounts.iter().position(|m| m.is_some_and(|m| m.path() == path)).ok_or(Error::NotAMountPoint)?;
+        if self.mounts.iter().flatten().any(|m| m.path() != path && Mount::strip(self.mounts[index].as_ref().unwrap(), m.path()).is_some()) {
+            return Err(Error::Busy);
+        }
+        Ok(self.mounts[index].take().unwrap().fs)
+    }
+
+    /// Mount points and their filesystems
+    pub fn mounts(&self) -> impl Iterator<Item = (&str, &'a dyn FileSystem)> + '_ {
+        self.mounts.iter().flatten().map(|m| (core::str::from_utf8(m.path()).unwrap_or(""), m.fs))
+    }
+
+    fn mount_of(&self, id: u32) -> Result<&Mount<'a>, Error> {
+        self.mounts.iter().flatten().find(|m| m.id == id).ok_or(Error::Stale)
+    }
+
+    /// Mount covering normalized `path`, and the rest of the path within it
+    fn covering<'p>(&self, path: &'p [u8]) -> Result<(&Mount<'a>, &'p [u8]), Error> {
+        self.mounts
+            .iter()
+            .flatten()
+            .filter_map(|m| Some((m, m.strip(path)?)))
+            .max_by_key(|(m, _)| m.len)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Mount and node `path` leads to; a symlink at the end is followed
+    /// if `follow`
+    fn resolve(&self, path: &[u8], follow: bool) -> Result<(&Mount<'a>, NodeId), Error> {
+        let (mut buf, mut next) = ([0; MAX_PATH], [0; MAX_PATH]);
+        let mut len = normalize(&[path], &mut buf)?;
+        for _ in 0..=MAX_LINK_DEPTH {
+            match self.walk(&buf[..len], follow, &mut next)? {
+                Walk::Found(mount, node) => return Ok((mount, node)),
+                Walk::Link(next_len) => (buf, len) = (next, next_len),
+            }
+        }
+        Err(Error::TooManyLinks)
+    }
+
+    /// Walk normalized `path` as far as the first symlink to follow, and
+    /// put the path with it spliced in into `next`
+    fn walk(&self, path: &[u8], follow: bool, next: &mut [u8; MAX_PATH]) -> Result<Walk<'_, 'a>, Error> {
+        let (mount, rest) = self.covering(path)?;
+        let fs = mount.fs;
+        let (mut node, mut is_dir) = (fs.root(), true);
+        // Byte offset in `path` of each name
+        let mut start = path.len() - rest.len();
+        for name in rest.split(|&b| b == b'/').filter(|n| !n.is_empty()) {
+            start += 1;
+            let end = start + name.len();
+            if !is_dir {
+                return Err(Error::NotADirectory);
+            }
+            let child = fs.lookup(node, name)?;
+            let stat = fs.stat(child)?;
+            if stat.file_type == FileType::Symlink && (follow || end < path.len()) {
+                let mut target = [0; MAX_PATH];
+                let len = fs.read_link(child, &mut target)?;
+                let target = &target[..len];
+                // A relative target starts from the link's directory
+                let len = match target.first() {
+                    Some(b'/') => normalize(&[target, &path[end..]], next)?,
+                    _ => normalize(&[&path[..start], target, &path[end..]], next)?,
+                };
+                return Ok(Walk::Link(len));
+            }
+            (node, is_dir, start) = (child, stat.file_type == FileType::Dir, end);
+        }
+        Ok(Walk::Found(mount, node))
+    }
+
+    /// Directory holding the last name of `path`, and that name (in `buf`)
+    fn parent<'p>(&self, path: &str, buf: &'p mut [u8; MAX_PATH]) -> Result<(&Mount<'a>, NodeId, &'p [u8]), Error> {
+        let len = normalize(&[path.as_bytes()], buf)?;
+        let buf = &buf[..len];
+        if self.mounts.iter().flatten().any(|m| m.path() == buf) {
+            return Err(Error::Busy);
+        }
+        let split = buf.iter().rposition(|&b| b == b'/').unwrap_or(0);
+        let (mount, dir) = self.resolve(&buf[..split.max(1)], true)?;
+        if mount.fs.stat(dir)?.file_type != FileType::Dir {
+            return Err(Error::NotADirectory);
+        }
+        Ok((mount, dir, &buf[split + 1..]))
+    }
+
+    /// Open the file at `path`
+    pub fn open(&self, path: &str, flags: OpenFlags) -> Result<File, Error> {
+        let mut buf = [0; MAX_PATH];
+        let len = normalize(&[path.as_bytes()], &mut buf)?;
+        let (mount, node) = match self.resolve(&buf[..len], true) {
+            Ok(_) if flags.create && flags.exclusive => return Err(Error::Exists),
+            // As on Linux, a missing file is ENOENT unless it is to be
+            // created, and only creating it on a read-only filesystem is
+            // EROFS
+            Err(Error::NotFound) if flags.create => {
+                let mut buf = [0; MAX_PATH];
+                let (mount, dir, name) = self.parent(path, &mut buf)?;
+                if mount.fs.read_only() {
+                    return Err(Error::ReadOnlyFs);
+                }
+                (mount, mount.fs.create(dir, name, FileType::File)?)
+            }
+            found => found?,
+        };
+        match mount.fs.stat(node)?.file_type {
+            FileType::Dir if flags.write => return Err(Error::IsADirectory),
+            FileType::Other => return Err(Error::NoDevice),
+            _ => {}
+        }
+        if flags.write && mount.fs.read_only() {
+            return Err(Error::ReadOnlyFs);
+        }
+        if flags.write && flags.truncate {
+            mount.fs.truncate(node, 0)?;
+        }
+        Ok(File { mount: mount.id, node, pos: 0, flags })
+    }
+
+    /// Read from `file`'s position into `buf`; how many bytes (0 at the end)
+    pub fn read(&self, file: &mut File, buf: &mut [u8]) -> Result<usize, Error> {
+        if !file.flags.read {
+            return Err(Error::NotReadable);
+        }
+        let n = self.mount_of(file.mount)?.fs.read(file.node, file.pos, buf)?;
+        file.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Write `data` at `file`'s position, or its end if it was opened to
+    /// append
+    pub fn write(&self, file: &mut File, data: &[u8]) -> Result<usize, Error> {
+        if !file.flags.write {
+            return Err(Error::NotWritable);
+        }
+        let fs = self.mount_of(file.mount)?.fs;
+        if file.flags.append {
+            file.pos = fs.stat(file.node)?.size;
+        }
+        let n = fs.write(file.node, file.pos, data)?;
+        file.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Move `file`'s position; the new one. Past the end is allowed: a
+    /// write there leaves a gap of zeros.
+    pub fn seek(&self, file: &mut File, to: SeekFrom) -> Result<u64, Error> {
+        let pos = match to {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => file.pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.fstat(file)?.size.checked_add_signed(delta),
+        };
+        file.pos = pos.filter(|&pos| pos <= i64::MAX as u64).ok_or(Error::InvalidSeek)?;
+        Ok(file.pos)
+    }
+
+    /// Cut `file` to `len` bytes, or zero-fill it out to them
+    pub fn truncate(&self, file: &File, len: u64) -> Result<(), Error> {
+        if !file.flags.write {
+            return Err(Error::NotWritable);
+        }
+        self.mount_of(file.mount)?.fs.truncate(file.node, len)
+    }
+
+    pub fn fstat(&self, file: &File) -> Result<Stat, Error> {
+        self.mount_of(file.mount)?.fs.stat(file.node)
+    }
+
+    /// What is at `path`, following a symlink there
+    pub fn stat(&self, path: &str) -> Result<Stat, Error> {
+        let (mount, node) = self.resolve(path.as_bytes(), true)?;
+        mount.fs.stat(node)
+    }
+
+    /// What is at `path`; a symlink there is not followed
+    pub fn lstat(&self, path: &str) -> Result<Stat, Error> {
+        let (mount, node) = self.resolve(path.as_bytes(), false)?;
+        mount.fs.stat(node)
+    }
+
+    /// Target of the symlink at `path` into `buf`; its length
+    pub fn read_link(&self, path: &str, buf: &mut [u8]) -> Result<usize, Error> {
+        let (mount, node) = self.resolve(path.as_bytes(), false)?;
+        mount.fs.read_link(node, buf)
+    }
+
+    /// Open the directory at `path` to list it
+    pub fn open_dir(&self, path: &str) -> Result<Dir, Error> {
+        let (mount, node) = self.resolve(path.as_bytes(), true)?;
+        if mount.fs.stat(node)?.file_type != FileType::Dir {
+            return Err(Error::NotADirectory);
+        }
+        Ok(Dir { mount: mount.id, node, index: 0 })
+    }
+
+    /// Call `visit` with the next entries of `dir` until it returns false;
+    /// the entry it refused comes up again next time. Mount points are
+    /// only listed if their directory exists below them.
+    pub fn read_dir(&self, dir: &mut Dir, mut visit: impl FnMut(&DirEntry) -> bool) -> Result<(), Error> {
+        let fs = self.mount_of(dir.mount)?.fs;
+        let index = &mut dir.index;
+        fs.read_dir(dir.node, *index, &mut |entry| {
+            let more = visit(entry);
+            if more {
+                *index += 1;
+            }
+            more
+        })
+    }
+
+    /// Next entry of `dir`; None once all have been seen
+    pub fn next_entry(&self, dir: &mut Dir) -> Result<Option<DirEntry>, Error> {
+        let mut next = None;
+        self.read_dir(dir, |entry| {
+            next = Some(*entry);
+            false
+        })?;
+        if next.is_some() {
+            dir.index += 1;
+        }
+        Ok(next)
+    }
+
+    /// Make the directory `path`
+    pub fn mkdir(&self, path: &str) -> Result<(), Error> {
+        let mut buf = [0; MAX_PATH];
+        let (mount, dir, name) = self.parent(path, &mut buf)?;
+        mount.fs.create(dir, name, FileType::Dir).map(drop)
+    }
+
+    /// Remove the file or empty directory at `path`
+    pub fn remove(&self, path: &str) -> Result<(), Error> {
+        let mut buf = [0; MAX_PATH];
+        let (mount, dir, name) = self.parent(path, &mut buf)?;
+        mount.fs.remove(dir, name)
+    }
+
+    /// Write back every filesystem; the first error, after trying them all
+    pub fn sync(&self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for mount in self.mounts.iter().flatten() {
+            let synced = mount.fs.sync();
+            result = result.and(synced);
+        }
+        result
+    }
+}
+
+/// Handles of one task by number, the lowest free number first as POSIX
+/// hands out descriptors
+pub struct FdTable<T> {
+    slots: [Option<T>; MAX_FDS],
+}
+
+impl<T> Default for FdTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> FdTable<T> {
+    pub const fn new() -> Self {
+        FdTable { slots: [const { None }; MAX_FDS] }
+    }
+
+    /// Store `handle` under the lowest free number; that number
+    pub fn insert(&mut self, handle: T) -> Result<usize, Error> {
+        let fd = self.slots.iter().position(Option::is_none).ok_or(Error::TooManyOpen)?;
+        self.slots[fd] = Some(handle);
+        Ok(fd)
+    }
+
+    /// Store `handle` under `fd`; whatever was there before
+    pub fn insert_at(&mut self, fd: usize, handle: T) -> Result<Option<T>, Error> {
+        let slot = self.slots.get_mut(fd).ok_or(Error::BadDescriptor)?;
+        Ok(slot.replace(handle))
+    }
+
+    pub fn get(&self, fd: usize) -> Option<&T> {
+        self.slots.get(fd)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Option<&mut T> {
+        self.slots.get_mut(fd)?.as_mut()
+    }
+
+    /// Close `fd`, handing back what it held
+    pub fn remove(&mut self, fd: usize) -> Option<T> {
+        self.slots.get_mut(fd)?.take()
+    }
+
+    /// Close everything
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CPIO archive: read-only. A node is the length of its path above an
+// archive offset: the member there, or a directory on the way to it, so
+// that the directories archivers leave out ("bin" of "bin/sh") have one too.
+
+const CPIO_ROOT: NodeId = 0;
+
+fn cpio_node(path_len: usize, offset: usize) -> NodeId {
+    (path_len as u64) << 32 | offset as u64
+}
+
+/// Path of `node`, and its member unless it is only a directory on the way
+/// to one
+fn cpio_path<'d>(archive: &Archive<'d>, node: NodeId) -> Result<(&'d str, Option<crate::cpio::Entry<'d>>), Error> {
+    if node == CPIO_ROOT {
+        return Ok(("", archive.find("")));
+    }
+    let entry = archive.entry_at(node as u32 as usize).ok_or(Error::Stale)?;
+    let path = entry.path.get(..(node >> 32) as usize).ok_or(Error::Stale)?;
+    Ok((path, (path.len() == entry.path.len()).then_some(entry)))
+}
+
+/// First name of `path` inside directory `dir`, and whether more follow
+fn cpio_child<'p>(dir: &str, path: &'p str) -> Option<(&'p str, bool)> {
+    let rest = if dir.is_empty() { path } else { path.strip_prefix(dir)?.strip_prefix('/')? };
+    match rest.split_once('/') {
+        _ if rest.is_empty() => None,
+        Some((name, _)) => Some((name, true)),
+        None => Some((rest, false)),
+    }
+}
+
+/// The archive's directory at `node`
+fn cpio_dir<'d>(archive: &Archive<'d>, node: NodeId) -> Result<&'d str, Error> {
+    match cpio_path(archive, node)? {
+        (_, Some(entry)) if !entry.is_dir() => Err(Error::NotADirectory),
+        (path, _) => Ok(path),
+    }
+}
+
+impl FileSystem for Archive<'_> {
+    fn root(&self) -> NodeId {
+        CPIO_ROOT
+    }
+
+    fn lookup(&self, dir: NodeId, name: &[u8]) -> Result<NodeId, Error> {
+        let dir = cpio_dir(self, dir)?;
+        let path_len = if dir.is_empty() { name.len() } else { dir.len() + 1 + name.len() };
+        // Later members replace earlier ones; a member beats a directory
+        // that is only implied
+        let (mut member, mut implied) = (None, None);
+        let mut entries = self.entries();
+        while let Some(entry) = entries.next() {
+            match cpio_child(dir, entry.path) {
+                Some((child, false)) if child.as_bytes() == name => member = Some(cpio_node(path_len, entries.start())),
+                Some((child, true)) if child.as_bytes() == name => implied = implied.or(Some(cpio_node(path_len, entries.start()))),
+                _ => {}
+            }
+        }
+        member.or(implied).ok_or(Error::NotFound)
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, Error> {
+        let (mode, size) = match cpio_path(self, node)? {
+            (_, Some(entry)) => (entry.mode, entry.data.len() as u64),
+            (_, None) => (S_IFDIR | 0o755, 0),
+        };
+        Ok(Stat { node, file_type: FileType::from_mode(mode), mode, size, links: 1, mtime: 0 })
+    }
+
+    fn read(&self, node: NodeId, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = match cpio_path(self, node)? {
+            (_, Some(entry)) if !entry.is_dir() => entry.data,
+            _ => return Err(Error::IsADirectory),
+        };
+        let rest = data.get(offset.min(data.len() as u64) as usize..).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+
+    fn read_dir(&self, dir: NodeId, start: usize, visit: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Error> {
+        let path = cpio_dir(self, dir)?;
+        let mut index = 0;
+        for (i, entry) in self.entries().enumerate() {
+            let Some((name, _)) = cpio_child(path, entry.path) else {
+                continue;
+            };
+            // Listed where the name first comes up
+            if self.entries().take(i).any(|earlier| cpio_child(path, earlier.path).is_some_and(|(n, _)| n == name)) {
+                continue;
+            }
+            if index >= start {
+                let node = self.lookup(dir, name.as_bytes())?;
+                if !visit(&DirEntry::new(node, self.stat(node)?.file_type, name.as_bytes())?) {
+                    break;
+                }
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, node: NodeId, buf: &mut [u8]) -> Result<usize, Error> {
+        let target = match cpio_path(self, node)? {
+            (_, Some(entry)) if entry.is_symlink() => entry.data,
+            _ => return Err(Error::NotASymlink),
+        };
+        buf.get_mut(..target.len()).ok_or(Error::LinkTooLong)?.copy_from_slice(target);
+        Ok(target.len())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// FAT32: read/write. A node is Node::id(), where its entry is.
+
+fn fat_name(name: &[u8]) -> Result<&str, Error> {
+    core::str::from_utf8(name).map_err(|_| Error::InvalidName)
+}
+
+fn fat_type(node: &fat::Node) -> FileType {
+    if node.is_dir() {
+        FileType::Dir
+    } else {
+        FileType::File
+    }
+}
+
+impl<D: BlockDevice> FileSystem for FatFs<D> {
+    fn root(&self) -> NodeId {
+        FatFs::root(self).id()
+    }
+
+    fn lookup(&self, dir: NodeId, name: &[u8]) -> Result<NodeId, Error> {
+        let entry = self.find(&self.node(dir)?, fat_name(name)?)?.ok_or(Error::NotFound)?;
+        Ok(entry.node().id())
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, Error> {
+        let fat = self.node(node)?;
+        let mode = match fat_type(&fat) {
+            FileType::Dir => S_IFDIR | 0o755,
+            _ if fat.attributes() & fat::ATTR_READ_ONLY != 0 => S_IFREG | 0o444,
+            _ => S_IFREG | 0o644,
+        };
+        Ok(Stat { node, file_type: fat_type(&fat), mode, size: fat.size(), links: 1, mtime: 0 })
+    }
+
+    fn read(&self, node: NodeId, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        FatFs::read(self, &self.node(node)?, offset, buf)
+    }
+
+    fn read_dir(&self, dir: NodeId, start: usize, visit: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Error> {
+        let entries = FatFs::read_dir(self, &self.node(dir)?)?;
+        for entry in entries.filter(|e| !e.as_ref().is_ok_and(fat::DirEntry::is_dot)).skip(start) {
+            let entry = entry?;
+            // UTF-16 on disk, UTF-8 here
+            let (mut name, mut len) = ([0; MAX_NAME], 0);
+            for c in entry.name() {
+                let dst = name.get_mut(len..len + c.len_utf8()).ok_or(Error::NameTooLong)?;
+                len += c.encode_utf8(dst).len();
+            }
+            if !visit(&DirEntry::new(entry.node().id(), fat_type(&entry.node()), &name[..len])?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, _node: NodeId, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::NotASymlink)
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn write(&self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        FatFs::write(self, &mut self.node(node)?, offset, data)
+    }
+
+    fn truncate(&self, node: NodeId, len: u64) -> Result<(), Error> {
+        FatFs::truncate(self, &mut self.node(node)?, len)
+    }
+
+    fn create(&self, dir: NodeId, name: &[u8], file_type: FileType) -> Result<NodeId, Error> {
+        let (dir, name) = (self.node(dir)?, fat_name(name)?);
+        let node = match file_type {
+            FileType::File => FatFs::create(self, &dir, name)?,
+            FileType::Dir => self.mkdir(&dir, name)?,
+            _ => return Err(Error::NotSupported),
+        };
+        Ok(node.id())
+    }
+
+    fn remove(&self, dir: NodeId, name: &[u8]) -> Result<(), Error> {
+        FatFs::remove(self, &self.node(dir)?, fat_name(name)?)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ext2/3/4: read-only. A node is its inode number.
+
+fn ext_inode<D: BlockDevice>(fs: &ExtFs<D>, node: NodeId) -> Result<ext::Inode, Error> {
+    fs.inode(u32::try_from(node).map_err(|_| "bad inode number")?)
+}
+
+impl<D: BlockDevice> FileSystem for ExtFs<D> {
+    fn root(&self) -> NodeId {
+        ext::ROOT_INODE as NodeId
+    }
+
+    fn lookup(&self, dir: NodeId, name: &[u8]) -> Result<NodeId, Error> {
+        let entry = self.find(&ext_inode(self, dir)?, name)?.ok_or(Error::NotFound)?;
+        Ok(entry.inode() as NodeId)
+    }
+
+    fn stat(&self, node: NodeId) -> Result<Stat, Error> {
+        let inode = ext_inode(self, node)?;
+        Ok(Stat {
+            node,
+            file_type: FileType::from_mode(inode.mode()),
+            mode: inode.mode(),
+            size: inode.size(),
+            links: inode.links() as u32,
+            mtime: inode.mtime() as u64,
+        })
+    }
+
+    fn read(&self, node: NodeId, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        ExtFs::read(self, &ext_inode(self, node)?, offset, buf)
+    }
+
+    fn read_dir(&self, dir: NodeId, start: usize, visit: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), Error> {
+        let entries = ExtFs::read_dir(self, &ext_inode(self, dir)?)?;
+        for entry in entries.filter(|e| !e.as_ref().is_ok_and(ext::DirEntry::is_dot)).skip(start) {
+            let entry = entry?;
+            let file_type = match entry.file_type() {
+                ext::FT_REG_FILE => FileType::File,
+                ext::FT_DIR => FileType::Dir,
+                ext::FT_SYMLINK => FileType::Symlink,
+                // Without the filetype feature only the inode knows
+                ext::FT_UNKNOWN => FileType::from_mode(self.inode(entry.inode())?.mode()),
+                _ => FileType::Other,
+            };
+            if !visit(&DirEntry::new(entry.inode() as NodeId, file_type, entry.name())?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, node: NodeId, buf: &mut [u8]) -> Result<usize, Error> {
+        ExtFs::read_link(self, &ext_inode(self, node)?, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use crate::cpio::tests::{initramfs, member};
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn path(parts: &[&str]) -> Result<String, Error> {
+        let parts: Vec<&[u8]> = parts.iter().map(|p| p.as_bytes()).collect();
+        let mut buf = [0; MAX_PATH];
+        let len = normalize(&parts, &mut buf)?;
+        Ok(String::from_utf8(buf[..len].to_vec()).unwrap())
+    }
+
+    fn names(vfs: &Vfs, dir: &str) -> Vec<String> {
+        let mut dir = vfs.open_dir(dir).unwrap();
+        let mut names = Vec::new();
+        vfs.read_dir(&mut dir, |entry| {
+            names.push(String::from_utf8(entry.name().to_vec()).unwrap());
+            true
+        })
+        .unwrap();
+        names
+    }
+
+    fn contents(vfs: &Vfs, path: &str) -> Vec<u8> {
+        let mut file = vfs.open(path, OpenFlags::READ).unwrap();
+        let mut data = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match vfs.read(&mut file, &mut buf).unwrap() {
+                0 => return data,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn fat_volume(data: &mut Vec<u8>) -> RamDisk<'_> {
+        data.resize(1 << 20, 0);
+        let disk = RamDisk::new(data, 512).unwrap();
+        fat::format(&disk, "scratch", 1).unwrap();
+        disk
+    }
+
+    #[test]
+    fn paths_are_settled_before_lookup() {
+        assert_eq!(path(&[""]).unwrap(), "/");
+        assert_eq!(path(&["etc//hostname/"]).unwrap(), "/etc/hostname");
+        assert_eq!(path(&["/a/./b/../../c", "d/.."]).unwrap(), "/c");
+        assert_eq!(path(&["/../.."]).unwrap(), "/");
+        assert_eq!(path(&[&"x".repeat(MAX_NAME + 1)]).unwrap_err(), Error::NameTooLong);
+        assert_eq!(path(&[&"/abc".repeat(MAX_PATH / 4 + 1)]).unwrap_err(), Error::PathTooLong);
+    }
+
+    #[test]
+    fn reads_the_initrd() {
+        let data = initramfs();
+        let archive = Archive::new(&data).unwrap();
+        let mut vfs = Vfs::new();
+        vfs.mount("/", &archive).unwrap();
+
+        assert_eq!(contents(&vfs, "/etc/hostname"), b"unikernel\n");
+        // "bin" has no member of its own; "init" is a symlink to bin/app
+        assert_eq!(names(&vfs, "/"), ["etc", "init", "bin"]);
+        assert_eq!(names(&vfs, "/bin"), ["app"]);
+        assert_eq!(contents(&vfs, "/init"), b"\x7fELF");
+        assert_eq!(vfs.lstat("/init").unwrap().file_type, FileType::Symlink);
+        assert_eq!(vfs.stat("/init").unwrap(), vfs.stat("/bin/../bin/app").unwrap());
+        let mut target = [0; 16];
+        let len = vfs.read_link("init", &mut target).unwrap();
+        assert_eq!(&target[..len], b"bin/app");
+
+        let mut file = vfs.open("/etc/hostname", OpenFlags::READ).unwrap();
+        assert_eq!(vfs.seek(&mut file, SeekFrom::End(-3)).unwrap(), 7);
+        let mut buf = [0; 8];
+        assert_eq!(vfs.read(&mut file, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"el\n");
+        assert_eq!(vfs.seek(&mut file, SeekFrom::Current(-11)).unwrap_err(), Error::InvalidSeek);
+        assert_eq!(vfs.write(&mut file, b"x").unwrap_err(), Error::NotWritable);
+
+        assert_eq!(vfs.open("/etc/missing", OpenFlags::READ).unwrap_err(), Error::NotFound);
+        assert_eq!(vfs.open("/etc/hostname/x", OpenFlags::READ).unwrap_err(), Error::NotADirectory);
+        assert_eq!(vfs.open("/etc/hostname", OpenFlags::WRITE).unwrap_err(), Error::ReadOnlyFs);
+        assert_eq!(vfs.mkdir("/tmp").unwrap_err(), Error::ReadOnlyFs);
+        assert_eq!(vfs.open_dir("/etc/hostname").unwrap_err(), Error::NotADirectory);
+    }
+
+    #[test]
+    fn later_members_and_links_between_mounts() {
+        let mut data = initramfs();
+        let mut overlay = Vec::new();
+        member(&mut overlay, "etc/hostname", S_IFREG | 0o644, b"overlay\n");
+        member(&mut overlay, "data", S_IFLNK | 0o777, b"/mnt/data");
+        member(&mut overlay, "loop", S_IFLNK | 0o777, b"./loop");
+        member(&mut overlay, "TRAILER!!!", 0, b"");
+        data.extend(overlay);
+        let archive = Archive::new(&data).unwrap();
+        let mut disk = Vec::new();
+        let fat = FatFs::new(fat_volume(&mut disk)).unwrap();
+        let mut vfs = Vfs::new();
+        vfs.mount("/", &archive).unwrap();
+        vfs.mount("/mnt", &fat).unwrap();
+
+        assert_eq!(contents(&vfs, "etc/hostname"), b"overlay\n");
+        assert_eq!(names(&vfs, "/etc"), ["hostname"]);
+        vfs.mkdir("/data").unwrap_err();
+        vfs.mkdir("/mnt/data").unwrap();
+        let mut file = vfs.open("/data/notes", OpenFlags::WRITE).unwrap();
+        vfs.write(&mut file, b"through a link").unwrap();
+        assert_eq!(contents(&vfs, "/mnt/data/notes"), b"through a link");
+        // ".." leaves the mount the way it came in
+        assert_eq!(contents(&vfs, "/mnt/../etc/hostname"), b"overlay\n");
+        assert_eq!(vfs.stat("/loop").unwrap_err(), Error::TooManyLinks);
+    }
+
+    #[test]
+    fn writes_through_a_mount() {
+        let data = initramfs();
+        let archive = Archive::new(&data).unwrap();
+        let mut disk = Vec::new();
+        let fat = FatFs::new(fat_volume(&mut disk)).unwrap();
+        let mut vfs = Vfs::new();
+        vfs.mount("/", &archive).unwrap();
+        vfs.mount("/tmp/", &fat).unwrap();
+        assert_eq!(vfs.mount("/tmp", &fat).unwrap_err(), Error::AlreadyMounted);
+        assert_eq!(vfs.mounts().map(|(path, _)| path).collect::<Vec<_>>(), ["/", "/tmp"]);
+
+        vfs.mkdir("/tmp/logs").unwrap();
+        assert_eq!(vfs.mkdir("/tmp/logs").unwrap_err(), Error::Exists);
+        let mut log = vfs.open("/tmp/logs/boot.log", OpenFlags::WRITE).unwrap();
+        assert_eq!(vfs.write(&mut log, b"first line\n").unwrap(), 11);
+        let mut more = vfs.open("/tmp/logs/boot.log", OpenFlags::APPEND).unwrap();
+        vfs.write(&mut more, b"second line\n").unwrap();
+        assert_eq!(contents(&vfs, "/tmp/logs/boot.log"), b"first line\nsecond line\n");
+        assert_eq!(vfs.fstat(&log).unwrap().size, 23);
+
+        // A gap past the end reads as zeros
+        vfs.seek(&mut log, SeekFrom::Start(30)).unwrap();
+        vfs.write(&mut log, b"!").unwrap();
+        assert_eq!(contents(&vfs, "/tmp/logs/boot.log")[23..], *b"\0\0\0\0\0\0\0!");
+        vfs.truncate(&log, 4).unwrap();
+        assert_eq!(contents(&vfs, "/tmp/logs/boot.log"), b"firs");
+        let exclusive = OpenFlags { exclusive: true, ..OpenFlags::WRITE };
+        assert_eq!(vfs.open("/tmp/logs/boot.log", exclusive).unwrap_err(), Error::Exists);
+        vfs.open("/tmp/logs/boot.log", OpenFlags::WRITE).unwrap();
+        assert_eq!(vfs.stat("/tmp/logs/boot.log").unwrap().size, 0);
+
+        assert_eq!(names(&vfs, "/tmp"), ["logs"]);
+        let mut dir = vfs.open_dir("/tmp/logs").unwrap();
+        assert_eq!(vfs.next_entry(&mut dir).unwrap().map(|e| e.file_type()), Some(FileType::File));
+        assert!(vfs.next_entry(&mut dir).unwrap().is_none());
+        assert_eq!(vfs.open("/tmp/logs", OpenFlags::WRITE).unwrap_err(), Error::IsADirectory);
+        assert_eq!(vfs.remove("/tmp/logs").unwrap_err(), Error::NotEmpty);
+        vfs.remove("/tmp/logs/boot.log").unwrap();
+        vfs.remove("/tmp/logs").unwrap();
+        assert_eq!(vfs.remove("/tmp").unwrap_err(), Error::Busy);
+        vfs.sync().unwrap();
+
+        // Handles on an unmounted filesystem go stale, even once something
+        // else is mounted there
+        let mut file = vfs.open("/tmp/x", OpenFlags::WRITE).unwrap();
+        assert_eq!(vfs.unmount("/").err(), Some(Error::Busy));
+        assert!(core::ptr::addr_eq(vfs.unmount("/tmp").unwrap(), &fat));
+        vfs.mount("/tmp", &fat).unwrap();
+        assert_eq!(vfs.write(&mut file, b"x").unwrap_err(), Error::Stale);
+        assert_eq!(vfs.stat("/tmp/x").unwrap().size, 0);
+    }
+
+    #[test]
+    fn reads_ext() {
+        let root = std::env::temp_dir().join(std::format!("driver_core-vfs-ext-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("share/doc")).unwrap();
+        std::fs::write(root.join("share/doc/README"), b"read me\n").unwrap();
+        std::os::unix::fs::symlink("share/doc", root.join("doc")).unwrap();
+        let image = root.with_extension("img");
+        let made = std::process::Command::new("mke2fs").args(["-q", "-F", "-t", "ext4", "-d"]).arg(&root).arg(&image).arg("1024").output();
+        let _ = std::fs::remove_dir_all(&root);
+        let Ok(made) = made else {
+            std::eprintln!("mke2fs not installed; skipping");
+            return;
+        };
+        assert!(made.status.success(), "{}", String::from_utf8_lossy(&made.stderr));
+        let mut data = std::fs::read(&image).unwrap();
+        let _ = std::fs::remove_file(&image);
+
+        let ext = ExtFs::new(RamDisk::new(&mut data, 1024).unwrap()).unwrap();
+        let mut vfs = Vfs::new();
+        vfs.mount("/data", &ext).unwrap();
+        let mut root = names(&vfs, "/data");
+        root.sort();
+        assert_eq!(root, ["doc", "lost+found", "share"]);
+        assert_eq!(contents(&vfs, "/data/doc/README"), b"read me\n");
+        assert_eq!(vfs.lstat("/data/doc").unwrap().file_type, FileType::Symlink);
+        assert_eq!(vfs.stat("/data/doc").unwrap().file_type, FileType::Dir);
+        assert_eq!(vfs.open("/data/new", OpenFlags::WRITE).unwrap_err(), Error::ReadOnlyFs);
+        let existing = OpenFlags { create: false, ..OpenFlags::WRITE };
+        assert_eq!(vfs.open("/data/new", existing).unwrap_err(), Error::NotFound);
+        assert_eq!(vfs.stat("/elsewhere").unwrap_err(), Error::NotFound);
+    }
+
+    #[test]
+    fn descriptors_reuse_the_lowest_number() {
+        let mut table = FdTable::new();
+        for i in 0..3 {
+            assert_eq!(table.insert(i * 10).unwrap(), i);
+        }
+        assert_eq!(table.remove(1), Some(10));
+        assert_eq!(table.remove(1), None);
+        assert_eq!(table.insert(7).unwrap(), 1);
+        *table.get_mut(1).unwrap() += 1;
+        assert_eq!(table.get(1), Some(&8));
+        assert_eq!(table.insert_at(0, 5).unwrap(), Some(0));
+        assert_eq!(table.insert_at(MAX_FDS, 5).unwrap_err(), Error::BadDescriptor);
+        while table.insert(0).is_ok() {}
+        assert_eq!(table.insert(0).unwrap_err(), Error::TooManyOpen);
+        table.clear();
+        assert_eq!(table.get(0), None);
+    }
+}
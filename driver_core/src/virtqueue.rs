@@ -0,0 +1,366 @@
+//! Split virtqueue (virtio 1.x, section 2.7)
+//!
+//! The descriptor table and both rings live in one struct so a driver can
+//! keep a whole queue in a static and give the device its addresses
+//! directly (the kernel runs identity mapped). Free descriptors are kept on
+//! a list threaded through their `next` fields, as in Linux.
+//!
+//! A queue may run smaller than its `N` descriptors (see set_size()), so
+//! one static type can serve devices with different queue_size_max.
+//!
+//! The driver side only: notifying the device is up to the transport.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+/// Descriptor continues in `next`
+pub const VRING_DESC_F_NEXT: u16 = 1;
+/// Device writes this buffer (otherwise it reads it)
+pub const VRING_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Desc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+pub struct Avail<const N: usize> {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [u16; N],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct UsedElem {
+    /// Head descriptor of the returned chain
+    pub id: u32,
+    /// Bytes the device wrote into the chain
+    pub len: u32,
+}
+
+#[repr(C)]
+pub struct Used<const N: usize> {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [UsedElem; N],
+}
+
+/// One buffer of a request, by physical address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+impl Buffer {
+    /// Buffer the device reads
+    pub fn readable<T: ?Sized>(data: &T) -> Self {
+        Buffer {
+            addr: data as *const T as *const u8 as u64,
+            len: core::mem::size_of_val(data) as u32,
+            device_writes: false,
+        }
+    }
+
+    /// Buffer the device fills in
+    pub fn writable<T: ?Sized>(data: &mut T) -> Self {
+        Buffer {
+            addr: data as *mut T as *mut u8 as u64,
+            len: core::mem::size_of_val(data) as u32,
+            device_writes: true,
+        }
+    }
+}
+
+/// Queue of up to `N` descriptors (a power of two)
+#[repr(C, align(16))]
+pub struct Virtqueue<const N: usize> {
+    descs: [Desc; N],
+    avail: Avail<N>,
+    used: Used<N>,
+    /// Descriptors and ring entries in use: N unless set_size() said less
+    size: u16,
+    free_head: u16,
+    num_free: u16,
+    last_used: u16,
+}
+
+impl<const N: usize> Virtqueue<N> {
+    pub const fn new() -> Self {
+        const { assert!(N.is_power_of_two() && N <= 32768) };
+
+        let mut descs = [Desc { addr: 0, len: 0, flags: 0, next: 0 }; N];
+        let mut i = 0;
+        while i < N {
+            descs[i].next = (i + 1) as u16;
+            i += 1;
+        }
+
+        Virtqueue {
+            descs,
+            avail: Avail { flags: 0, idx: 0, ring: [0; N] },
+            used: Used { flags: 0, idx: 0, ring: [UsedElem { id: 0, len: 0 }; N] },
+            size: N as u16,
+            free_head: 0,
+            num_free: N as u16,
+            last_used: 0,
+        }
+    }
+
+    /// Queue size to program into the device
+    pub const fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Run as a queue of `size` descriptors, for a device whose
+    /// queue_size_max is below `N`; starts the queue afresh
+    ///
+    /// Only before the queue is handed to the device. False (and no
+    /// change) unless `size` is a power of two no larger than `N`.
+    pub fn set_size(&mut self, size: u16) -> bool {
+        if !size.is_power_of_two() || size as usize > N {
+            return false;
+        }
+        for (i, desc) in self.descs.iter_mut().enumerate() {
+            *desc = Desc { next: (i + 1) as u16, ..Desc::default() };
+        }
+        self.avail.idx = 0;
+        self.used.idx = 0;
+        self.size = size;
+        self.free_head = 0;
+        self.num_free = size;
+        self.last_used = 0;
+        true
+    }
+
+    /// Physical addresses of the descriptor table, available and used rings
+    pub fn addrs(&self) -> (u64, u64, u64) {
+        (
+            self.descs.as_ptr() as u64,
+            &self.avail as *const Avail<N> as u64,
+            &self.used as *const Used<N> as u64,
+        )
+    }
+
+    /// Descriptors not currently owned by the device
+    pub fn num_free(&self) -> usize {
+        self.num_free as usize
+    }
+
+    /// True when the device has given back everything it was handed
+    pub fn is_idle(&self) -> bool {
+        self.num_free == self.size
+    }
+
+    /// Chain `bufs` and make the chain available to the device
+    ///
+    /// Returns the head descriptor, which comes back as `UsedElem::id`, or
+    /// None if there are not enough free descriptors. The caller notifies
+    /// the device.
+    pub fn push(&mut self, bufs: &[Buffer]) -> Option<u16> {
+        if bufs.is_empty() || bufs.len() > self.num_free as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut idx = head;
+        for (i, buf) in bufs.iter().enumerate() {
+            let desc = &mut self.descs[idx as usize];
+            desc.addr = buf.addr;
+            desc.len = buf.len;
+            desc.flags = if buf.device_writes { VRING_DESC_F_WRITE } else { 0 };
+            if i + 1 < bufs.len() {
+                desc.flags |= VRING_DESC_F_NEXT;
+                idx = desc.next;
+            } else {
+                self.free_head = desc.next;
+            }
+        }
+        self.num_free -= bufs.len() as u16;
+
+        // Descriptors must be visible before the ring entry, and the entry
+        // before the index
+        fence(Ordering::SeqCst);
+        unsafe {
+            let avail_idx = read_volatile(&self.avail.idx);
+            write_volatile(&mut self.avail.ring[avail_idx as usize % self.size as usize], head);
+            fence(Ordering::SeqCst);
+            write_volatile(&mut self.avail.idx, avail_idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+
+        Some(head)
+    }
+
+    /// True if the device has returned chains we have not popped yet
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        unsafe { read_volatile(&self.used.idx) != self.last_used }
+    }
+
+    /// Take the next chain the device has finished with and free its
+    /// descriptors
+    pub fn pop_used(&mut self) -> Option<UsedElem> {
+        if !self.has_used() {
+            return None;
+        }
+        let elem = unsafe { read_volatile(&self.used.ring[self.last_used as usize % self.size as usize]) };
+        self.last_used = self.last_used.wrapping_add(1);
+        self.free_chain(elem.id as u16);
+        Some(elem)
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        // A bad id from the device must not corrupt the free list
+        if head >= self.size {
+            return;
+        }
+        let mut idx = head;
+        for _ in 0..self.size {
+            self.num_free += 1;
+            let desc = &mut self.descs[idx as usize];
+            if desc.flags & VRING_DESC_F_NEXT == 0 {
+                desc.next = self.free_head;
+                break;
+            }
+            idx = desc.next;
+        }
+        self.free_head = head;
+    }
+}
+
+impl<const N: usize> Default for Virtqueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Play the device: take the next available chain, report `written`
+    /// bytes and return the chain's descriptors
+    fn device_complete<const N: usize>(q: &mut Virtqueue<N>, written: u32) -> Vec<Desc> {
+        let slot = q.used.idx as usize % q.size() as usize;
+        let head = q.avail.ring[slot];
+        let mut chain = vec![q.descs[head as usize]];
+        while chain.last().unwrap().flags & VRING_DESC_F_NEXT != 0 {
+            chain.push(q.descs[chain.last().unwrap().next as usize]);
+        }
+        q.used.ring[slot] = UsedElem { id: head as u32, len: written };
+        q.used.idx = q.used.idx.wrapping_add(1);
+        chain
+    }
+
+    #[test]
+    fn push_links_chain_and_publishes_head() {
+        let mut q = Virtqueue::<8>::new();
+        let header = [0u8; 16];
+        let mut data = [0u8; 512];
+        let mut status = 0u8;
+        let bufs = [Buffer::readable(&header), Buffer::writable(&mut data), Buffer::writable(&mut status)];
+
+        let head = q.push(&bufs).unwrap();
+        assert_eq!(q.avail.idx, 1);
+        assert_eq!(q.avail.ring[0], head);
+        assert_eq!(q.num_free(), 5);
+
+        let chain = device_complete(&mut q, 513);
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0].addr, header.as_ptr() as u64);
+        assert_eq!(chain[0].len, 16);
+        assert_eq!(chain[0].flags, VRING_DESC_F_NEXT);
+        assert_eq!(chain[1].len, 512);
+        assert_eq!(chain[1].flags, VRING_DESC_F_NEXT | VRING_DESC_F_WRITE);
+        assert_eq!(chain[2].len, 1);
+        assert_eq!(chain[2].flags, VRING_DESC_F_WRITE);
+    }
+
+    #[test]
+    fn pop_used_returns_descriptors_to_free_list() {
+        let mut q = Virtqueue::<4>::new();
+        let buf = [0u8; 4];
+        assert!(!q.has_used());
+        assert_eq!(q.pop_used(), None);
+
+        let a = q.push(&[Buffer::readable(&buf); 3]).unwrap();
+        assert!(q.push(&[Buffer::readable(&buf); 2]).is_none());
+        let b = q.push(&[Buffer::readable(&buf)]).unwrap();
+        assert_eq!(q.num_free(), 0);
+
+        device_complete(&mut q, 0);
+        assert_eq!(q.pop_used(), Some(UsedElem { id: a as u32, len: 0 }));
+        assert_eq!(q.num_free(), 3);
+        assert!(!q.is_idle());
+
+        device_complete(&mut q, 7);
+        assert_eq!(q.pop_used(), Some(UsedElem { id: b as u32, len: 7 }));
+        assert!(q.is_idle());
+
+        // Freed descriptors can all be chained again
+        q.push(&[Buffer::readable(&buf); 4]).unwrap();
+        assert_eq!(q.num_free(), 0);
+    }
+
+    #[test]
+    fn indices_wrap_around_the_ring() {
+        let mut q = Virtqueue::<2>::new();
+        let buf = [0u8; 1];
+        for i in 0..70_000u32 {
+            q.push(&[Buffer::readable(&buf)]).unwrap();
+            device_complete(&mut q, i % 100);
+            assert_eq!(q.pop_used().unwrap().len, i % 100);
+        }
+        assert!(q.is_idle());
+        assert_eq!(q.avail.idx, (70_000u32 % 65536) as u16);
+    }
+
+    #[test]
+    fn bad_used_id_is_ignored() {
+        let mut q = Virtqueue::<4>::new();
+        let buf = [0u8; 1];
+        q.push(&[Buffer::readable(&buf)]).unwrap();
+        q.used.ring[0] = UsedElem { id: 99, len: 0 };
+        q.used.idx = 1;
+        assert!(q.pop_used().is_some());
+        assert_eq!(q.num_free(), 3);
+    }
+
+    #[test]
+    fn smaller_size_wraps_at_its_own_length() {
+        let mut q = Virtqueue::<8>::new();
+        assert!(!q.set_size(3) && !q.set_size(16) && !q.set_size(0));
+        assert!(q.set_size(2));
+        assert_eq!((q.size(), q.num_free()), (2, 2));
+
+        let buf = [0u8; 1];
+        assert_eq!(q.push(&[Buffer::readable(&buf); 3]), None);
+        for i in 0..5u16 {
+            let head = q.push(&[Buffer::readable(&buf)]).unwrap();
+            assert!(head < 2);
+            assert_eq!(q.avail.ring[i as usize % 2], head);
+            device_complete(&mut q, 0);
+            assert_eq!(q.pop_used().unwrap().id, head as u32);
+        }
+        assert!(q.is_idle());
+    }
+
+    #[test]
+    fn rings_have_spec_layout() {
+        assert_eq!(core::mem::size_of::<Desc>(), 16);
+        assert_eq!(core::mem::size_of::<Avail<8>>(), 4 + 2 * 8);
+        assert_eq!(core::mem::size_of::<Used<8>>(), 4 + 8 * 8);
+        let q = Virtqueue::<8>::new();
+        let (desc, avail, used) = q.addrs();
+        assert_eq!(desc % 16, 0);
+        assert_eq!(avail % 2, 0);
+        assert_eq!(used % 4, 0);
+    }
+}
@@ -0,0 +1,56 @@
+//! Procedural macros for the kernel
+//!
+//! `#[kernel_test]` registers a `fn() -> ktest::Outcome` with the in-kernel
+//! test runner by placing a descriptor in the `.kernel_tests` linker section.
+//! The function's name is the test name used by `tests=` on the command line.
+//!
+//! Written against plain `proc_macro` so the kernel keeps a single external
+//! dependency.
+
+use proc_macro::{TokenStream, TokenTree};
+
+#[proc_macro_attribute]
+pub fn kernel_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return compile_error("#[kernel_test] takes no arguments");
+    }
+
+    let Some(name) = fn_name(&item) else {
+        return compile_error("#[kernel_test] can only be applied to a function");
+    };
+
+    let descriptor = format!(
+        "#[used]
+        #[link_section = \".kernel_tests\"]
+        static __KERNEL_TEST_{upper}: crate::ktest::KernelTest = crate::ktest::KernelTest {{
+            name: \"{name}\",
+            func: {name},
+        }};",
+        upper = name.to_uppercase(),
+        name = name,
+    );
+
+    let mut out = item;
+    out.extend(descriptor.parse::<TokenStream>().unwrap());
+    out
+}
+
+/// Identifier following the `fn` keyword
+fn fn_name(item: &TokenStream) -> Option<String> {
+    let mut tokens = item.clone().into_iter();
+    while let Some(token) = tokens.next() {
+        if let TokenTree::Ident(ident) = token {
+            if ident.to_string() == "fn" {
+                return match tokens.next() {
+                    Some(TokenTree::Ident(name)) => Some(name.to_string()),
+                    _ => None,
+                };
+            }
+        }
+    }
+    None
+}
+
+fn compile_error(message: &str) -> TokenStream {
+    format!("compile_error!({:?});", message).parse().unwrap()
+}
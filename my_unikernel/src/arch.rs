@@ -0,0 +1,49 @@
+//! AArch64 register access helpers
+
+/// Read a system register by name, e.g. `read_sysreg!("esr_el1")`
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack)); }
+        value
+    }};
+}
+
+/// Write a system register by name, e.g. `write_sysreg!("vbar_el1", addr)`
+macro_rules! write_sysreg {
+    ($reg:literal, $value:expr) => {{
+        let value: u64 = $value;
+        unsafe { core::arch::asm!(concat!("msr ", $reg, ", {}"), in(reg) value, options(nostack)); }
+    }};
+}
+
+pub(crate) use read_sysreg;
+pub(crate) use write_sysreg;
+
+/// Current frame pointer (x29)
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let fp: u64;
+    unsafe { core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack)); }
+    fp
+}
+
+/// Current stack pointer
+#[inline(always)]
+pub fn stack_pointer() -> u64 {
+    let sp: u64;
+    unsafe { core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack)); }
+    sp
+}
+
+/// Instruction synchronization barrier
+#[inline(always)]
+pub fn isb() {
+    unsafe { core::arch::asm!("isb", options(nostack)); }
+}
+
+/// Unmask IRQs (clear DAIF.I)
+#[inline(always)]
+pub fn irq_enable() {
+    unsafe { core::arch::asm!("msr daifclr, #2", options(nostack)); }
+}
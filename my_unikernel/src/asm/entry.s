@@ -5,7 +5,22 @@
 .section .text.boot
 .global _start
 
+// Linux arm64 Image header (Documentation/arch/arm64/booting.rst)
+// Loaders enter at the first byte, so code0 branches over the header.
+// text_offset, image_size and flags are filled in by `cargo xtask image`.
 _start:
+    b primary_entry             // code0
+    .word 0                     // code1
+    .quad 0                     // text_offset
+    .quad 0                     // image_size
+    .quad 0                     // flags
+    .quad 0                     // res2
+    .quad 0                     // res3
+    .quad 0                     // res4
+    .ascii "ARM\x64"            // magic
+    .word 0                     // res5
+
+primary_entry:
     // ================================================================
     // CRITICAL: Enable FP/SIMD FIRST (before ANY Rust code)
     // ================================================================
@@ -42,15 +57,42 @@ _start:
     // 1. Save DTB pointer (x0) to callee-saved register
     mov x19, x0
 
+    // 1b. Relocate: the kernel is linked at 0 (see linker.ld), so the
+    // runtime address of _start is the load offset. Each Elf64_Rela is
+    // (r_offset, r_info, r_addend); only R_AARCH64_RELATIVE appears in a
+    // static PIE, and it wants *(base + r_offset) = base + r_addend.
+    adr x0, _start
+    adrp x1, __rela_start
+    add x1, x1, :lo12:__rela_start
+    adrp x2, __rela_end
+    add x2, x2, :lo12:__rela_end
+relocate:
+    cmp x1, x2
+    b.hs relocate_done
+    ldp x3, x4, [x1], #16
+    ldr x5, [x1], #8
+    cmp w4, #1027               // R_AARCH64_RELATIVE
+    b.ne relocate
+    add x5, x5, x0
+    str x5, [x0, x3]
+    b relocate
+relocate_done:
+
     // 2. Disable interrupts
     msr daifset, #0xf
 
-    // 3. Set up stack pointer (16-byte aligned)
+    // 3. Install exception vectors (asm/vectors.s)
+    adrp x0, exception_vectors
+    add x0, x0, :lo12:exception_vectors
+    msr vbar_el1, x0
+    isb
+
+    // 4. Set up stack pointer (16-byte aligned)
     adrp x0, _stack_top
     add x0, x0, :lo12:_stack_top
     mov sp, x0
 
-    // 4. Clear BSS section (using general registers, not SIMD)
+    // 5. Clear BSS section (using general registers, not SIMD)
     adrp x0, _bss_start
     add x0, x0, :lo12:_bss_start
     adrp x1, _bss_end
@@ -62,11 +104,15 @@ clear_bss:
     b clear_bss
 bss_done:
 
-    // 5. Pass DTB pointer as first argument to kmain
+    // 6. Terminate the frame-pointer chain for backtraces
+    mov x29, xzr
+    mov x30, xzr
+
+    // 7. Pass DTB pointer as first argument to kmain
     mov x0, x19
     bl kmain
 
-    // 6. If kmain returns, halt
+    // 8. If kmain returns, halt
 halt:
     wfi
     b halt
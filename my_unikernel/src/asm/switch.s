@@ -0,0 +1,61 @@
+// Kernel thread context switch
+//
+// Only callee-saved state is switched here: a thread always leaves through
+// a call to context_switch, so the caller-saved registers are dead. A thread
+// preempted by an IRQ has its full register state, including FP/SIMD, in the
+// TrapFrame on its own stack (see vectors.s).
+//
+// Context layout (must match thread.rs):
+//   0x00  x19 - x28
+//   0x50  x29 (fp), x30 (lr)
+//   0x60  sp
+//   0x68  d8 - d15
+//   0xa8  fpcr, fpsr
+
+.section .text
+.balign 4
+
+// context_switch(prev: *mut Context, next: *const Context)
+.global context_switch
+context_switch:
+    stp x19, x20, [x0, #0x00]
+    stp x21, x22, [x0, #0x10]
+    stp x23, x24, [x0, #0x20]
+    stp x25, x26, [x0, #0x30]
+    stp x27, x28, [x0, #0x40]
+    stp x29, x30, [x0, #0x50]
+    mov x9, sp
+    str x9, [x0, #0x60]
+    stp d8, d9, [x0, #0x68]
+    stp d10, d11, [x0, #0x78]
+    stp d12, d13, [x0, #0x88]
+    stp d14, d15, [x0, #0x98]
+    mrs x9, fpcr
+    mrs x10, fpsr
+    stp x9, x10, [x0, #0xa8]
+
+    ldp x19, x20, [x1, #0x00]
+    ldp x21, x22, [x1, #0x10]
+    ldp x23, x24, [x1, #0x20]
+    ldp x25, x26, [x1, #0x30]
+    ldp x27, x28, [x1, #0x40]
+    ldp x29, x30, [x1, #0x50]
+    ldr x9, [x1, #0x60]
+    mov sp, x9
+    ldp d8, d9, [x1, #0x68]
+    ldp d10, d11, [x1, #0x78]
+    ldp d12, d13, [x1, #0x88]
+    ldp d14, d15, [x1, #0x98]
+    ldp x9, x10, [x1, #0xa8]
+    msr fpcr, x9
+    msr fpsr, x10
+    ret
+
+// First code run by a new thread, reached through the initial lr
+// x19 = argument, x20 = entry function (never returns)
+.global thread_start
+thread_start:
+    msr daifclr, #2
+    mov x0, x19
+    blr x20
+    brk #0
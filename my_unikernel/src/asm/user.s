@@ -0,0 +1,136 @@
+// Entering and leaving EL0
+//
+// user_enter saves the kernel's callee-saved state and erets to a program;
+// the program's traps come back through the vector table on the same kernel
+// stack, and when it is done process.rs calls user_return, which unwinds to
+// where user_enter was called as if it had returned.
+//
+// KernelContext layout (must match process.rs):
+//   0x00  x19 - x28
+//   0x50  x29 (fp), x30 (lr)
+//   0x60  sp
+//   0x68  d8 - d15
+//   0xa8  fpcr, fpsr
+//   0xb8  daif
+
+.section .text
+.balign 4
+
+// user_enter(ctx: *mut KernelContext, entry: u64, sp: u64)
+.global user_enter
+user_enter:
+    stp x19, x20, [x0, #0x00]
+    stp x21, x22, [x0, #0x10]
+    stp x23, x24, [x0, #0x20]
+    stp x25, x26, [x0, #0x30]
+    stp x27, x28, [x0, #0x40]
+    stp x29, x30, [x0, #0x50]
+    mov x9, sp
+    str x9, [x0, #0x60]
+    stp d8, d9, [x0, #0x68]
+    stp d10, d11, [x0, #0x78]
+    stp d12, d13, [x0, #0x88]
+    stp d14, d15, [x0, #0x98]
+    mrs x9, fpcr
+    mrs x10, fpsr
+    stp x9, x10, [x0, #0xa8]
+    mrs x9, daif
+    str x9, [x0, #0xb8]
+
+    // No IRQ may use ELR/SPSR between here and the eret
+    msr daifset, #2
+    msr sp_el0, x2
+    msr elr_el1, x1
+    // EL0t with every exception unmasked
+    msr spsr_el1, xzr
+
+    // Nothing of the kernel's leaks into the program
+    msr fpcr, xzr
+    msr fpsr, xzr
+    fmov d0, xzr
+    fmov d1, xzr
+    fmov d2, xzr
+    fmov d3, xzr
+    fmov d4, xzr
+    fmov d5, xzr
+    fmov d6, xzr
+    fmov d7, xzr
+    fmov d8, xzr
+    fmov d9, xzr
+    fmov d10, xzr
+    fmov d11, xzr
+    fmov d12, xzr
+    fmov d13, xzr
+    fmov d14, xzr
+    fmov d15, xzr
+    fmov d16, xzr
+    fmov d17, xzr
+    fmov d18, xzr
+    fmov d19, xzr
+    fmov d20, xzr
+    fmov d21, xzr
+    fmov d22, xzr
+    fmov d23, xzr
+    fmov d24, xzr
+    fmov d25, xzr
+    fmov d26, xzr
+    fmov d27, xzr
+    fmov d28, xzr
+    fmov d29, xzr
+    fmov d30, xzr
+    fmov d31, xzr
+    mov x0, xzr
+    mov x1, xzr
+    mov x2, xzr
+    mov x3, xzr
+    mov x4, xzr
+    mov x5, xzr
+    mov x6, xzr
+    mov x7, xzr
+    mov x8, xzr
+    mov x9, xzr
+    mov x10, xzr
+    mov x11, xzr
+    mov x12, xzr
+    mov x13, xzr
+    mov x14, xzr
+    mov x15, xzr
+    mov x16, xzr
+    mov x17, xzr
+    mov x18, xzr
+    mov x19, xzr
+    mov x20, xzr
+    mov x21, xzr
+    mov x22, xzr
+    mov x23, xzr
+    mov x24, xzr
+    mov x25, xzr
+    mov x26, xzr
+    mov x27, xzr
+    mov x28, xzr
+    mov x29, xzr
+    mov x30, xzr
+    eret
+
+// user_return(ctx: *const KernelContext) -> !
+// Called from a trap handler; the trap frame below the saved sp is dropped
+.global user_return
+user_return:
+    ldp x19, x20, [x0, #0x00]
+    ldp x21, x22, [x0, #0x10]
+    ldp x23, x24, [x0, #0x20]
+    ldp x25, x26, [x0, #0x30]
+    ldp x27, x28, [x0, #0x40]
+    ldp x29, x30, [x0, #0x50]
+    ldr x9, [x0, #0x60]
+    mov sp, x9
+    ldp d8, d9, [x0, #0x68]
+    ldp d10, d11, [x0, #0x78]
+    ldp d12, d13, [x0, #0x88]
+    ldp d14, d15, [x0, #0x98]
+    ldp x9, x10, [x0, #0xa8]
+    msr fpcr, x9
+    msr fpsr, x10
+    ldr x9, [x0, #0xb8]
+    msr daif, x9
+    ret
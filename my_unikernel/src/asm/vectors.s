@@ -0,0 +1,143 @@
+// EL1 exception vector table
+//
+// Every entry saves the full register state into a TrapFrame on the current
+// stack and calls handle_exception(kind, frame) in exceptions.rs. On return
+// the (possibly modified) frame is restored and we eret.
+//
+// TrapFrame layout (must match exceptions.rs):
+//   0x000  x0 - x30
+//   0x0f8  sp_el0
+//   0x100  elr_el1
+//   0x108  spsr_el1
+//   0x110  q0 - q31
+//   0x310  fpcr
+//   0x318  fpsr
+//
+// The FP/SIMD registers are saved too: handlers are Rust code that may use
+// them, and an IRQ may switch to another thread (thread.rs).
+
+.equ TRAP_FRAME_SIZE, 0x320
+.equ TRAP_FRAME_FP, 0x110
+
+.macro VECTOR kind
+    .balign 0x80
+    sub sp, sp, #TRAP_FRAME_SIZE
+    stp x0, x1, [sp, #16 * 0]
+    mov x0, #\kind
+    b exception_common
+.endm
+
+.section .text.vectors, "ax"
+.balign 0x800
+.global exception_vectors
+exception_vectors:
+    // Current EL with SP_EL0
+    VECTOR 0        // Synchronous
+    VECTOR 1        // IRQ
+    VECTOR 2        // FIQ
+    VECTOR 3        // SError
+    // Current EL with SP_ELx
+    VECTOR 4
+    VECTOR 5
+    VECTOR 6
+    VECTOR 7
+    // Lower EL, AArch64
+    VECTOR 8
+    VECTOR 9
+    VECTOR 10
+    VECTOR 11
+    // Lower EL, AArch32
+    VECTOR 12
+    VECTOR 13
+    VECTOR 14
+    VECTOR 15
+
+exception_common:
+    stp x2, x3, [sp, #16 * 1]
+    stp x4, x5, [sp, #16 * 2]
+    stp x6, x7, [sp, #16 * 3]
+    stp x8, x9, [sp, #16 * 4]
+    stp x10, x11, [sp, #16 * 5]
+    stp x12, x13, [sp, #16 * 6]
+    stp x14, x15, [sp, #16 * 7]
+    stp x16, x17, [sp, #16 * 8]
+    stp x18, x19, [sp, #16 * 9]
+    stp x20, x21, [sp, #16 * 10]
+    stp x22, x23, [sp, #16 * 11]
+    stp x24, x25, [sp, #16 * 12]
+    stp x26, x27, [sp, #16 * 13]
+    stp x28, x29, [sp, #16 * 14]
+    mrs x9, sp_el0
+    stp x30, x9, [sp, #16 * 15]
+    mrs x10, elr_el1
+    mrs x11, spsr_el1
+    stp x10, x11, [sp, #16 * 16]
+    add x9, sp, #TRAP_FRAME_FP
+    stp q0, q1, [x9, #32 * 0]
+    stp q2, q3, [x9, #32 * 1]
+    stp q4, q5, [x9, #32 * 2]
+    stp q6, q7, [x9, #32 * 3]
+    stp q8, q9, [x9, #32 * 4]
+    stp q10, q11, [x9, #32 * 5]
+    stp q12, q13, [x9, #32 * 6]
+    stp q14, q15, [x9, #32 * 7]
+    stp q16, q17, [x9, #32 * 8]
+    stp q18, q19, [x9, #32 * 9]
+    stp q20, q21, [x9, #32 * 10]
+    stp q22, q23, [x9, #32 * 11]
+    stp q24, q25, [x9, #32 * 12]
+    stp q26, q27, [x9, #32 * 13]
+    stp q28, q29, [x9, #32 * 14]
+    stp q30, q31, [x9, #32 * 15]
+    mrs x10, fpcr
+    mrs x11, fpsr
+    str x10, [x9, #32 * 16]
+    str x11, [x9, #32 * 16 + 8]
+
+    // handle_exception(kind, frame)
+    mov x1, sp
+    bl handle_exception
+
+    add x9, sp, #TRAP_FRAME_FP
+    ldr x10, [x9, #32 * 16]
+    ldr x11, [x9, #32 * 16 + 8]
+    msr fpcr, x10
+    msr fpsr, x11
+    ldp q0, q1, [x9, #32 * 0]
+    ldp q2, q3, [x9, #32 * 1]
+    ldp q4, q5, [x9, #32 * 2]
+    ldp q6, q7, [x9, #32 * 3]
+    ldp q8, q9, [x9, #32 * 4]
+    ldp q10, q11, [x9, #32 * 5]
+    ldp q12, q13, [x9, #32 * 6]
+    ldp q14, q15, [x9, #32 * 7]
+    ldp q16, q17, [x9, #32 * 8]
+    ldp q18, q19, [x9, #32 * 9]
+    ldp q20, q21, [x9, #32 * 10]
+    ldp q22, q23, [x9, #32 * 11]
+    ldp q24, q25, [x9, #32 * 12]
+    ldp q26, q27, [x9, #32 * 13]
+    ldp q28, q29, [x9, #32 * 14]
+    ldp q30, q31, [x9, #32 * 15]
+    ldp x10, x11, [sp, #16 * 16]
+    msr elr_el1, x10
+    msr spsr_el1, x11
+    ldp x30, x9, [sp, #16 * 15]
+    msr sp_el0, x9
+    ldp x28, x29, [sp, #16 * 14]
+    ldp x26, x27, [sp, #16 * 13]
+    ldp x24, x25, [sp, #16 * 12]
+    ldp x22, x23, [sp, #16 * 11]
+    ldp x20, x21, [sp, #16 * 10]
+    ldp x18, x19, [sp, #16 * 9]
+    ldp x16, x17, [sp, #16 * 8]
+    ldp x14, x15, [sp, #16 * 7]
+    ldp x12, x13, [sp, #16 * 6]
+    ldp x10, x11, [sp, #16 * 5]
+    ldp x8, x9, [sp, #16 * 4]
+    ldp x6, x7, [sp, #16 * 3]
+    ldp x4, x5, [sp, #16 * 2]
+    ldp x2, x3, [sp, #16 * 1]
+    ldp x0, x1, [sp, #16 * 0]
+    add sp, sp, #TRAP_FRAME_SIZE
+    eret
@@ -0,0 +1,272 @@
+//! Kernel command line parsing
+//!
+//! Reads /chosen/bootargs from the DTB and exposes typed accessors for the
+//! options this kernel understands, so one Image can be steered per run by
+//! the VMM (VZLinuxBootLoader.commandLine, QEMU -append, ...).
+//!
+//! Recognised options (unknown ones are ignored, later ones win):
+//!   loglevel=N | quiet | debug      boot/driver message verbosity (Linux numbering)
+//!   tests=entropy,block,net,...     driver self-tests to run (all/none)
+//!   console=ttyAMA0 | console=hvc0  output console(s), may be repeated
+//!   ip=dhcp | ip=<client>:<server>:<gw>:<netmask>[:...]  network config
+//!   root=/dev/vda                   root block device
+//!   init=/bin/app                   program in the initrd to run after the tests
+//!   video=1280x720                  GPU resolution
+//!   gdb | gdb=ttyAMA0 | gdb=hvc0    wait for a debugger on that console
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::dtb;
+use crate::sync::Once;
+
+/// Console verbosity, ordered from quietest to noisiest
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// Output console selectable with `console=`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Console {
+    /// PL011 UART (ttyAMA0 / ttyS0)
+    Uart,
+    /// virtio-console, PCI or MMIO (hvc0)
+    Virtio,
+}
+
+/// Network configuration from `ip=`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpConfig {
+    Dhcp,
+    Static {
+        addr: [u8; 4],
+        gateway: [u8; 4],
+        netmask: [u8; 4],
+    },
+}
+
+const CONSOLE_UART: u8 = 1 << 0;
+const CONSOLE_VIRTIO: u8 = 1 << 1;
+
+// Raw bootargs (points into the DTB, which stays mapped) plus cached
+// values that are consulted on every character of output
+static BOOTARGS: Once<&'static str> = Once::new();
+static CONSOLE_MASK: AtomicU8 = AtomicU8::new(CONSOLE_UART | CONSOLE_VIRTIO);
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Parse /chosen/bootargs from the DTB
+/// Returns false if the DTB has no command line (defaults stay in effect)
+pub unsafe fn init(dtb_ptr: u64) -> bool {
+    let Some(args) = dtb::find_bootargs(dtb_ptr) else {
+        return false;
+    };
+
+    if BOOTARGS.set(args).is_err() {
+        return true;
+    }
+    CONSOLE_MASK.store(parse_console_mask(), Ordering::Relaxed);
+    LOG_LEVEL.store(parse_log_level() as u8, Ordering::Relaxed);
+    true
+}
+
+/// The full command line as passed by the VMM
+pub fn raw() -> &'static str {
+    BOOTARGS.get().copied().unwrap_or("")
+}
+
+/// Iterate over all options as (key, value) pairs
+/// Bare flags such as `quiet` have no value
+pub fn options() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    raw().split_ascii_whitespace().map(|tok| match tok.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (tok, None),
+    })
+}
+
+/// Value of the last `key=value` occurrence
+pub fn get(key: &str) -> Option<&'static str> {
+    options()
+        .filter(|&(k, _)| k == key)
+        .filter_map(|(_, v)| v)
+        .last()
+}
+
+/// True if `key` appears at all, with or without a value
+pub fn has_flag(key: &str) -> bool {
+    options().any(|(k, _)| k == key)
+}
+
+/// Current console verbosity
+pub fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        _ => LogLevel::Debug,
+    }
+}
+
+/// True if messages at `level` should be printed (see `LogWriter`)
+pub fn log_enabled(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+/// True if output should go to the given console
+pub fn console_enabled(console: Console) -> bool {
+    let bit = match console {
+        Console::Uart => CONSOLE_UART,
+        Console::Virtio => CONSOLE_VIRTIO,
+    };
+    CONSOLE_MASK.load(Ordering::Relaxed) & bit != 0
+}
+
+/// True if the named driver self-test should run
+/// `tests=` takes a comma-separated list, or `all` / `none`; default is all
+pub fn test_enabled(name: &str) -> bool {
+    match get("tests") {
+        None | Some("all") => true,
+        Some("none") => false,
+        Some(list) => list.split(',').any(|t| t == name),
+    }
+}
+
+/// Network configuration, or None if `ip=` is absent or `off`
+pub fn ip_config() -> Option<IpConfig> {
+    let value = get("ip")?;
+    match value {
+        "off" | "none" => None,
+        "dhcp" | "on" | "any" => Some(IpConfig::Dhcp),
+        _ => {
+            // <client-ip>:<server-ip>:<gw-ip>:<netmask>:<hostname>:<device>:<autoconf>
+            let mut fields = value.split(':');
+            let addr = parse_ipv4(fields.next()?)?;
+            let _server = fields.next();
+            let gateway = fields.next().and_then(parse_ipv4).unwrap_or([0; 4]);
+            let netmask = fields.next().and_then(parse_ipv4).unwrap_or([255, 255, 255, 0]);
+            Some(IpConfig::Static { addr, gateway, netmask })
+        }
+    }
+}
+
+/// Root block device (e.g. `/dev/vda1`)
+pub fn root() -> Option<&'static str> {
+    get("root")
+}
+
+/// Program to run at EL0 once the self-tests are done (`init=/sbin/init`)
+pub fn init_program() -> Option<&'static str> {
+    get("init")
+}
+
+/// Requested GPU resolution from `video=WxH`
+/// Linux-style suffixes (`video=1280x720@60`, `video=Virtual-1:1024x768`) are accepted
+pub fn gpu_resolution() -> Option<(u32, u32)> {
+    let mut value = get("video")?;
+    if let Some((_, mode)) = value.split_once(':') {
+        value = mode;
+    }
+    let mode = value.split(|c| c == '@' || c == '-' || c == 'M').next()?;
+    let (w, h) = mode.split_once('x')?;
+    let width = parse_u32(w)?;
+    let height = parse_u32(h)?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
+
+/// Console to run the GDB stub on, if `gdb` was given
+/// A bare `gdb` flag selects the PL011 UART
+pub fn gdb_port() -> Option<Console> {
+    let (_, value) = options().filter(|&(k, _)| k == "gdb").last()?;
+    match value {
+        None => Some(Console::Uart),
+        Some(name) if name.starts_with("ttyAMA") || name.starts_with("ttyS") => Some(Console::Uart),
+        Some(name) if name.starts_with("hvc") => Some(Console::Virtio),
+        Some(_) => None,
+    }
+}
+
+fn parse_console_mask() -> u8 {
+    let mut mask = 0u8;
+    for (key, value) in options() {
+        if key != "console" {
+            continue;
+        }
+        let name = value.unwrap_or("");
+        if name.starts_with("ttyAMA") || name.starts_with("ttyS") {
+            mask |= CONSOLE_UART;
+        } else if name.starts_with("hvc") {
+            mask |= CONSOLE_VIRTIO;
+        }
+    }
+
+    // No recognised console= (or only tty0): keep everything on
+    if mask == 0 {
+        CONSOLE_UART | CONSOLE_VIRTIO
+    } else {
+        mask
+    }
+}
+
+fn parse_log_level() -> LogLevel {
+    let mut level = LogLevel::Info;
+    for (key, value) in options() {
+        match (key, value) {
+            ("quiet", None) => level = LogLevel::Warn,
+            ("debug", None) => level = LogLevel::Debug,
+            ("loglevel", Some(v)) => {
+                level = match v {
+                    "error" => LogLevel::Error,
+                    "warn" => LogLevel::Warn,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    // Linux numbering: messages below N are shown, so
+                    // 4 is errors (3) only and 8 includes debug (7)
+                    _ => match parse_u32(v) {
+                        Some(0..=4) => LogLevel::Error,
+                        Some(5..=6) => LogLevel::Warn,
+                        Some(7) => LogLevel::Info,
+                        Some(_) => LogLevel::Debug,
+                        None => level,
+                    },
+                };
+            }
+            _ => {}
+        }
+    }
+    level
+}
+
+fn parse_u32(s: &str) -> Option<u32> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut n = 0u32;
+    for b in s.bytes() {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((b - b'0') as u32)?;
+    }
+    Some(n)
+}
+
+fn parse_ipv4(s: &str) -> Option<[u8; 4]> {
+    let mut out = [0u8; 4];
+    let mut parts = s.split('.');
+    for octet in out.iter_mut() {
+        let n = parse_u32(parts.next()?)?;
+        if n > 255 {
+            return None;
+        }
+        *octet = n as u8;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(out)
+}
@@ -0,0 +1,165 @@
+//! EL1 exception handling
+//!
+//! The vector table in asm/vectors.s (installed in VBAR_EL1 by entry.s) saves
+//! a TrapFrame and calls handle_exception(). IRQs are dispatched through the
+//! GIC once it is up, and may end in a thread switch. Synchronous exceptions
+//! from a program at EL0 go to process.rs. Debug traps go to the GDB stub
+//! when it is enabled; everything else is reported with a decoded
+//! ESR, symbolized ELR and a backtrace, and the VM is powered off.
+
+use core::arch::global_asm;
+use core::fmt::Write;
+
+use crate::arch::read_sysreg;
+use crate::{gdb, gic, panic, power, process, puts, symbols, thread, timer, ConsoleWriter};
+
+global_asm!(include_str!("asm/vectors.s"));
+
+// Vector kinds passed by vectors.s: origin * 4 + type
+pub const KIND_SYNC: u64 = 0;
+pub const KIND_IRQ: u64 = 1;
+pub const KIND_FIQ: u64 = 2;
+pub const KIND_SERROR: u64 = 3;
+// Origin (kind / 4) of exceptions taken from EL0 in AArch64 state
+const ORIGIN_EL0_64: u64 = 2;
+
+// ESR_EL1 exception classes
+pub const EC_UNKNOWN: u64 = 0x00;
+pub const EC_SVC64: u64 = 0x15;
+pub const EC_HVC64: u64 = 0x16;
+pub const EC_SYSREG: u64 = 0x18;
+pub const EC_IABT_LOWER: u64 = 0x20;
+pub const EC_IABT_CUR: u64 = 0x21;
+pub const EC_PC_ALIGN: u64 = 0x22;
+pub const EC_DABT_LOWER: u64 = 0x24;
+pub const EC_DABT_CUR: u64 = 0x25;
+pub const EC_SP_ALIGN: u64 = 0x26;
+pub const EC_SERROR: u64 = 0x2F;
+pub const EC_BREAKPT_LOWER: u64 = 0x30;
+pub const EC_BREAKPT_CUR: u64 = 0x31;
+pub const EC_SOFTSTP_LOWER: u64 = 0x32;
+pub const EC_SOFTSTP_CUR: u64 = 0x33;
+pub const EC_WATCHPT_LOWER: u64 = 0x34;
+pub const EC_WATCHPT_CUR: u64 = 0x35;
+pub const EC_BRK64: u64 = 0x3C;
+
+/// Register state saved by the vector stubs (layout shared with vectors.s)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TrapFrame {
+    pub x: [u64; 31],
+    pub sp_el0: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    pub q: [u128; 32],
+    pub fpcr: u64,
+    pub fpsr: u64,
+}
+
+fn origin_name(kind: u64) -> &'static str {
+    match kind / 4 {
+        0 => "EL1t",
+        1 => "EL1h",
+        2 => "EL0 (AArch64)",
+        _ => "EL0 (AArch32)",
+    }
+}
+
+fn type_name(kind: u64) -> &'static str {
+    match kind % 4 {
+        KIND_SYNC => "Synchronous",
+        KIND_IRQ => "IRQ",
+        KIND_FIQ => "FIQ",
+        _ => "SError",
+    }
+}
+
+pub fn class_name(ec: u64) -> &'static str {
+    match ec {
+        EC_UNKNOWN => "unknown/undefined instruction",
+        EC_SVC64 => "SVC",
+        EC_HVC64 => "HVC",
+        EC_SYSREG => "trapped MSR/MRS",
+        EC_IABT_LOWER | EC_IABT_CUR => "instruction abort",
+        EC_PC_ALIGN => "PC alignment fault",
+        EC_DABT_LOWER | EC_DABT_CUR => "data abort",
+        EC_SP_ALIGN => "SP alignment fault",
+        EC_SERROR => "SError",
+        EC_BREAKPT_LOWER | EC_BREAKPT_CUR => "hardware breakpoint",
+        EC_SOFTSTP_LOWER | EC_SOFTSTP_CUR => "software step",
+        EC_WATCHPT_LOWER | EC_WATCHPT_CUR => "watchpoint",
+        EC_BRK64 => "BRK",
+        _ => "other",
+    }
+}
+
+/// Print everything known about an exception
+pub fn report(kind: u64, frame: &TrapFrame) {
+    let esr = read_sysreg!("esr_el1");
+    let far = read_sysreg!("far_el1");
+    let ec = (esr >> 26) & 0x3F;
+
+    let _ = writeln!(
+        ConsoleWriter,
+        "\n!!! EXCEPTION: {} from {}: {} (EC={:#x}, ISS={:#x})",
+        type_name(kind), origin_name(kind), class_name(ec), ec, esr & 0x1FF_FFFF
+    );
+    if ec == EC_BRK64 {
+        let _ = writeln!(ConsoleWriter, "  brk #{:#x}", esr & 0xFFFF);
+    }
+
+    puts("  ELR  = ");
+    symbols::print_address(frame.elr);
+    puts("\n  LR   = ");
+    symbols::print_address(frame.x[30]);
+    let _ = writeln!(ConsoleWriter, "\n  FAR  = {:#018x}", far);
+    let _ = writeln!(ConsoleWriter, "  ESR  = {:#018x}  SPSR = {:#018x}", esr, frame.spsr);
+
+    for i in (0..31).step_by(2) {
+        if i + 1 < 31 {
+            let _ = writeln!(ConsoleWriter, "  x{:<2} = {:#018x}  x{:<2} = {:#018x}", i, frame.x[i], i + 1, frame.x[i + 1]);
+        } else {
+            let _ = writeln!(ConsoleWriter, "  x{:<2} = {:#018x}", i, frame.x[i]);
+        }
+    }
+
+    panic::print_backtrace(frame.x[29]);
+}
+
+/// Acknowledge and dispatch pending interrupts, then let the scheduler
+/// preempt the interrupted thread if its time slice is over
+fn handle_irq() {
+    while let Some(intid) = gic::acknowledge() {
+        if intid == timer::TIMER_INTID {
+            timer::handle_irq();
+            thread::tick();
+        }
+        gic::end_of_interrupt(intid);
+    }
+    thread::preempt();
+}
+
+/// Entry point from vectors.s
+#[no_mangle]
+extern "C" fn handle_exception(kind: u64, frame: &mut TrapFrame) {
+    if kind % 4 == KIND_IRQ && gic::version() != 0 {
+        handle_irq();
+        return;
+    }
+
+    if kind == ORIGIN_EL0_64 * 4 + KIND_SYNC && process::handle_trap(frame) {
+        return;
+    }
+
+    if kind % 4 == KIND_SYNC {
+        let ec = (read_sysreg!("esr_el1") >> 26) & 0x3F;
+        if gdb::memory_fault(ec, frame) || gdb::handle_trap(kind, ec, frame) {
+            return;
+        }
+    }
+
+    // The fault may have come with a console lock held
+    crate::console_crashing();
+    report(kind, frame);
+    power::shutdown(power::EXIT_PANIC);
+}
@@ -0,0 +1,241 @@
+//! Cooperative async executor for driver I/O
+//!
+//! Single core and no heap: tasks are futures pinned by the caller (usually
+//! with `core::pin::pin!` on the stack) and handed to an Executor, which
+//! polls the ones that have been woken. A driver parks a task on the IoEvent
+//! of the virtqueue it is waiting for. When no task is runnable the executor
+//! calls poll_io(), which checks each driver's used rings and wakes the
+//! events whose requests completed. poll_io() only takes locks with
+//! try_lock, so an interrupt handler may call it too.
+//!
+//! Wake-ups land in one global ready mask, because a waker can outlive its
+//! task (an IoEvent keeps it until the next wake()) and must not point into
+//! a dropped Executor. So only one Executor may exist at a time: new()
+//! panics on a second one, which also catches block_on() called from inside
+//! a task.
+
+use core::future::{poll_fn, Future};
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::sync::IrqSpinLock;
+use crate::thread::WaitQueue;
+
+/// Task slots per Executor (one bit each in READY)
+pub const MAX_TASKS: usize = 32;
+
+// Task slots with a pending wake-up
+static READY: AtomicU32 = AtomicU32::new(0);
+
+// Set while an Executor exists, as READY belongs to it
+static EXISTS: AtomicBool = AtomicBool::new(false);
+
+// Wakers carry their task slot in the data pointer, so cloning and dropping
+// are free and a stale waker at worst causes one spurious poll
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+fn raw_waker(slot: usize) -> RawWaker {
+    RawWaker::new(slot as *const (), &WAKER_VTABLE)
+}
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    raw_waker(data as usize)
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    READY.fetch_or(1 << data as usize, Ordering::Release);
+}
+
+unsafe fn waker_drop(_data: *const ()) {}
+
+fn task_waker(slot: usize) -> Waker {
+    unsafe { Waker::from_raw(raw_waker(slot)) }
+}
+
+/// Fixed set of tasks polled to completion by run()
+pub struct Executor<'a> {
+    tasks: [Option<Pin<&'a mut dyn Future<Output = ()>>>; MAX_TASKS],
+}
+
+impl<'a> Executor<'a> {
+    /// Panics if another Executor is still alive
+    pub fn new() -> Self {
+        if EXISTS.swap(true, Ordering::Acquire) {
+            panic!("executor: a second Executor would share the ready mask");
+        }
+        // Left over from the last Executor's stale wakers
+        READY.store(0, Ordering::Relaxed);
+        Executor { tasks: [const { None }; MAX_TASKS] }
+    }
+
+    /// Add a task; returns false if every slot is taken
+    pub fn spawn(&mut self, task: Pin<&'a mut dyn Future<Output = ()>>) -> bool {
+        let Some(slot) = self.tasks.iter().position(Option::is_none) else {
+            return false;
+        };
+        self.tasks[slot] = Some(task);
+        READY.fetch_or(1 << slot, Ordering::Release);
+        true
+    }
+
+    /// Poll tasks as they are woken until all of them have finished
+    pub fn run(&mut self) {
+        while self.tasks.iter().any(Option::is_some) {
+            let ready = READY.swap(0, Ordering::Acquire);
+            if ready == 0 {
+                poll_io();
+                core::hint::spin_loop();
+                continue;
+            }
+
+            for slot in 0..MAX_TASKS {
+                if ready & (1 << slot) == 0 {
+                    continue;
+                }
+                let Some(task) = self.tasks[slot].as_mut() else {
+                    continue;
+                };
+                let waker = task_waker(slot);
+                let mut cx = Context::from_waker(&waker);
+                if task.as_mut().poll(&mut cx).is_ready() {
+                    self.tasks[slot] = None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Executor<'_> {
+    fn drop(&mut self) {
+        EXISTS.store(false, Ordering::Release);
+    }
+}
+
+/// Run a single future to completion
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut output = None;
+    {
+        let task = pin!(async {
+            output = Some(fut.await);
+        });
+        let mut executor = Executor::new();
+        executor.spawn(task);
+        executor.run();
+    }
+    output.expect("block_on task did not complete")
+}
+
+/// Run two futures concurrently within one task and return both outputs
+pub async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut out_a = None;
+    let mut out_b = None;
+
+    poll_fn(|cx| {
+        if out_a.is_none() {
+            if let Poll::Ready(v) = a.as_mut().poll(cx) {
+                out_a = Some(v);
+            }
+        }
+        if out_b.is_none() {
+            if let Poll::Ready(v) = b.as_mut().poll(cx) {
+                out_b = Some(v);
+            }
+        }
+        if out_a.is_some() && out_b.is_some() {
+            Poll::Ready((out_a.take().unwrap(), out_b.take().unwrap()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Let the other tasks run once before continuing
+pub async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Tasks and kernel threads waiting for one virtqueue to make progress
+pub struct IoEvent {
+    wakers: IrqSpinLock<[Option<Waker>; MAX_TASKS]>,
+    threads: WaitQueue,
+}
+
+impl IoEvent {
+    pub const fn new() -> Self {
+        IoEvent {
+            wakers: IrqSpinLock::new([const { None }; MAX_TASKS]),
+            threads: WaitQueue::new(),
+        }
+    }
+
+    /// Arrange for `waker` to be woken by the next wake()
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if wakers.iter().flatten().any(|w| w.will_wake(waker)) {
+            return;
+        }
+        match wakers.iter_mut().find(|w| w.is_none()) {
+            Some(free) => *free = Some(waker.clone()),
+            // No room: have the task poll again rather than lose the wake-up
+            None => waker.wake_by_ref(),
+        }
+    }
+
+    /// Wake every registered task and blocked thread
+    pub fn wake(&self) {
+        let wakers = core::mem::replace(&mut *self.wakers.lock(), [const { None }; MAX_TASKS]);
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+        self.threads.wake_all();
+    }
+
+    /// Thread counterpart of wait_for(): block the calling kernel thread
+    /// until `ready` returns a value
+    pub fn block_until<T>(&self, mut ready: impl FnMut() -> Option<T>) -> T {
+        let mut value = None;
+        self.threads.wait_until(|| {
+            value = ready();
+            value.is_some()
+        });
+        value.unwrap()
+    }
+}
+
+/// Resolve once `ready` returns a value, parking the task on `event` until then
+///
+/// The waker is registered before `ready` runs, so a completion that lands in
+/// between still wakes the task.
+pub async fn wait_for<T>(event: &IoEvent, mut ready: impl FnMut() -> Option<T>) -> T {
+    poll_fn(|cx| {
+        event.register(cx.waker());
+        match ready() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Check every driver's used rings and wake tasks whose I/O has completed
+pub fn poll_io() {
+    crate::virtio_block::poll_io();
+    crate::virtio_net::poll_io();
+    crate::virtio_console::poll_io();
+    crate::virtio_entropy::poll_io();
+}
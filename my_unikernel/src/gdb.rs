@@ -0,0 +1,699 @@
+//! GDB remote serial protocol stub
+//!
+//! Enabled with `gdb` / `gdb=ttyAMA0` (PL011) or `gdb=hvc0` (virtio-console)
+//! on the command line; kmain then stops in `breakpoint()` until a debugger
+//! attaches (`target remote` on the host side of that console).
+//!
+//! Supported: register read/write (x0-x30, sp, pc, cpsr), memory read/write,
+//! software breakpoints (Z0/z0, planted as `brk`), single-step through
+//! MDSCR_EL1.SS, continue, detach and kill. The stub runs inside the
+//! exception handler, so it is entered on any `brk`, breakpoint or step
+//! trap. There is no async Ctrl-C: the console is only polled while stopped.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::{read_sysreg, write_sysreg};
+use crate::cmdline::{self, Console};
+use crate::exceptions::{self, TrapFrame};
+use crate::sync::{Once, SpinLock};
+use crate::{power, puts, virtio_console};
+
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+/// `brk #0`, the instruction GDB itself uses on AArch64
+const BRK_INSN: u32 = 0xD420_0000;
+const BRK_MASK: u32 = 0xFFE0_001F;
+
+// MDSCR_EL1 / SPSR bits
+const MDSCR_SS: u64 = 1 << 0;
+const MDSCR_KDE: u64 = 1 << 13;
+const SPSR_D: u64 = 1 << 9;
+const SPSR_SS: u64 = 1 << 21;
+
+// GDB register numbers (aarch64 core feature)
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const REG_CPSR: usize = 33;
+
+// PL011 registers
+const UART_DR: usize = 0x00;
+const UART_FR: usize = 0x18;
+const UART_FR_RXFE: u32 = 1 << 4;
+const UART_FR_TXFF: u32 = 1 << 5;
+
+const SIGTRAP: u8 = 5;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    orig: u32,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+static PORT: Once<Console> = Once::new();
+
+/// Planted breakpoints, and the one being stepped over (its `brk` lifted)
+struct Breakpoints {
+    slots: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    step_over: Option<u64>,
+}
+
+/// The debugger connection: buffered input and the reply being built
+struct Link {
+    rx_buf: [u8; 64],
+    rx_pos: usize,
+    rx_len: usize,
+    reply: [u8; PACKET_SIZE],
+}
+
+/// Everything the stub keeps between traps; split so the command loop can
+/// hold the received packet while it replies and edits breakpoints
+struct StubState {
+    breakpoints: Breakpoints,
+    link: Link,
+    packet: [u8; PACKET_SIZE],
+    attached: bool,
+    stepping: bool,
+    /// SPSR.D of the stepped context, restored when the step is done
+    step_saved_d: u64,
+}
+
+static STATE: SpinLock<StubState> = SpinLock::new(StubState {
+    breakpoints: Breakpoints { slots: [None; MAX_BREAKPOINTS], step_over: None },
+    link: Link { rx_buf: [0; 64], rx_pos: 0, rx_len: 0, reply: [0; PACKET_SIZE] },
+    packet: [0; PACKET_SIZE],
+    attached: false,
+    stepping: false,
+    step_saved_d: 0,
+});
+
+// Set while the stub touches debugger-supplied addresses; a data abort in
+// that window is reported back as an error instead of being fatal
+static MEM_PROBE: AtomicBool = AtomicBool::new(false);
+static MEM_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// Set up the stub from the command line
+/// Returns true if a debugger port was selected
+pub fn init() -> bool {
+    let Some(port) = cmdline::gdb_port() else {
+        return false;
+    };
+
+    unsafe {
+        let _ = PORT.set(port);
+
+        // Unlock the OS lock and allow debug exceptions at EL1 so that
+        // MDSCR_EL1.SS single-stepping works
+        write_sysreg!("oslar_el1", 0);
+        let mdscr = read_sysreg!("mdscr_el1");
+        write_sysreg!("mdscr_el1", mdscr | MDSCR_KDE);
+    }
+    crate::arch::isb();
+    true
+}
+
+/// True if the stub is active
+pub fn enabled() -> bool {
+    PORT.is_completed()
+}
+
+/// Trap into the debugger
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { core::arch::asm!("brk #0"); }
+}
+
+/// Called by the exception handler for data aborts
+/// Returns true if the fault came from a stub memory access and was absorbed
+pub fn memory_fault(ec: u64, frame: &mut TrapFrame) -> bool {
+    if ec != exceptions::EC_DABT_CUR || !MEM_PROBE.load(Ordering::SeqCst) {
+        return false;
+    }
+    MEM_FAULT.store(true, Ordering::SeqCst);
+    frame.elr += 4;
+    true
+}
+
+/// Called by the exception handler for debug exceptions
+/// Returns true if the stub handled the trap and execution should resume
+pub fn handle_trap(kind: u64, ec: u64, frame: &mut TrapFrame) -> bool {
+    if !enabled() {
+        return false;
+    }
+    // Held until the trap is resumed; a trap inside the stub itself is
+    // left to the fatal handler rather than deadlocking here
+    let Some(mut guard) = STATE.try_lock() else {
+        return false;
+    };
+    let st = &mut *guard;
+
+    unsafe {
+        match ec {
+            exceptions::EC_BRK64
+            | exceptions::EC_BREAKPT_CUR
+            | exceptions::EC_BREAKPT_LOWER
+            | exceptions::EC_WATCHPT_CUR
+            | exceptions::EC_WATCHPT_LOWER => {}
+            exceptions::EC_SOFTSTP_CUR | exceptions::EC_SOFTSTP_LOWER => {
+                st.disarm_step(frame);
+
+                // Finished stepping over a planted breakpoint: put it back
+                if let Some(addr) = st.breakpoints.step_over.take() {
+                    st.breakpoints.insert(addr);
+                    if !st.stepping {
+                        return true;
+                    }
+                }
+                st.stepping = false;
+            }
+            _ => return false,
+        }
+
+        if st.attached {
+            st.link.send_stop_reply();
+        } else {
+            puts("GDB: stopped, waiting for debugger\n");
+        }
+
+        let resume = command_loop(st, kind, frame);
+        st.attached = true;
+
+        // Resuming from a brk: step over our own breakpoint, skip compiled-in ones
+        let pc = frame.elr;
+        if st.breakpoints.find(pc).is_some() {
+            st.breakpoints.remove_insn(pc);
+            st.breakpoints.step_over = Some(pc);
+            st.arm_step(frame);
+        } else if ec == exceptions::EC_BRK64 && read_insn(pc) & BRK_MASK == BRK_INSN & BRK_MASK {
+            frame.elr += 4;
+        }
+
+        if let Resume::Step = resume {
+            st.stepping = true;
+            st.arm_step(frame);
+        }
+    }
+    true
+}
+
+// ---------------------------------------------------------------------------
+// Single-step
+
+impl StubState {
+    unsafe fn arm_step(&mut self, frame: &mut TrapFrame) {
+        if frame.spsr & SPSR_SS == 0 {
+            self.step_saved_d = frame.spsr & SPSR_D;
+        }
+        frame.spsr = (frame.spsr | SPSR_SS) & !SPSR_D;
+        write_sysreg!("mdscr_el1", read_sysreg!("mdscr_el1") | MDSCR_SS | MDSCR_KDE);
+        crate::arch::isb();
+    }
+
+    unsafe fn disarm_step(&self, frame: &mut TrapFrame) {
+        write_sysreg!("mdscr_el1", read_sysreg!("mdscr_el1") & !MDSCR_SS);
+        crate::arch::isb();
+        frame.spsr = (frame.spsr & !SPSR_SS & !SPSR_D) | self.step_saved_d;
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Breakpoints
+
+unsafe fn read_insn(addr: u64) -> u32 {
+    read_volatile(addr as *const u32)
+}
+
+unsafe fn write_insn(addr: u64, insn: u32) {
+    write_volatile(addr as *mut u32, insn);
+    sync_icache(addr, 4);
+}
+
+/// Make freshly written code in [start, start + len) visible to instruction fetch
+unsafe fn sync_icache(start: u64, len: u64) {
+    let ctr = read_sysreg!("ctr_el0");
+    let dline = 4u64 << ((ctr >> 16) & 0xF);
+    let iline = 4u64 << (ctr & 0xF);
+    let end = start + len;
+
+    let mut addr = start & !(dline - 1);
+    while addr < end {
+        core::arch::asm!("dc cvau, {0}", in(reg) addr, options(nostack));
+        addr += dline;
+    }
+    core::arch::asm!("dsb ish", options(nostack));
+
+    let mut addr = start & !(iline - 1);
+    while addr < end {
+        core::arch::asm!("ic ivau, {0}", in(reg) addr, options(nostack));
+        addr += iline;
+    }
+    core::arch::asm!("dsb ish", "isb", options(nostack));
+}
+
+impl Breakpoints {
+    fn find(&self, addr: u64) -> Option<usize> {
+        self.slots.iter().position(|bp| matches!(bp, Some(b) if b.addr == addr))
+    }
+
+    unsafe fn insert(&mut self, addr: u64) -> bool {
+        if addr & 3 != 0 {
+            return false;
+        }
+        if self.find(addr).is_some() {
+            // Re-plant (e.g. after stepping over it)
+            write_insn(addr, BRK_INSN);
+            return true;
+        }
+        let Some(slot) = self.slots.iter().position(|bp| bp.is_none()) else {
+            return false;
+        };
+        let Some(orig) = probe(|| read_insn(addr)) else {
+            return false;
+        };
+        self.slots[slot] = Some(Breakpoint { addr, orig });
+        write_insn(addr, BRK_INSN);
+        true
+    }
+
+    /// Restore the original instruction but keep the breakpoint registered
+    unsafe fn remove_insn(&self, addr: u64) {
+        if let Some(bp) = self.find(addr).and_then(|i| self.slots[i]) {
+            write_insn(bp.addr, bp.orig);
+        }
+    }
+
+    unsafe fn delete(&mut self, addr: u64) -> bool {
+        match self.find(addr) {
+            Some(i) => {
+                self.remove_insn(addr);
+                self.slots[i] = None;
+                if self.step_over == Some(addr) {
+                    self.step_over = None;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    unsafe fn delete_all(&mut self) {
+        for i in 0..MAX_BREAKPOINTS {
+            if let Some(bp) = self.slots[i] {
+                self.delete(bp.addr);
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Guarded memory access
+
+fn probe<T>(access: impl FnOnce() -> T) -> Option<T> {
+    MEM_FAULT.store(false, Ordering::SeqCst);
+    MEM_PROBE.store(true, Ordering::SeqCst);
+    let value = access();
+    MEM_PROBE.store(false, Ordering::SeqCst);
+    if MEM_FAULT.load(Ordering::SeqCst) {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Transport
+
+impl Link {
+    unsafe fn getc(&mut self) -> u8 {
+        match PORT.get() {
+            Some(Console::Virtio) => loop {
+                if self.rx_pos < self.rx_len {
+                    let b = self.rx_buf[self.rx_pos];
+                    self.rx_pos += 1;
+                    return b;
+                }
+                self.rx_len = virtio_console::read_bytes(&mut self.rx_buf);
+                self.rx_pos = 0;
+                core::hint::spin_loop();
+            },
+            _ => {
+                let base = crate::UART_BASE.load(Ordering::Relaxed);
+                while read_volatile((base + UART_FR) as *const u32) & UART_FR_RXFE != 0 {
+                    core::hint::spin_loop();
+                }
+                read_volatile((base + UART_DR) as *const u32) as u8
+            }
+        }
+    }
+
+    /// Receive one packet into `packet`, returning its length
+    unsafe fn recv_packet(&mut self, packet: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            // Wait for start of packet (drops acks, Ctrl-C and noise)
+            while self.getc() != b'$' {}
+
+            let mut len = 0usize;
+            let mut sum = 0u8;
+            let mut c = self.getc();
+            while c != b'#' {
+                if c == b'$' {
+                    // Restart
+                    len = 0;
+                    sum = 0;
+                } else if len < PACKET_SIZE {
+                    packet[len] = c;
+                    len += 1;
+                    sum = sum.wrapping_add(c);
+                }
+                c = self.getc();
+            }
+
+            let hi = hex_val(self.getc());
+            let lo = hex_val(self.getc());
+            match (hi, lo) {
+                (Some(h), Some(l)) if (h << 4 | l) == sum => {
+                    write_raw(b"+");
+                    return len;
+                }
+                _ => write_raw(b"-"),
+            }
+        }
+    }
+
+    /// Send `reply[..len]` as a packet, retrying until acknowledged
+    unsafe fn send_packet(&mut self, len: usize) {
+        let sum = self.reply[..len].iter().fold(0u8, |s, &b| s.wrapping_add(b));
+        let cs = [HEX[(sum >> 4) as usize], HEX[(sum & 0xF) as usize]];
+
+        loop {
+            write_raw(b"$");
+            write_raw(&self.reply[..len]);
+            write_raw(b"#");
+            write_raw(&cs);
+            match self.getc() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+
+    unsafe fn send_str(&mut self, s: &str) {
+        let n = s.len().min(PACKET_SIZE);
+        self.reply[..n].copy_from_slice(&s.as_bytes()[..n]);
+        self.send_packet(n);
+    }
+
+    unsafe fn send_stop_reply(&mut self) {
+        self.reply[0] = b'S';
+        self.reply[1] = HEX[(SIGTRAP >> 4) as usize];
+        self.reply[2] = HEX[(SIGTRAP & 0xF) as usize];
+        self.send_packet(3);
+    }
+
+    /// Append `size` bytes of `value` to the reply as little-endian hex
+    fn put_le(&mut self, pos: &mut usize, value: u64, size: usize) {
+        for i in 0..size {
+            let b = (value >> (i * 8)) as u8;
+            self.reply[*pos] = HEX[(b >> 4) as usize];
+            self.reply[*pos + 1] = HEX[(b & 0xF) as usize];
+            *pos += 2;
+        }
+    }
+}
+
+unsafe fn write_raw(bytes: &[u8]) {
+    match PORT.get() {
+        Some(Console::Virtio) => virtio_console::write_bytes(bytes),
+        _ => {
+            let base = crate::UART_BASE.load(Ordering::Relaxed);
+            for &b in bytes {
+                while read_volatile((base + UART_FR) as *const u32) & UART_FR_TXFF != 0 {
+                    core::hint::spin_loop();
+                }
+                write_volatile((base + UART_DR) as *mut u32, b as u32);
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Hex helpers
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_val(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a hex number, returning it and the remaining input
+fn parse_hex(s: &[u8]) -> Option<(u64, &[u8])> {
+    let mut n = 0u64;
+    let mut i = 0;
+    while i < s.len() {
+        match hex_val(s[i]) {
+            Some(v) => n = (n << 4) | v as u64,
+            None => break,
+        }
+        i += 1;
+    }
+    if i == 0 {
+        None
+    } else {
+        Some((n, &s[i..]))
+    }
+}
+
+/// Parse `size` little-endian hex bytes
+fn get_le(s: &[u8], size: usize) -> Option<u64> {
+    if s.len() < size * 2 {
+        return None;
+    }
+    let mut value = 0u64;
+    for i in 0..size {
+        let b = (hex_val(s[i * 2])? << 4) | hex_val(s[i * 2 + 1])?;
+        value |= (b as u64) << (i * 8);
+    }
+    Some(value)
+}
+
+// ---------------------------------------------------------------------------
+// Registers
+
+/// Stack pointer of the interrupted context
+fn interrupted_sp(kind: u64, frame: &TrapFrame) -> u64 {
+    if kind / 4 >= 2 {
+        frame.sp_el0
+    } else {
+        // vectors.s pushed the frame on the interrupted EL1 stack
+        frame as *const TrapFrame as u64 + core::mem::size_of::<TrapFrame>() as u64
+    }
+}
+
+fn read_reg(kind: u64, frame: &TrapFrame, n: usize) -> Option<(u64, usize)> {
+    match n {
+        0..=30 => Some((frame.x[n], 8)),
+        REG_SP => Some((interrupted_sp(kind, frame), 8)),
+        REG_PC => Some((frame.elr, 8)),
+        REG_CPSR => Some((frame.spsr, 4)),
+        _ => None,
+    }
+}
+
+fn write_reg(kind: u64, frame: &mut TrapFrame, n: usize, value: u64) -> bool {
+    match n {
+        0..=30 => frame.x[n] = value,
+        // Moving the EL1 stack would move the frame itself; only EL0 SP is writable
+        REG_SP if kind / 4 >= 2 => frame.sp_el0 = value,
+        REG_SP => return value == interrupted_sp(kind, frame),
+        REG_PC => frame.elr = value,
+        REG_CPSR => frame.spsr = (frame.spsr & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF),
+        _ => return false,
+    }
+    true
+}
+
+// ---------------------------------------------------------------------------
+// Command loop
+
+unsafe fn command_loop(st: &mut StubState, kind: u64, frame: &mut TrapFrame) -> Resume {
+    let StubState { breakpoints, link, packet, attached, .. } = st;
+    loop {
+        let len = link.recv_packet(packet);
+        let pkt = &packet[..len];
+        if pkt.is_empty() {
+            link.send_packet(0);
+            continue;
+        }
+
+        let args = &pkt[1..];
+        match pkt[0] {
+            b'?' => link.send_stop_reply(),
+
+            b'g' => {
+                let mut pos = 0;
+                for n in 0..=REG_CPSR {
+                    if let Some((value, size)) = read_reg(kind, frame, n) {
+                        link.put_le(&mut pos, value, size);
+                    }
+                }
+                link.send_packet(pos);
+            }
+
+            b'G' => {
+                let mut ok = true;
+                let mut rest = args;
+                for n in 0..=REG_CPSR {
+                    let size = if n == REG_CPSR { 4 } else { 8 };
+                    match get_le(rest, size) {
+                        Some(value) => ok &= write_reg(kind, frame, n, value),
+                        None => break,
+                    }
+                    rest = &rest[size * 2..];
+                }
+                link.send_str(if ok { "OK" } else { "E01" });
+            }
+
+            b'p' => match parse_hex(args).and_then(|(n, _)| read_reg(kind, frame, n as usize)) {
+                Some((value, size)) => {
+                    let mut pos = 0;
+                    link.put_le(&mut pos, value, size);
+                    link.send_packet(pos);
+                }
+                None => link.send_str("E01"),
+            },
+
+            b'P' => {
+                let ok = parse_hex(args)
+                    .and_then(|(n, rest)| {
+                        let rest = rest.strip_prefix(b"=")?;
+                        let size = if n as usize == REG_CPSR { 4 } else { 8 };
+                        Some((n as usize, get_le(rest, size)?))
+                    })
+                    .map(|(n, value)| write_reg(kind, frame, n, value))
+                    .unwrap_or(false);
+                link.send_str(if ok { "OK" } else { "E01" });
+            }
+
+            b'm' => {
+                let parsed = parse_hex(args).and_then(|(addr, rest)| {
+                    let (len, _) = parse_hex(rest.strip_prefix(b",")?)?;
+                    Some((addr, len as usize))
+                });
+                match parsed {
+                    Some((addr, len)) if len * 2 <= PACKET_SIZE => {
+                        let mut pos = 0;
+                        let mut failed = false;
+                        for i in 0..len as u64 {
+                            match probe(|| read_volatile((addr + i) as *const u8)) {
+                                Some(b) => link.put_le(&mut pos, b as u64, 1),
+                                None => {
+                                    failed = true;
+                                    break;
+                                }
+                            }
+                        }
+                        if failed && pos == 0 {
+                            link.send_str("E14");
+                        } else {
+                            link.send_packet(pos);
+                        }
+                    }
+                    _ => link.send_str("E01"),
+                }
+            }
+
+            b'M' => {
+                let parsed = parse_hex(args).and_then(|(addr, rest)| {
+                    let (len, rest) = parse_hex(rest.strip_prefix(b",")?)?;
+                    Some((addr, len as usize, rest.strip_prefix(b":")?))
+                });
+                match parsed {
+                    Some((addr, len, data)) if data.len() >= len * 2 => {
+                        let mut ok = true;
+                        for i in 0..len {
+                            let b = get_le(&data[i * 2..], 1).unwrap_or(0) as u8;
+                            let target = addr + i as u64;
+                            if probe(|| write_volatile(target as *mut u8, b)).is_none() {
+                                ok = false;
+                                break;
+                            }
+                        }
+                        if ok && len > 0 {
+                            // May have patched code
+                            sync_icache(addr, len as u64);
+                        }
+                        link.send_str(if ok { "OK" } else { "E14" });
+                    }
+                    _ => link.send_str("E01"),
+                }
+            }
+
+            b'Z' | b'z' => {
+                // Only software breakpoints (type 0)
+                let parsed = args.strip_prefix(b"0,").and_then(parse_hex);
+                match parsed {
+                    Some((addr, _)) => {
+                        let ok = if pkt[0] == b'Z' {
+                            breakpoints.insert(addr)
+                        } else {
+                            breakpoints.delete(addr)
+                        };
+                        link.send_str(if ok { "OK" } else { "E01" });
+                    }
+                    None => link.send_packet(0),
+                }
+            }
+
+            b'c' | b's' => {
+                if let Some((addr, _)) = parse_hex(args) {
+                    frame.elr = addr;
+                }
+                return if pkt[0] == b'c' { Resume::Continue } else { Resume::Step };
+            }
+
+            b'D' => {
+                link.send_str("OK");
+                breakpoints.delete_all();
+                *attached = false;
+                return Resume::Continue;
+            }
+
+            b'k' => {
+                puts("GDB: killed\n");
+                power::shutdown(power::EXIT_FAILURE);
+            }
+
+            b'H' | b'T' => link.send_str("OK"),
+
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    link.send_str("PacketSize=400");
+                } else if args.starts_with(b"Attached") {
+                    link.send_str("1");
+                } else if args.starts_with(b"C") {
+                    link.send_str("QC1");
+                } else if args.starts_with(b"fThreadInfo") {
+                    link.send_str("m1");
+                } else if args.starts_with(b"sThreadInfo") {
+                    link.send_str("l");
+                } else {
+                    link.send_packet(0);
+                }
+            }
+
+            // Unsupported (vCont, X, ...): empty reply
+            _ => link.send_packet(0),
+        }
+    }
+}
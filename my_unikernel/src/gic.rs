@@ -0,0 +1,148 @@
+//! Generic Interrupt Controller (GICv2 / GICv3)
+//!
+//! Just enough to take private peripheral interrupts (the generic timer) on
+//! the boot CPU. The controller is located through the DTB; GICv3 uses the
+//! ICC system-register CPU interface, GICv2 the memory-mapped GICC.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::arch::{isb, read_sysreg, write_sysreg};
+use crate::dtb;
+
+// Distributor registers
+const GICD_CTLR: u64 = 0x000;
+const GICD_ISENABLER: u64 = 0x100;
+const GICD_IPRIORITYR: u64 = 0x400;
+
+const GICD_CTLR_ENABLE_GRP0: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+
+// GICv3 redistributor: RD_base frame, then SGI_base frame 64 KiB above it
+const GICR_WAKER: u64 = 0x014;
+const GICR_SGI_BASE: u64 = 0x1_0000;
+const GICR_IGROUPR0: u64 = GICR_SGI_BASE + 0x080;
+const GICR_ISENABLER0: u64 = GICR_SGI_BASE + 0x100;
+const GICR_IPRIORITYR: u64 = GICR_SGI_BASE + 0x400;
+
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+// GICv2 CPU interface
+const GICC_CTLR: u64 = 0x000;
+const GICC_PMR: u64 = 0x004;
+const GICC_IAR: u64 = 0x00C;
+const GICC_EOIR: u64 = 0x010;
+
+/// IDs 1020-1023 are special; 1023 means nothing pending
+const INTID_SPURIOUS: u32 = 1020;
+
+/// Priority given to every interrupt we enable (lower is more urgent)
+const DEFAULT_PRIORITY: u8 = 0x80;
+
+// 0 = not initialised, otherwise the GIC architecture version
+static VERSION: AtomicU8 = AtomicU8::new(0);
+static DIST_BASE: AtomicU64 = AtomicU64::new(0);
+static CPU_BASE: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn mmio_read_u32(addr: u64) -> u32 {
+    read_volatile(addr as *const u32)
+}
+
+unsafe fn mmio_write_u32(addr: u64, v: u32) {
+    write_volatile(addr as *mut u32, v)
+}
+
+unsafe fn mmio_write_u8(addr: u64, v: u8) {
+    write_volatile(addr as *mut u8, v)
+}
+
+/// Find the GIC in the DTB and enable its CPU interface
+/// Interrupts stay masked in DAIF; returns false if no GIC was found
+pub unsafe fn init(dtb_ptr: u64) -> bool {
+    let Some(info) = dtb::find_gic(dtb_ptr) else {
+        return false;
+    };
+
+    DIST_BASE.store(info.dist_base, Ordering::Relaxed);
+    CPU_BASE.store(info.cpu_base, Ordering::Relaxed);
+
+    match info.version {
+        3 => {
+            mmio_write_u32(info.dist_base + GICD_CTLR, GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1 | GICD_CTLR_ENABLE_GRP0);
+
+            // Wake the redistributor of this (only) CPU
+            let waker = mmio_read_u32(info.cpu_base + GICR_WAKER);
+            mmio_write_u32(info.cpu_base + GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+            while mmio_read_u32(info.cpu_base + GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                core::hint::spin_loop();
+            }
+
+            // System-register interface, accept every priority, enable group 1
+            let sre = read_sysreg!("S3_0_C12_C12_5"); // ICC_SRE_EL1
+            write_sysreg!("S3_0_C12_C12_5", sre | 1);
+            isb();
+            write_sysreg!("S3_0_C4_C6_0", 0xFF); // ICC_PMR_EL1
+            write_sysreg!("S3_0_C12_C12_7", 1); // ICC_IGRPEN1_EL1
+            isb();
+        }
+        _ => {
+            mmio_write_u32(info.dist_base + GICD_CTLR, GICD_CTLR_ENABLE_GRP0);
+            mmio_write_u32(info.cpu_base + GICC_PMR, 0xFF);
+            mmio_write_u32(info.cpu_base + GICC_CTLR, 1);
+        }
+    }
+
+    VERSION.store(info.version, Ordering::Release);
+    true
+}
+
+/// GIC architecture version, or 0 if init() has not succeeded
+pub fn version() -> u8 {
+    VERSION.load(Ordering::Acquire)
+}
+
+/// Unmask one private (SGI/PPI, intid < 32) or shared interrupt
+pub fn enable(intid: u32) {
+    let dist = DIST_BASE.load(Ordering::Relaxed);
+    let cpu = CPU_BASE.load(Ordering::Relaxed);
+    let bit = 1u32 << (intid % 32);
+
+    unsafe {
+        match version() {
+            0 => {}
+            3 if intid < 32 => {
+                // Private interrupts live in the redistributor when ARE is set
+                let group = mmio_read_u32(cpu + GICR_IGROUPR0);
+                mmio_write_u32(cpu + GICR_IGROUPR0, group | bit);
+                mmio_write_u8(cpu + GICR_IPRIORITYR + intid as u64, DEFAULT_PRIORITY);
+                mmio_write_u32(cpu + GICR_ISENABLER0, bit);
+            }
+            _ => {
+                mmio_write_u8(dist + GICD_IPRIORITYR + intid as u64, DEFAULT_PRIORITY);
+                mmio_write_u32(dist + GICD_ISENABLER + (intid / 32) as u64 * 4, bit);
+            }
+        }
+    }
+}
+
+/// Acknowledge the highest-priority pending interrupt
+/// Returns None for a spurious interrupt
+pub fn acknowledge() -> Option<u32> {
+    let intid = match version() {
+        3 => (read_sysreg!("S3_0_C12_C12_0") & 0xFF_FFFF) as u32, // ICC_IAR1_EL1
+        2 => unsafe { mmio_read_u32(CPU_BASE.load(Ordering::Relaxed) + GICC_IAR) & 0x3FF },
+        _ => return None,
+    };
+    (intid < INTID_SPURIOUS).then_some(intid)
+}
+
+/// Signal the end of handling for an acknowledged interrupt
+pub fn end_of_interrupt(intid: u32) {
+    match version() {
+        3 => write_sysreg!("S3_0_C12_C12_1", intid as u64), // ICC_EOIR1_EL1
+        2 => unsafe { mmio_write_u32(CPU_BASE.load(Ordering::Relaxed) + GICC_EOIR, intid) },
+        _ => {}
+    }
+}
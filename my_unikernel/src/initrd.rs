@@ -0,0 +1,74 @@
+//! Initial ramdisk: files shipped alongside the Image
+//!
+//! The VMM loads a CPIO newc archive into RAM (VZLinuxBootLoader's
+//! initialRamdiskURL, QEMU's -initrd) and records where in /chosen.
+//! memory::init() keeps the DMA areas clear of it, so the archive is read in
+//! place and files are handed out as slices of it.
+
+use core::fmt::Write;
+
+use driver_core::cpio::Archive;
+use kernel_macros::kernel_test;
+
+use crate::cmdline::LogLevel;
+use crate::dtb;
+use crate::ktest::Outcome;
+use crate::sync::Once;
+use crate::{ConsoleWriter, LogWriter};
+
+static ARCHIVE: Once<Archive<'static>> = Once::new();
+
+/// Find the initrd and check it is a CPIO archive
+/// Returns false if there is none (or it is in some other format)
+///
+/// # Safety
+/// `dtb_ptr` must be the DTB pointer the boot code was handed (or 0).
+pub unsafe fn init(dtb_ptr: u64) -> bool {
+    let Some((start, end)) = dtb::find_initrd(dtb_ptr) else {
+        return false;
+    };
+    let data = core::slice::from_raw_parts(start as *const u8, (end - start) as usize);
+    match Archive::new(data) {
+        Some(archive) => {
+            let _ = writeln!(LogWriter(LogLevel::Info), "Initrd: {:#018X} - {:#018X}", start, end);
+            let _ = ARCHIVE.set(archive);
+            true
+        }
+        None => {
+            let _ = writeln!(
+                LogWriter(LogLevel::Warn),
+                "Initrd: {:#018X} - {:#018X} (not a CPIO newc archive, ignored)",
+                start, end
+            );
+            false
+        }
+    }
+}
+
+/// The archive, if the VMM supplied one
+pub fn archive() -> Option<Archive<'static>> {
+    ARCHIVE.get().copied()
+}
+
+/// Contents of the regular file at `path` ("/etc/hostname", "etc/hostname")
+pub fn file(path: &str) -> Option<&'static [u8]> {
+    archive()?.file(path)
+}
+
+#[kernel_test]
+fn initrd() -> Outcome {
+    let Some(archive) = archive() else {
+        return Outcome::Skip("no initrd");
+    };
+    let (mut files, mut bytes) = (0, 0);
+    for entry in archive.entries().filter(|e| e.is_file()) {
+        // A lookup by path must find this entry (or a later one replacing it)
+        if file(entry.path).is_none() {
+            return Outcome::Fail("file listed in the archive not found by path");
+        }
+        files += 1;
+        bytes += entry.data.len();
+    }
+    let _ = writeln!(ConsoleWriter, "# files={} bytes={}", files, bytes);
+    Outcome::Pass
+}
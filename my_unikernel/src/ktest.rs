@@ -0,0 +1,117 @@
+//! In-kernel test framework
+//!
+//! Functions marked `#[kernel_test]` are collected by the linker into the
+//! `.kernel_tests` section (see kernel_macros and linker.ld). run() executes
+//! the ones selected with `tests=` and reports them on the console as TAP,
+//! alongside the `TEST:<NAME>=PASS|FAIL|SKIP` lines that vz_test.swift
+//! scrapes. Tests print their own details as `# ` diagnostic lines.
+
+use core::fmt::Write;
+
+use crate::{cmdline, power, puts, ConsoleWriter};
+
+/// Result of one test
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Pass,
+    Fail(&'static str),
+    /// Could not run, e.g. the device is not attached to this VM
+    Skip(&'static str),
+}
+
+impl Outcome {
+    /// Pass if `ok`, otherwise fail with `reason`
+    pub fn check(ok: bool, reason: &'static str) -> Self {
+        if ok {
+            Outcome::Pass
+        } else {
+            Outcome::Fail(reason)
+        }
+    }
+}
+
+/// Descriptor emitted by `#[kernel_test]`
+#[repr(C)]
+pub struct KernelTest {
+    pub name: &'static str,
+    pub func: fn() -> Outcome,
+}
+
+/// Totals from run()
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+impl Summary {
+    /// Exit status for power::shutdown()
+    pub fn exit_code(&self) -> u32 {
+        if self.failed == 0 {
+            power::EXIT_SUCCESS
+        } else {
+            power::EXIT_FAILURE
+        }
+    }
+}
+
+extern "C" {
+    static __kernel_tests_start: KernelTest;
+    static __kernel_tests_end: KernelTest;
+}
+
+/// Every registered test, in link order
+pub fn tests() -> &'static [KernelTest] {
+    unsafe {
+        let start = &raw const __kernel_tests_start;
+        let end = &raw const __kernel_tests_end;
+        let count = (end as usize - start as usize) / core::mem::size_of::<KernelTest>();
+        core::slice::from_raw_parts(start, count)
+    }
+}
+
+/// Run the tests enabled on the command line and print the report
+pub fn run() -> Summary {
+    let tests = tests();
+    let mut summary = Summary::default();
+
+    puts("TAP version 13\n");
+    let _ = writeln!(ConsoleWriter, "1..{}", tests.len());
+
+    for (i, test) in tests.iter().enumerate() {
+        let outcome = if cmdline::test_enabled(test.name) {
+            let _ = writeln!(ConsoleWriter, "# {}", test.name);
+            (test.func)()
+        } else {
+            Outcome::Skip("disabled on command line")
+        };
+
+        puts("TEST:");
+        for c in test.name.bytes() {
+            crate::putc(c.to_ascii_uppercase());
+        }
+        let n = i + 1;
+        let _ = match outcome {
+            Outcome::Pass => {
+                summary.passed += 1;
+                writeln!(ConsoleWriter, "=PASS\nok {} - {}", n, test.name)
+            }
+            Outcome::Fail(reason) => {
+                summary.failed += 1;
+                writeln!(ConsoleWriter, "=FAIL\nnot ok {} - {} # {}", n, test.name, reason)
+            }
+            Outcome::Skip(reason) => {
+                summary.skipped += 1;
+                writeln!(ConsoleWriter, "=SKIP\nok {} - {} # SKIP {}", n, test.name, reason)
+            }
+        };
+    }
+
+    let _ = writeln!(
+        ConsoleWriter,
+        "# passed {}, failed {}, skipped {}",
+        summary.passed, summary.failed, summary.skipped
+    );
+    summary
+}
@@ -1,13 +1,31 @@
 #![no_std]
 #![no_main]
 
-use core::panic::PanicInfo;
+use core::fmt::Write;
 use core::ptr::{write_volatile, read_volatile};
 use core::arch::global_asm;
-
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use cmdline::LogLevel;
+use sync::{Once, SpinLock};
+
+mod arch;
+mod sync;
+mod executor;
+mod gic;
+mod timer;
+mod thread;
+mod ktest;
 mod virtio;
 mod pci;
 mod dtb;
+mod memory;
+mod mmu;
+mod process;
+mod syscall;
+mod initrd;
+mod vfs;
+mod cmdline;
 mod virtio_pci;
 mod virtio_gpu;
 mod virtio_gpu_mmio;
@@ -16,18 +34,34 @@ mod virtio_entropy;
 mod virtio_block;
 mod virtio_net;
 mod virtio_balloon;
+mod power;
+mod panic;
+mod symbols;
+mod exceptions;
+mod gdb;
 
 global_asm!(include_str!("asm/entry.s"));
 
 // PL011 UART base (standard QEMU/HVF address)
-pub static mut UART_BASE: usize = 0x0900_0000;
+pub static UART_BASE: AtomicUsize = AtomicUsize::new(0x0900_0000);
+
+// PL011 flag register and its receive-FIFO-empty bit
+const UART_FR: usize = 0x18;
+const UART_FR_RXFE: u32 = 1 << 4;
+
+// Scheduler time slice: 10 ms
+const SCHED_HZ: u64 = 100;
 
 // Global state for console drivers
-static mut USE_VIRTIO_PCI: bool = false;
-static mut VIRTIO_PCI_CONSOLE: Option<virtio_pci::VirtioPciConsole> = None;
+static VIRTIO_PCI_CONSOLE: Once<virtio_pci::VirtioPciConsole> = Once::new();
+
+// Set once the kernel is going down: the code that panicked or faulted may
+// hold a virtio console's lock, so those are skipped when locked (the PL011
+// has no lock)
+static CRASHING: AtomicBool = AtomicBool::new(false);
 
 // PCI device storage for Linux-like boot sequence
-static mut DEVICES: [Option<pci::PciDevice>; 32] = [None; 32];
+static DEVICES: SpinLock<[Option<pci::PciDevice>; 32]> = SpinLock::new([None; 32]);
 
 /// Get BAR0 address for a VirtIO device (reads from PCI config space)
 /// Used by legacy driver find() methods
@@ -46,43 +80,96 @@ pub fn get_virtio_bar0(config_base: u64, _device_id: u16) -> u64 {
     }
 }
 
+/// First VirtIO device with this PCI device id that has a modern transport
+/// Used by the driver self-tests, after the boot phases have set up the BARs
+pub fn find_virtio_device(device_id: u16) -> Option<(pci::PciDevice, pci::VirtioModern)> {
+    let devices = *DEVICES.lock();
+    devices
+        .iter()
+        .flatten()
+        .filter(|dev| dev.device_id == device_id && dev.bars[0] != 0)
+        .find_map(|dev| pci::VirtioModern::probe(&pci::mmio(), dev).map(|modern| (*dev, modern)))
+}
+
 fn uart_putc(c: u8) {
     unsafe {
-        write_volatile(UART_BASE as *mut u32, c as u32);
+        write_volatile(UART_BASE.load(Ordering::Relaxed) as *mut u32, c as u32);
     }
 }
 
 fn putc(c: u8) {
-    uart_putc(c);
-    unsafe {
-        if USE_VIRTIO_PCI {
-            if let Some(ref console) = VIRTIO_PCI_CONSOLE {
-                console.putc(c);
-            }
+    if cmdline::console_enabled(cmdline::Console::Uart) {
+        uart_putc(c);
+    }
+    virtio_write(&[c]);
+}
+
+/// Stop waiting for console locks: the panic handler and fatal exception
+/// reports call this before printing
+pub fn console_crashing() {
+    CRASHING.store(true, Ordering::SeqCst);
+}
+
+/// Write to the virtio consoles, if enabled
+/// While crashing, a locked console is skipped, and if that leaves the
+/// output nowhere the PL011 gets it even when it is not enabled.
+fn virtio_write(bytes: &[u8]) {
+    if !cmdline::console_enabled(cmdline::Console::Virtio) {
+        return;
+    }
+    if !CRASHING.load(Ordering::SeqCst) {
+        if let Some(console) = VIRTIO_PCI_CONSOLE.get() {
+            console.write(bytes);
+        }
+        if virtio_console::console_available() {
+            virtio_console::write_bytes(bytes);
         }
+        return;
+    }
+    let mut written = true;
+    if let Some(console) = VIRTIO_PCI_CONSOLE.get() {
+        written &= console.try_write(bytes);
     }
     if virtio_console::console_available() {
-        virtio_console::putc(c);
+        written &= virtio_console::try_write_bytes(bytes);
+    }
+    if !written && !cmdline::console_enabled(cmdline::Console::Uart) {
+        bytes.iter().for_each(|&b| uart_putc(b));
     }
 }
 
 fn puts(s: &str) {
-    for b in s.bytes() {
-        if b == b'\n' {
-            uart_putc(b'\r');
+    console_write(s.as_bytes());
+}
+
+/// Write raw bytes (not necessarily UTF-8) to every enabled console
+pub fn console_write(bytes: &[u8]) {
+    if cmdline::console_enabled(cmdline::Console::Uart) {
+        for &b in bytes {
+            if b == b'\n' {
+                uart_putc(b'\r');
+            }
+            uart_putc(b);
         }
-        uart_putc(b);
     }
-    unsafe {
-        if USE_VIRTIO_PCI {
-            if let Some(ref console) = VIRTIO_PCI_CONSOLE {
-                console.puts(s);
-            }
+    virtio_write(bytes);
+}
+
+/// Input waiting on the enabled consoles (non-blocking, 0 if none)
+/// A console the GDB stub is using is left to it.
+pub fn console_read(out: &mut [u8]) -> usize {
+    let mut n = 0;
+    if cmdline::console_enabled(cmdline::Console::Uart) && !gdb::enabled() {
+        let base = UART_BASE.load(Ordering::Relaxed);
+        while n < out.len() && unsafe { read_volatile((base + UART_FR) as *const u32) } & UART_FR_RXFE == 0 {
+            out[n] = unsafe { read_volatile(base as *const u32) } as u8;
+            n += 1;
         }
     }
-    if virtio_console::console_available() {
-        virtio_console::puts(s);
+    if n == 0 && cmdline::console_enabled(cmdline::Console::Virtio) && !gdb::enabled() {
+        n = virtio_console::read_bytes(out);
     }
+    n
 }
 
 fn print_hex(n: u64) {
@@ -93,18 +180,34 @@ fn print_hex(n: u64) {
     for i in 0..16 {
         buf[2 + i] = hex[((n >> ((15 - i) * 4)) & 0xF) as usize];
     }
-    for &b in &buf {
-        uart_putc(b);
-    }
-    unsafe {
-        if USE_VIRTIO_PCI {
-            if let Some(ref console) = VIRTIO_PCI_CONSOLE {
-                console.write(&buf);
-            }
+    if cmdline::console_enabled(cmdline::Console::Uart) {
+        for &b in &buf {
+            uart_putc(b);
         }
     }
-    if virtio_console::console_available() {
-        virtio_console::write_bytes(&buf);
+    virtio_write(&buf);
+}
+
+/// `core::fmt::Write` adapter over the console multiplexer
+pub struct ConsoleWriter;
+
+impl core::fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        puts(s);
+        Ok(())
+    }
+}
+
+/// ConsoleWriter for boot and driver messages: drops them when `level` is
+/// above the command line's `loglevel=`
+pub struct LogWriter(pub LogLevel);
+
+impl core::fmt::Write for LogWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if cmdline::log_enabled(self.0) {
+            puts(s);
+        }
+        Ok(())
     }
 }
 
@@ -128,7 +231,15 @@ fn draw_demo(gpu: &mut virtio_gpu_mmio::VirtioGpuMmio) {
 
 #[no_mangle]
 pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
-    let ecam: u64 = 0x40000000;
+    // VZ puts ECAM at 0x40000000; QEMU virt has RAM there and ECAM high up
+    let ecam = unsafe { dtb::find_ecam_base(dtb_ptr) }.unwrap_or(0x4000_0000);
+
+    // Command line first: it selects which consoles the output goes to
+    unsafe { cmdline::init(dtb_ptr); }
+    // Then the DMA areas, which the console drivers already need
+    unsafe { memory::init(dtb_ptr); }
+    // Caches on from here; the page tables come out of the memory just laid out
+    let mmu_on = unsafe { mmu::init() };
 
     // =========================================================================
     // PHASE 0: Bring up console first (patience scanner)
@@ -149,17 +260,14 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
             found
         };
 
-        if console_exists && unsafe { !USE_VIRTIO_PCI } {
+        if console_exists && !VIRTIO_PCI_CONSOLE.is_completed() {
             if let Some(console) = virtio_pci::find_virtio_pci_console(ecam) {
-                unsafe {
-                    VIRTIO_PCI_CONSOLE = Some(console);
-                    USE_VIRTIO_PCI = true;
-                }
+                let _ = VIRTIO_PCI_CONSOLE.set(console);
                 break;
             }
         }
 
-        if console_exists && unsafe { !USE_VIRTIO_PCI } && !virtio_console::console_available() {
+        if console_exists && !VIRTIO_PCI_CONSOLE.is_completed() && !virtio_console::console_available() {
             virtio_console::console_init();
             if virtio_console::console_available() {
                 break;
@@ -176,59 +284,83 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
     // =========================================================================
     puts("\n=== AArch64 VirtIO Unikernel ===\n");
 
-    puts("DTB: ");
-    print_hex(dtb_ptr);
-    puts("\n");
+    let _ = writeln!(LogWriter(LogLevel::Info), "DTB: {:#018X}", dtb_ptr);
+
+    if !cmdline::raw().is_empty() {
+        let _ = writeln!(LogWriter(LogLevel::Info), "Cmdline: {}", cmdline::raw());
+    }
+
+    if gdb::init() {
+        puts("GDB: waiting for debugger\n");
+        gdb::breakpoint();
+    }
 
     // =========================================================================
     // PHASE 1: Parse DTB for valid MMIO window
     // =========================================================================
-    puts("\n--- Phase 1: Parse DTB ---\n");
+    let mut log = LogWriter(LogLevel::Info);
+    let _ = writeln!(log, "\n--- Phase 1: Parse DTB ---");
+    let (ram_base, ram_size) = memory::ram();
+    let _ = writeln!(log, "RAM: {:#018X} - {:#018X}", ram_base, ram_base + ram_size);
+    let _ = writeln!(log, "Kernel: {:#018X} - {:#018X}", memory::kernel_base(), memory::kernel_end());
+    if mmu_on {
+        let _ = writeln!(log, "MMU: on (identity map)");
+    } else {
+        let _ = writeln!(LogWriter(LogLevel::Warn), "MMU: off (no memory for page tables)");
+    }
+    unsafe { initrd::init(dtb_ptr); }
+    vfs::init();
     let (mmio_base, mmio_size) = unsafe {
         if let Some(window) = dtb::find_pci_mmio_window(dtb_ptr) {
-            puts("MMIO Window: ");
-            print_hex(window.0);
-            puts(" - ");
-            print_hex(window.0 + window.1);
-            puts("\n");
+            let _ = writeln!(log, "MMIO Window: {:#018X} - {:#018X}", window.0, window.0 + window.1);
             window
         } else {
-            puts("DTB parse failed, using fallback\n");
+            let _ = writeln!(LogWriter(LogLevel::Warn), "DTB parse failed, using fallback");
             (0x5000_0000, 0x2000_0000)
         }
     };
 
-    unsafe { pci::init_allocator(mmio_base, mmio_size); }
+    pci::init_allocator(mmio_base, mmio_size);
+
+    // Device memory the kernel uses while a program runs; programs are
+    // never mapped over it (see mmu.rs)
+    mmu::reserve_io(UART_BASE.load(Ordering::Relaxed) as u64, 0x1000);
+    mmu::reserve_io(ecam, 0x1000_0000);
+    mmu::reserve_io(mmio_base, mmio_size);
+    if let Some(gic) = unsafe { dtb::find_gic(dtb_ptr) } {
+        mmu::reserve_io(gic.dist_base, 0x1_0000);
+        mmu::reserve_io(gic.cpu_base, 0x20_0000);
+    }
 
     // =========================================================================
     // PHASE 2: Scan bus and reserve VZ's pre-programmed addresses
     // =========================================================================
-    puts("\n--- Phase 2: Scan & Reserve ---\n");
-    unsafe {
+    let _ = writeln!(log, "\n--- Phase 2: Scan & Reserve ---");
+    let mmio = pci::mmio();
+    {
+        let mut devices = DEVICES.lock();
         for slot in 0u8..32 {
-            if let Some(mut dev) = pci::PciDevice::new(ecam, 0, slot, 0) {
+            if let Some(mut dev) = pci::PciDevice::new(&mmio, ecam, 0, slot, 0) {
                 if dev.vendor_id == pci::VIRTIO_VENDOR_ID {
                     // Read existing BAR values
-                    dev.read_bars();
+                    dev.read_bars(&mmio);
 
                     // Reserve any valid addresses
                     for i in 0..6 {
                         let addr = dev.bars[i];
-                        let (size, _is_64) = dev.get_bar_size(i);
+                        let (size, _is_64) = dev.get_bar_size(&mmio, i);
 
                         if addr >= mmio_base && addr < (mmio_base + mmio_size) && size > 0 {
-                            puts("Slot ");
-                            print_hex(slot as u64);
-                            puts(" Reserved BAR");
-                            print_hex(i as u64);
-                            puts(": ");
-                            print_hex(addr);
-                            puts("\n");
+                            let _ = writeln!(
+                                LogWriter(LogLevel::Debug),
+                                "Slot {:#018X} Reserved BAR{:#018X}: {:#018X}",
+                                slot, i, addr
+                            );
                             pci::reserve_range(addr, size);
                         }
                     }
 
-                    DEVICES[slot as usize] = Some(dev);
+                    devices[slot as usize] = Some(dev);
                 }
             }
         }
@@ -237,44 +369,33 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
     // =========================================================================
     // PHASE 3: Allocate missing BARs
     // =========================================================================
-    puts("\n--- Phase 3: Allocate Missing ---\n");
-    unsafe {
+    let _ = writeln!(log, "\n--- Phase 3: Allocate Missing ---");
+    {
+        let mut devices = DEVICES.lock();
         for slot in 0u8..32 {
-            if let Some(ref mut dev) = DEVICES[slot as usize] {
+            if let Some(ref mut dev) = devices[slot as usize] {
                 // Skip GPU (0x1050, 0x1040) - let GPU driver handle its own BAR programming
                 // The GPU driver knows the specific address VZ accepts (0x50008000)
                 if dev.device_id == 0x1050 || dev.device_id == 0x1040 {
-                    puts("Slot ");
-                    print_hex(slot as u64);
-                    puts(" (GPU) - skipped, driver handles BAR\n");
+                    let _ = writeln!(LogWriter(LogLevel::Debug), "Slot {:#018X} (GPU) - skipped, driver handles BAR", slot);
                     continue;
                 }
 
                 let mut i = 0usize;
                 while i < 6 {
-                    let (size, is_64) = dev.get_bar_size(i);
+                    let (size, is_64) = dev.get_bar_size(&mmio, i);
 
                     // If BAR has size but no address, allocate
                     if size > 0 && dev.bars[i] == 0 {
                         if let Some(addr) = pci::allocate(size) {
-                            puts("Slot ");
-                            print_hex(slot as u64);
-                            puts(" (");
-                            print_hex(dev.device_id as u64);
-                            puts(") Alloc BAR");
-                            print_hex(i as u64);
-                            puts(" -> ");
-                            print_hex(addr);
-
-                            if dev.program_bar(i, addr) {
-                                puts(" [OK]\n");
-                            } else {
-                                puts(" [FAIL]\n");
-                            }
+                            let ok = dev.program_bar(&mmio, i, addr);
+                            let _ = writeln!(
+                                LogWriter(if ok { LogLevel::Debug } else { LogLevel::Warn }),
+                                "Slot {:#018X} ({:#018X}) Alloc BAR{:#018X} -> {:#018X} [{}]",
+                                slot, dev.device_id, i, addr, if ok { "OK" } else { "FAIL" }
+                            );
                         } else {
-                            puts("Alloc failed for slot ");
-                            print_hex(slot as u64);
-                            puts("\n");
+                            let _ = writeln!(LogWriter(LogLevel::Warn), "Alloc failed for slot {:#018X}", slot);
                         }
                     }
 
@@ -287,20 +408,14 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
     // =========================================================================
     // PHASE 4: Show final state (simplified to avoid probe hangs)
     // =========================================================================
-    puts("\n--- Phase 4: Final State ---\n");
-    unsafe {
-        let (base, head, limit) = pci::get_allocator_state();
-        puts("Allocator: ");
-        print_hex(base);
-        puts(" -> ");
-        print_hex(head);
-        puts("\n");
-    }
+    let _ = writeln!(log, "\n--- Phase 4: Final State ---");
+    let (base, head, _limit) = pci::get_allocator_state();
+    let _ = writeln!(log, "Allocator: {:#018X} -> {:#018X}", base, head);
 
     // =========================================================================
     // PHASE 5: Initialize GPU (patience scanner)
     // =========================================================================
-    puts("\n--- Phase 5: GPU Init ---\n");
+    let _ = writeln!(log, "\n--- Phase 5: GPU Init ---");
     let mut gpu_initialized = false;
 
     for attempt in 1u32..=50 {
@@ -321,18 +436,16 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
 
         if let Some(slot) = found_slot {
             if let Some(mut gpu) = virtio_gpu::VirtioGpu::try_new(ecam, 0, slot) {
-                puts("GPU found at slot ");
-                print_hex(slot as u64);
-                puts(" (attempt ");
-                print_hex(attempt as u64);
-                puts(")\n");
+                let _ = writeln!(log, "GPU found at slot {:#018X} (attempt {:#018X})", slot, attempt);
+
+                if let Some((w, h)) = cmdline::gpu_resolution() {
+                    if !gpu.set_resolution(w, h) {
+                        let _ = writeln!(LogWriter(LogLevel::Warn), "Requested resolution too large, using default");
+                    }
+                }
 
                 if gpu.init_display() {
-                    puts("Display: ");
-                    print_hex(gpu.width() as u64);
-                    puts("x");
-                    print_hex(gpu.height() as u64);
-                    puts("\n");
+                    let _ = writeln!(log, "Display: {:#018X}x{:#018X}", gpu.width(), gpu.height());
 
                     // Draw colorful pattern
                     gpu.fill(0xFFFFFFFF);
@@ -350,20 +463,22 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
                         gpu.draw_rect(x + 200, 95, 5, 210, 0xFFFFFFFF);
                     }
                     gpu.flush();
-                    puts("Graphics rendered!\n");
+                    let _ = writeln!(log, "Graphics rendered!");
 
                     // Output test data
-                    let samples = gpu.sample_test_pixels();
-                    puts("TEST:PIXELS=");
-                    for (i, &p) in samples.iter().enumerate() {
-                        if i > 0 { puts(","); }
-                        print_hex(p as u64);
-                    }
-                    puts("\n");
+                    if cmdline::test_enabled("graphics") {
+                        let samples = gpu.sample_test_pixels();
+                        puts("TEST:PIXELS=");
+                        for (i, &p) in samples.iter().enumerate() {
+                            if i > 0 { puts(","); }
+                            print_hex(p as u64);
+                        }
+                        puts("\n");
 
-                    let all_black = samples.iter().all(|&p| p == 0);
-                    puts("TEST:GRAPHICS=");
-                    puts(if all_black { "FAIL\n" } else { "PASS\n" });
+                        let all_black = samples.iter().all(|&p| p == 0);
+                        puts("TEST:GRAPHICS=");
+                        puts(if all_black { "FAIL\n" } else { "PASS\n" });
+                    }
 
                     gpu_initialized = true;
                 }
@@ -383,141 +498,63 @@ pub extern "C" fn kmain(dtb_ptr: u64) -> ! {
     // Try MMIO GPU for HVF as fallback
     if !gpu_initialized {
         if let Some(mut gpu) = virtio_gpu_mmio::VirtioGpuMmio::try_new() {
-            puts("Found MMIO GPU\n");
+            let _ = writeln!(log, "Found MMIO GPU");
+            if let Some((w, h)) = cmdline::gpu_resolution() {
+                if !gpu.set_resolution(w, h) {
+                    let _ = writeln!(LogWriter(LogLevel::Warn), "Requested resolution too large, using default");
+                }
+            }
             if gpu.init_display() {
                 draw_demo(&mut gpu);
                 gpu.flush();
-                puts("MMIO Graphics rendered!\n");
+                let _ = writeln!(log, "MMIO Graphics rendered!");
                 gpu_initialized = true;
             }
         }
     }
 
     if !gpu_initialized {
-        puts("No GPU found\n");
+        let _ = writeln!(log, "No GPU found");
     }
 
     // =========================================================================
-    // PHASE 6: Test VirtIO Drivers
+    // Threads: scheduler, plus the timer tick if there is a GIC
     // =========================================================================
-    puts("\n--- Phase 6: Driver Tests ---\n");
-
-    // Test Entropy
-    puts("Testing Entropy...\n");
-    unsafe {
-        for slot in 0u8..32 {
-            if let Some(ref dev) = DEVICES[slot as usize] {
-                if dev.device_id == 0x1044 && dev.bars[0] != 0 {
-                    if let Some(modern) = pci::VirtioModern::probe(dev) {
-                        if let Some(entropy) = virtio_entropy::VirtioEntropy::from_modern(&modern, dev.ecam_addr) {
-                            let stats = entropy.test_entropy();
-                            puts("TEST:ENTROPY=");
-                            puts(if stats.looks_random { "PASS" } else { "FAIL" });
-                            puts(" (bytes=");
-                            print_hex(stats.bytes_read as u64);
-                            puts(", unique=");
-                            print_hex(stats.unique_bytes as u64);
-                            puts(")\n");
-                            break;
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    // Test Block
-    puts("Testing Block...\n");
-    unsafe {
-        for slot in 0u8..32 {
-            if let Some(ref dev) = DEVICES[slot as usize] {
-                if dev.device_id == 0x1042 && dev.bars[0] != 0 {
-                    if let Some(modern) = pci::VirtioModern::probe(dev) {
-                        if let Some(block) = virtio_block::VirtioBlock::from_modern(&modern, dev.ecam_addr) {
-                            puts("Capacity: ");
-                            print_hex(block.capacity());
-                            puts(" sectors\n");
-                            let result = block.test_read_write();
-                            puts("TEST:BLOCK=");
-                            puts(if result.test_passed { "PASS" } else { "FAIL" });
-                            puts(" (w=");
-                            puts(if result.write_ok { "ok" } else { "fail" });
-                            puts(",r=");
-                            puts(if result.read_ok { "ok" } else { "fail" });
-                            puts(",match=");
-                            print_hex(result.data_matches as u64);
-                            puts(")\n");
-                            break;
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    // Test Network
-    puts("Testing Network...\n");
-    unsafe {
-        for slot in 0u8..32 {
-            if let Some(ref dev) = DEVICES[slot as usize] {
-                if dev.device_id == 0x1041 && dev.bars[0] != 0 {
-                    if let Some(modern) = pci::VirtioModern::probe(dev) {
-                        if let Some(net) = virtio_net::VirtioNet::from_modern(&modern, dev.ecam_addr) {
-                            let mac = net.mac();
-                            puts("MAC: ");
-                            for (i, &b) in mac.iter().enumerate() {
-                                if i > 0 { puts(":"); }
-                                print_hex(b as u64);
-                            }
-                            puts("\n");
-                            let result = net.test_network();
-                            puts("TEST:NET=");
-                            puts(if result.init_ok && result.send_ok { "PASS" } else { "FAIL" });
-                            puts(" (send=");
-                            puts(if result.send_ok { "ok" } else { "fail" });
-                            puts(")\n");
-                            break;
-                        }
-                    }
-                }
-            }
-        }
+    let _ = writeln!(log, "\n--- Threads ---");
+    thread::init();
+    if unsafe { gic::init(dtb_ptr) } {
+        timer::start_tick(SCHED_HZ);
+        arch::irq_enable();
+        let _ = writeln!(log, "GICv{} found, preemptive scheduling on", gic::version());
+    } else {
+        let _ = writeln!(log, "No GIC found, threads are cooperative only");
     }
 
-    // Test Balloon
-    puts("Testing Balloon...\n");
-    unsafe {
-        for slot in 0u8..32 {
-            if let Some(ref dev) = DEVICES[slot as usize] {
-                if dev.device_id == 0x1045 && dev.bars[0] != 0 {
-                    if let Some(modern) = pci::VirtioModern::probe(dev) {
-                        if let Some(mut balloon) = virtio_balloon::VirtioBalloon::from_modern(&modern, dev.ecam_addr) {
-                            let result = balloon.test_balloon();
-                            puts("TEST:BALLOON=");
-                            puts(if result.init_ok { "PASS" } else { "FAIL" });
-                            puts(" (inflate=");
-                            puts(if result.inflate_ok { "ok" } else { "fail" });
-                            puts(",deflate=");
-                            puts(if result.deflate_ok { "ok" } else { "fail" });
-                            puts(")\n");
-                            break;
-                        }
-                    }
-                }
-            }
+    // =========================================================================
+    // PHASE 6: Test VirtIO Drivers
+    // =========================================================================
+    let _ = writeln!(log, "\n--- Phase 6: Driver Tests ---");
+    let summary = ktest::run();
+
+    // init=: a program from the initrd gets the machine next
+    if let Some(path) = cmdline::init_program() {
+        let _ = writeln!(ConsoleWriter, "\n--- Running {} ---", path);
+        match process::exec_file(path) {
+            Ok(exit) => { let _ = writeln!(ConsoleWriter, "{}: {}", path, exit); }
+            Err(err) => { let _ = writeln!(ConsoleWriter, "{}: not started: {}", path, err); }
         }
     }
 
     puts("\n=== All Tests Complete ===\n");
     puts("Halting.\n");
 
+    // An explicit tests= means a test run: report the result to the VMM
+    if cmdline::get("tests").is_some() {
+        power::shutdown(summary.exit_code());
+    }
+
     loop {
         unsafe { core::arch::asm!("wfi"); }
     }
 }
 
-#[panic_handler]
-fn panic(_: &PanicInfo) -> ! {
-    puts("PANIC!\n");
-    loop { unsafe { core::arch::asm!("wfi"); } }
-}
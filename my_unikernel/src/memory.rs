@@ -0,0 +1,178 @@
+//! Physical memory layout, worked out at boot
+//!
+//! The kernel is position-independent (entry.s applies its own relocations),
+//! so nothing here assumes where it was loaded. RAM comes from the DTB's
+//! /memory node, and the DMA areas the drivers used to find at fixed
+//! addresses are carved out of RAM just past `_end`, stepping over the DTB
+//! and initrd the loader put there. The rest of RAM is handed out a page at
+//! a time by alloc_page(), for page tables and programs' memory.
+
+use crate::dtb;
+use crate::sync::{Once, SpinLock};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Assumed RAM size past the kernel when the DTB has no /memory node
+const FALLBACK_RAM_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Fixed-size DMA areas handed out by `region()`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    /// virtio-gpu control queue rings, command and response buffers
+    GpuQueue,
+    /// virtio-gpu framebuffer, 1280x720 at 4 bytes per pixel
+    Framebuffer,
+    /// virtio-console (MMIO/legacy driver) rings and buffers
+    Console,
+    /// Page the balloon self-test inflates and deflates
+    BalloonPage,
+}
+
+const REGIONS: [Region; 4] = [Region::GpuQueue, Region::Framebuffer, Region::Console, Region::BalloonPage];
+
+impl Region {
+    const fn size(self) -> u64 {
+        match self {
+            Region::GpuQueue => 0x5000,
+            Region::Framebuffer => 1280 * 720 * 4,
+            Region::Console => 0x9000,
+            Region::BalloonPage => PAGE_SIZE,
+        }
+    }
+}
+
+struct Layout {
+    ram_base: u64,
+    ram_size: u64,
+    regions: [u64; REGIONS.len()],
+}
+
+static LAYOUT: Once<Layout> = Once::new();
+
+/// The page pool: a bump pointer through the RAM nobody has used yet, plus
+/// the pages given back, linked through their first word
+struct Pages {
+    next: u64,
+    end: u64,
+    /// DTB and initrd, which the bump pointer steps over
+    reserved: [(u64, u64); 2],
+    /// Most recently freed page (0 if none)
+    free: u64,
+    in_use: usize,
+}
+
+static PAGES: SpinLock<Pages> = SpinLock::new(Pages { next: 0, end: 0, reserved: [(0, 0); 2], free: 0, in_use: 0 });
+
+extern "C" {
+    static _start: u8;
+    static _end: u8;
+}
+
+/// Runtime address of the first byte of the kernel
+pub fn kernel_base() -> u64 {
+    &raw const _start as u64
+}
+
+/// Runtime address just past the kernel's BSS
+pub fn kernel_end() -> u64 {
+    &raw const _end as u64
+}
+
+const fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Work out the layout; call once, before any driver touches a region
+///
+/// # Safety
+/// `dtb_ptr` must be the DTB pointer the boot code was handed (or 0).
+pub unsafe fn init(dtb_ptr: u64) {
+    let (ram_base, ram_size) = dtb::find_memory(dtb_ptr).unwrap_or((kernel_base(), FALLBACK_RAM_SIZE));
+
+    // What the loader placed in RAM alongside us
+    let mut reserved = [(0u64, 0u64); 2];
+    if let Some(size) = dtb::blob_size(dtb_ptr) {
+        reserved[0] = (dtb_ptr, dtb_ptr + size);
+    }
+    if let Some(initrd) = dtb::find_initrd(dtb_ptr) {
+        reserved[1] = initrd;
+    }
+
+    let mut next = align_up(kernel_end(), PAGE_SIZE);
+    let mut regions = [0; REGIONS.len()];
+    for (slot, region) in regions.iter_mut().zip(REGIONS) {
+        let mut addr = next;
+        while let Some(&(_, end)) = reserved.iter().find(|&&(start, end)| addr < end && start < addr + region.size()) {
+            addr = align_up(end, PAGE_SIZE);
+        }
+        if addr + region.size() > ram_base + ram_size {
+            panic!("no RAM left for {:?} past the kernel", region);
+        }
+        *slot = addr;
+        next = addr + region.size();
+    }
+
+    let _ = LAYOUT.set(Layout { ram_base, ram_size, regions });
+    *PAGES.lock() = Pages { next: align_up(next, PAGE_SIZE), end: ram_base + ram_size, reserved, free: 0, in_use: 0 };
+}
+
+fn layout() -> &'static Layout {
+    LAYOUT.get().expect("memory::init() has not run")
+}
+
+/// Physical address of a DMA area (page aligned, not zeroed)
+pub fn region(region: Region) -> u64 {
+    let index = REGIONS.iter().position(|&r| r == region).unwrap();
+    layout().regions[index]
+}
+
+/// (base, size) of RAM
+pub fn ram() -> (u64, u64) {
+    let layout = layout();
+    (layout.ram_base, layout.ram_size)
+}
+
+/// A zeroed page of RAM, or None when RAM is exhausted
+pub fn alloc_page() -> Option<u64> {
+    let page = {
+        let mut pages = PAGES.lock();
+        let page = if pages.free != 0 {
+            let page = pages.free;
+            pages.free = unsafe { (page as *const u64).read() };
+            page
+        } else {
+            let mut page = pages.next;
+            while let Some(&(_, end)) = pages.reserved.iter().find(|&&(start, end)| page < end && start < page + PAGE_SIZE) {
+                page = align_up(end, PAGE_SIZE);
+            }
+            if page + PAGE_SIZE > pages.end {
+                return None;
+            }
+            pages.next = page + PAGE_SIZE;
+            page
+        };
+        pages.in_use += 1;
+        page
+    };
+    unsafe { core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE as usize) };
+    Some(page)
+}
+
+/// Give back a page from alloc_page()
+pub fn free_page(page: u64) {
+    let mut pages = PAGES.lock();
+    unsafe { (page as *mut u64).write(pages.free) };
+    pages.free = page;
+    pages.in_use -= 1;
+}
+
+/// Pages currently allocated
+pub fn pages_in_use() -> usize {
+    PAGES.lock().in_use
+}
+
+/// End of the RAM the kernel has touched so far: the image, the DMA areas
+/// and every page the bump pointer has handed out
+pub fn used_end() -> u64 {
+    PAGES.lock().next
+}
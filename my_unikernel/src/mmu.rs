@@ -0,0 +1,310 @@
+//! MMU: an identity map for the kernel, plus address spaces for programs
+//!
+//! The kernel keeps running at physical addresses once the MMU is on: RAM
+//! is mapped as normal cacheable memory and everything else below 1 TiB as
+//! device memory, all of it EL1-only. A program's AddressSpace starts as a
+//! copy of that map (see driver_core::pagetable) with the program's pages
+//! laid over it, so the kernel works unchanged while a program's tables are
+//! live in TTBR0_EL1. Program pages therefore must not cover RAM or the
+//! device ranges the kernel uses; those are registered with reserve_io().
+
+use core::mem::ManuallyDrop;
+
+use driver_core::pagetable::{Attrs, Frames, PageTable, MAIR, PAGE_SIZE, VA_BITS};
+
+use crate::arch::{isb, read_sysreg, write_sysreg};
+use crate::memory;
+use crate::sync::{Once, SpinLock};
+
+/// Highest physical address identity mapped (QEMU's ECAM is at 257 GiB)
+const IDENTITY_LIMIT: u64 = 1 << 40;
+
+// TCR_EL1: 48-bit VA in TTBR0, 4 KiB granule, write-back inner-shareable
+// walks; TTBR1 walks disabled
+const TCR_T0SZ: u64 = 64 - VA_BITS as u64;
+const TCR_IRGN0_WBWA: u64 = 1 << 8;
+const TCR_ORGN0_WBWA: u64 = 1 << 10;
+const TCR_SH0_INNER: u64 = 3 << 12;
+const TCR_EPD1: u64 = 1 << 23;
+const TCR_IPS_SHIFT: u64 = 32;
+
+// SCTLR_EL1
+const SCTLR_M: u64 = 1 << 0;
+const SCTLR_C: u64 = 1 << 2;
+const SCTLR_I: u64 = 1 << 12;
+
+/// Page tables come from memory::alloc_page() and are reached through the
+/// identity map (or directly, before the MMU is on)
+pub struct KernelFrames;
+
+impl Frames for KernelFrames {
+    fn alloc(&mut self) -> Option<u64> {
+        memory::alloc_page()
+    }
+
+    fn free(&mut self, pa: u64) {
+        memory::free_page(pa)
+    }
+
+    fn table(&mut self, pa: u64) -> &mut [u64; 512] {
+        unsafe { &mut *(pa as *mut [u64; 512]) }
+    }
+}
+
+static KERNEL: Once<PageTable> = Once::new();
+
+// Device ranges the kernel uses while programs run
+const MAX_IO_RANGES: usize = 8;
+static IO_RANGES: SpinLock<([(u64, u64); MAX_IO_RANGES], usize)> = SpinLock::new(([(0, 0); MAX_IO_RANGES], 0));
+
+/// Clean and invalidate [start, end) to the point of coherency
+unsafe fn flush_dcache(start: u64, end: u64) {
+    let line = 4 << ((read_sysreg!("ctr_el0") >> 16) & 0xF);
+    let mut addr = start & !(line - 1);
+    while addr < end {
+        core::arch::asm!("dc civac, {}", in(reg) addr, options(nostack));
+        addr += line;
+    }
+    core::arch::asm!("dsb sy", options(nostack));
+}
+
+/// Make instructions just written to [pa, pa + len) visible to fetches
+pub fn sync_icache(pa: u64, len: u64) {
+    let line = 4 << (read_sysreg!("ctr_el0") & 0xF);
+    let mut addr = pa & !(line - 1);
+    while addr < pa + len {
+        unsafe { core::arch::asm!("dc cvau, {}", in(reg) addr, options(nostack)) };
+        addr += line;
+    }
+    unsafe { core::arch::asm!("dsb ish", "ic iallu", "dsb ish", "isb", options(nostack)) };
+}
+
+/// Build the identity map and turn on the MMU and caches
+/// Returns false if the tables could not be allocated (the MMU stays off)
+///
+/// # Safety
+/// Call once, after memory::init(), before anything relies on TTBR0.
+pub unsafe fn init() -> bool {
+    // Physical address size, for TCR_EL1.IPS and the end of the map
+    let parange = (read_sysreg!("id_aa64mmfr0_el1") & 0xF).min(5);
+    let pa_bits = [32, 36, 40, 42, 44, 48][parange as usize];
+    let limit = IDENTITY_LIMIT.min(1 << pa_bits);
+
+    let (ram_base, ram_size) = memory::ram();
+    let ram_end = ram_base + ram_size;
+    let frames = &mut KernelFrames;
+    let Some(mut table) = PageTable::new(frames) else {
+        return false;
+    };
+    if !(table.map(frames, 0, 0, ram_base, Attrs::DEVICE, false)
+        && table.map(frames, ram_base, ram_base, ram_size, Attrs::KERNEL, false)
+        && table.map(frames, ram_end, ram_end, limit.saturating_sub(ram_end), Attrs::DEVICE, false))
+    {
+        return false;
+    }
+
+    // Everything so far was written with the MMU (so the caches) off; make
+    // sure no stale line hides it once they are on
+    flush_dcache(memory::kernel_base(), memory::used_end());
+
+    write_sysreg!("mair_el1", MAIR);
+    write_sysreg!(
+        "tcr_el1",
+        TCR_T0SZ | TCR_IRGN0_WBWA | TCR_ORGN0_WBWA | TCR_SH0_INNER | TCR_EPD1 | (parange << TCR_IPS_SHIFT)
+    );
+    write_sysreg!("ttbr0_el1", table.root());
+    core::arch::asm!("dsb ish", "tlbi vmalle1", "dsb ish", options(nostack));
+    isb();
+    write_sysreg!("sctlr_el1", read_sysreg!("sctlr_el1") | SCTLR_M | SCTLR_C | SCTLR_I);
+    isb();
+
+    let _ = KERNEL.set(table);
+    true
+}
+
+/// True once init() has turned the MMU on
+pub fn enabled() -> bool {
+    KERNEL.is_completed()
+}
+
+/// TTBR0_EL1 value for the kernel's own map
+pub fn kernel_ttbr0() -> u64 {
+    KERNEL.get().map_or(0, PageTable::root)
+}
+
+/// Load `ttbr0` (0 for the kernel's map) and drop stale translations
+pub fn switch(ttbr0: u64) {
+    if !enabled() {
+        return;
+    }
+    let ttbr0 = if ttbr0 == 0 { kernel_ttbr0() } else { ttbr0 };
+    write_sysreg!("ttbr0_el1", ttbr0);
+    flush_tlb();
+}
+
+/// Drop every cached translation, after tables in use have changed
+fn flush_tlb() {
+    unsafe { core::arch::asm!("dsb ishst", "tlbi vmalle1", "dsb ish", "isb", options(nostack)) };
+}
+
+/// Note a device range the kernel touches, so no program is mapped over it
+pub fn reserve_io(base: u64, size: u64) {
+    let mut io = IO_RANGES.lock();
+    let (ranges, count) = &mut *io;
+    if *count < MAX_IO_RANGES {
+        ranges[*count] = (base, size);
+        *count += 1;
+    }
+}
+
+/// True if [va, va + size) may hold program memory: inside the user VA
+/// range and clear of RAM and the reserved device ranges
+pub fn user_range_ok(va: u64, size: u64) -> bool {
+    let Some(end) = va.checked_add(size) else {
+        return false;
+    };
+    let overlaps = |(base, len): (u64, u64)| va < base + len && base < end;
+    let (ram_base, ram_size) = memory::ram();
+    let io = IO_RANGES.lock();
+    end <= 1 << VA_BITS && !overlaps((ram_base, ram_size)) && !io.0[..io.1].iter().copied().any(overlaps)
+}
+
+/// A program's view of memory: the kernel's map plus its own pages
+pub struct AddressSpace {
+    table: ManuallyDrop<PageTable>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        let table = PageTable::share(&mut KernelFrames, KERNEL.get()?)?;
+        Some(AddressSpace { table: ManuallyDrop::new(table) })
+    }
+
+    /// Value for TTBR0_EL1
+    pub fn ttbr0(&self) -> u64 {
+        self.table.root()
+    }
+
+    /// Back [va, va + size) with fresh zeroed pages (page aligned)
+    ///
+    /// Pages already mapped here keep their contents and gain the new
+    /// permissions (segments may share a page). False if out of memory.
+    pub fn map_zeroed(&mut self, va: u64, size: u64, attrs: Attrs) -> bool {
+        let frames = &mut KernelFrames;
+        for page in (va..va + size).step_by(PAGE_SIZE as usize) {
+            let (pa, attrs) = match self.table.lookup(frames, page) {
+                Some(m) if m.owned => {
+                    let write = m.attrs.write || attrs.write;
+                    (m.pa, Attrs { write, exec: m.attrs.exec || attrs.exec, ..attrs })
+                }
+                _ => match memory::alloc_page() {
+                    Some(pa) => (pa, attrs),
+                    None => return false,
+                },
+            };
+            if !self.table.map(frames, page, pa, PAGE_SIZE, attrs, true) {
+                // The page is not in the table unless the mapping took
+                if self.translate(page) != Some(pa) {
+                    memory::free_page(pa);
+                }
+                return false;
+            }
+        }
+        // The range may have been a (shared) block of the kernel's map
+        flush_tlb();
+        true
+    }
+
+    /// Drop the pages in [va, va + size) (page aligned); false if out of memory
+    pub fn unmap(&mut self, va: u64, size: u64) -> bool {
+        let ok = self.table.unmap(&mut KernelFrames, va, size);
+        flush_tlb();
+        ok
+    }
+
+    /// New permissions for the program's pages in [va, va + size)
+    /// False if some page in the range is not mapped.
+    pub fn protect(&mut self, va: u64, size: u64, attrs: Attrs) -> bool {
+        let frames = &mut KernelFrames;
+        for page in (va..va + size).step_by(PAGE_SIZE as usize) {
+            match self.table.lookup(frames, page) {
+                Some(m) if m.owned => {
+                    if !self.table.map(frames, page, m.pa, PAGE_SIZE, attrs, true) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        flush_tlb();
+        true
+    }
+
+    /// Physical address of a user address the program may access this way
+    fn user_pa(&self, va: u64, write: bool) -> Option<u64> {
+        if va >= 1 << VA_BITS {
+            return None;
+        }
+        let m = self.table.lookup(&mut KernelFrames, va)?;
+        (m.owned && m.attrs.user && (m.attrs.write || !write)).then_some(m.pa)
+    }
+
+    /// Copy from program memory, as the program could read it
+    /// False if any of it is not readable by the program.
+    pub fn copy_in(&self, mut va: u64, mut out: &mut [u8]) -> bool {
+        while !out.is_empty() {
+            let Some(pa) = self.user_pa(va, false) else {
+                return false;
+            };
+            let n = out.len().min((PAGE_SIZE - va % PAGE_SIZE) as usize);
+            unsafe { core::ptr::copy_nonoverlapping(pa as *const u8, out.as_mut_ptr(), n) };
+            va += n as u64;
+            out = &mut out[n..];
+        }
+        true
+    }
+
+    /// Copy to program memory, as the program could write it
+    /// False if any of it is not writable by the program (some bytes may
+    /// have been copied).
+    pub fn copy_out(&self, mut va: u64, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            let Some(pa) = self.user_pa(va, true) else {
+                return false;
+            };
+            let n = data.len().min((PAGE_SIZE - va % PAGE_SIZE) as usize);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), pa as *mut u8, n) };
+            va += n as u64;
+            data = &data[n..];
+        }
+        true
+    }
+
+    /// Physical address behind a mapped user address
+    pub fn translate(&self, va: u64) -> Option<u64> {
+        self.table.lookup(&mut KernelFrames, va).filter(|m| m.owned).map(|m| m.pa)
+    }
+
+    /// Copy `data` to `va` through the physical pages, so read-only pages
+    /// can be filled too; false if part of the range is not mapped
+    pub fn write(&self, mut va: u64, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            let Some(pa) = self.translate(va) else {
+                return false;
+            };
+            let n = data.len().min((PAGE_SIZE - va % PAGE_SIZE) as usize);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), pa as *mut u8, n) };
+            va += n as u64;
+            data = &data[n..];
+        }
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Nothing can be using the tables any more; hand them back
+        let table = unsafe { ManuallyDrop::take(&mut self.table) };
+        table.free(&mut KernelFrames);
+    }
+}
@@ -0,0 +1,100 @@
+//! Panic handler with backtrace and register dump
+//!
+//! Prints the panic message and location, walks the frame-pointer chain
+//! (the kernel is built with `-C force-frame-pointers=yes`, see
+//! .cargo/config.toml), dumps the EL1 system registers and then powers the
+//! VM off with `power::EXIT_PANIC`.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::{self, read_sysreg};
+use crate::{power, puts, symbols, ConsoleWriter};
+
+/// Stop walking after this many frames (guards against corrupt chains)
+const MAX_FRAMES: usize = 32;
+
+/// Largest plausible distance between two frame records
+const MAX_FRAME_SIZE: u64 = 0x10_0000;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Walk the AArch64 frame record chain starting at `fp` and print each
+/// return address, symbolized when the embedded symbol table is populated
+///
+/// Each record is `[fp] = caller's fp, [fp + 8] = return address`; the chain
+/// ends at the zero fp set up in entry.s.
+pub fn print_backtrace(mut fp: u64) {
+    puts("Backtrace:\n");
+    for depth in 0..MAX_FRAMES {
+        if fp == 0 || fp & 0xF != 0 {
+            break;
+        }
+
+        let (next_fp, lr) = unsafe {
+            (read_volatile(fp as *const u64), read_volatile((fp + 8) as *const u64))
+        };
+        if lr == 0 {
+            break;
+        }
+
+        let _ = write!(ConsoleWriter, "  #{:<2} ", depth);
+        symbols::print_address(lr);
+        puts("\n");
+
+        // The stack grows down, so callers' records live at higher addresses
+        if next_fp <= fp || next_fp - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = next_fp;
+    }
+}
+
+/// Print the EL1 system registers relevant to a crash
+pub fn dump_registers() {
+    let lr: u64;
+    unsafe { core::arch::asm!("mov {}, x30", out(reg) lr, options(nomem, nostack)); }
+
+    let regs = [
+        ("CurrentEL", read_sysreg!("CurrentEL")),
+        ("SCTLR_EL1", read_sysreg!("sctlr_el1")),
+        ("ESR_EL1  ", read_sysreg!("esr_el1")),
+        ("FAR_EL1  ", read_sysreg!("far_el1")),
+        ("ELR_EL1  ", read_sysreg!("elr_el1")),
+        ("SPSR_EL1 ", read_sysreg!("spsr_el1")),
+        ("DAIF     ", read_sysreg!("daif")),
+        ("SP       ", arch::stack_pointer()),
+        ("FP       ", arch::frame_pointer()),
+        ("LR       ", lr),
+    ];
+
+    puts("Registers:\n");
+    for (name, value) in regs.iter() {
+        let _ = writeln!(ConsoleWriter, "  {} = {:#018x}", name, value);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Whatever panicked may hold a console lock
+    crate::console_crashing();
+    // A panic while printing the report: don't recurse, just get out
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        puts("\nPANIC while panicking\n");
+        power::shutdown(power::EXIT_PANIC);
+    }
+
+    puts("\n!!! PANIC: ");
+    let _ = write!(ConsoleWriter, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(ConsoleWriter, "\n    at {}:{}:{}", location.file(), location.line(), location.column());
+    }
+    puts("\n");
+
+    dump_registers();
+    print_backtrace(arch::frame_pointer());
+
+    power::shutdown(power::EXIT_PANIC);
+}
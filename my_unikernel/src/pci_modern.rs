@@ -2,7 +2,7 @@
 //! This implements Linux-like PCI resource allocation
 
 use core::ptr::{read_volatile, write_volatile};
-use core::sync::atomic::{fence, Ordering};
+use core::sync::atomic::{fence, AtomicU64, Ordering};
 
 const PCI_COMMAND: u64 = 0x04;
 const PCI_STATUS: u64 = 0x06;
@@ -14,7 +14,19 @@ const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
 // 1. Inside the PCI MMIO window (0x5000_0000 - 0x6FFF_FFFF)
 // 2. Above VZ's pre-mapped devices (Console=0x5000C000, GPU=0x50008000)
 // NOTE: 0x8000_0000 was WRONG - it's inside RAM (0x7000_0000-0xAFFF_FFFF)!
-static mut MMIO_ALLOC_PTR: u64 = 0x5100_0000;
+static MMIO_ALLOC_PTR: AtomicU64 = AtomicU64::new(0x5100_0000);
+
+/// Take `size` bytes, aligned to `size` (BARs are naturally aligned), from
+/// the pool; the bump is one compare-exchange, so concurrent probes never
+/// hand out the same window
+fn alloc_mmio(size: u64) -> u64 {
+    let align_mask = size.saturating_sub(1);
+    let align = |ptr: u64| (ptr + align_mask) & !align_mask;
+    let prev = MMIO_ALLOC_PTR
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ptr| Some(align(ptr) + size))
+        .unwrap_or_else(|ptr| ptr);
+    align(prev)
+}
 
 /// PCI device with probed and allocated BARs
 pub struct PciDevice {
@@ -106,11 +118,7 @@ impl PciDevice {
 
             if final_addr == 0 {
                 // BAR is unmapped - allocate from our pool
-                let align_mask = (size as u64).saturating_sub(1);
-                MMIO_ALLOC_PTR = (MMIO_ALLOC_PTR + align_mask) & !align_mask;
-
-                final_addr = MMIO_ALLOC_PTR;
-                MMIO_ALLOC_PTR += size as u64;
+                final_addr = alloc_mmio(size as u64);
 
                 // Write new address (disable decode first per PCI spec)
                 let cmd_save = read_volatile(cmd_ptr);
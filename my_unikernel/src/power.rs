@@ -0,0 +1,55 @@
+//! System power control via PSCI
+//!
+//! VZ, HVF and QEMU's virt machine all expose PSCI through the HVC conduit.
+//! SYSTEM_OFF carries no status, so the exit code is printed as an
+//! `EXIT:<code>` line first for test VMMs to scrape.
+
+const PSCI_SYSTEM_OFF: u64 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u64 = 0x8400_0009;
+
+/// Clean shutdown
+pub const EXIT_SUCCESS: u32 = 0;
+/// Generic failure (e.g. a self-test failed)
+pub const EXIT_FAILURE: u32 = 1;
+/// Kernel panic
+pub const EXIT_PANIC: u32 = 101;
+
+/// Issue a PSCI call through HVC, returning x0
+unsafe fn psci_call(function_id: u64) -> u64 {
+    let ret: u64;
+    core::arch::asm!(
+        "hvc #0",
+        inout("x0") function_id => ret,
+        out("x1") _, out("x2") _, out("x3") _,
+        options(nomem, nostack),
+    );
+    ret
+}
+
+/// Halt the CPU forever (fallback when PSCI is unavailable)
+pub fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("wfi"); }
+    }
+}
+
+/// Power the VM off, reporting `code` on the console first
+pub fn shutdown(code: u32) -> ! {
+    crate::puts("EXIT:");
+    crate::print_hex(code as u64);
+    crate::puts("\n");
+
+    unsafe {
+        // Does not return on success
+        psci_call(PSCI_SYSTEM_OFF);
+    }
+    halt()
+}
+
+/// Reset the VM
+pub fn reboot() -> ! {
+    unsafe {
+        psci_call(PSCI_SYSTEM_RESET);
+    }
+    halt()
+}
@@ -0,0 +1,340 @@
+//! Static ELF programs, run at EL0
+//!
+//! load() maps an AArch64 executable's PT_LOAD segments into a new
+//! AddressSpace (mmu.rs) along with a stack; run() lays out argv, envp and
+//! the auxiliary vector on that stack the way Linux does, then erets to the
+//! entry point on the calling thread (asm/user.s). The program's traps land
+//! in handle_trap() on the same thread's kernel stack: system calls go to
+//! syscall.rs, and exit, exit_group or any fault ends the run, unwinding
+//! back out of run().
+//!
+//! Only statically linked programs: there is no dynamic loader to hand a
+//! PT_INTERP binary to. A static PIE (ET_DYN) is loaded at LOAD_BIAS. All
+//! memory is mapped up front, nothing is paged in on demand.
+
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use driver_core::elf::{self, Elf};
+use driver_core::pagetable::{Attrs, PAGE_SIZE};
+use kernel_macros::kernel_test;
+
+use crate::arch::read_sysreg;
+use crate::exceptions::{self, TrapFrame, EC_SVC64};
+use crate::syscall::{self, ENOSYS};
+use crate::ktest::Outcome;
+use crate::mmu::{self, AddressSpace};
+use crate::{initrd, memory, thread, timer, virtio_entropy, ConsoleWriter};
+
+global_asm!(include_str!("asm/user.s"));
+
+/// Where a position-independent (ET_DYN) program is placed
+const LOAD_BIAS: u64 = 0x5555_0000_0000;
+/// Top of every program's stack (the stack grows down from here)
+const STACK_TOP: u64 = 0x8000_0000_0000;
+const STACK_SIZE: u64 = 256 * 1024;
+
+/// Callee-saved kernel state across a run (layout shared with asm/user.s)
+#[repr(C)]
+struct KernelContext {
+    x19_x28: [u64; 10],
+    fp: u64,
+    lr: u64,
+    sp: u64,
+    d8_d15: [u64; 8],
+    fpcr: u64,
+    fpsr: u64,
+    daif: u64,
+}
+
+/// A run in progress: where to unwind to, what is running and how it ended
+#[repr(C)]
+struct Session {
+    // First, so a *Session is also a *KernelContext
+    context: KernelContext,
+    program: *mut Program,
+    exit: Exit,
+}
+
+extern "C" {
+    fn user_enter(context: *mut KernelContext, entry: u64, sp: u64);
+    fn user_return(context: *const KernelContext) -> !;
+}
+
+// The Session of the program each thread is running (0 if none)
+static SESSIONS: [AtomicUsize; thread::MAX_THREADS] = [const { AtomicUsize::new(0) }; thread::MAX_THREADS];
+
+/// How a program's run ended
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exit {
+    /// exit() or exit_group() with this status
+    Code(i32),
+    /// Killed by an exception it caused
+    Fault { esr: u64, far: u64, elr: u64 },
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Exit::Code(code) => write!(f, "exit code {}", code),
+            Exit::Fault { esr, far, elr } => write!(f, "fault (ESR={:#x}, FAR={:#x}, ELR={:#x})", esr, far, elr),
+        }
+    }
+}
+
+/// A program loaded and ready to run
+pub struct Program {
+    space: AddressSpace,
+    entry: u64,
+    /// Load address of the program headers (0 if they are not loaded)
+    phdr: u64,
+    phent: u64,
+    phnum: u64,
+    state: syscall::State,
+}
+
+/// Map `image`'s segments and a stack into a new address space
+pub fn load(image: &[u8]) -> Result<Program, &'static str> {
+    if !mmu::enabled() {
+        return Err("the MMU is off");
+    }
+    let elf = Elf::parse(image)?;
+    if elf.is_dynamic() {
+        return Err("dynamically linked programs are not supported");
+    }
+    let bias = if elf.kind == elf::ET_DYN { LOAD_BIAS } else { 0 };
+    let mut space = AddressSpace::new().ok_or("out of memory")?;
+    let mut image_end = 0;
+
+    for segment in elf.load_segments().filter(|s| s.memsz != 0) {
+        if segment.filesz > segment.memsz {
+            return Err("segment is larger in the file than in memory");
+        }
+        let data = elf.file_data(&segment).ok_or("segment runs past the end of the file")?;
+        let va = segment.vaddr.checked_add(bias).ok_or("segment address out of range")?;
+        let start = va & !(PAGE_SIZE - 1);
+        let end = va
+            .checked_add(segment.memsz)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or("segment address out of range")?
+            & !(PAGE_SIZE - 1);
+        if !mmu::user_range_ok(start, end - start) {
+            return Err("segment overlaps memory the kernel uses");
+        }
+        image_end = image_end.max(end);
+        let attrs = Attrs::user(segment.writable(), segment.executable());
+        if !space.map_zeroed(start, end - start, attrs) || !space.write(va, data) {
+            return Err("out of memory");
+        }
+        if segment.executable() {
+            for page in (start..end).step_by(PAGE_SIZE as usize) {
+                if let Some(pa) = space.translate(page) {
+                    mmu::sync_icache(pa, PAGE_SIZE);
+                }
+            }
+        }
+    }
+
+    let stack = STACK_TOP - STACK_SIZE;
+    if !mmu::user_range_ok(stack, STACK_SIZE) {
+        return Err("stack overlaps memory the kernel uses");
+    }
+    if !space.map_zeroed(stack, STACK_SIZE, Attrs::user(true, false)) {
+        return Err("out of memory");
+    }
+
+    Ok(Program {
+        space,
+        entry: elf.entry.wrapping_add(bias),
+        phdr: elf.phdr_vaddr().map_or(0, |va| va.wrapping_add(bias)),
+        phent: elf.phentsize(),
+        phnum: elf.phnum(),
+        state: syscall::State::new(image_end),
+    })
+}
+
+/// Random bytes from virtio-rng, or counter-derived ones if the VM has no
+/// such device (good enough to seed a stack protector but nothing more)
+pub fn fill_random(buf: &mut [u8]) {
+    if virtio_entropy::fill(buf) {
+        return;
+    }
+    let mut state = timer::counter();
+    for chunk in buf.chunks_mut(8) {
+        // splitmix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        chunk.copy_from_slice(&(z ^ (z >> 31)).to_le_bytes()[..chunk.len()]);
+    }
+}
+
+impl Program {
+    /// Initial stack pointer, with argv/envp/auxv in the stack's top page
+    fn build_stack(&self, argv: &[&[u8]], envp: &[&[u8]]) -> Option<u64> {
+        let page = self.space.translate(STACK_TOP - PAGE_SIZE)?;
+        let stack = unsafe { core::slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE as usize) };
+        let auxv = [
+            (elf::AT_PHDR, self.phdr),
+            (elf::AT_PHENT, self.phent),
+            (elf::AT_PHNUM, self.phnum),
+            (elf::AT_PAGESZ, PAGE_SIZE),
+            (elf::AT_BASE, 0),
+            (elf::AT_FLAGS, 0),
+            (elf::AT_ENTRY, self.entry),
+            (elf::AT_UID, 0),
+            (elf::AT_EUID, 0),
+            (elf::AT_GID, 0),
+            (elf::AT_EGID, 0),
+            (elf::AT_HWCAP, 0),
+            (elf::AT_CLKTCK, 100),
+            (elf::AT_SECURE, 0),
+        ];
+        let mut random = [0; 16];
+        fill_random(&mut random);
+        elf::build_stack(stack, STACK_TOP, argv, envp, &auxv, &random)
+    }
+
+    /// Carry out the system call in `frame` for this program
+    /// Returns how it ended if the call was exit or exit_group.
+    pub fn syscall(&mut self, frame: &mut TrapFrame) -> Option<Exit> {
+        syscall::dispatch(&mut self.space, &mut self.state, frame)
+    }
+}
+
+/// Run a loaded program on the current thread until it exits or faults
+/// Its memory is freed afterwards. Fails if argv and envp do not fit in a
+/// page, or if this thread is already running a program.
+pub fn run(mut program: Program, argv: &[&[u8]], envp: &[&[u8]]) -> Result<Exit, &'static str> {
+    let sp = program.build_stack(argv, envp).ok_or("arguments do not fit on the stack")?;
+    let slot = &SESSIONS[thread::current()];
+    let mut session = Session {
+        context: KernelContext { x19_x28: [0; 10], fp: 0, lr: 0, sp: 0, d8_d15: [0; 8], fpcr: 0, fpsr: 0, daif: 0 },
+        program: &raw mut program,
+        exit: Exit::Code(0),
+    };
+    let session_ptr = &raw mut session;
+    if slot.compare_exchange(0, session_ptr as usize, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return Err("this thread is already running a program");
+    }
+
+    thread::set_address_space(program.space.ttbr0());
+    unsafe { user_enter(session_ptr.cast(), program.entry, sp) };
+    thread::set_address_space(0);
+
+    slot.store(0, Ordering::Release);
+    drop(program);
+    Ok(unsafe { session_ptr.read() }.exit)
+}
+
+/// Load and run a file from the initrd, with its path as argv[0]
+pub fn exec_file(path: &str) -> Result<Exit, &'static str> {
+    let image = initrd::file(path).ok_or("no such file in the initrd")?;
+    let program = load(image)?;
+    run(program, &[path.as_bytes()], &[b"HOME=/", b"TERM=linux"])
+}
+
+/// Synchronous exception from EL0
+/// Returns false if this thread is not running a program (not ours)
+pub fn handle_trap(frame: &mut TrapFrame) -> bool {
+    let session = SESSIONS[thread::current()].load(Ordering::Acquire) as *mut Session;
+    if session.is_null() {
+        return false;
+    }
+    let esr = read_sysreg!("esr_el1");
+    let ec = (esr >> 26) & 0x3F;
+
+    let exit = if ec == EC_SVC64 {
+        // ELR already points past the svc
+        match unsafe { (*(*session).program).syscall(frame) } {
+            Some(exit) => exit,
+            None => return true,
+        }
+    } else {
+        let far = read_sysreg!("far_el1");
+        let _ = writeln!(
+            ConsoleWriter,
+            "Program fault: {} at {:#x} (ESR={:#x}, FAR={:#x})",
+            exceptions::class_name(ec), frame.elr, esr, far
+        );
+        Exit::Fault { esr, far, elr: frame.elr }
+    };
+
+    unsafe {
+        (*session).exit = exit;
+        user_return(session.cast())
+    }
+}
+
+/// A minimal executable for self-tests: ELF header, one R+X PT_LOAD
+/// program header, `code` and then `data`, all loaded at 0x400000
+/// Returns the image size and the address `data` is loaded at.
+pub fn tiny_elf(code: &[u32], data: &[u8], buf: &mut [u8]) -> (usize, u64) {
+    const BASE: u64 = 0x40_0000;
+    const CODE: usize = 64 + 56;
+    let data_at = CODE + 4 * code.len();
+    let size = data_at + data.len();
+    let mut put = |off: usize, bytes: &[u8]| buf[off..off + bytes.len()].copy_from_slice(bytes);
+
+    put(0, b"\x7fELF\x02\x01\x01");
+    put(0x10, &elf::ET_EXEC.to_le_bytes());
+    put(0x12, &elf::EM_AARCH64.to_le_bytes());
+    put(0x14, &1u32.to_le_bytes());
+    put(0x18, &(BASE + CODE as u64).to_le_bytes());
+    put(0x20, &64u64.to_le_bytes());
+    put(0x34, &64u16.to_le_bytes());
+    put(0x36, &56u16.to_le_bytes());
+    put(0x38, &1u16.to_le_bytes());
+
+    put(64, &elf::PT_LOAD.to_le_bytes());
+    put(64 + 0x04, &(elf::PF_R | elf::PF_X).to_le_bytes());
+    put(64 + 0x10, &BASE.to_le_bytes());
+    put(64 + 0x18, &BASE.to_le_bytes());
+    put(64 + 0x20, &(size as u64).to_le_bytes());
+    put(64 + 0x28, &(size as u64).to_le_bytes());
+    put(64 + 0x30, &PAGE_SIZE.to_le_bytes());
+
+    for (i, insn) in code.iter().enumerate() {
+        put(CODE + 4 * i, &insn.to_le_bytes());
+    }
+    put(data_at, data);
+    (size, BASE + data_at as u64)
+}
+
+fn run_tiny(code: &[u32], data: &[u8]) -> Result<Exit, &'static str> {
+    let mut buf = [0; 256];
+    let (size, _) = tiny_elf(code, data, &mut buf);
+    run(load(&buf[..size])?, &[b"tiny"], &[])
+}
+
+#[kernel_test]
+fn exec() -> Outcome {
+    if !mmu::enabled() {
+        return Outcome::Skip("MMU is off");
+    }
+    let pages = memory::pages_in_use();
+
+    // mov x0, #42; mov x8, #93; svc #0
+    if run_tiny(&[0xd280_0540, 0xd280_0ba8, 0xd400_0001], &[]) != Ok(Exit::Code(42)) {
+        return Outcome::Fail("exit(42) program did not exit with 42");
+    }
+    // mov x8, #1234; svc #0 (ENOSYS); mov x8, #94; svc #0 (exit_group(-ENOSYS))
+    if run_tiny(&[0xd280_9a48, 0xd400_0001, 0xd280_0bc8, 0xd400_0001], &[]) != Ok(Exit::Code(-ENOSYS as i32)) {
+        return Outcome::Fail("unknown system call did not return -ENOSYS");
+    }
+    // write(1, message, 17) then exit with what write returned:
+    // mov x0, #1; adr x1, message; mov x2, #17; mov x8, #64; svc #0;
+    // mov x8, #93; svc #0
+    let code = [0xd280_0020, 0x1000_00c1, 0xd280_0222, 0xd280_0808, 0xd400_0001, 0xd280_0ba8, 0xd400_0001];
+    if run_tiny(&code, b"# hello from EL0\n") != Ok(Exit::Code(17)) {
+        return Outcome::Fail("write() from a program did not write its message");
+    }
+    // mov x0, #0; ldr x1, [x0] (the kernel's memory is not the program's)
+    if !matches!(run_tiny(&[0xd280_0000, 0xf940_0001], &[]), Ok(Exit::Fault { far: 0, .. })) {
+        return Outcome::Fail("faulting program was not stopped");
+    }
+
+    Outcome::check(memory::pages_in_use() == pages, "program memory was not freed")
+}
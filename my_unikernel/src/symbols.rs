@@ -0,0 +1,131 @@
+//! Embedded kernel symbol table for symbolized backtraces
+//!
+//! The `.ksymtab` section is reserved here with a fixed size and filled in
+//! after linking by `cargo xtask symtab` (xtask/src/symtab.rs), which
+//! extracts function symbols from the `kernel` ELF. Patching in place keeps
+//! the layout identical, so no second link is needed.
+//!
+//! Table layout (little endian, offsets from the table start):
+//!   0x00  magic "KSYM"
+//!   0x04  u32 entry count (0 until xtask has filled it in)
+//!   0x08  u32 offset of the string pool
+//!   0x0c  u32 reserved
+//!   0x10  entries: { u32 addr (relative to _start), u32 size, u32 name offset }
+//!         sorted by address
+//!   ....  NUL-terminated demangled names
+
+use core::arch::global_asm;
+use core::fmt::Write;
+use core::ptr::read_volatile;
+
+use crate::ConsoleWriter;
+
+/// Reserved size of the table; xtask refuses to overflow it
+pub const KSYMTAB_SIZE: usize = 64 * 1024;
+
+const KSYMTAB_MAGIC: u32 = u32::from_le_bytes(*b"KSYM");
+const HEADER_SIZE: u64 = 16;
+const ENTRY_SIZE: u64 = 12;
+const MAX_NAME_LEN: usize = 256;
+
+global_asm!(
+    ".section .ksymtab, \"a\"",
+    ".balign 8",
+    ".global __ksymtab",
+    "__ksymtab:",
+    ".ascii \"KSYM\"",
+    ".space {size} - 4",
+    size = const KSYMTAB_SIZE,
+);
+
+extern "C" {
+    // Extern so the compiler can't assume the (patched) contents
+    static __ksymtab: u8;
+    static _start: u8;
+}
+
+fn table_base() -> u64 {
+    unsafe { &raw const __ksymtab as u64 }
+}
+
+/// Runtime address of `_start`, which symbol addresses are relative to
+fn kernel_base() -> u64 {
+    unsafe { &raw const _start as u64 }
+}
+
+unsafe fn read_u32(offset: u64) -> u32 {
+    read_volatile((table_base() + offset) as *const u32)
+}
+
+/// Number of symbols in the table (0 if xtask did not fill the table in)
+pub fn count() -> u32 {
+    unsafe {
+        if read_u32(0) != KSYMTAB_MAGIC {
+            return 0;
+        }
+        read_u32(4)
+    }
+}
+
+unsafe fn entry(index: u32) -> (u64, u64, u32) {
+    let off = HEADER_SIZE + index as u64 * ENTRY_SIZE;
+    (read_u32(off) as u64, read_u32(off + 4) as u64, read_u32(off + 8))
+}
+
+unsafe fn name_at(name_off: u32) -> &'static str {
+    let start = table_base() + read_u32(8) as u64 + name_off as u64;
+    let end = table_base() + KSYMTAB_SIZE as u64;
+
+    let mut len = 0usize;
+    while len < MAX_NAME_LEN && start + (len as u64) < end {
+        if read_volatile((start + len as u64) as *const u8) == 0 {
+            break;
+        }
+        len += 1;
+    }
+
+    let slice = core::slice::from_raw_parts(start as *const u8, len);
+    core::str::from_utf8(slice).unwrap_or("<bad utf8>")
+}
+
+/// Find the function containing `addr`
+/// Returns (name, offset into the function)
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let n = count();
+    let base = kernel_base();
+    if n == 0 || addr < base {
+        return None;
+    }
+    let rel = addr - base;
+
+    unsafe {
+        // Last entry whose start is <= rel
+        let (mut lo, mut hi) = (0u32, n);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if entry(mid).0 <= rel {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            return None;
+        }
+
+        let (start, size, name_off) = entry(lo - 1);
+        let offset = rel - start;
+        if offset >= size {
+            return None;
+        }
+        Some((name_at(name_off), offset))
+    }
+}
+
+/// Print `addr` followed by ` <function+0xoff>` when it can be resolved
+pub fn print_address(addr: u64) {
+    let _ = write!(ConsoleWriter, "{:#018x}", addr);
+    if let Some((name, offset)) = lookup(addr) {
+        let _ = write!(ConsoleWriter, " <{}+{:#x}>", name, offset);
+    }
+}
@@ -0,0 +1,290 @@
+//! Synchronisation primitives
+//!
+//! Driver and console state lives in statics wrapped in these types rather
+//! than `static mut`, so that access stays sound once interrupts and
+//! secondary cores come up:
+//!
+//!   SpinLock<T>      fair ticket lock
+//!   IrqSpinLock<T>   ticket lock that also masks IRQs while held, for state
+//!                    shared with interrupt handlers (console output)
+//!   Once<T>          one-time initialisation, then lock-free shared reads
+//!   Lazy<T>          Once plus the function that produces the value
+//!
+//! Simple flags and counters use `core::sync::atomic` directly.
+
+use core::cell::{Cell, UnsafeCell};
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::arch::{read_sysreg, write_sysreg};
+
+// ---------------------------------------------------------------------------
+// SpinLock
+
+/// Ticket spinlock: waiters are served in arrival order
+pub struct SpinLock<T> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Spin until the lock is ours
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    /// Take the lock only if nobody holds or waits for it
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let ticket = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(ticket, ticket.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
+    /// Exclusive access without locking; `&mut self` proves nobody else has it
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+// ---------------------------------------------------------------------------
+// IrqSpinLock
+
+/// DAIF.I: IRQs masked
+const DAIF_I: u64 = 1 << 7;
+
+/// Spinlock that keeps IRQs masked while held
+///
+/// Use for state an interrupt handler may also touch; a plain SpinLock
+/// would deadlock if the handler fired on the core holding it.
+pub struct IrqSpinLock<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock { inner: SpinLock::new(value) }
+    }
+
+    /// Mask IRQs, then spin until the lock is ours
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let daif = irq_save();
+        IrqSpinLockGuard { guard: Some(self.inner.lock()), daif }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let daif = irq_save();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard { guard: Some(guard), daif }),
+            None => {
+                irq_restore(daif);
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: Option<SpinLockGuard<'a, T>>,
+    daif: u64,
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before unmasking, or a pending IRQ could spin on it
+        drop(self.guard.take());
+        irq_restore(self.daif);
+    }
+}
+
+/// Mask IRQs and return the previous DAIF value
+pub fn irq_save() -> u64 {
+    let daif = read_sysreg!("daif");
+    unsafe { core::arch::asm!("msr daifset, #2", options(nomem, nostack)); }
+    daif
+}
+
+/// Restore the IRQ mask saved by irq_save()
+pub fn irq_restore(daif: u64) {
+    if daif & DAIF_I == 0 {
+        write_sysreg!("daif", daif);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Once / Lazy
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value written exactly once, then shared read-only
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Run `f` if this is the first call; every caller gets the stored value
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe { (*self.value.get()).write(f()); }
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+        self.wait()
+    }
+
+    /// Store `value` unless already initialised; gives it back on failure
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value); }
+        self.state.store(COMPLETE, Ordering::Release);
+        Ok(())
+    }
+
+    /// The value, if initialisation has finished
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    fn wait(&self) -> &T {
+        while !self.is_completed() {
+            core::hint::spin_loop();
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop(); }
+        }
+    }
+}
+
+/// A value computed on first access
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+// `init` is only taken inside Once::call_once, which runs at most once
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy { once: Once::new(), init: Cell::new(Some(init)) }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| match this.init.take() {
+            Some(f) => f(),
+            None => unreachable!("Lazy initialiser already taken"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
@@ -0,0 +1,789 @@
+//! Linux system calls for EL0 programs
+//!
+//! Enough of the arm64 Linux ABI for a static musl binary to start, print,
+//! use files, allocate memory and exit: a program does
+//! `svc #0` with the number in x8 and arguments in x0-x5, and gets the
+//! result (or -errno) back in x0. Anything not listed in dispatch() fails
+//! with ENOSYS.
+//!
+//! File descriptors 0-2 are the console. Input is line-disciplined only as
+//! far as echoing and turning CR into LF. Files and directories are the
+//! kernel's VFS (see vfs.rs): the initrd at "/", read-only, and scratch
+//! space at "/tmp". There is one thread per program and no signals, so the
+//! thread and signal calls musl makes at startup just succeed. Clocks run
+//! from boot: there is no RTC, so CLOCK_REALTIME starts at the epoch.
+
+use core::fmt::Write;
+
+use driver_core::pagetable::{Attrs, PAGE_SIZE};
+use driver_core::vfs::{Dir, Error, FdTable, File, FileType, OpenFlags, SeekFrom, Stat};
+use kernel_macros::kernel_test;
+
+use crate::exceptions::TrapFrame;
+use crate::ktest::Outcome;
+use crate::mmu::{self, AddressSpace};
+use crate::process::{self, Exit};
+use crate::{console_read, console_write, initrd, memory, thread, timer, vfs, ConsoleWriter};
+
+// System call numbers (arm64 generic table)
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_MKDIRAT: u64 = 34;
+pub const SYS_UNLINKAT: u64 = 35;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_GETDENTS64: u64 = 61;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_WRITEV: u64 = 66;
+pub const SYS_NEWFSTATAT: u64 = 79;
+pub const SYS_FSTAT: u64 = 80;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_CLOCK_GETTIME: u64 = 113;
+pub const SYS_RT_SIGACTION: u64 = 134;
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_GETTID: u64 = 178;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+pub const SYS_GETRANDOM: u64 = 278;
+
+// errno values
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EBUSY: i64 = 16;
+pub const EEXIST: i64 = 17;
+pub const ENODEV: i64 = 19;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const ENOTTY: i64 = 25;
+pub const EFBIG: i64 = 27;
+pub const ENOSPC: i64 = 28;
+pub const ESPIPE: i64 = 29;
+pub const EROFS: i64 = 30;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const ELOOP: i64 = 40;
+pub const EOPNOTSUPP: i64 = 95;
+
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+const O_ACCMODE: u64 = 3;
+const O_RDONLY: u64 = 0;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+// arm64 numbers these differently from the generic table
+const O_DIRECTORY: u64 = 0o40000;
+
+// d_type in getdents64() records
+const DT_UNKNOWN: u8 = 0;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// struct stat on arm64
+const STAT_SIZE: usize = 128;
+const S_IFCHR: u32 = 0o020_000;
+
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const TIOCGWINSZ: u64 = 0x5413;
+const IOV_MAX: u64 = 1024;
+
+/// Longest path a call accepts, NUL included
+const PATH_MAX: usize = 256;
+/// How far brk() may grow past the end of the program
+const HEAP_MAX: u64 = 256 * 1024 * 1024;
+/// Where mmap() places memory when the program does not ask for an address
+const MMAP_BASE: u64 = 0x7000_0000_0000;
+const MMAP_END: u64 = 0x7F00_0000_0000;
+
+/// Program memory is copied in and out in pieces this big
+const CHUNK: usize = 256;
+
+/// What a program's file descriptor refers to
+#[derive(Clone, Copy)]
+enum Descriptor {
+    Console,
+    File(File),
+    Dir(Dir),
+}
+
+/// System call state of one program: heap, mappings and open files
+pub struct State {
+    brk_start: u64,
+    brk: u64,
+    mmap_next: u64,
+    files: FdTable<Descriptor>,
+}
+
+const fn page_up(value: u64) -> u64 {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+impl State {
+    /// Fresh state for a program whose highest segment ends at `end`
+    pub fn new(end: u64) -> Self {
+        let mut files = FdTable::new();
+        for fd in 0..3 {
+            let _ = files.insert_at(fd, Descriptor::Console);
+        }
+        let brk = page_up(end);
+        State { brk_start: brk, brk, mmap_next: MMAP_BASE, files }
+    }
+}
+
+/// Handle the system call in `frame`, leaving the result in x0
+/// Returns how the program ended if the call was exit or exit_group.
+pub fn dispatch(space: &mut AddressSpace, state: &mut State, frame: &mut TrapFrame) -> Option<Exit> {
+    let [a0, a1, a2, a3, a4, _] = [frame.x[0], frame.x[1], frame.x[2], frame.x[3], frame.x[4], frame.x[5]];
+    let result = match frame.x[8] {
+        SYS_EXIT | SYS_EXIT_GROUP => return Some(Exit::Code(a0 as i32)),
+        SYS_READ => read(space, state, a0, a1, a2),
+        SYS_WRITE => write(space, state, a0, a1, a2),
+        SYS_WRITEV => writev(space, state, a0, a1, a2),
+        SYS_OPENAT => openat(space, state, a0 as i64, a1, a2),
+        SYS_CLOSE => match state.files.remove(a0 as usize) {
+            Some(_) => 0,
+            None => -EBADF,
+        },
+        SYS_LSEEK => lseek(state, a0, a1 as i64, a2),
+        SYS_FSTAT => fstat(space, state, a0, a1),
+        SYS_NEWFSTATAT => newfstatat(space, state, a0 as i64, a1, a2, a3),
+        SYS_GETDENTS64 => getdents64(space, state, a0, a1, a2),
+        SYS_MKDIRAT => match user_path(space, a0 as i64, a1, &mut [0; PATH_MAX]) {
+            Ok(path) => vfs::with(|vfs| vfs.mkdir(path)).map_or_else(|err| -errno(err), |_| 0),
+            Err(err) => -err,
+        },
+        SYS_UNLINKAT => unlinkat(space, a0 as i64, a1, a2),
+        SYS_IOCTL => ioctl(space, state, a0, a1, a2),
+        SYS_BRK => brk(space, state, a0) as i64,
+        SYS_MMAP => mmap(space, state, a0, a1, a2, a3, a4 as i64),
+        SYS_MUNMAP => munmap(space, a0, a1),
+        SYS_MPROTECT => mprotect(space, a0, a1, a2),
+        SYS_CLOCK_GETTIME => clock_gettime(space, a0, a1),
+        SYS_GETRANDOM => getrandom(space, a0, a1),
+        // One thread, no signals
+        SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => 1,
+        SYS_RT_SIGACTION => 0,
+        SYS_RT_SIGPROCMASK => rt_sigprocmask(space, a2, a3),
+        _ => -ENOSYS,
+    };
+    frame.x[0] = result as u64;
+    None
+}
+
+fn descriptor(state: &mut State, fd: u64) -> Option<&mut Descriptor> {
+    state.files.get_mut(fd as usize)
+}
+
+/// errno for a VFS error
+fn errno(err: Error) -> i64 {
+    match err {
+        Error::NotFound => ENOENT,
+        Error::NotADirectory => ENOTDIR,
+        Error::IsADirectory => EISDIR,
+        Error::Exists => EEXIST,
+        Error::NotEmpty => ENOTEMPTY,
+        Error::Busy | Error::AlreadyMounted => EBUSY,
+        Error::ReadOnlyFs => EROFS,
+        Error::ReadOnlyFile => EACCES,
+        Error::NameTooLong | Error::PathTooLong | Error::LinkTooLong => ENAMETOOLONG,
+        Error::TooManyLinks => ELOOP,
+        Error::TooManyOpen => EMFILE,
+        Error::NotReadable | Error::NotWritable | Error::Stale | Error::BadDescriptor => EBADF,
+        Error::InvalidName | Error::InvalidSeek | Error::NotASymlink | Error::NotAMountPoint => EINVAL,
+        Error::NoSpace => ENOSPC,
+        Error::FileTooLarge => EFBIG,
+        Error::NoDevice => ENODEV,
+        Error::NotSupported => EOPNOTSUPP,
+        Error::MountTableFull => ENOMEM,
+        Error::Io(_) => EIO,
+    }
+}
+
+/// Result of a transfer that stopped early: what got through, if anything
+fn partial(done: u64, err: i64) -> i64 {
+    if done > 0 {
+        done as i64
+    } else {
+        -err
+    }
+}
+
+fn read(space: &AddressSpace, state: &mut State, fd: u64, buf: u64, count: u64) -> i64 {
+    match descriptor(state, fd) {
+        None => -EBADF,
+        Some(_) if count == 0 => 0,
+        Some(Descriptor::Console) => {
+            // Wait for some input, then hand over what arrived
+            let mut bytes = [0; CHUNK];
+            let want = (count as usize).min(CHUNK);
+            let n = loop {
+                match console_read(&mut bytes[..want]) {
+                    0 => thread::sleep(10),
+                    n => break n,
+                }
+            };
+            for b in &mut bytes[..n] {
+                if *b == b'\r' {
+                    *b = b'\n';
+                }
+            }
+            console_write(&bytes[..n]);
+            if space.copy_out(buf, &bytes[..n]) { n as i64 } else { -EFAULT }
+        }
+        Some(Descriptor::File(file)) => {
+            let mut done = 0;
+            let mut bytes = [0; CHUNK];
+            while done < count {
+                let want = (count - done).min(CHUNK as u64) as usize;
+                let n = match vfs::with(|vfs| vfs.read(file, &mut bytes[..want])) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) => return partial(done, errno(err)),
+                };
+                if !space.copy_out(buf.wrapping_add(done), &bytes[..n]) {
+                    return partial(done, EFAULT);
+                }
+                done += n as u64;
+            }
+            done as i64
+        }
+        Some(Descriptor::Dir(_)) => -EISDIR,
+    }
+}
+
+fn write(space: &AddressSpace, state: &mut State, fd: u64, buf: u64, count: u64) -> i64 {
+    let mut done = 0;
+    let mut bytes = [0; CHUNK];
+    let mut file = match descriptor(state, fd) {
+        Some(Descriptor::Console) => None,
+        Some(Descriptor::File(file)) => Some(file),
+        _ => return -EBADF,
+    };
+    while done < count {
+        let n = (count - done).min(CHUNK as u64) as usize;
+        if !space.copy_in(buf.wrapping_add(done), &mut bytes[..n]) {
+            return partial(done, EFAULT);
+        }
+        match file.as_mut() {
+            None => console_write(&bytes[..n]),
+            Some(file) => {
+                if let Err(err) = vfs::with(|vfs| vfs.write(file, &bytes[..n])) {
+                    return partial(done, errno(err));
+                }
+            }
+        }
+        done += n as u64;
+    }
+    done as i64
+}
+
+fn writev(space: &AddressSpace, state: &mut State, fd: u64, iov: u64, iovcnt: u64) -> i64 {
+    if iovcnt > IOV_MAX {
+        return -EINVAL;
+    }
+    let mut done = 0;
+    for i in 0..iovcnt {
+        let mut vec = [0; 16];
+        if !space.copy_in(iov.wrapping_add(16 * i), &mut vec) {
+            return partial(done, EFAULT);
+        }
+        let base = u64::from_le_bytes(vec[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(vec[8..].try_into().unwrap());
+        match write(space, state, fd, base, len) {
+            n if n < 0 => return partial(done, -n),
+            n => done += n as u64,
+        }
+    }
+    done as i64
+}
+
+/// NUL-terminated path from the program, read a byte at a time up to
+/// PATH_MAX; Err is an errno
+fn user_path<'a>(space: &AddressSpace, dirfd: i64, path: u64, name: &'a mut [u8; PATH_MAX]) -> Result<&'a str, i64> {
+    let mut len = 0;
+    loop {
+        if len == PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        if !space.copy_in(path.wrapping_add(len as u64), &mut name[len..len + 1]) {
+            return Err(EFAULT);
+        }
+        if name[len] == 0 {
+            break;
+        }
+        len += 1;
+    }
+    let name = core::str::from_utf8(&name[..len]).map_err(|_| ENOENT)?;
+    // The working directory is always "/", and paths are not looked up
+    // from directory fds
+    if !name.starts_with('/') && dirfd != AT_FDCWD {
+        return Err(EBADF);
+    }
+    Ok(name)
+}
+
+fn openat(space: &AddressSpace, state: &mut State, dirfd: i64, path: u64, flags: u64) -> i64 {
+    let mut name = [0; PATH_MAX];
+    let name = match user_path(space, dirfd, path, &mut name) {
+        Ok(name) => name,
+        Err(err) => return -err,
+    };
+    let open = OpenFlags {
+        read: flags & O_ACCMODE != O_WRONLY,
+        write: flags & O_ACCMODE != O_RDONLY,
+        create: flags & O_CREAT != 0,
+        exclusive: flags & O_EXCL != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0,
+    };
+    // Directories open for listing with getdents64()
+    let opened = vfs::with(|vfs| match vfs.stat(name) {
+        Ok(stat) if stat.file_type == FileType::Dir && !open.write => vfs.open_dir(name).map(Descriptor::Dir),
+        Ok(_) if flags & O_DIRECTORY != 0 => Err(Error::NotADirectory),
+        _ => vfs.open(name, open).map(Descriptor::File),
+    });
+    match opened.and_then(|descriptor| state.files.insert(descriptor)) {
+        Ok(fd) => fd as i64,
+        Err(err) => -errno(err),
+    }
+}
+
+fn lseek(state: &mut State, fd: u64, offset: i64, whence: u64) -> i64 {
+    match descriptor(state, fd) {
+        None => -EBADF,
+        Some(Descriptor::Console) => -ESPIPE,
+        Some(Descriptor::File(file)) => {
+            let to = match whence {
+                0 if offset >= 0 => SeekFrom::Start(offset as u64),
+                1 => SeekFrom::Current(offset),
+                2 => SeekFrom::End(offset),
+                _ => return -EINVAL,
+            };
+            match vfs::with(|vfs| vfs.seek(file, to)) {
+                Ok(pos) => pos as i64,
+                Err(err) => -errno(err),
+            }
+        }
+        Some(Descriptor::Dir(_)) => -EINVAL,
+    }
+}
+
+/// `stat` laid out as struct stat; the console is a character device
+fn copy_stat(space: &AddressSpace, statbuf: u64, stat: Option<&Stat>) -> i64 {
+    let mut bytes = [0; STAT_SIZE];
+    let mut put = |off: usize, value: &[u8]| bytes[off..off + value.len()].copy_from_slice(value);
+    match stat {
+        Some(stat) => {
+            put(8, &stat.node.to_le_bytes());
+            put(16, &stat.mode.to_le_bytes());
+            put(20, &stat.links.to_le_bytes());
+            put(48, &stat.size.to_le_bytes());
+            put(64, &stat.size.div_ceil(512).to_le_bytes());
+            // atime, mtime and ctime
+            for off in [72, 88, 104] {
+                put(off, &stat.mtime.to_le_bytes());
+            }
+        }
+        None => {
+            put(16, &(S_IFCHR | 0o620).to_le_bytes());
+            put(20, &1u32.to_le_bytes());
+        }
+    }
+    put(56, &(PAGE_SIZE as u32).to_le_bytes());
+    if space.copy_out(statbuf, &bytes) { 0 } else { -EFAULT }
+}
+
+fn fstat(space: &AddressSpace, state: &mut State, fd: u64, statbuf: u64) -> i64 {
+    let stat = match descriptor(state, fd) {
+        None => return -EBADF,
+        Some(Descriptor::Console) => return copy_stat(space, statbuf, None),
+        Some(Descriptor::File(file)) => vfs::with(|vfs| vfs.fstat(file)),
+        Some(Descriptor::Dir(_)) => return -EINVAL,
+    };
+    match stat {
+        Ok(stat) => copy_stat(space, statbuf, Some(&stat)),
+        Err(err) => -errno(err),
+    }
+}
+
+fn newfstatat(space: &AddressSpace, state: &mut State, dirfd: i64, path: u64, statbuf: u64, flags: u64) -> i64 {
+    let mut name = [0; PATH_MAX];
+    let name = match user_path(space, AT_FDCWD, path, &mut name) {
+        Ok(name) => name,
+        Err(err) => return -err,
+    };
+    if name.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        return fstat(space, state, dirfd as u64, statbuf);
+    }
+    if !name.starts_with('/') && dirfd != AT_FDCWD {
+        return -EBADF;
+    }
+    let stat = vfs::with(|vfs| if flags & AT_SYMLINK_NOFOLLOW != 0 { vfs.lstat(name) } else { vfs.stat(name) });
+    match stat {
+        Ok(stat) => copy_stat(space, statbuf, Some(&stat)),
+        Err(err) => -errno(err),
+    }
+}
+
+/// Fill `buf` with struct linux_dirent64 records for the next entries of
+/// directory `fd`; the bytes used (0 at the end)
+fn getdents64(space: &AddressSpace, state: &mut State, fd: u64, buf: u64, count: u64) -> i64 {
+    let dir = match descriptor(state, fd) {
+        None => return -EBADF,
+        Some(Descriptor::Dir(dir)) => dir,
+        Some(_) => return -ENOTDIR,
+    };
+    let (mut done, mut full, mut fault) = (0, false, false);
+    let listed = vfs::with(|vfs| {
+        vfs.read_dir(dir, |entry| {
+            // Inode, offset, length, type and the name with its NUL
+            let name = entry.name();
+            let len = (19 + name.len() + 1).next_multiple_of(8);
+            if done + len as u64 > count {
+                full = true;
+                return false;
+            }
+            let d_type = match entry.file_type() {
+                FileType::File => DT_REG,
+                FileType::Dir => DT_DIR,
+                FileType::Symlink => DT_LNK,
+                FileType::Other => DT_UNKNOWN,
+            };
+            let mut record = [0; 19 + 256 + 5];
+            record[..8].copy_from_slice(&entry.node().to_le_bytes());
+            record[8..16].copy_from_slice(&(done + len as u64).to_le_bytes());
+            record[16..18].copy_from_slice(&(len as u16).to_le_bytes());
+            record[18] = d_type;
+            record[19..19 + name.len()].copy_from_slice(name);
+            fault = !space.copy_out(buf.wrapping_add(done), &record[..len]);
+            if !fault {
+                done += len as u64;
+            }
+            !fault
+        })
+    });
+    match listed {
+        Err(err) => partial(done, errno(err)),
+        Ok(()) if fault => partial(done, EFAULT),
+        // Not even one entry fits
+        Ok(()) if full && done == 0 => -EINVAL,
+        Ok(()) => done as i64,
+    }
+}
+
+fn unlinkat(space: &AddressSpace, dirfd: i64, path: u64, flags: u64) -> i64 {
+    let mut name = [0; PATH_MAX];
+    let name = match user_path(space, dirfd, path, &mut name) {
+        Ok(name) => name,
+        Err(err) => return -err,
+    };
+    let removed = vfs::with(|vfs| {
+        let is_dir = vfs.lstat(name)?.file_type == FileType::Dir;
+        match flags & AT_REMOVEDIR != 0 {
+            true if !is_dir => Err(Error::NotADirectory),
+            false if is_dir => Err(Error::IsADirectory),
+            _ => vfs.remove(name),
+        }
+    });
+    match removed {
+        Ok(()) => 0,
+        Err(err) => -errno(err),
+    }
+}
+
+fn ioctl(space: &AddressSpace, state: &mut State, fd: u64, request: u64, arg: u64) -> i64 {
+    match descriptor(state, fd) {
+        None => -EBADF,
+        Some(Descriptor::Console) if request == TIOCGWINSZ => {
+            // struct winsize: 24 rows, 80 columns, no pixel size
+            let winsize = [24u16, 80, 0, 0];
+            let mut bytes = [0; 8];
+            for (dst, v) in bytes.chunks_mut(2).zip(winsize) {
+                dst.copy_from_slice(&v.to_le_bytes());
+            }
+            if space.copy_out(arg, &bytes) { 0 } else { -EFAULT }
+        }
+        Some(Descriptor::Console) => -EINVAL,
+        Some(_) => -ENOTTY,
+    }
+}
+
+/// New program break, or the old one if it cannot move there
+fn brk(space: &mut AddressSpace, state: &mut State, addr: u64) -> u64 {
+    if addr < state.brk_start || addr - state.brk_start > HEAP_MAX {
+        return state.brk;
+    }
+    let (old_end, new_end) = (page_up(state.brk), page_up(addr));
+    if new_end > old_end {
+        let size = new_end - old_end;
+        if !mmu::user_range_ok(old_end, size) || !space.map_zeroed(old_end, size, Attrs::user(true, false)) {
+            space.unmap(old_end, size);
+            return state.brk;
+        }
+    } else if new_end < old_end {
+        space.unmap(new_end, old_end - new_end);
+    }
+    state.brk = addr;
+    addr
+}
+
+/// Size of [addr, addr + len) in whole pages, if addr is page aligned and
+/// the range may hold program memory
+fn user_pages(addr: u64, len: u64) -> Option<u64> {
+    if addr % PAGE_SIZE != 0 || len == 0 || len > MMAP_END {
+        return None;
+    }
+    let size = page_up(len);
+    mmu::user_range_ok(addr, size).then_some(size)
+}
+
+fn prot_attrs(prot: u64) -> Attrs {
+    Attrs::user(prot & PROT_WRITE != 0, prot & PROT_EXEC != 0)
+}
+
+fn mmap(space: &mut AddressSpace, state: &mut State, addr: u64, len: u64, prot: u64, flags: u64, fd: i64) -> i64 {
+    if flags & MAP_ANONYMOUS == 0 || fd != -1 {
+        // Files cannot be mapped (yet)
+        return -ENODEV;
+    }
+    if len == 0 || len > MMAP_END - MMAP_BASE {
+        return -EINVAL;
+    }
+    let size = page_up(len);
+
+    let va = if flags & MAP_FIXED != 0 {
+        if user_pages(addr, size).is_none() {
+            return -EINVAL;
+        }
+        // Whatever was there goes
+        space.unmap(addr, size);
+        addr
+    } else {
+        if size > MMAP_END - state.mmap_next {
+            return -ENOMEM;
+        }
+        let va = state.mmap_next;
+        state.mmap_next += size;
+        va
+    };
+
+    // PROT_NONE only reserves the addresses
+    if prot != 0 && !space.map_zeroed(va, size, prot_attrs(prot)) {
+        space.unmap(va, size);
+        return -ENOMEM;
+    }
+    va as i64
+}
+
+fn munmap(space: &mut AddressSpace, addr: u64, len: u64) -> i64 {
+    match user_pages(addr, len) {
+        Some(size) if space.unmap(addr, size) => 0,
+        Some(_) => -ENOMEM,
+        None => -EINVAL,
+    }
+}
+
+fn mprotect(space: &mut AddressSpace, addr: u64, len: u64, prot: u64) -> i64 {
+    match user_pages(addr, len) {
+        Some(size) if space.protect(addr, size, prot_attrs(prot)) => 0,
+        Some(_) => -ENOMEM,
+        None => -EINVAL,
+    }
+}
+
+fn clock_gettime(space: &AddressSpace, clock: u64, tp: u64) -> i64 {
+    // REALTIME, MONOTONIC, MONOTONIC_RAW, REALTIME_COARSE, MONOTONIC_COARSE,
+    // BOOTTIME: all the same clock here
+    if ![0, 1, 4, 5, 6, 7].contains(&clock) {
+        return -EINVAL;
+    }
+    let ns = timer::counter() as u128 * 1_000_000_000 / timer::frequency() as u128;
+    let mut timespec = [0; 16];
+    timespec[..8].copy_from_slice(&((ns / 1_000_000_000) as u64).to_le_bytes());
+    timespec[8..].copy_from_slice(&((ns % 1_000_000_000) as u64).to_le_bytes());
+    if space.copy_out(tp, &timespec) { 0 } else { -EFAULT }
+}
+
+/// Nothing is ever blocked: the old mask, if asked for, is empty
+fn rt_sigprocmask(space: &AddressSpace, oldset: u64, sigsetsize: u64) -> i64 {
+    if sigsetsize != 8 {
+        return -EINVAL;
+    }
+    if oldset != 0 && !space.copy_out(oldset, &[0; 8]) {
+        return -EFAULT;
+    }
+    0
+}
+
+fn getrandom(space: &AddressSpace, buf: u64, len: u64) -> i64 {
+    let mut done = 0;
+    let mut bytes = [0; CHUNK];
+    while done < len {
+        let n = (len - done).min(CHUNK as u64) as usize;
+        process::fill_random(&mut bytes[..n]);
+        if !space.copy_out(buf.wrapping_add(done), &bytes[..n]) {
+            return partial(done, EFAULT);
+        }
+        done += n as u64;
+    }
+    done as i64
+}
+
+#[kernel_test]
+fn syscalls() -> Outcome {
+    if !mmu::enabled() {
+        return Outcome::Skip("MMU is off");
+    }
+    let pages = memory::pages_in_use();
+
+    // Strings the calls below point at, loaded read-only with the program:
+    // a message, a missing path, the path of a file in the initrd and two
+    // paths in /tmp
+    let message = b"# write() from a program\n";
+    let missing = b"/no/such/file\0";
+    let scratch = b"/tmp/program.txt\0";
+    let file = initrd::archive().and_then(|a| a.entries().find(|e| e.is_file() && e.path.len() < 63));
+    let mut data = [0; 160];
+    data[..message.len()].copy_from_slice(message);
+    data[32..32 + missing.len()].copy_from_slice(missing);
+    data[128..128 + scratch.len()].copy_from_slice(scratch);
+    data[152..157].copy_from_slice(b"/tmp\0");
+    if let Some(entry) = file {
+        data[64] = b'/';
+        data[65..65 + entry.path.len()].copy_from_slice(entry.path.as_bytes());
+    }
+    let mut image = [0; 512];
+    let (size, data_va) = process::tiny_elf(&[], &data, &mut image);
+    let mut program = match process::load(&image[..size]) {
+        Ok(program) => program,
+        Err(err) => {
+            let _ = writeln!(ConsoleWriter, "# load: {}", err);
+            return Outcome::Fail("could not load the test program");
+        }
+    };
+
+    let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+    let mut call = |nr: u64, args: &[u64]| -> i64 {
+        frame.x[8] = nr;
+        frame.x[..args.len()].copy_from_slice(args);
+        match program.syscall(&mut frame) {
+            Some(_) => i64::MIN,
+            None => frame.x[0] as i64,
+        }
+    };
+
+    let checks: &[(&str, bool)] = &[
+        ("write", call(SYS_WRITE, &[1, data_va, message.len() as u64]) == message.len() as i64),
+        ("write to a closed fd", call(SYS_WRITE, &[3, data_va, 1]) == -EBADF),
+        ("openat of a missing file", call(SYS_OPENAT, &[AT_FDCWD as u64, data_va + 32, 0]) == -ENOENT),
+        ("openat for writing", call(SYS_OPENAT, &[AT_FDCWD as u64, data_va + 32, 1]) == -ENOENT),
+        // "/file", the tail of the missing path, in the read-only root
+        ("openat to create", call(SYS_OPENAT, &[AT_FDCWD as u64, data_va + 40, 1 | O_CREAT]) == -EROFS),
+        ("rt_sigprocmask", call(SYS_RT_SIGPROCMASK, &[0, 0, 0, 8]) == 0),
+        // The old mask is written out, here to read-only memory
+        ("rt_sigprocmask oldset", call(SYS_RT_SIGPROCMASK, &[0, 0, data_va, 8]) == -EFAULT),
+        ("rt_sigprocmask size", call(SYS_RT_SIGPROCMASK, &[0, 0, 0, 4]) == -EINVAL),
+        ("unknown call", call(4000, &[]) == -ENOSYS),
+        ("close", call(SYS_CLOSE, &[2]) == 0 && call(SYS_CLOSE, &[2]) == -EBADF),
+    ];
+    for &(name, ok) in checks {
+        if !ok {
+            let _ = writeln!(ConsoleWriter, "# {} failed", name);
+            return Outcome::Fail("system call returned the wrong result");
+        }
+    }
+
+    // The heap grows and shrinks; memory past the break is gone
+    let start = call(SYS_BRK, &[0]) as u64;
+    if call(SYS_BRK, &[start + 10_000]) != (start + 10_000) as i64
+        || call(SYS_GETRANDOM, &[start + 9_000, 1_000, 0]) != 1_000
+        || call(SYS_CLOCK_GETTIME, &[1, start]) != 0
+        || call(SYS_BRK, &[start]) != start as i64
+        || call(SYS_GETRANDOM, &[start, 1, 0]) != -EFAULT
+    {
+        return Outcome::Fail("brk did not grow and shrink the heap");
+    }
+
+    // Anonymous mappings come and go the same way
+    let addr = call(SYS_MMAP, &[0, 3 * PAGE_SIZE, 3, 0x22, u64::MAX, 0]);
+    if addr <= 0
+        || call(SYS_GETRANDOM, &[addr as u64 + PAGE_SIZE - 8, 16, 0]) != 16
+        || call(SYS_MUNMAP, &[addr as u64, 3 * PAGE_SIZE]) != 0
+        || call(SYS_GETRANDOM, &[addr as u64, 1, 0]) != -EFAULT
+    {
+        return Outcome::Fail("mmap/munmap did not map and unmap memory");
+    }
+
+    // Reading a file from the initrd, if there is one
+    if let Some(entry) = file {
+        let buf = call(SYS_MMAP, &[0, PAGE_SIZE, 3, 0x22, u64::MAX, 0]) as u64;
+        let fd = call(SYS_OPENAT, &[AT_FDCWD as u64, data_va + 64, 0]);
+        let want = entry.data.len().min(64) as i64;
+        if fd < 0 || call(SYS_READ, &[fd as u64, buf, 64]) != want || call(SYS_CLOSE, &[fd as u64]) != 0 {
+            return Outcome::Fail("could not read an initrd file");
+        }
+    }
+
+    // A file in /tmp is created, written, read back, listed and removed,
+    // and a directory made and removed in its place
+    let buf = call(SYS_MMAP, &[0, PAGE_SIZE, 3, 0x22, u64::MAX, 0]) as u64;
+    let (tmp, scratch) = (data_va + 152, data_va + 128);
+    let fd = call(SYS_OPENAT, &[AT_FDCWD as u64, scratch, O_RDWR | O_CREAT | O_EXCL]) as u64;
+    let checks: &[(&str, bool)] = &[
+        ("create", (fd as i64) >= 3),
+        ("exclusive create", call(SYS_OPENAT, &[AT_FDCWD as u64, scratch, O_WRONLY | O_CREAT | O_EXCL]) == -EEXIST),
+        ("write to a file", call(SYS_WRITE, &[fd, data_va, message.len() as u64]) == message.len() as i64),
+        ("lseek", call(SYS_LSEEK, &[fd, 2, 0]) == 2),
+        ("read from a file", call(SYS_READ, &[fd, buf, 64]) == message.len() as i64 - 2),
+        ("fstat", call(SYS_FSTAT, &[fd, buf]) == 0),
+        ("close a file", call(SYS_CLOSE, &[fd]) == 0),
+        ("newfstatat", call(SYS_NEWFSTATAT, &[AT_FDCWD as u64, tmp, buf, 0]) == 0),
+        ("getdents64", {
+            let dir = call(SYS_OPENAT, &[AT_FDCWD as u64, tmp, O_DIRECTORY]) as u64;
+            let listed = call(SYS_GETDENTS64, &[dir, buf, PAGE_SIZE]);
+            listed > 0 && call(SYS_GETDENTS64, &[dir, buf, PAGE_SIZE]) == 0 && call(SYS_CLOSE, &[dir]) == 0
+        }),
+        ("getdents64 of a file", call(SYS_GETDENTS64, &[1, buf, PAGE_SIZE]) == -ENOTDIR),
+        ("rmdir of a file", call(SYS_UNLINKAT, &[AT_FDCWD as u64, scratch, AT_REMOVEDIR]) == -ENOTDIR),
+        ("unlink", call(SYS_UNLINKAT, &[AT_FDCWD as u64, scratch, 0]) == 0),
+        ("open after unlink", call(SYS_OPENAT, &[AT_FDCWD as u64, scratch, 0]) == -ENOENT),
+        ("mkdirat", call(SYS_MKDIRAT, &[AT_FDCWD as u64, scratch, 0o755]) == 0),
+        ("unlink of a directory", call(SYS_UNLINKAT, &[AT_FDCWD as u64, scratch, 0]) == -EISDIR),
+        ("rmdir", call(SYS_UNLINKAT, &[AT_FDCWD as u64, scratch, AT_REMOVEDIR]) == 0),
+    ];
+    for &(name, ok) in checks {
+        if !ok {
+            let _ = writeln!(ConsoleWriter, "# {} failed", name);
+            return Outcome::Fail("file system call returned the wrong result");
+        }
+    }
+
+    if call(SYS_EXIT_GROUP, &[3]) != i64::MIN {
+        return Outcome::Fail("exit_group did not end the program");
+    }
+    drop(program);
+    Outcome::check(memory::pages_in_use() == pages, "program memory was not freed")
+}
@@ -0,0 +1,517 @@
+//! Kernel threads and a preemptive round-robin scheduler
+//!
+//! Threads run at EL1 on fixed stacks from a static array (there is no heap);
+//! the closure passed to spawn() is moved onto the top of its new stack. The
+//! highest-priority ready thread runs, round-robin among equals. When the
+//! timer tick is running (timer.rs, through the GIC) the current thread is
+//! preempted at the end of the tick IRQ; without a GIC, threads switch only
+//! when they yield, sleep or block.
+//!
+//! Thread 0 is the boot thread (kmain on the boot stack). An idle thread at
+//! the lowest priority polls the drivers' used rings so that threads blocked
+//! in IoEvent::block_until() make progress.
+//!
+//! A thread running a program (process.rs) has its own TTBR0_EL1 value,
+//! which schedule() loads when it switches to that thread.
+//!
+//! Single core only: scheduler state is an IrqSpinLock, and a thread switch
+//! always happens with IRQs masked.
+
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use kernel_macros::kernel_test;
+
+use crate::ktest::Outcome;
+use crate::sync::{irq_restore, irq_save, IrqSpinLock};
+use crate::{executor, mmu, timer};
+
+global_asm!(include_str!("asm/switch.s"));
+
+/// Thread slots, including the boot and idle threads (at most 32, see WaitQueue)
+pub const MAX_THREADS: usize = 16;
+const STACK_SIZE: usize = 16 * 1024;
+
+const BOOT_THREAD: usize = 0;
+
+/// Scheduling priority; a ready thread never waits for a lower one
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Priority {
+    Low = 1,
+    Normal = 2,
+    High = 3,
+}
+
+// Below every Priority: only runs when nothing else can
+const IDLE_PRIORITY: u8 = 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Free,
+    Ready,
+    Running,
+    Blocked,
+    Sleeping,
+    Exited,
+}
+
+/// Callee-saved registers (layout shared with asm/switch.s)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Context {
+    x19_x28: [u64; 10],
+    fp: u64,
+    lr: u64,
+    sp: u64,
+    d8_d15: [u64; 8],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl Context {
+    const fn zero() -> Self {
+        Context { x19_x28: [0; 10], fp: 0, lr: 0, sp: 0, d8_d15: [0; 8], fpcr: 0, fpsr: 0 }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Thread {
+    state: State,
+    priority: u8,
+    // Nobody will join: free the slot as soon as the thread exits
+    detached: bool,
+    // Counter value to wake at while Sleeping
+    wake_at: u64,
+    exit_code: usize,
+    // Translation table root (0 for the kernel's own map)
+    ttbr0: u64,
+    context: Context,
+}
+
+const FREE_THREAD: Thread = Thread {
+    state: State::Free,
+    priority: IDLE_PRIORITY,
+    detached: false,
+    wake_at: 0,
+    exit_code: 0,
+    ttbr0: 0,
+    context: Context::zero(),
+};
+
+struct Scheduler {
+    threads: [Thread; MAX_THREADS],
+    current: usize,
+}
+
+impl Scheduler {
+    /// Choose the next thread to run and update bookkeeping on the way
+    fn pick_next(&mut self) -> usize {
+        let now = timer::counter();
+        for (id, t) in self.threads.iter_mut().enumerate() {
+            match t.state {
+                State::Sleeping if now >= t.wake_at => t.state = State::Ready,
+                State::Exited if t.detached && id != self.current => *t = FREE_THREAD,
+                _ => {}
+            }
+        }
+
+        // Scan from the thread after the current one so equal priorities
+        // take turns; the current thread itself is considered last
+        let mut best: Option<usize> = None;
+        for offset in 1..=MAX_THREADS {
+            let id = (self.current + offset) % MAX_THREADS;
+            if self.threads[id].state != State::Ready {
+                continue;
+            }
+            if best.map_or(true, |b| self.threads[id].priority > self.threads[b].priority) {
+                best = Some(id);
+            }
+        }
+        best.unwrap_or(self.current)
+    }
+}
+
+static SCHED: IrqSpinLock<Scheduler> = IrqSpinLock::new(Scheduler {
+    threads: [FREE_THREAD; MAX_THREADS],
+    current: BOOT_THREAD,
+});
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+// Woken whenever a thread exits, for join()
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Stacks for every thread except the boot thread
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+struct Stacks(UnsafeCell<[Stack; MAX_THREADS - 1]>);
+
+// A stack is only touched by spawn() while its slot is Free, then by its thread
+unsafe impl Sync for Stacks {}
+
+static STACKS: Stacks = Stacks(UnsafeCell::new([const { Stack([0; STACK_SIZE]) }; MAX_THREADS - 1]));
+
+extern "C" {
+    fn context_switch(prev: *mut Context, next: *const Context);
+    fn thread_start();
+}
+
+/// Make the boot flow thread 0 and start the idle thread
+pub fn init() {
+    {
+        let mut s = SCHED.lock();
+        s.threads[BOOT_THREAD].state = State::Running;
+        s.threads[BOOT_THREAD].priority = Priority::Normal as u8;
+        s.current = BOOT_THREAD;
+    }
+    STARTED.store(true, Ordering::Release);
+
+    if let Some(idle) = spawn_at(IDLE_PRIORITY, idle_loop) {
+        idle.detach();
+    }
+}
+
+fn idle_loop() -> usize {
+    loop {
+        executor::poll_io();
+        yield_now();
+    }
+}
+
+/// True once init() has run
+pub fn started() -> bool {
+    STARTED.load(Ordering::Acquire)
+}
+
+/// Handle to a spawned thread; dropping it detaches the thread
+pub struct JoinHandle {
+    id: usize,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Wait for the thread to exit and return its exit code
+    pub fn join(self) -> usize {
+        let id = self.id;
+        core::mem::forget(self);
+
+        EXITED.wait_until(|| SCHED.lock().threads[id].state == State::Exited);
+
+        let mut s = SCHED.lock();
+        let code = s.threads[id].exit_code;
+        s.threads[id] = FREE_THREAD;
+        code
+    }
+
+    /// Let the thread run on without anyone waiting for it
+    pub fn detach(self) {}
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut s = SCHED.lock();
+        let t = &mut s.threads[self.id];
+        if t.state == State::Exited {
+            *t = FREE_THREAD;
+        } else {
+            t.detached = true;
+        }
+    }
+}
+
+/// Start a thread at normal priority; None if every slot is taken
+pub fn spawn<F>(f: F) -> Option<JoinHandle>
+where
+    F: FnOnce() -> usize + Send + 'static,
+{
+    spawn_with_priority(Priority::Normal, f)
+}
+
+pub fn spawn_with_priority<F>(priority: Priority, f: F) -> Option<JoinHandle>
+where
+    F: FnOnce() -> usize + Send + 'static,
+{
+    spawn_at(priority as u8, f)
+}
+
+fn spawn_at<F>(priority: u8, f: F) -> Option<JoinHandle>
+where
+    F: FnOnce() -> usize + Send + 'static,
+{
+    if !started() || core::mem::size_of::<F>() > STACK_SIZE / 2 {
+        return None;
+    }
+
+    let mut s = SCHED.lock();
+    let id = s.threads.iter().position(|t| t.state == State::Free)?;
+
+    // Move the closure to the top of the new stack; the thread starts below it
+    let base = STACKS.0.get() as usize + (id - 1) * STACK_SIZE;
+    let closure = (base + STACK_SIZE - core::mem::size_of::<F>()) & !(core::mem::align_of::<F>().max(16) - 1);
+    unsafe { (closure as *mut F).write(f) };
+
+    let mut context = Context::zero();
+    context.x19_x28[0] = closure as u64;
+    context.x19_x28[1] = thread_entry::<F> as extern "C" fn(*mut F) -> ! as usize as u64;
+    context.lr = thread_start as unsafe extern "C" fn() as usize as u64;
+    context.sp = closure as u64 & !15;
+
+    s.threads[id] = Thread {
+        state: State::Ready,
+        priority,
+        detached: false,
+        wake_at: 0,
+        exit_code: 0,
+        ttbr0: 0,
+        context,
+    };
+    Some(JoinHandle { id })
+}
+
+/// Reached from thread_start with the closure stored by spawn_at()
+extern "C" fn thread_entry<F: FnOnce() -> usize>(f: *mut F) -> ! {
+    let f = unsafe { f.read() };
+    exit(f())
+}
+
+/// Finish the current thread with an exit code for join()
+pub fn exit(code: usize) -> ! {
+    // Descriptors die with their thread
+    crate::vfs::close_all();
+    let _daif = irq_save();
+    {
+        let mut s = SCHED.lock();
+        let current = s.current;
+        s.threads[current].exit_code = code;
+        s.threads[current].state = State::Exited;
+    }
+    EXITED.wake_all();
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Id of the running thread
+pub fn current() -> usize {
+    SCHED.lock().current
+}
+
+/// Run the current thread on other translation tables from now on
+/// (an AddressSpace's ttbr0, or 0 for the kernel's map)
+pub fn set_address_space(ttbr0: u64) {
+    let daif = irq_save();
+    {
+        let mut s = SCHED.lock();
+        let current = s.current;
+        s.threads[current].ttbr0 = ttbr0;
+    }
+    mmu::switch(ttbr0);
+    irq_restore(daif);
+}
+
+/// Give the CPU to another ready thread of the same or higher priority
+pub fn yield_now() {
+    if started() {
+        schedule();
+    }
+}
+
+/// Sleep for at least `ms` milliseconds
+pub fn sleep(ms: u64) {
+    let deadline = timer::deadline_after_ms(ms);
+    if !started() {
+        timer::delay_ms(ms);
+        return;
+    }
+
+    let daif = irq_save();
+    {
+        let mut s = SCHED.lock();
+        let current = s.current;
+        s.threads[current].wake_at = deadline;
+        s.threads[current].state = State::Sleeping;
+    }
+    schedule();
+    irq_restore(daif);
+}
+
+/// Timer tick: the running thread's time slice is over
+pub fn tick() {
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+/// End of an IRQ: switch threads if a tick asked for it
+pub fn preempt() {
+    if started() && NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+/// Mark the current thread Blocked and switch away; IRQs must be masked
+fn block_current() {
+    {
+        let mut s = SCHED.lock();
+        let current = s.current;
+        s.threads[current].state = State::Blocked;
+    }
+    schedule();
+}
+
+fn unblock(id: usize) {
+    let mut s = SCHED.lock();
+    if s.threads[id].state == State::Blocked {
+        s.threads[id].state = State::Ready;
+    }
+}
+
+/// Switch to the best ready thread, which may be the current one
+fn schedule() {
+    let daif = irq_save();
+    let switch = {
+        let mut s = SCHED.lock();
+        let prev = s.current;
+        if s.threads[prev].state == State::Running {
+            s.threads[prev].state = State::Ready;
+        }
+        let next = s.pick_next();
+        s.threads[next].state = State::Running;
+        s.current = next;
+
+        // The contexts are only touched here, with IRQs masked on the one
+        // core, so they stay valid after the lock is released
+        if prev != next && s.threads[prev].ttbr0 != s.threads[next].ttbr0 {
+            mmu::switch(s.threads[next].ttbr0);
+        }
+        (prev != next).then(|| {
+            (
+                &mut s.threads[prev].context as *mut Context,
+                &s.threads[next].context as *const Context,
+            )
+        })
+    };
+
+    if let Some((prev, next)) = switch {
+        unsafe { context_switch(prev, next) };
+    }
+    irq_restore(daif);
+}
+
+/// Threads blocked until a condition holds
+///
+/// Wakers (other threads, IRQ handlers, IoEvent::wake) only make the
+/// sleepers runnable; each one re-checks its condition before returning.
+pub struct WaitQueue {
+    // One bit per thread id
+    waiters: AtomicU32,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: AtomicU32::new(0) }
+    }
+
+    /// Block the current thread until `cond` returns true
+    /// Before init() this busy-polls the drivers instead
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        loop {
+            // Checking and blocking with IRQs masked means a wake-up from
+            // an interrupt handler cannot slip in between
+            let daif = irq_save();
+            if cond() {
+                irq_restore(daif);
+                return;
+            }
+            if started() {
+                self.waiters.fetch_or(1 << current(), Ordering::AcqRel);
+                block_current();
+                irq_restore(daif);
+            } else {
+                irq_restore(daif);
+                executor::poll_io();
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Wake the lowest-numbered waiter; false if there was none
+    pub fn wake_one(&self) -> bool {
+        let taken = self
+            .waiters
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |w| (w != 0).then(|| w & (w - 1)));
+        match taken {
+            Ok(waiters) => {
+                unblock(waiters.trailing_zeros() as usize);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Wake every waiter
+    pub fn wake_all(&self) {
+        let mut waiters = self.waiters.swap(0, Ordering::AcqRel);
+        while waiters != 0 {
+            unblock(waiters.trailing_zeros() as usize);
+            waiters &= waiters - 1;
+        }
+    }
+}
+
+/// Exercise spawn/join, yield, sleep and wait queues
+/// With the timer tick running, also check that a thread spinning without
+/// yielding is preempted
+pub fn test_threads() -> bool {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    static GO: AtomicBool = AtomicBool::new(false);
+    static GO_QUEUE: WaitQueue = WaitQueue::new();
+
+    let mut workers: [Option<JoinHandle>; 3] = [None, None, None];
+    for (i, slot) in workers.iter_mut().enumerate() {
+        *slot = spawn(move || {
+            GO_QUEUE.wait_until(|| GO.load(Ordering::Acquire));
+            for _ in 0..100 {
+                COUNTER.fetch_add(1, Ordering::Relaxed);
+                yield_now();
+            }
+            if i == 0 {
+                sleep(10);
+            }
+            i + 1
+        });
+    }
+
+    GO.store(true, Ordering::Release);
+    GO_QUEUE.wake_all();
+
+    let mut ok = workers.iter().all(Option::is_some);
+    for (i, worker) in workers.into_iter().enumerate() {
+        ok &= worker.map(JoinHandle::join) == Some(i + 1);
+    }
+    ok &= COUNTER.load(Ordering::Relaxed) == 300;
+
+    if timer::is_ticking() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let spinner = spawn(|| {
+            while !STOP.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+            0
+        });
+        // We only get here again if the spinner is preempted
+        yield_now();
+        STOP.store(true, Ordering::Relaxed);
+        ok &= spinner.map(JoinHandle::join) == Some(0);
+    }
+
+    ok
+}
+
+#[kernel_test]
+fn threads() -> Outcome {
+    Outcome::check(test_threads(), "spawn/join, sleep, wait queue or preemption check failed")
+}
@@ -0,0 +1,75 @@
+//! ARM generic timer
+//!
+//! The virtual counter is the time base everywhere (VZ, HVF and QEMU all
+//! expose it), and the EL1 virtual timer (PPI 27) drives the scheduler tick.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::{isb, read_sysreg, write_sysreg};
+use crate::gic;
+
+/// Virtual timer private peripheral interrupt
+pub const TIMER_INTID: u32 = 27;
+
+/// CNTV_CTL_EL0.ENABLE (IMASK clear)
+const CNTV_CTL_ENABLE: u64 = 1;
+
+// Counter ticks per scheduler tick; 0 until start_tick()
+static TICK_INTERVAL: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counter frequency in Hz
+pub fn frequency() -> u64 {
+    read_sysreg!("cntfrq_el0")
+}
+
+/// Current value of the virtual counter
+pub fn counter() -> u64 {
+    isb();
+    read_sysreg!("cntvct_el0")
+}
+
+/// Counter value `ms` milliseconds from now
+pub fn deadline_after_ms(ms: u64) -> u64 {
+    counter() + ms * frequency() / 1000
+}
+
+/// Milliseconds since the counter started (roughly, since boot)
+pub fn uptime_ms() -> u64 {
+    counter() * 1000 / frequency()
+}
+
+/// Busy-wait for `ms` milliseconds
+pub fn delay_ms(ms: u64) {
+    let deadline = deadline_after_ms(ms);
+    while counter() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Start a periodic interrupt at `hz` through the GIC
+pub fn start_tick(hz: u64) {
+    let interval = frequency() / hz;
+    TICK_INTERVAL.store(interval, Ordering::Relaxed);
+
+    write_sysreg!("cntv_tval_el0", interval);
+    write_sysreg!("cntv_ctl_el0", CNTV_CTL_ENABLE);
+    isb();
+    gic::enable(TIMER_INTID);
+}
+
+/// True once start_tick() has run
+pub fn is_ticking() -> bool {
+    TICK_INTERVAL.load(Ordering::Relaxed) != 0
+}
+
+/// Number of scheduler ticks so far
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Timer interrupt: re-arm for the next tick
+pub fn handle_irq() {
+    write_sysreg!("cntv_tval_el0", TICK_INTERVAL.load(Ordering::Relaxed));
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,274 @@
+//! The kernel's file namespace
+//!
+//! One driver_core::vfs::Vfs holds every filesystem: the initrd's archive
+//! at "/" (read-only) and a FAT32 RAM disk at "/tmp" for scratch files,
+//! formatted at boot. More can be added with mount(), say a partition of
+//! a virtio disk opened as FatFs or ExtFs.
+//!
+//! Kernel threads each get a descriptor table, used through open(),
+//! read(), write() and the rest here; it is emptied when the thread exits.
+//! Programs at EL0 keep theirs in syscall::State and go through with().
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+
+use driver_core::block::RamDisk;
+use driver_core::cpio::Archive;
+use driver_core::fat::{self, FatFs};
+use driver_core::vfs::{Dir, DirEntry, Error, FdTable, File, FileSystem, FileType, OpenFlags, SeekFrom, Stat, Vfs};
+use kernel_macros::kernel_test;
+
+use crate::cmdline::LogLevel;
+use crate::ktest::Outcome;
+use crate::sync::{Once, SpinLock};
+use crate::thread::{self, MAX_THREADS};
+use crate::{initrd, ConsoleWriter, LogWriter};
+
+/// Size of the RAM disk behind /tmp
+const TMP_BYTES: usize = 256 * 1024;
+const TMP_BLOCK: usize = 512;
+
+/// What a kernel thread's descriptor refers to
+#[derive(Clone, Copy)]
+pub enum Handle {
+    File(File),
+    Dir(Dir),
+}
+
+/// The mount table, and the filesystem behind /tmp
+///
+/// Vfs refers to filesystems as `&dyn FileSystem`, which is not Send:
+/// FatFs, for one, keeps Cells and RefCells. Here they are the initrd's
+/// archive (bytes that never change), filesystems mount() took as Sync,
+/// and `tmp`, which lives alongside the table.
+struct Namespace {
+    vfs: Vfs<'static>,
+    tmp: Option<FatFs<RamDisk<'static>>>,
+}
+
+// The one filesystem here that is not Sync is `tmp`, and it is reached only
+// through NAMESPACE, locked
+unsafe impl Send for Namespace {}
+
+static NAMESPACE: SpinLock<Namespace> = SpinLock::new(Namespace { vfs: Vfs::new(), tmp: None });
+static INITRD: Once<Archive<'static>> = Once::new();
+
+struct TmpDisk(UnsafeCell<[u8; TMP_BYTES]>);
+
+// Borrowed once, by the first init() (with NAMESPACE held)
+unsafe impl Sync for TmpDisk {}
+
+static TMP_DISK: TmpDisk = TmpDisk(UnsafeCell::new([0; TMP_BYTES]));
+
+/// An archive holding only its trailer, mounted at "/" when there is no
+/// initrd so that every path still resolves to a read-only filesystem
+const EMPTY_ROOT: &[u8] = concat!(
+    "070701",
+    "00000000", "00000000", "00000000", "00000000", "00000000", "00000000",
+    "00000000", "00000000", "00000000", "00000000", "00000000",
+    "0000000B", "00000000",
+    "TRAILER!!!\0\0\0\0",
+)
+.as_bytes();
+
+static TABLES: [SpinLock<FdTable<Handle>>; MAX_THREADS] = [const { SpinLock::new(FdTable::new()) }; MAX_THREADS];
+
+/// Mount the initrd (or an empty archive) at "/" and a fresh RAM disk at
+/// "/tmp"
+pub fn init() {
+    let mut namespace = NAMESPACE.lock();
+    if INITRD.get().is_some() {
+        // Already done
+        return;
+    }
+    if initrd::archive().is_some() {
+        let _ = writeln!(LogWriter(LogLevel::Info), "VFS: initrd at /");
+    }
+    if let Some(root) = initrd::archive().or_else(|| Archive::new(EMPTY_ROOT)) {
+        let _ = namespace.vfs.mount("/", INITRD.call_once(|| root));
+    }
+
+    let data: &'static mut [u8] = unsafe { &mut *TMP_DISK.0.get() };
+    let tmp = RamDisk::new(data, TMP_BLOCK)
+        .ok_or(Error::Io("bad RAM disk block size"))
+        .and_then(|disk| fat::format(&disk, "TMP", 0).and_then(|_| FatFs::new(disk)));
+    let namespace = &mut *namespace;
+    let mounted = tmp.and_then(|fs| {
+        let fs = namespace.tmp.insert(fs);
+        // NAMESPACE is a static and `tmp` is never replaced, so the FatFs
+        // stays put for as long as the table refers to it
+        namespace.vfs.mount("/tmp", unsafe { &*(fs as *const FatFs<RamDisk<'static>>) })
+    });
+    match mounted {
+        Ok(()) => {
+            let _ = writeln!(LogWriter(LogLevel::Info), "VFS: {} KiB RAM disk at /tmp", TMP_BYTES / 1024);
+        }
+        Err(err) => {
+            let _ = writeln!(LogWriter(LogLevel::Warn), "VFS: no /tmp: {}", err);
+        }
+    }
+}
+
+/// Run `f` on the namespace, locked
+pub fn with<R>(f: impl FnOnce(&Vfs<'static>) -> R) -> R {
+    f(&NAMESPACE.lock().vfs)
+}
+
+/// Mount `fs` at `path`
+/// It must be Sync: other threads use it too, through the namespace.
+pub fn mount(path: &str, fs: &'static (dyn FileSystem + Sync)) -> Result<(), Error> {
+    NAMESPACE.lock().vfs.mount(path, fs)
+}
+
+/// Unmount whatever is at `path`; descriptors open on it go stale
+pub fn unmount(path: &str) -> Result<(), Error> {
+    NAMESPACE.lock().vfs.unmount(path).map(drop)
+}
+
+fn table() -> &'static SpinLock<FdTable<Handle>> {
+    &TABLES[thread::current()]
+}
+
+/// Run `f` on the current thread's file `fd`
+fn with_file<R>(fd: usize, f: impl FnOnce(&Vfs<'static>, &mut File) -> Result<R, Error>) -> Result<R, Error> {
+    match table().lock().get_mut(fd) {
+        Some(Handle::File(file)) => with(|vfs| f(vfs, file)),
+        Some(Handle::Dir(_)) => Err(Error::IsADirectory),
+        None => Err(Error::BadDescriptor),
+    }
+}
+
+/// Open the file at `path` for the current thread; its descriptor
+pub fn open(path: &str, flags: OpenFlags) -> Result<usize, Error> {
+    let file = with(|vfs| vfs.open(path, flags))?;
+    table().lock().insert(Handle::File(file))
+}
+
+/// Open the directory at `path` for the current thread; its descriptor
+pub fn open_dir(path: &str) -> Result<usize, Error> {
+    let dir = with(|vfs| vfs.open_dir(path))?;
+    table().lock().insert(Handle::Dir(dir))
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, Error> {
+    with_file(fd, |vfs, file| vfs.read(file, buf))
+}
+
+pub fn write(fd: usize, data: &[u8]) -> Result<usize, Error> {
+    with_file(fd, |vfs, file| vfs.write(file, data))
+}
+
+pub fn seek(fd: usize, to: SeekFrom) -> Result<u64, Error> {
+    with_file(fd, |vfs, file| vfs.seek(file, to))
+}
+
+pub fn fstat(fd: usize) -> Result<Stat, Error> {
+    match table().lock().get(fd) {
+        Some(Handle::File(file)) => with(|vfs| vfs.fstat(file)),
+        Some(Handle::Dir(_)) => Err(Error::IsADirectory),
+        None => Err(Error::BadDescriptor),
+    }
+}
+
+/// Next entry of directory `fd`; None at the end
+pub fn read_dir(fd: usize) -> Result<Option<DirEntry>, Error> {
+    match table().lock().get_mut(fd) {
+        Some(Handle::Dir(dir)) => with(|vfs| vfs.next_entry(dir)),
+        Some(Handle::File(_)) => Err(Error::NotADirectory),
+        None => Err(Error::BadDescriptor),
+    }
+}
+
+pub fn close(fd: usize) -> Result<(), Error> {
+    table().lock().remove(fd).map(drop).ok_or(Error::BadDescriptor)
+}
+
+/// Close all of the current thread's descriptors; thread::exit() does
+pub fn close_all() {
+    table().lock().clear();
+}
+
+pub fn stat(path: &str) -> Result<Stat, Error> {
+    with(|vfs| vfs.stat(path))
+}
+
+pub fn mkdir(path: &str) -> Result<(), Error> {
+    with(|vfs| vfs.mkdir(path))
+}
+
+pub fn remove(path: &str) -> Result<(), Error> {
+    with(|vfs| vfs.remove(path))
+}
+
+#[kernel_test]
+fn vfs() -> Outcome {
+    if NAMESPACE.lock().tmp.is_none() {
+        return Outcome::Fail("no /tmp");
+    }
+    let text = b"written through the VFS\n";
+    let Ok(out) = open("/tmp/ktest.txt", OpenFlags::WRITE) else {
+        return Outcome::Fail("could not create a file in /tmp");
+    };
+    let written = write(out, text);
+    let _ = close(out);
+    if written != Ok(text.len()) {
+        return Outcome::Fail("write to /tmp failed");
+    }
+
+    let Ok(fd) = open("/tmp/ktest.txt", OpenFlags::READ) else {
+        return Outcome::Fail("could not open the file again");
+    };
+    let mut buf = [0; 64];
+    let read_back = read(fd, &mut buf);
+    let tail = seek(fd, SeekFrom::End(-3)).and_then(|_| read(fd, &mut buf[32..]));
+    let size = fstat(fd).map(|stat| stat.size);
+    let _ = close(fd);
+    if read_back != Ok(text.len()) || buf[..text.len()] != text[..] || tail != Ok(3) || size != Ok(text.len() as u64) {
+        return Outcome::Fail("read back the wrong data");
+    }
+    if close(fd) != Err(Error::BadDescriptor) || write(fd, text) != Err(Error::BadDescriptor) {
+        return Outcome::Fail("closed descriptor still usable");
+    }
+
+    let Ok(dir) = open_dir("/tmp") else {
+        return Outcome::Fail("could not list /tmp");
+    };
+    let mut listed = false;
+    while let Ok(Some(entry)) = read_dir(dir) {
+        listed |= entry.name() == b"ktest.txt";
+    }
+    let _ = close(dir);
+    if !listed {
+        return Outcome::Fail("new file not listed");
+    }
+    if remove("/tmp/ktest.txt").is_err() || stat("/tmp/ktest.txt") != Err(Error::NotFound) {
+        return Outcome::Fail("remove did not remove the file");
+    }
+    let made = mkdir("/tmp/kdir").and_then(|_| stat("/tmp/kdir"));
+    if made.map(|stat| stat.file_type) != Ok(FileType::Dir) || remove("/tmp/kdir").is_err() {
+        return Outcome::Fail("could not make and remove a directory");
+    }
+
+    // Files from the initrd read the same through the VFS
+    let Some(archive) = initrd::archive() else {
+        return Outcome::Pass;
+    };
+    let Some(entry) = archive.entries().find(|e| e.is_file() && e.path.len() < 60) else {
+        return Outcome::Pass;
+    };
+    let mut path = [0; 64];
+    path[0] = b'/';
+    path[1..1 + entry.path.len()].copy_from_slice(entry.path.as_bytes());
+    let path = core::str::from_utf8(&path[..1 + entry.path.len()]).unwrap_or("");
+    let want = entry.data.len().min(buf.len());
+    let got = open(path, OpenFlags::READ).and_then(|fd| {
+        let n = read(fd, &mut buf);
+        let _ = close(fd);
+        n
+    });
+    if got != Ok(want) || buf[..want] != entry.data[..want] {
+        let _ = writeln!(ConsoleWriter, "# {}: {:?}", path, got);
+        return Outcome::Fail("initrd file reads differently through the VFS");
+    }
+    Outcome::check(open(path, OpenFlags::WRITE) == Err(Error::ReadOnlyFs), "initrd was writable")
+}
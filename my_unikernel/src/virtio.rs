@@ -5,6 +5,8 @@
 use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::{fence, Ordering};
 
+use crate::sync::IrqSpinLock;
+
 // Virtio MMIO register offsets
 const VIRTIO_MMIO_MAGIC: usize = 0x000;
 const VIRTIO_MMIO_VERSION: usize = 0x004;
@@ -97,16 +99,28 @@ struct TxBuffer {
     data: [u8; 256],
 }
 
-static mut TX_QUEUE: VirtqueueBuffers = VirtqueueBuffers {
-    descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
-    avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
-    _pad: [0; 4096 - core::mem::size_of::<[VringDesc; QUEUE_SIZE as usize]>() - core::mem::size_of::<VringAvail>()],
-    used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
-};
+/// Transmit virtqueue, its buffer and ring indices, shared with the device
+///
+/// Console output may come from interrupt context, so IRQs stay masked
+/// while it is held.
+struct TxQueue {
+    ring: VirtqueueBuffers,
+    buffer: TxBuffer,
+    idx: u16,
+    last_used: u16,
+}
 
-static mut TX_BUFFER: TxBuffer = TxBuffer { data: [0; 256] };
-static mut TX_IDX: u16 = 0;
-static mut LAST_USED_IDX: u16 = 0;
+static TX_QUEUE: IrqSpinLock<TxQueue> = IrqSpinLock::new(TxQueue {
+    ring: VirtqueueBuffers {
+        descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
+        avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
+        _pad: [0; 4096 - core::mem::size_of::<[VringDesc; QUEUE_SIZE as usize]>() - core::mem::size_of::<VringAvail>()],
+        used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
+    },
+    buffer: TxBuffer { data: [0; 256] },
+    idx: 0,
+    last_used: 0,
+});
 
 pub struct VirtioConsole {
     base: usize,
@@ -181,9 +195,14 @@ impl VirtioConsole {
             write_volatile((base + VIRTIO_MMIO_QUEUE_NUM) as *mut u32, QUEUE_SIZE as u32);
 
             // Set queue addresses
-            let desc_addr = &TX_QUEUE.descs as *const _ as u64;
-            let avail_addr = &TX_QUEUE.avail as *const _ as u64;
-            let used_addr = &TX_QUEUE.used as *const _ as u64;
+            let (desc_addr, avail_addr, used_addr) = {
+                let tx = TX_QUEUE.lock();
+                (
+                    &tx.ring.descs as *const _ as u64,
+                    &tx.ring.avail as *const _ as u64,
+                    &tx.ring.used as *const _ as u64,
+                )
+            };
 
             write_volatile((base + VIRTIO_MMIO_QUEUE_DESC_LOW) as *mut u32, desc_addr as u32);
             write_volatile((base + VIRTIO_MMIO_QUEUE_DESC_HIGH) as *mut u32, (desc_addr >> 32) as u32);
@@ -241,39 +260,41 @@ impl VirtioConsole {
 
     /// Write a single character
     pub fn putc(&self, c: u8) {
+        let mut tx = TX_QUEUE.lock();
         unsafe {
             // Wait for space in the queue
             fence(Ordering::SeqCst);
 
-            let idx = TX_IDX % QUEUE_SIZE;
+            let idx = tx.idx % QUEUE_SIZE;
 
             // Set up descriptor
-            TX_BUFFER.data[0] = c;
-            TX_QUEUE.descs[idx as usize] = VringDesc {
-                addr: TX_BUFFER.data.as_ptr() as u64,
+            tx.buffer.data[0] = c;
+            tx.ring.descs[idx as usize] = VringDesc {
+                addr: tx.buffer.data.as_ptr() as u64,
                 len: 1,
                 flags: 0,
                 next: 0,
             };
 
             // Add to available ring
-            let avail_idx = TX_QUEUE.avail.idx;
-            TX_QUEUE.avail.ring[(avail_idx % QUEUE_SIZE) as usize] = idx;
+            let avail_idx = tx.ring.avail.idx;
+            tx.ring.avail.ring[(avail_idx % QUEUE_SIZE) as usize] = idx;
             fence(Ordering::SeqCst);
-            TX_QUEUE.avail.idx = avail_idx.wrapping_add(1);
+            tx.ring.avail.idx = avail_idx.wrapping_add(1);
             fence(Ordering::SeqCst);
 
             // Notify device (queue 1 = transmitq)
             write_volatile((self.base + VIRTIO_MMIO_QUEUE_NOTIFY) as *mut u32, 1);
             fence(Ordering::SeqCst);
 
-            TX_IDX = TX_IDX.wrapping_add(1);
+            tx.idx = tx.idx.wrapping_add(1);
 
             // Simple busy wait for completion
             for _ in 0..10000 {
                 fence(Ordering::SeqCst);
-                if TX_QUEUE.used.idx != LAST_USED_IDX {
-                    LAST_USED_IDX = TX_QUEUE.used.idx;
+                let used_idx = read_volatile(&tx.ring.used.idx);
+                if used_idx != tx.last_used {
+                    tx.last_used = used_idx;
                     break;
                 }
                 core::hint::spin_loop();
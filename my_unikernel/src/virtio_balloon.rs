@@ -3,9 +3,17 @@
 //! Memory ballooning allows the host to reclaim memory from the guest.
 //! Device IDs: 0x1005 (transitional), 0x1045 (modern)
 
+use core::fmt::Write;
 use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::{fence, Ordering};
 
+use kernel_macros::kernel_test;
+
+use crate::ConsoleWriter;
+use crate::ktest::Outcome;
+use crate::memory;
+use crate::sync::SpinLock;
+
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_BALLOON_TRANSITIONAL: u16 = 0x1005;
 const VIRTIO_BALLOON_MODERN: u16 = 0x1045;
@@ -80,33 +88,43 @@ struct VringUsed {
     ring: [VringUsedElem; QUEUE_SIZE as usize],
 }
 
-// Inflate queue (give pages to host)
-static mut INFLATE_QUEUE_DESCS: [VringDesc; QUEUE_SIZE as usize] =
-    [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize];
-static mut INFLATE_QUEUE_AVAIL: VringAvail = VringAvail {
-    flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize]
-};
-static mut INFLATE_QUEUE_USED: VringUsed = VringUsed {
-    flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize]
-};
-
-// Deflate queue (get pages back from host)
-static mut DEFLATE_QUEUE_DESCS: [VringDesc; QUEUE_SIZE as usize] =
-    [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize];
-static mut DEFLATE_QUEUE_AVAIL: VringAvail = VringAvail {
-    flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize]
-};
-static mut DEFLATE_QUEUE_USED: VringUsed = VringUsed {
-    flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize]
-};
-
-// Page frame numbers buffer (balloon uses PFNs, not addresses)
-static mut PFN_BUFFER: [u32; 16] = [0; 16];
-
-static mut INFLATE_IDX: u16 = 0;
-static mut DEFLATE_IDX: u16 = 0;
-static mut INFLATE_LAST_USED: u16 = 0;
-static mut DEFLATE_LAST_USED: u16 = 0;
+/// One virtqueue with its page frame number buffer (balloon uses PFNs,
+/// not addresses) and ring indices, shared with the device
+#[repr(C, align(16))]
+struct BalloonQueue {
+    descs: [VringDesc; QUEUE_SIZE as usize],
+    avail: VringAvail,
+    used: VringUsed,
+    pfns: [u32; 16],
+    idx: u16,
+    last_used: u16,
+}
+
+impl BalloonQueue {
+    const fn new() -> Self {
+        BalloonQueue {
+            descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
+            avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
+            used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
+            pfns: [0; 16],
+            idx: 0,
+            last_used: 0,
+        }
+    }
+
+    /// Physical addresses of the descriptor table, available and used rings
+    fn ring_addrs(&self) -> (u64, u64, u64) {
+        (
+            self.descs.as_ptr() as u64,
+            &self.avail as *const VringAvail as u64,
+            &self.used as *const VringUsed as u64,
+        )
+    }
+}
+
+// Inflate queue gives pages to the host, deflate queue gets them back
+static INFLATE_QUEUE: SpinLock<BalloonQueue> = SpinLock::new(BalloonQueue::new());
+static DEFLATE_QUEUE: SpinLock<BalloonQueue> = SpinLock::new(BalloonQueue::new());
 
 #[derive(Clone, Copy)]
 struct VirtioCap {
@@ -172,9 +190,7 @@ impl VirtioBalloon {
         let actual_size = queue_size_max.min(QUEUE_SIZE);
         write_volatile((common_base + 24) as *mut u16, actual_size);
 
-        let desc_addr = &raw const INFLATE_QUEUE_DESCS as u64;
-        let avail_addr = &raw const INFLATE_QUEUE_AVAIL as u64;
-        let used_addr = &raw const INFLATE_QUEUE_USED as u64;
+        let (desc_addr, avail_addr, used_addr) = INFLATE_QUEUE.lock().ring_addrs();
 
         write_volatile((common_base + 32) as *mut u32, desc_addr as u32);
         write_volatile((common_base + 36) as *mut u32, (desc_addr >> 32) as u32);
@@ -197,9 +213,7 @@ impl VirtioBalloon {
         let actual_size = queue_size_max.min(QUEUE_SIZE);
         write_volatile((common_base + 24) as *mut u16, actual_size);
 
-        let desc_addr = &raw const DEFLATE_QUEUE_DESCS as u64;
-        let avail_addr = &raw const DEFLATE_QUEUE_AVAIL as u64;
-        let used_addr = &raw const DEFLATE_QUEUE_USED as u64;
+        let (desc_addr, avail_addr, used_addr) = DEFLATE_QUEUE.lock().ring_addrs();
 
         write_volatile((common_base + 32) as *mut u32, desc_addr as u32);
         write_volatile((common_base + 36) as *mut u32, (desc_addr >> 32) as u32);
@@ -376,9 +390,7 @@ impl VirtioBalloon {
             let actual_size = queue_size_max.min(QUEUE_SIZE);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
-            let desc_addr = &raw const INFLATE_QUEUE_DESCS as u64;
-            let avail_addr = &raw const INFLATE_QUEUE_AVAIL as u64;
-            let used_addr = &raw const INFLATE_QUEUE_USED as u64;
+            let (desc_addr, avail_addr, used_addr) = INFLATE_QUEUE.lock().ring_addrs();
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -404,9 +416,7 @@ impl VirtioBalloon {
             let actual_size = queue_size_max.min(QUEUE_SIZE);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
-            let desc_addr = &raw const DEFLATE_QUEUE_DESCS as u64;
-            let avail_addr = &raw const DEFLATE_QUEUE_AVAIL as u64;
-            let used_addr = &raw const DEFLATE_QUEUE_USED as u64;
+            let (desc_addr, avail_addr, used_addr) = DEFLATE_QUEUE.lock().ring_addrs();
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -481,16 +491,17 @@ impl VirtioBalloon {
             return false;
         }
 
+        let mut inflate = INFLATE_QUEUE.lock();
         unsafe {
             // Convert addresses to PFNs (page frame numbers)
             for (i, &addr) in page_addrs.iter().enumerate() {
-                PFN_BUFFER[i] = (addr / PAGE_SIZE) as u32;
+                inflate.pfns[i] = (addr / PAGE_SIZE) as u32;
             }
 
-            let idx = INFLATE_IDX % QUEUE_SIZE;
+            let idx = inflate.idx % QUEUE_SIZE;
 
-            INFLATE_QUEUE_DESCS[idx as usize] = VringDesc {
-                addr: PFN_BUFFER.as_ptr() as u64,
+            inflate.descs[idx as usize] = VringDesc {
+                addr: inflate.pfns.as_ptr() as u64,
                 len: (page_addrs.len() * 4) as u32,
                 flags: 0,
                 next: 0,
@@ -498,13 +509,13 @@ impl VirtioBalloon {
 
             fence(Ordering::SeqCst);
 
-            let avail_idx = read_volatile(&INFLATE_QUEUE_AVAIL.idx);
+            let avail_idx = read_volatile(&inflate.avail.idx);
             write_volatile(
-                &raw mut INFLATE_QUEUE_AVAIL.ring[(avail_idx % QUEUE_SIZE) as usize],
+                &mut inflate.avail.ring[(avail_idx % QUEUE_SIZE) as usize],
                 idx,
             );
             fence(Ordering::SeqCst);
-            write_volatile(&raw mut INFLATE_QUEUE_AVAIL.idx, avail_idx.wrapping_add(1));
+            write_volatile(&mut inflate.avail.idx, avail_idx.wrapping_add(1));
             fence(Ordering::SeqCst);
 
             // Notify
@@ -514,14 +525,14 @@ impl VirtioBalloon {
             write_volatile(notify_addr as *mut u16, 0);
             fence(Ordering::SeqCst);
 
-            INFLATE_IDX = INFLATE_IDX.wrapping_add(1);
+            inflate.idx = inflate.idx.wrapping_add(1);
 
             // Wait for completion
             for _ in 0..100_000 {
                 fence(Ordering::SeqCst);
-                let used_idx = read_volatile(&INFLATE_QUEUE_USED.idx);
-                if used_idx != INFLATE_LAST_USED {
-                    INFLATE_LAST_USED = used_idx;
+                let used_idx = read_volatile(&inflate.used.idx);
+                if used_idx != inflate.last_used {
+                    inflate.last_used = used_idx;
                     self.actual_pages += page_addrs.len() as u32;
                     return true;
                 }
@@ -538,16 +549,17 @@ impl VirtioBalloon {
             return false;
         }
 
+        let mut deflate = DEFLATE_QUEUE.lock();
         unsafe {
             // Convert addresses to PFNs
             for (i, &addr) in page_addrs.iter().enumerate() {
-                PFN_BUFFER[i] = (addr / PAGE_SIZE) as u32;
+                deflate.pfns[i] = (addr / PAGE_SIZE) as u32;
             }
 
-            let idx = DEFLATE_IDX % QUEUE_SIZE;
+            let idx = deflate.idx % QUEUE_SIZE;
 
-            DEFLATE_QUEUE_DESCS[idx as usize] = VringDesc {
-                addr: PFN_BUFFER.as_ptr() as u64,
+            deflate.descs[idx as usize] = VringDesc {
+                addr: deflate.pfns.as_ptr() as u64,
                 len: (page_addrs.len() * 4) as u32,
                 flags: 0,
                 next: 0,
@@ -555,13 +567,13 @@ impl VirtioBalloon {
 
             fence(Ordering::SeqCst);
 
-            let avail_idx = read_volatile(&DEFLATE_QUEUE_AVAIL.idx);
+            let avail_idx = read_volatile(&deflate.avail.idx);
             write_volatile(
-                &raw mut DEFLATE_QUEUE_AVAIL.ring[(avail_idx % QUEUE_SIZE) as usize],
+                &mut deflate.avail.ring[(avail_idx % QUEUE_SIZE) as usize],
                 idx,
             );
             fence(Ordering::SeqCst);
-            write_volatile(&raw mut DEFLATE_QUEUE_AVAIL.idx, avail_idx.wrapping_add(1));
+            write_volatile(&mut deflate.avail.idx, avail_idx.wrapping_add(1));
             fence(Ordering::SeqCst);
 
             // Notify
@@ -571,14 +583,14 @@ impl VirtioBalloon {
             write_volatile(notify_addr as *mut u16, 1);
             fence(Ordering::SeqCst);
 
-            DEFLATE_IDX = DEFLATE_IDX.wrapping_add(1);
+            deflate.idx = deflate.idx.wrapping_add(1);
 
             // Wait for completion
             for _ in 0..100_000 {
                 fence(Ordering::SeqCst);
-                let used_idx = read_volatile(&DEFLATE_QUEUE_USED.idx);
-                if used_idx != DEFLATE_LAST_USED {
-                    DEFLATE_LAST_USED = used_idx;
+                let used_idx = read_volatile(&deflate.used.idx);
+                if used_idx != deflate.last_used {
+                    deflate.last_used = used_idx;
                     self.actual_pages = self.actual_pages.saturating_sub(page_addrs.len() as u32);
                     return true;
                 }
@@ -591,9 +603,9 @@ impl VirtioBalloon {
 
     /// Test balloon by inflating and deflating
     pub fn test_balloon(&mut self) -> BalloonTestResult {
-        // Use a fixed "dummy" page address for testing
+        // Use a page set aside at boot for testing
         // In a real system, we'd allocate real pages
-        let test_page: u64 = 0x8100_0000; // High memory, unlikely to conflict
+        let test_page = memory::region(memory::Region::BalloonPage);
 
         // Try to inflate (give page to host)
         let inflate_ok = self.inflate(&[test_page]);
@@ -622,3 +634,20 @@ pub struct BalloonTestResult {
     pub actual_pages: u32,
     pub init_ok: bool,
 }
+
+#[kernel_test]
+fn balloon() -> Outcome {
+    let Some((dev, modern)) = crate::find_virtio_device(0x1045) else {
+        return Outcome::Skip("no virtio-balloon device");
+    };
+    let Some(mut balloon) = (unsafe { VirtioBalloon::from_modern(&modern, dev.ecam_addr) }) else {
+        return Outcome::Fail("device init failed");
+    };
+    let result = balloon.test_balloon();
+    let _ = writeln!(
+        ConsoleWriter,
+        "# inflate={} deflate={} pages={}/{}",
+        result.inflate_ok, result.deflate_ok, result.actual_pages, result.num_pages
+    );
+    Outcome::check(result.init_ok, "queue setup failed")
+}
@@ -2,9 +2,48 @@
 //!
 //! Provides sector-based read/write access to virtual disk.
 //! Device IDs: 0x1001 (transitional), 0x1042 (modern)
+//!
+//! I/O is in logical blocks of the device's blk_size (512 bytes unless it
+//! says otherwise); only the request headers count in 512-byte sectors.
+//! Requests DMA straight into the caller's buffer, which may be any whole
+//! number of blocks: a transfer is cut into as few requests as the
+//! device's size_max/seg_max and the queue size allow.
+//!
+//! With VIRTIO_BLK_F_MQ the driver runs up to MAX_QUEUES request queues,
+//! each as deep as the device allows (up to QUEUE_SIZE descriptors). The
+//! kernel is single core, so kernel threads stand in for the per-CPU
+//! queues Linux uses: a thread submits on queue (thread id % queues).
+//! Every request on a queue has its own tag, header and status byte, so
+//! many can be outstanding and the device may finish them in any order.
+//!
+//! Flush, discard, write-zeroes and the serial number are used when the
+//! device offers them. A read-only disk (VIRTIO_BLK_F_RO) refuses anything
+//! that writes before it reaches the device.
 
+use core::fmt::Write;
 use core::ptr::{read_volatile, write_volatile};
-use core::sync::atomic::{fence, Ordering};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+
+use driver_core::blk::{
+    self, Config, Data, Limits, RangeLimits, RangeSegment, Requests, ID_BYTES, SECTOR_SIZE, VIRTIO_BLK_F_BLK_SIZE,
+    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_GEOMETRY, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
+    VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_T_DISCARD,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+    VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+};
+use driver_core::block::{BlockCache, BlockDevice, Partition, RamDisk};
+use driver_core::ext::ExtFs;
+use driver_core::fat::{self, FatFs};
+use driver_core::partition::{self, Guid, NewPartition, Scheme};
+use driver_core::vfs::Error;
+use driver_core::virtqueue::{Buffer, Virtqueue};
+use kernel_macros::kernel_test;
+
+use crate::executor::{self, IoEvent};
+use crate::ktest::Outcome;
+use crate::sync::SpinLock;
+use crate::cmdline::{self, LogLevel};
+use crate::{thread, ConsoleWriter, LogWriter};
 
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_BLK_TRANSITIONAL: u16 = 0x1001;
@@ -47,77 +86,191 @@ const VIRTIO_PCI_COMMON_Q_AVAILHI: usize = 0x2c;
 const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
 const VIRTIO_PCI_COMMON_Q_USEDHI: usize = 0x34;
 
-// Block request types
-const VIRTIO_BLK_T_IN: u32 = 0;   // Read
-const VIRTIO_BLK_T_OUT: u32 = 1;  // Write
+/// Request queues driven at most
+const MAX_QUEUES: usize = 4;
+
+/// Descriptors per queue at most; a device with shorter queues gets
+/// queues cut down to fit
+const QUEUE_SIZE: usize = 128;
+
+/// Requests outstanding on one queue at most
+const MAX_REQUESTS: usize = 32;
+
+/// Data segments in one request at most, so that a chain fits on the
+/// stack and one request cannot fill a queue
+const MAX_SEGMENTS: usize = 32;
+
+/// Polls a blocking request waits before giving up
+const SPIN_LIMIT: usize = 1_000_000;
+
+/// Device-specific features we use when offered
+const WANTED_FEATURES: u64 = VIRTIO_BLK_F_SIZE_MAX
+    | VIRTIO_BLK_F_SEG_MAX
+    | VIRTIO_BLK_F_GEOMETRY
+    | VIRTIO_BLK_F_RO
+    | VIRTIO_BLK_F_BLK_SIZE
+    | VIRTIO_BLK_F_TOPOLOGY
+    | VIRTIO_BLK_F_MQ
+    | VIRTIO_BLK_F_FLUSH
+    | VIRTIO_BLK_F_DISCARD
+    | VIRTIO_BLK_F_WRITE_ZEROES;
+
+/// Segments per discard or write-zeroes request we send at most
+const MAX_RANGE_SEGMENTS: usize = 8;
+
+/// One request virtqueue and the requests outstanding on it, shared with
+/// the device
+struct BlkQueue {
+    /// Queue index, which is what a notification writes
+    index: u16,
+    ring: Virtqueue<QUEUE_SIZE>,
+    requests: Requests<MAX_REQUESTS>,
+}
+
+static BLK_QUEUES: [SpinLock<BlkQueue>; MAX_QUEUES] =
+    [const { SpinLock::new(BlkQueue { index: 0, ring: Virtqueue::new(), requests: Requests::new() }) }; MAX_QUEUES];
 
-// Block request status
-const VIRTIO_BLK_S_OK: u8 = 0;
+// Woken when requests on the matching queue complete, freeing room
+static BLK_EVENTS: [IoEvent; MAX_QUEUES] = [const { IoEvent::new() }; MAX_QUEUES];
 
-// Descriptor flags
-const VRING_DESC_F_NEXT: u16 = 1;
-const VRING_DESC_F_WRITE: u16 = 2;
+static QUEUES_CLAIMED: AtomicBool = AtomicBool::new(false);
 
-const QUEUE_SIZE: u16 = 8;
-const SECTOR_SIZE: usize = 512;
+/// The right to BLK_QUEUES and BLK_EVENTS, which serve one device at a
+/// time: a second device set up on them would reset rings the first is
+/// still using
+struct QueueClaim;
 
-/// Block device request header
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct VirtioBlkReqHeader {
-    req_type: u32,
-    reserved: u32,
-    sector: u64,
+impl QueueClaim {
+    fn take() -> Option<Self> {
+        if QUEUES_CLAIMED.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            let _ = writeln!(LogWriter(LogLevel::Warn), "virtio-blk: request queues already in use by another device");
+            return None;
+        }
+        Some(QueueClaim)
+    }
 }
 
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct VringDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+impl Drop for QueueClaim {
+    fn drop(&mut self) {
+        QUEUES_CLAIMED.store(false, Ordering::Release);
+    }
 }
 
-#[repr(C)]
-struct VringAvail {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE as usize],
+/// Bytes test_blocks() moves in one transfer
+const TEST_BYTES: usize = 16 * 1024;
+
+// test_blocks() buffer: too big for a thread stack
+static TEST_BUF: SpinLock<[u8; TEST_BYTES]> = SpinLock::new([0; TEST_BYTES]);
+
+/// Size of the RAM disk the partition and FAT tests format, so that they
+/// never write a table or filesystem over the user's disk
+const SCRATCH_BYTES: usize = 256 * 1024;
+
+static SCRATCH: SpinLock<[u8; SCRATCH_BYTES]> = SpinLock::new([0; SCRATCH_BYTES]);
+
+impl BlkQueue {
+    /// Start afresh as queue `index`, of `size` descriptors
+    fn reset(&mut self, index: u16, size: u16) {
+        self.index = index;
+        self.ring.set_size(size);
+        self.requests = Requests::new();
+    }
+
+    /// Queue a request for as much of `data` as one request can carry and
+    /// notify the device
+    ///
+    /// Returns the request's tag and how many bytes it covers, or None if
+    /// the queue has no room for it yet. The device reads or writes `data`
+    /// in place until the request is finished, so the buffer must stay put
+    /// until then.
+    fn submit(&mut self, notify_addr: u64, limits: &Limits, req_type: u32, sector: u64, data: Data<'_>) -> Option<(usize, usize)> {
+        let tag = self.requests.claim(req_type, sector)?;
+        let (header, status) = self.requests.parts(tag);
+        let mut chain = [Buffer { addr: 0, len: 0, device_writes: false }; MAX_SEGMENTS + 2];
+        let (n, len) = blk::request_chain_sg(header, data, status, limits, &mut chain);
+        let Some(head) = self.ring.push(&chain[..n]) else {
+            self.requests.cancel(tag);
+            return None;
+        };
+        self.requests.submitted(tag, head);
+
+        // Notify device
+        unsafe { write_volatile(notify_addr as *mut u16, self.index) };
+        fence(Ordering::SeqCst);
+        Some((tag, len))
+    }
+
+    /// Match every chain the device has returned to its request; how many
+    /// there were
+    fn reap(&mut self) -> usize {
+        let mut n = 0;
+        while let Some(used) = self.ring.pop_used() {
+            self.requests.complete(used.id as u16);
+            n += 1;
+        }
+        n
+    }
 }
 
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct VringUsedElem {
-    id: u32,
-    len: u32,
+/// How request `tag` on `queue` went, once the device has returned it
+///
+/// Completions for other requests found on the way are matched up too,
+/// and whoever waits on the queue is woken to look at them.
+fn finish(queue: usize, tag: usize) -> Option<Result<(), &'static str>> {
+    let mut q = BLK_QUEUES[queue].lock();
+    let reaped = q.reap();
+    let result = q.requests.take(tag);
+    drop(q);
+    if reaped > 0 || result.is_some() {
+        BLK_EVENTS[queue].wake();
+    }
+    result
 }
 
-#[repr(C)]
-struct VringUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VringUsedElem; QUEUE_SIZE as usize],
+/// Queue size for a device allowing `max` descriptors: the largest power
+/// of two within that and QUEUE_SIZE; None if the queue does not exist
+fn queue_depth(max: u16) -> Option<u16> {
+    if max == 0 {
+        return None;
+    }
+    Some((QUEUE_SIZE as u16).min(1 << (15 - max.leading_zeros())))
 }
 
-// Static buffers
-static mut BLK_QUEUE_DESCS: [VringDesc; QUEUE_SIZE as usize] =
-    [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize];
-static mut BLK_QUEUE_AVAIL: VringAvail = VringAvail {
-    flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize]
-};
-static mut BLK_QUEUE_USED: VringUsed = VringUsed {
-    flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize]
-};
+/// Request limits on queues of `queue_size` descriptors; None, after
+/// saying why, for a device no request could move a block on
+fn request_limits(queue_size: u16, config: &Config) -> Option<Limits> {
+    Limits::for_config(queue_size.min(MAX_SEGMENTS as u16 + 2), config)
+        .inspect_err(|err| {
+            let _ = writeln!(LogWriter(LogLevel::Warn), "virtio-blk: {}", err);
+        })
+        .ok()
+}
 
-// Request buffers
-static mut BLK_REQ_HEADER: VirtioBlkReqHeader = VirtioBlkReqHeader {
-    req_type: 0, reserved: 0, sector: 0
-};
-static mut BLK_DATA_BUFFER: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
-static mut BLK_STATUS: u8 = 0xFF;
-static mut BLK_DESC_IDX: u16 = 0;
-static mut BLK_LAST_USED: u16 = 0;
+/// Wake tasks waiting on block requests that have completed
+pub fn poll_io() {
+    for (queue, event) in BLK_QUEUES.iter().zip(&BLK_EVENTS) {
+        if let Some(mut q) = queue.try_lock() {
+            if q.reap() > 0 {
+                drop(q);
+                event.wake();
+            }
+        }
+    }
+}
+
+/// A submitted request_async() request; dropped unfinished, it waits for
+/// the device to be done with the request's buffer
+struct Pending<'a> {
+    blk: &'a VirtioBlock,
+    queue: usize,
+    tag: usize,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        let _ = self.blk.wait(self.queue, self.tag);
+    }
+}
 
 #[derive(Clone, Copy)]
 struct VirtioCap {
@@ -128,92 +281,69 @@ struct VirtioCap {
 
 /// VirtIO Block driver
 pub struct VirtioBlock {
-    bar0: u64,
-    notify_offset: u32,
-    notify_multiplier: u32,
-    queue_notify_off: u16,
-    capacity: u64,  // in sectors
+    /// Common configuration, whose status register resets the device
+    common: u64,
+    /// Set once a request timed out and the device was reset; it takes no
+    /// more requests after that
+    failed: AtomicBool,
+    /// Notify address of each request queue in use
+    notify: [u64; MAX_QUEUES],
+    num_queues: usize,
+    /// Descriptors per queue
+    queue_size: u16,
+    config: Config,
+    limits: Limits,
+    /// Device-specific features negotiated
+    features: u64,
+    /// Held for as long as the device may use the queues
+    _queues: QueueClaim,
+}
+
+impl Drop for VirtioBlock {
+    fn drop(&mut self) {
+        // The queues are free for another device only once this one has
+        // let go of them
+        self.reset();
+    }
 }
 
 impl VirtioBlock {
     /// Create from pre-configured VirtioModern transport
     pub unsafe fn from_modern(modern: &crate::pci::VirtioModern, _ecam_addr: u64) -> Option<Self> {
-        use core::sync::atomic::{fence, Ordering};
-
-        let common_base = modern.common;
-        let notify_base = modern.notify;
-        let device_base = modern.device;
-
-        // Reset device
-        write_volatile((common_base + 20) as *mut u8, 0);
-        fence(Ordering::SeqCst);
-        for _ in 0..1000 {
-            if read_volatile((common_base + 20) as *const u8) == 0 { break; }
-            core::hint::spin_loop();
-        }
-
-        // Acknowledge + Driver
-        write_volatile((common_base + 20) as *mut u8, 0x03);
-        fence(Ordering::SeqCst);
-
-        // Accept VERSION_1 only
-        write_volatile((common_base + 8) as *mut u32, 0);
-        write_volatile((common_base + 12) as *mut u32, 0);
-        write_volatile((common_base + 8) as *mut u32, 1);
-        write_volatile((common_base + 12) as *mut u32, 1);
-        fence(Ordering::SeqCst);
-
-        // Features OK
-        write_volatile((common_base + 20) as *mut u8, 0x0B);
-        fence(Ordering::SeqCst);
-
-        if (read_volatile((common_base + 20) as *const u8) & 0x08) == 0 {
+        let mmio = crate::pci::mmio();
+        if modern.device == 0 {
             return None;
         }
-
-        // Read capacity from device config
-        let capacity = if device_base != 0 {
-            read_volatile(device_base as *const u64)
-        } else {
-            0
-        };
-
-        // Setup queue 0
-        write_volatile((common_base + 22) as *mut u16, 0);
-        fence(Ordering::SeqCst);
-
-        let queue_size_max = read_volatile((common_base + 24) as *const u16);
-        if queue_size_max == 0 { return None; }
-
-        let actual_size = queue_size_max.min(QUEUE_SIZE);
-        write_volatile((common_base + 24) as *mut u16, actual_size);
-
-        let desc_addr = &raw const BLK_QUEUE_DESCS as u64;
-        let avail_addr = &raw const BLK_QUEUE_AVAIL as u64;
-        let used_addr = &raw const BLK_QUEUE_USED as u64;
-
-        write_volatile((common_base + 32) as *mut u32, desc_addr as u32);
-        write_volatile((common_base + 36) as *mut u32, (desc_addr >> 32) as u32);
-        write_volatile((common_base + 40) as *mut u32, avail_addr as u32);
-        write_volatile((common_base + 44) as *mut u32, (avail_addr >> 32) as u32);
-        write_volatile((common_base + 48) as *mut u32, used_addr as u32);
-        write_volatile((common_base + 52) as *mut u32, (used_addr >> 32) as u32);
-
-        let queue_notify_off = read_volatile((common_base + 30) as *const u16);
-
-        write_volatile((common_base + 28) as *mut u16, 1);
-        fence(Ordering::SeqCst);
-
-        // Driver OK
-        write_volatile((common_base + 20) as *mut u8, 0x0F);
-        fence(Ordering::SeqCst);
+        // Before negotiation resets the device
+        let queues = QueueClaim::take()?;
+        let features = modern.negotiate_features(&mmio, WANTED_FEATURES)? & WANTED_FEATURES;
+        let config = Config::read(&mmio, modern.device, features);
+
+        // As many request queues as the device has and we can use, each as
+        // deep as it allows
+        let num_queues = (config.num_queues as usize).min(MAX_QUEUES);
+        let mut notify = [0; MAX_QUEUES];
+        let mut queue_size = QUEUE_SIZE as u16;
+        for (index, addr) in notify.iter_mut().enumerate().take(num_queues) {
+            let size = queue_depth(modern.queue_max_size(&mmio, index as u16))?;
+            let mut q = BLK_QUEUES[index].lock();
+            q.reset(index as u16, size);
+            *addr = modern.setup_queue(&mmio, index as u16, &q.ring)?;
+            queue_size = queue_size.min(size);
+        }
+        let limits = request_limits(queue_size, &config)?;
+        modern.driver_ok(&mmio);
 
         Some(VirtioBlock {
-            bar0: notify_base,
-            notify_offset: 0,
-            notify_multiplier: modern.notify_mult,
-            queue_notify_off,
-            capacity,
+            common: modern.common,
+            failed: AtomicBool::new(false),
+            notify,
+            num_queues,
+            queue_size,
+            config,
+            limits,
+            features,
+            _queues: queues,
         })
     }
 
@@ -311,18 +441,14 @@ impl VirtioBlock {
 
             let common_base = bar0 + common.offset as u64;
 
-            // Read device capacity if device config is available
-            let capacity = if let Some(dev_cfg) = device_cap {
-                if dev_cfg.bar == 0 {
-                    let dev_base = bar0 + dev_cfg.offset as u64;
-                    read_volatile(dev_base as *const u64)
-                } else {
-                    0
-                }
-            } else {
-                0
+            // Read device config; no optional features are negotiated here
+            let config = match device_cap {
+                Some(dev_cfg) if dev_cfg.bar == 0 => Config::read(&crate::pci::mmio(), bar0 + dev_cfg.offset as u64, 0),
+                _ => return None,
             };
 
+            let queues = QueueClaim::take()?;
+
             // Initialize device
             // 1. Reset
             write_volatile((common_base + VIRTIO_PCI_COMMON_STATUS as u64) as *mut u8, 0);
@@ -370,16 +496,15 @@ impl VirtioBlock {
             fence(Ordering::SeqCst);
 
             let queue_size_max = read_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *const u16);
-            if queue_size_max == 0 {
-                return None;
-            }
-
-            let actual_size = queue_size_max.min(QUEUE_SIZE);
+            let actual_size = queue_depth(queue_size_max)?;
+            let limits = request_limits(actual_size, &config)?;
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
-            let desc_addr = &raw const BLK_QUEUE_DESCS as u64;
-            let avail_addr = &raw const BLK_QUEUE_AVAIL as u64;
-            let used_addr = &raw const BLK_QUEUE_USED as u64;
+            let (desc_addr, avail_addr, used_addr) = {
+                let mut q = BLK_QUEUES[0].lock();
+                q.reset(0, actual_size);
+                q.ring.addrs()
+            };
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -400,144 +525,300 @@ impl VirtioBlock {
             );
             fence(Ordering::SeqCst);
 
+            let mut notify_addrs = [0; MAX_QUEUES];
+            notify_addrs[0] = bar0 + notify.offset as u64 + queue_notify_off as u64 * notify.notify_off_multiplier as u64;
             Some(VirtioBlock {
-                bar0,
-                notify_offset: notify.offset,
-                notify_multiplier: notify.notify_off_multiplier,
-                queue_notify_off,
-                capacity,
+                common: common_base,
+                failed: AtomicBool::new(false),
+                notify: notify_addrs,
+                num_queues: 1,
+                queue_size: actual_size,
+                config,
+                limits,
+                features: 0,
+                _queues: queues,
             })
         }
     }
 
     /// Get disk capacity in sectors
     pub fn capacity(&self) -> u64 {
-        self.capacity
+        self.config.capacity
+    }
+
+    /// Everything the device config says about the disk
+    pub fn info(&self) -> &Config {
+        &self.config
     }
 
-    /// Read a sector from disk
+    /// Logical block size in bytes
+    pub fn block_size(&self) -> usize {
+        self.config.blk_size as usize
+    }
+
+    /// Size in logical blocks
+    pub fn block_count(&self) -> u64 {
+        self.config.blocks()
+    }
+
+    /// True if the device only allows reads
+    pub fn read_only(&self) -> bool {
+        self.features & VIRTIO_BLK_F_RO != 0
+    }
+
+    /// Read `buf.len() / block_size()` blocks starting at block `lba`
+    pub fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        // The device writes the buffer in place
+        self.transfer(VIRTIO_BLK_T_IN, lba, Data::In(buf))
+    }
+
+    /// Write `buf` (whole blocks) starting at block `lba`
+    pub fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.writable()?;
+        self.transfer(VIRTIO_BLK_T_OUT, lba, Data::Out(buf))
+    }
+
+    /// read_blocks() without blocking the executor
+    ///
+    /// Dropping the future while a request is pending waits for the device
+    /// to finish with `buf` (or resets it) before the borrow ends.
+    ///
+    /// # Safety
+    ///
+    /// The future must not be leaked (mem::forget, a reference cycle) while
+    /// pending: the device fills `buf` until the request is done, and a
+    /// leaked future gives the buffer back without waiting.
+    pub async unsafe fn read_blocks_async(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.transfer_async(VIRTIO_BLK_T_IN, lba, Data::In(buf)).await
+    }
+
+    /// write_blocks() without blocking the executor
+    ///
+    /// # Safety
+    ///
+    /// As for read_blocks_async(): the device reads `buf` until the request
+    /// is done, so the future must not be leaked while pending.
+    pub async unsafe fn write_blocks_async(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.writable()?;
+        self.transfer_async(VIRTIO_BLK_T_OUT, lba, Data::Out(buf)).await
+    }
+
+    /// Read a sector from disk (a block, on disks with 512-byte blocks)
     pub fn read_sector(&self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> bool {
-        self.do_request(VIRTIO_BLK_T_IN, sector, buf)
+        self.read_blocks(sector, buf).is_ok()
     }
 
     /// Write a sector to disk
     pub fn write_sector(&self, sector: u64, buf: &[u8; SECTOR_SIZE]) -> bool {
-        // Copy to static buffer for write
-        unsafe {
-            for i in 0..SECTOR_SIZE {
-                BLK_DATA_BUFFER[i] = buf[i];
-            }
+        self.write_blocks(sector, buf).is_ok()
+    }
+
+    /// Read a sector without blocking the executor
+    ///
+    /// # Safety
+    ///
+    /// As for read_blocks_async()
+    pub async unsafe fn read_sector_async(&self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> bool {
+        unsafe { self.read_blocks_async(sector, buf) }.await.is_ok()
+    }
+
+    /// Write a sector without blocking the executor
+    ///
+    /// # Safety
+    ///
+    /// As for write_blocks_async()
+    pub async unsafe fn write_sector_async(&self, sector: u64, buf: &[u8; SECTOR_SIZE]) -> bool {
+        unsafe { self.write_blocks_async(sector, buf) }.await.is_ok()
+    }
+
+    /// Make completed writes durable
+    /// Without VIRTIO_BLK_F_FLUSH the device has no write cache to flush.
+    pub fn flush(&self) -> Result<(), &'static str> {
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
         }
-        let mut dummy = [0u8; SECTOR_SIZE];
-        self.do_request(VIRTIO_BLK_T_OUT, sector, &mut dummy)
+        self.do_request(VIRTIO_BLK_T_FLUSH, 0, Data::Out(&[])).map(|_| ())
     }
 
-    fn do_request(&self, req_type: u32, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> bool {
-        unsafe {
-            // Setup request header
-            BLK_REQ_HEADER.req_type = req_type;
-            BLK_REQ_HEADER.reserved = 0;
-            BLK_REQ_HEADER.sector = sector;
-            BLK_STATUS = 0xFF;
+    /// Tell the device `count` blocks at `lba` are no longer needed; they
+    /// read back as anything afterwards
+    pub fn discard(&self, lba: u64, count: u64) -> Result<(), &'static str> {
+        let limits = self.config.discard.ok_or("device does not support discard")?;
+        self.zero_ranges(VIRTIO_BLK_T_DISCARD, lba, count, 0, &limits)
+    }
 
-            fence(Ordering::SeqCst);
+    /// Zero `count` blocks at `lba` without sending the zeroes; with
+    /// `unmap`, the device may deallocate them too
+    pub fn write_zeroes(&self, lba: u64, count: u64, unmap: bool) -> Result<(), &'static str> {
+        let limits = self.config.write_zeroes.ok_or("device does not support write-zeroes")?;
+        let flags = if unmap && self.config.write_zeroes_may_unmap { VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP } else { 0 };
+        self.zero_ranges(VIRTIO_BLK_T_WRITE_ZEROES, lba, count, flags, &limits)
+    }
 
-            // We need 3 descriptors chained:
-            // 1. Header (device-readable)
-            // 2. Data buffer (device-readable for write, device-writable for read)
-            // 3. Status byte (device-writable)
-
-            let base_idx = (BLK_DESC_IDX % QUEUE_SIZE) as usize;
-            let idx0 = base_idx;
-            let idx1 = (base_idx + 1) % QUEUE_SIZE as usize;
-            let idx2 = (base_idx + 2) % QUEUE_SIZE as usize;
-
-            // Descriptor 0: Header
-            BLK_QUEUE_DESCS[idx0] = VringDesc {
-                addr: &raw const BLK_REQ_HEADER as u64,
-                len: core::mem::size_of::<VirtioBlkReqHeader>() as u32,
-                flags: VRING_DESC_F_NEXT,
-                next: idx1 as u16,
-            };
+    /// The disk's serial number, read into `buf`
+    pub fn serial<'a>(&self, buf: &'a mut [u8; ID_BYTES]) -> Result<&'a str, &'static str> {
+        // The device writes the buffer in place
+        self.do_request(VIRTIO_BLK_T_GET_ID, 0, Data::In(&mut buf[..]))?;
+        // NUL padded, and not NUL terminated at full length
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(ID_BYTES);
+        core::str::from_utf8(&buf[..len]).map_err(|_| "serial number is not UTF-8")
+    }
 
-            // Descriptor 1: Data buffer
-            if req_type == VIRTIO_BLK_T_IN {
-                // Read: device writes to buffer
-                BLK_QUEUE_DESCS[idx1] = VringDesc {
-                    addr: BLK_DATA_BUFFER.as_ptr() as u64,
-                    len: SECTOR_SIZE as u32,
-                    flags: VRING_DESC_F_WRITE | VRING_DESC_F_NEXT,
-                    next: idx2 as u16,
-                };
-            } else {
-                // Write: device reads from buffer
-                BLK_QUEUE_DESCS[idx1] = VringDesc {
-                    addr: BLK_DATA_BUFFER.as_ptr() as u64,
-                    len: SECTOR_SIZE as u32,
-                    flags: VRING_DESC_F_NEXT,
-                    next: idx2 as u16,
-                };
-            }
+    /// Request queues in use
+    pub fn num_queues(&self) -> usize {
+        self.num_queues
+    }
 
-            // Descriptor 2: Status byte
-            BLK_QUEUE_DESCS[idx2] = VringDesc {
-                addr: &raw mut BLK_STATUS as u64,
-                len: 1,
-                flags: VRING_DESC_F_WRITE,
-                next: 0,
-            };
+    /// The calling thread's request queue
+    fn queue(&self) -> usize {
+        thread::current() % self.num_queues
+    }
 
-            fence(Ordering::SeqCst);
+    fn writable(&self) -> Result<(), &'static str> {
+        if self.read_only() {
+            return Err("disk is read-only");
+        }
+        Ok(())
+    }
 
-            // Add first descriptor to available ring
-            let avail_idx = read_volatile(&BLK_QUEUE_AVAIL.idx);
-            write_volatile(
-                &raw mut BLK_QUEUE_AVAIL.ring[(avail_idx % QUEUE_SIZE) as usize],
-                idx0 as u16,
-            );
-            fence(Ordering::SeqCst);
-            write_volatile(&raw mut BLK_QUEUE_AVAIL.idx, avail_idx.wrapping_add(1));
-            fence(Ordering::SeqCst);
+    /// Check `len` bytes at block `lba` are whole blocks on the disk
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), &'static str> {
+        if !len.is_multiple_of(self.block_size()) {
+            return Err("not a whole number of blocks");
+        }
+        self.check_blocks(lba, (len / self.block_size()) as u64)
+    }
 
-            // Notify device
-            let notify_addr = self.bar0
-                + self.notify_offset as u64
-                + (self.queue_notify_off as u64 * self.notify_multiplier as u64);
-            write_volatile(notify_addr as *mut u16, 0);
-            fence(Ordering::SeqCst);
+    fn check_blocks(&self, lba: u64, count: u64) -> Result<(), &'static str> {
+        match lba.checked_add(count) {
+            Some(end) if end <= self.block_count() => Ok(()),
+            _ => Err("past the end of the disk"),
+        }
+    }
 
-            BLK_DESC_IDX = BLK_DESC_IDX.wrapping_add(3);
+    /// Header sector for the block `done` bytes past block `lba`
+    fn sector(&self, lba: u64, done: usize) -> u64 {
+        (lba + (done / self.block_size()) as u64) * self.config.sectors_per_block()
+    }
 
-            // Wait for completion
-            for _ in 0..1_000_000 {
-                fence(Ordering::SeqCst);
-                let used_idx = read_volatile(&BLK_QUEUE_USED.idx);
-                if used_idx != BLK_LAST_USED {
-                    BLK_LAST_USED = used_idx;
-                    break;
-                }
-                core::hint::spin_loop();
-            }
+    fn transfer(&self, req_type: u32, lba: u64, mut data: Data<'_>) -> Result<(), &'static str> {
+        self.check_range(lba, data.len())?;
+        let mut done = 0;
+        while done < data.len() {
+            done += self.do_request(req_type, self.sector(lba, done), data.tail(done))?;
+        }
+        Ok(())
+    }
 
-            fence(Ordering::SeqCst);
+    async fn transfer_async(&self, req_type: u32, lba: u64, mut data: Data<'_>) -> Result<(), &'static str> {
+        self.check_range(lba, data.len())?;
+        let mut done = 0;
+        while done < data.len() {
+            done += self.request_async(req_type, self.sector(lba, done), data.tail(done)).await?;
+        }
+        Ok(())
+    }
 
-            // Check status
-            let status = read_volatile(&BLK_STATUS);
-            if status != VIRTIO_BLK_S_OK {
-                return false;
+    /// Discard or write-zeroes requests covering `count` blocks at `lba`
+    fn zero_ranges(&self, req_type: u32, lba: u64, count: u64, flags: u32, limits: &RangeLimits) -> Result<(), &'static str> {
+        self.writable()?;
+        self.check_blocks(lba, count)?;
+        // The ranges are in 512-byte sectors
+        let (start, count) = (self.sector(lba, 0), count * self.config.sectors_per_block());
+        let mut segments = [RangeSegment::default(); MAX_RANGE_SEGMENTS];
+        let mut done = 0;
+        while done < count {
+            let (n, len) = blk::range_segments(start + done, count - done, flags, limits, &mut segments);
+            let bytes = unsafe {
+                core::slice::from_raw_parts(segments.as_ptr() as *const u8, n * core::mem::size_of::<RangeSegment>())
+            };
+            self.do_request(req_type, 0, Data::Out(bytes))?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// One request for the start of `data` on the calling thread's queue;
+    /// how much of it was covered
+    fn do_request(&self, req_type: u32, sector: u64, data: Data<'_>) -> Result<usize, &'static str> {
+        self.request_on(self.queue(), req_type, sector, data)
+    }
+
+    /// do_request() on a given queue, spinning until the request is done
+    fn request_on(&self, queue: usize, req_type: u32, sector: u64, mut data: Data<'_>) -> Result<usize, &'static str> {
+        self.usable()?;
+        let mut submitted = None;
+        for _ in 0..SPIN_LIMIT {
+            submitted = BLK_QUEUES[queue].lock().submit(self.notify[queue], &self.limits, req_type, sector, data.tail(0));
+            if submitted.is_some() {
+                break;
             }
+            // Full: wait for other requests to come back
+            BLK_QUEUES[queue].lock().reap();
+            core::hint::spin_loop();
+        }
+        let (tag, len) = submitted.ok_or("queue full")?;
+        self.wait(queue, tag).map(|_| len)
+    }
 
-            // Copy data for read
-            if req_type == VIRTIO_BLK_T_IN {
-                for i in 0..SECTOR_SIZE {
-                    buf[i] = read_volatile(&BLK_DATA_BUFFER[i]);
-                }
+    /// Spin until request `tag` on `queue` is done
+    ///
+    /// A request that never comes back leaves the device holding the
+    /// caller's buffer, so rather than return while it might still use it,
+    /// the device is reset.
+    fn wait(&self, queue: usize, tag: usize) -> Result<(), &'static str> {
+        for _ in 0..SPIN_LIMIT {
+            if let Some(result) = self.finished(queue, tag) {
+                return result;
             }
+            core::hint::spin_loop();
+        }
+        self.reset();
+        BLK_QUEUES[queue].lock().requests.abandon(tag);
+        Err("request timed out")
+    }
+
+    /// How request `tag` on `queue` went, if the device is done with it
+    fn finished(&self, queue: usize, tag: usize) -> Option<Result<(), &'static str>> {
+        finish(queue, tag).or_else(|| self.usable().err().map(Err))
+    }
 
-            true
+    /// Stop the device using any buffer it was given, by resetting it;
+    /// requests fail from then on
+    fn reset(&self) {
+        self.failed.store(true, Ordering::SeqCst);
+        let status = self.common + VIRTIO_PCI_COMMON_STATUS as u64;
+        unsafe { write_volatile(status as *mut u8, 0) };
+        fence(Ordering::SeqCst);
+        // The device has let go of its queues once the status reads 0
+        while unsafe { read_volatile(status as *const u8) } != 0 {
+            core::hint::spin_loop();
         }
     }
 
+    fn usable(&self) -> Result<(), &'static str> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err("device was reset");
+        }
+        Ok(())
+    }
+
+    async fn request_async(&self, req_type: u32, sector: u64, mut data: Data<'_>) -> Result<usize, &'static str> {
+        self.usable()?;
+        let queue = self.queue();
+        let event = &BLK_EVENTS[queue];
+        let (tag, len) = executor::wait_for(event, || {
+            BLK_QUEUES[queue].lock().submit(self.notify[queue], &self.limits, req_type, sector, data.tail(0))
+        })
+        .await;
+        // Until the request is done, dropping this future must not end the
+        // borrow of `data`
+        let pending = Pending { blk: self, queue, tag };
+        let result = executor::wait_for(event, || self.finished(queue, tag)).await;
+        core::mem::forget(pending);
+        result.map(|_| len)
+    }
+
     /// Test block device by writing and reading back a pattern
     pub fn test_read_write(&self) -> BlockTestResult {
         // Create test pattern
@@ -562,13 +843,345 @@ impl VirtioBlock {
         }
 
         BlockTestResult {
-            capacity: self.capacity,
+            capacity: self.config.capacity,
             write_ok,
             read_ok,
             data_matches: matches,
             test_passed: write_ok && read_ok && matches == SECTOR_SIZE,
         }
     }
+
+    /// Test the async path: write sectors 1-3, then read sectors 0-3 as four
+    /// concurrent requests and check each against its pattern
+    /// Expects sector 0 to hold the test_read_write() pattern
+    pub fn test_async(&self) -> bool {
+        let pattern = |sector: usize, i: usize| if sector == 0 { (i * 7 + 13) as u8 } else { (i * (7 + 4 * sector) + 5) as u8 };
+        let mut written = [[0u8; SECTOR_SIZE]; 4];
+        for (sector, buf) in written.iter_mut().enumerate() {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = pattern(sector, i);
+            }
+        }
+
+        let mut bufs = [[0u8; SECTOR_SIZE]; 4];
+        let [b0, b1, b2, b3] = &mut bufs;
+        // Every future is run to completion by block_on()
+        let (write_ok, ((ok0, ok1), (ok2, ok3))) = executor::block_on(async {
+            let mut write_ok = true;
+            for sector in 1..4 {
+                write_ok &= unsafe { self.write_sector_async(sector as u64, &written[sector]) }.await;
+            }
+            // All four are outstanding on the queue at once
+            let reads = unsafe {
+                executor::join(
+                    executor::join(self.read_sector_async(0, b0), self.read_sector_async(1, b1)),
+                    executor::join(self.read_sector_async(2, b2), self.read_sector_async(3, b3)),
+                )
+            }
+            .await;
+            (write_ok, reads)
+        });
+
+        write_ok && ok0 && ok1 && ok2 && ok3 && bufs == written
+    }
+
+    /// Test every request queue: the same blocks read on each must match
+    pub fn test_queues(&self) -> bool {
+        let bs = self.block_size();
+        if 2 * bs > TEST_BYTES {
+            // Blocks too big to compare two in the test buffer
+            return true;
+        }
+        let mut buf = TEST_BUF.lock();
+        let (first, rest) = buf.split_at_mut(bs);
+        if self.request_on(0, VIRTIO_BLK_T_IN, self.sector(16, 0), Data::In(first)).is_err() {
+            return false;
+        }
+        (1..self.num_queues).all(|queue| {
+            let other = &mut rest[..bs];
+            other.fill(0);
+            self.request_on(queue, VIRTIO_BLK_T_IN, self.sector(16, 0), Data::In(other)).is_ok() && *other == *first
+        })
+    }
+
+    /// Test the block layer over this disk: blocks written through an LRU
+    /// cache on a partition reach the disk when evicted or synced
+    pub fn test_cache(&self) -> bool {
+        // Blocks 48-55, clear of what test_blocks() writes
+        let Some(part) = Partition::new(self, 48, 8) else {
+            return false;
+        };
+        let Some(cache) = BlockCache::<_, 4, SECTOR_SIZE>::new(part) else {
+            // Only 512-byte blocks are cached here
+            return true;
+        };
+        let mut block = [0u8; SECTOR_SIZE];
+        for lba in 0..8u64 {
+            block.fill(0xC0 + lba as u8);
+            if cache.write_blocks(lba, &block).is_err() {
+                return false;
+            }
+        }
+        // The first four made room for the rest
+        if cache.dirty() != 4 || cache.sync().is_err() || cache.dirty() != 0 {
+            return false;
+        }
+        (0..8u64).all(|lba| self.read_blocks(48 + lba, &mut block).is_ok() && block.iter().all(|&b| b == 0xC0 + lba as u8))
+    }
+
+    /// Test multi-block transfers: write 16 KiB from block 16 in one call,
+    /// read it back in one call and one block at a time, and check bad
+    /// lengths and ranges are refused
+    pub fn test_blocks(&self) -> bool {
+        let mut buf = TEST_BUF.lock();
+        let bs = self.block_size().min(TEST_BYTES);
+        let pattern = |i: usize| ((i / bs) * 31 + i * 3) as u8;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = pattern(i);
+        }
+        let buf = &mut buf[..TEST_BYTES / bs * bs];
+        if self.write_blocks(16, buf).is_err() {
+            return false;
+        }
+
+        buf.fill(0);
+        if self.read_blocks(16, buf).is_err() || buf.iter().enumerate().any(|(i, &b)| b != pattern(i)) {
+            return false;
+        }
+        let last = buf.len() / bs - 1;
+        if self.read_blocks(16 + last as u64, &mut buf[..bs]).is_err()
+            || buf[..bs].iter().enumerate().any(|(i, &b)| b != pattern(last * bs + i))
+        {
+            return false;
+        }
+
+        self.read_blocks(16, &mut buf[..100]).is_err()
+            && self.read_blocks(self.block_count().saturating_sub(1), &mut buf[..2 * bs.min(TEST_BYTES / 2)]).is_err()
+    }
+
+    /// Test the commands the device offers: flush, write-zeroes over the
+    /// second half of what test_blocks() wrote, and discard after it
+    pub fn test_commands(&self) -> Result<(), &'static str> {
+        let mut id = [0; ID_BYTES];
+        let serial = self.serial(&mut id).unwrap_or("(none)");
+        let _ = writeln!(
+            ConsoleWriter,
+            "# serial={} read_only={} flush={} discard={} write_zeroes={}",
+            serial,
+            self.read_only(),
+            self.features & VIRTIO_BLK_F_FLUSH != 0,
+            self.config.discard.is_some(),
+            self.config.write_zeroes.is_some()
+        );
+        self.flush()?;
+
+        let bs = self.block_size();
+        let blocks = (TEST_BYTES / bs).max(1) as u64;
+        if self.config.write_zeroes.is_some() && blocks > 1 {
+            let half = blocks / 2;
+            self.write_zeroes(16 + half, blocks - half, false)?;
+            let mut buf = TEST_BUF.lock();
+            let bytes = blocks as usize * bs;
+            self.read_blocks(16, &mut buf[..bytes])?;
+            if buf[half as usize * bs..bytes].iter().any(|&b| b != 0) {
+                return Err("write-zeroes left data behind");
+            }
+            if buf[..half as usize * bs].iter().all(|&b| b == 0) {
+                return Err("write-zeroes cleared too much");
+            }
+        }
+        if self.config.discard.is_some() {
+            self.discard(16, blocks)?;
+        }
+        if self.write_zeroes(self.block_count(), 1, false).is_ok() {
+            return Err("write-zeroes past the end of the disk succeeded");
+        }
+        Ok(())
+    }
+
+    /// Print what the device config says, as test diagnostics
+    fn log_info(&self) {
+        let c = &self.config;
+        let _ = writeln!(
+            ConsoleWriter,
+            "# capacity={} sectors, {}-byte blocks, up to {} bytes per request",
+            c.capacity,
+            c.blk_size,
+            self.limits.max_request()
+        );
+        let _ = writeln!(
+            ConsoleWriter,
+            "# {} of {} request queue(s) in use, {} descriptors each, up to {} requests outstanding per queue",
+            self.num_queues,
+            c.num_queues,
+            self.queue_size,
+            MAX_REQUESTS
+        );
+        if let (Some(size_max), Some(seg_max)) = (c.size_max, c.seg_max) {
+            let _ = writeln!(ConsoleWriter, "# size_max={} seg_max={}", size_max, seg_max);
+        }
+        if let Some(g) = c.geometry {
+            let _ = writeln!(ConsoleWriter, "# geometry={}/{}/{}", g.cylinders, g.heads, g.sectors);
+        }
+        if let Some(t) = c.topology {
+            let _ = writeln!(
+                ConsoleWriter,
+                "# topology: {} blocks per physical block, alignment offset {}, min io {}, opt io {}",
+                1u32 << t.physical_block_exp,
+                t.alignment_offset,
+                t.min_io_size,
+                t.opt_io_size
+            );
+        }
+        if let Some(d) = c.discard {
+            let _ = writeln!(
+                ConsoleWriter,
+                "# discard: {} sectors x {} segments, alignment {}",
+                d.max_sectors,
+                d.max_seg,
+                d.alignment
+            );
+        }
+        if let Some(w) = c.write_zeroes {
+            let _ = writeln!(
+                ConsoleWriter,
+                "# write-zeroes: {} sectors x {} segments, may unmap {}",
+                w.max_sectors,
+                w.max_seg,
+                c.write_zeroes_may_unmap
+            );
+        }
+    }
+}
+
+/// Test partition tables on a RAM disk: lay out a GPT, read it back, and
+/// write through one of its partitions
+fn test_partitions() -> bool {
+    let mut scratch = SCRATCH.lock();
+    let Some(disk) = RamDisk::new(&mut scratch[..128 * SECTOR_SIZE], SECTOR_SIZE) else {
+        return false;
+    };
+    let parts = [
+        NewPartition { name: "data", type_guid: partition::TYPE_LINUX_FILESYSTEM, unique_guid: Guid::new(0, 0, 0, 0, 1), blocks: 8 },
+        NewPartition { name: "config", type_guid: partition::TYPE_BASIC_DATA, unique_guid: Guid::new(0, 0, 0, 0, 2), blocks: 4 },
+    ];
+    let disk_guid = Guid::new(0x756e696b, 0, 0, 0, 0x7465737464736b);
+    if partition::write_gpt(&disk, disk_guid, &parts).is_err() {
+        return false;
+    }
+    let Ok(table) = partition::read(&disk) else {
+        return false;
+    };
+    let Some(config) = table.find("config") else {
+        return false;
+    };
+    if table.scheme != Scheme::Gpt || table.disk_guid != disk_guid || table.len() != 2 {
+        return false;
+    }
+    let Some(part) = config.open(&disk) else {
+        return false;
+    };
+    let mut block = [0x5A; SECTOR_SIZE];
+    if part.write_blocks(3, &block).is_err() || part.write_blocks(4, &block).is_ok() {
+        return false;
+    }
+    block.fill(0);
+    disk.read_blocks(config.last_lba, &mut block).is_ok() && block.iter().all(|&b| b == 0x5A)
+}
+
+/// Test FAT32 on a RAM disk: format it, write a file in a subdirectory,
+/// read it back through a fresh mount and remove it
+fn test_fat() -> Result<(), Error> {
+    let mut scratch = SCRATCH.lock();
+    let volume = RamDisk::new(&mut scratch[..], SECTOR_SIZE).ok_or(Error::Io("bad RAM disk block size"))?;
+    fat::format(&volume, "KTEST", 0x6b74_6573)?;
+    let text = b"Written by the block self-test, across more than one cluster. ";
+    {
+        let fs = FatFs::new(&volume)?;
+        let dir = fs.mkdir(&fs.root(), "config")?;
+        let mut file = fs.create(&dir, "Self-test settings.txt")?;
+        for i in 0..20 {
+            fs.write(&mut file, (i * text.len()) as u64, text)?;
+        }
+        fs.flush()?;
+    }
+
+    let fs = FatFs::new(&volume)?;
+    let free = fs.free_clusters()?;
+    let file = fs.lookup("/CONFIG/self-test SETTINGS.txt")?;
+    let mut buf = TEST_BUF.lock();
+    let len = fs.read(&file, 0, &mut buf[..])?;
+    if len != 20 * text.len() || buf[..len].chunks(text.len()).any(|chunk| chunk != text) {
+        return Err(Error::Io("FAT file read-back mismatch"));
+    }
+    drop(buf);
+    fs.remove(&fs.lookup("config")?, "self-test settings.txt")?;
+    fs.remove(&fs.root(), "config")?;
+    if fs.free_clusters()? != free + len.div_ceil(fs.cluster_size()) as u32 + 1 {
+        return Err(Error::Io("FAT clusters not freed"));
+    }
+    fs.flush()
+}
+
+/// Print the disk's partitions, if it has a partition table, and any
+/// ext2/3/4 filesystem on them (or on the whole disk)
+fn log_partitions(dev: &impl BlockDevice) {
+    let Ok(table) = partition::read(dev) else {
+        log_ext(dev);
+        return;
+    };
+    let _ = writeln!(ConsoleWriter, "# partition table: {:?}, {} partition(s)", table.scheme, table.len());
+    for p in table.partitions() {
+        let _ = write!(ConsoleWriter, "#   {}: blocks {}-{}", p.number, p.first_lba, p.last_lba);
+        if table.scheme == Scheme::Mbr {
+            let _ = writeln!(ConsoleWriter, ", type {:#04x}", p.mbr_type);
+        } else {
+            let _ = write!(ConsoleWriter, ", type {} \"", p.type_guid);
+            p.name().for_each(|c| {
+                let _ = ConsoleWriter.write_char(c);
+            });
+            let _ = writeln!(ConsoleWriter, "\"");
+        }
+        if let Some(part) = p.open(dev) {
+            log_ext(&part);
+        }
+    }
+}
+
+/// Print the label and top-level entries of an ext2/3/4 filesystem on `dev`
+fn log_ext(dev: &impl BlockDevice) {
+    let Ok(fs) = ExtFs::new(dev) else {
+        return;
+    };
+    let _ = write!(ConsoleWriter, "#   ext, label \"{}\", {}-byte blocks:", fs.label(), fs.block_size());
+    if let Ok(entries) = fs.root().and_then(|root| fs.read_dir(&root)) {
+        for entry in entries.flatten().filter(|e| !e.is_dot()) {
+            let _ = write!(ConsoleWriter, " {}", core::str::from_utf8(entry.name()).unwrap_or("?"));
+        }
+    }
+    let _ = writeln!(ConsoleWriter);
+}
+
+impl BlockDevice for VirtioBlock {
+    fn block_size(&self) -> usize {
+        VirtioBlock::block_size(self)
+    }
+
+    fn block_count(&self) -> u64 {
+        VirtioBlock::block_count(self)
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        VirtioBlock::read_blocks(self, lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        VirtioBlock::write_blocks(self, lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        VirtioBlock::flush(self)
+    }
 }
 
 /// Block device test result
@@ -579,3 +1192,65 @@ pub struct BlockTestResult {
     pub data_matches: usize,
     pub test_passed: bool,
 }
+
+#[kernel_test]
+fn block() -> Outcome {
+    let Some((dev, modern)) = crate::find_virtio_device(0x1042) else {
+        return Outcome::Skip("no virtio-blk device");
+    };
+    let Some(block) = (unsafe { VirtioBlock::from_modern(&modern, dev.ecam_addr) }) else {
+        return Outcome::Fail("device init failed");
+    };
+    block.log_info();
+    if unsafe { VirtioBlock::from_modern(&modern, dev.ecam_addr) }.is_some() {
+        return Outcome::Fail("a second driver took over the queues in use");
+    }
+    // Before the tests below write over the start of the disk
+    log_partitions(&block);
+    if let Some(root) = cmdline::root() {
+        let _ = writeln!(ConsoleWriter, "# root={}", root);
+    }
+
+    if block.read_only() {
+        // Nothing to write to; just check writes are refused up front
+        return Outcome::check(
+            block.write_blocks(0, &[0; SECTOR_SIZE]) == Err("disk is read-only"),
+            "write to a read-only disk was not refused",
+        );
+    }
+
+    // The single-sector tests need 512-byte blocks
+    if block.block_size() == SECTOR_SIZE {
+        let result = block.test_read_write();
+        let _ = writeln!(
+            ConsoleWriter,
+            "# write={} read={} match={}",
+            result.write_ok, result.read_ok, result.data_matches
+        );
+        if !result.test_passed {
+            return Outcome::Fail("sector read-back mismatch");
+        }
+        if !block.test_async() {
+            return Outcome::Fail("async read-back mismatch");
+        }
+    }
+    if !block.test_blocks() {
+        return Outcome::Fail("multi-block read-back mismatch");
+    }
+    if !block.test_queues() {
+        return Outcome::Fail("request queues disagree");
+    }
+    if !block.test_cache() {
+        return Outcome::Fail("block cache write-back mismatch");
+    }
+    if !test_partitions() {
+        return Outcome::Fail("partition table round trip failed");
+    }
+    if let Err(e) = test_fat() {
+        return Outcome::Fail(e.message());
+    }
+    match block.test_commands() {
+        Ok(()) => Outcome::Pass,
+        Err(e) => Outcome::Fail(e),
+    }
+}
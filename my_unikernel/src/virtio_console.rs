@@ -6,7 +6,7 @@
 //! Notes:
 //! - virtio device id for "console" is 3, so modern PCI device id is 0x1040 + 3 = 0x1043.
 //! - Only negotiates VIRTIO_F_VERSION_1. Split ring only.
-//! - Uses fixed DMA-visible RAM for vrings + buffers (similar to your GPU driver).
+//! - Uses a DMA-visible RAM area set aside at boot for vrings + buffers (like the GPU driver).
 //! - Includes a tiny PCI BAR allocator for VZ where BARs start at 0.
 //!
 //! Integrate by calling `find_virtio_console()` early, storing it globally, and
@@ -15,7 +15,11 @@
 //! If you want input: call `console.poll_read(...)`.
 
 use core::ptr::{read_volatile, write_volatile};
-use core::sync::atomic::{fence, Ordering};
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+
+use crate::executor::{self, IoEvent};
+use crate::memory;
+use crate::sync::IrqSpinLock;
 
 // -------------------------- PCI constants --------------------------
 
@@ -73,28 +77,31 @@ const QUEUE_SIZE: u16 = 16;
 
 const VRING_DESC_F_WRITE: u16 = 2;
 
-// -------------------------- Fixed DMA layout --------------------------
-// Pick a region different from your GPU driver. Adjust if you already use 0x8001_xxxx.
+// ------------------------------ DMA layout ------------------------------
+// Offsets into memory::Region::Console, which is set aside at boot.
 
-const DMA_BASE: u64 = 0x8001_0000;
+/// Physical address of `offset` in the console's DMA area
+fn dma(offset: u64) -> u64 {
+    memory::region(memory::Region::Console) + offset
+}
 
 // Queue 0: RX
-const RX_DESC: u64 = DMA_BASE + 0x0000;
-const RX_AVAIL: u64 = DMA_BASE + 0x1000;
-const RX_USED: u64 = DMA_BASE + 0x2000;
+const RX_DESC: u64 = 0x0000;
+const RX_AVAIL: u64 = 0x1000;
+const RX_USED: u64 = 0x2000;
 
 // Queue 1: TX
-const TX_DESC: u64 = DMA_BASE + 0x3000;
-const TX_AVAIL: u64 = DMA_BASE + 0x4000;
-const TX_USED: u64 = DMA_BASE + 0x5000;
+const TX_DESC: u64 = 0x3000;
+const TX_AVAIL: u64 = 0x4000;
+const TX_USED: u64 = 0x5000;
 
-const TX_BUF: u64 = DMA_BASE + 0x6000;
+const TX_BUF: u64 = 0x6000;
 
 // RX buffers: QUEUE_SIZE * RX_BUF_SZ bytes
-const RX_BUFS: u64 = DMA_BASE + 0x7000;
+const RX_BUFS: u64 = 0x7000;
 const RX_BUF_SZ: usize = 512;
 
-// Size of fixed DMA region we clear on init (conservative)
+// Size of the DMA region we clear on init (conservative)
 const DMA_CLEAR_LEN: usize = 0x9000;
 
 // -------------------------- ECAM scan addresses --------------------------
@@ -150,7 +157,7 @@ fn align_up(x: u64, a: u64) -> u64 {
 
 // Minimal PCI BAR allocator (for VZ where BARs come up as 0).
 // Allocates memory BARs sequentially from a chosen MMIO window.
-static mut PCI_MMIO_NEXT: u64 = 0x5001_0000; // Start after GPU's 0x50008000
+static PCI_MMIO_NEXT: AtomicU64 = AtomicU64::new(0x5001_0000); // Start after GPU's 0x50008000
 
 unsafe fn pci_program_bars_if_needed(config_base: u64) {
     let mut bar = 0u8;
@@ -227,8 +234,11 @@ unsafe fn pci_program_bars_if_needed(config_base: u64) {
         }
 
         // Allocate a base aligned to size.
-        let base = align_up(PCI_MMIO_NEXT, size.max(0x1000));
-        PCI_MMIO_NEXT = base + size;
+        let align = size.max(0x1000);
+        let next = PCI_MMIO_NEXT
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| Some(align_up(next, align) + size))
+            .unwrap();
+        let base = align_up(next, align);
 
         // Program BAR
         mmio_write_u32(bar_reg, (base as u32) & !0xF);
@@ -404,14 +414,14 @@ impl VirtioConsole {
 
             // Clear DMA region for rings/buffers
             for i in 0..DMA_CLEAR_LEN {
-                write_volatile((DMA_BASE + i as u64) as *mut u8, 0);
+                write_volatile(dma(i as u64) as *mut u8, 0);
             }
             fence(Ordering::SeqCst);
 
             // Setup RX queue (queue 0)
-            let (rx_nof, qsize) = setup_queue(common, 0, QUEUE_SIZE, RX_DESC, RX_AVAIL, RX_USED)?;
+            let (rx_nof, qsize) = setup_queue(common, 0, QUEUE_SIZE, dma(RX_DESC), dma(RX_AVAIL), dma(RX_USED))?;
             // Setup TX queue (queue 1)
-            let (tx_nof, _qsize2) = setup_queue(common, 1, qsize, TX_DESC, TX_AVAIL, TX_USED)?;
+            let (tx_nof, _qsize2) = setup_queue(common, 1, qsize, dma(TX_DESC), dma(TX_AVAIL), dma(TX_USED))?;
 
             // DRIVER_OK
             mmio_write_u8(
@@ -456,33 +466,61 @@ impl VirtioConsole {
     }
 
     pub fn write(&mut self, bytes: &[u8]) {
-        unsafe {
-            if bytes.is_empty() {
+        if bytes.is_empty() {
+            return;
+        }
+
+        // Wait out an async write still on the ring, then our own
+        // If either times out, continue (debug prints best-effort)
+        self.tx_wait_idle();
+        self.tx_submit(bytes);
+        self.tx_wait_idle();
+    }
+
+    fn tx_wait_idle(&mut self) {
+        for _ in 0..10_000_000u64 {
+            if self.tx_reap() {
                 return;
             }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Catch up with the TX used ring; true once every posted buffer is back
+    fn tx_reap(&mut self) -> bool {
+        unsafe {
+            fence(Ordering::SeqCst);
+            self.tx_last_used = read_volatile((dma(TX_USED) + 2) as *const u16);
+            self.tx_last_used == read_volatile((dma(TX_AVAIL) + 2) as *const u16)
+        }
+    }
 
+    /// Copy up to RX_BUF_SZ bytes into TX_BUF and post them; returns the count
+    /// The previous transmit must have been reaped
+    fn tx_submit(&mut self, bytes: &[u8]) -> usize {
+        unsafe {
             // Copy to TX_BUF (truncate to RX_BUF_SZ just to bound runtime; adjust if needed)
             let n = core::cmp::min(bytes.len(), RX_BUF_SZ);
             for i in 0..n {
-                write_volatile((TX_BUF + i as u64) as *mut u8, bytes[i]);
+                write_volatile((dma(TX_BUF) + i as u64) as *mut u8, bytes[i]);
             }
             fence(Ordering::SeqCst);
 
-            // Use a single descriptor index derived from tx_last_used (safe since we wait)
+            // Use a single descriptor index derived from tx_last_used (safe since the ring is idle)
             let desc_idx = (self.tx_last_used as u16) % self.qsize;
 
             // desc[desc_idx] = TX buffer
-            let desc_addr = TX_DESC + (desc_idx as u64) * 16;
-            mmio_write_u64(desc_addr + 0, TX_BUF);
+            let desc_addr = dma(TX_DESC) + (desc_idx as u64) * 16;
+            mmio_write_u64(desc_addr + 0, dma(TX_BUF));
             mmio_write_u32(desc_addr + 8, n as u32);
             mmio_write_u16(desc_addr + 12, 0); // device reads only
             mmio_write_u16(desc_addr + 14, 0);
             fence(Ordering::SeqCst);
 
             // push desc_idx into avail ring
-            let avail_idx_ptr = (TX_AVAIL + 2) as *mut u16;
+            let avail_idx_ptr = (dma(TX_AVAIL) + 2) as *mut u16;
             let avail_idx = read_volatile(avail_idx_ptr as *const u16);
-            let ring_entry = (TX_AVAIL + 4 + ((avail_idx % self.qsize) as u64) * 2) as *mut u16;
+            let ring_entry = (dma(TX_AVAIL) + 4 + ((avail_idx % self.qsize) as u64) * 2) as *mut u16;
             write_volatile(ring_entry, desc_idx);
             fence(Ordering::SeqCst);
             write_volatile(avail_idx_ptr, avail_idx.wrapping_add(1));
@@ -491,21 +529,19 @@ impl VirtioConsole {
             // notify queue 1
             self.notify(1, self.tx_notify_off);
 
-            // wait for used idx to advance (polled)
-            let used_idx_ptr = (TX_USED + 2) as *const u16;
-            for _ in 0..10_000_000u64 {
-                fence(Ordering::SeqCst);
-                let used_idx = read_volatile(used_idx_ptr);
-                if used_idx != self.tx_last_used {
-                    self.tx_last_used = used_idx;
-                    return;
-                }
-                core::hint::spin_loop();
-            }
-            // If it times out, continue (debug prints best-effort)
+            n
         }
     }
 
+    /// True if the device has returned TX / RX buffers we have not reaped yet
+    fn tx_has_used(&self) -> bool {
+        unsafe { read_volatile((dma(TX_USED) + 2) as *const u16) != self.tx_last_used }
+    }
+
+    fn rx_has_used(&self) -> bool {
+        unsafe { read_volatile((dma(RX_USED) + 2) as *const u16) != self.rx_last_used }
+    }
+
     // Optional: implement core::fmt::Write so you can use write_fmt!/format_args!
     pub fn write_str(&mut self, s: &str) {
         self.write(s.as_bytes());
@@ -517,9 +553,9 @@ impl VirtioConsole {
         unsafe {
             // Post one buffer per descriptor slot
             for i in 0..self.qsize {
-                let buf_addr = RX_BUFS + (i as u64) * (RX_BUF_SZ as u64);
+                let buf_addr = dma(RX_BUFS) + (i as u64) * (RX_BUF_SZ as u64);
 
-                let desc_addr = RX_DESC + (i as u64) * 16;
+                let desc_addr = dma(RX_DESC) + (i as u64) * 16;
                 mmio_write_u64(desc_addr + 0, buf_addr);
                 mmio_write_u32(desc_addr + 8, RX_BUF_SZ as u32);
                 mmio_write_u16(desc_addr + 12, VRING_DESC_F_WRITE);
@@ -528,10 +564,10 @@ impl VirtioConsole {
             fence(Ordering::SeqCst);
 
             // Fill avail ring with all descriptors
-            let avail_idx_ptr = (RX_AVAIL + 2) as *mut u16;
+            let avail_idx_ptr = (dma(RX_AVAIL) + 2) as *mut u16;
             write_volatile(avail_idx_ptr, 0);
             for i in 0..self.qsize {
-                let ring_entry = (RX_AVAIL + 4 + (i as u64) * 2) as *mut u16;
+                let ring_entry = (dma(RX_AVAIL) + 4 + (i as u64) * 2) as *mut u16;
                 write_volatile(ring_entry, i);
             }
             fence(Ordering::SeqCst);
@@ -547,7 +583,7 @@ impl VirtioConsole {
     /// Non-blocking: returns 0 if no input available.
     pub fn poll_read(&mut self, out: &mut [u8]) -> usize {
         unsafe {
-            let used_idx_ptr = (RX_USED + 2) as *const u16;
+            let used_idx_ptr = (dma(RX_USED) + 2) as *const u16;
             let used_idx = read_volatile(used_idx_ptr);
             if used_idx == self.rx_last_used {
                 return 0;
@@ -555,22 +591,22 @@ impl VirtioConsole {
 
             // Read used element at (rx_last_used % qsize)
             let elem_idx = (self.rx_last_used % self.qsize) as u64;
-            let used_elem_addr = RX_USED + 4 + elem_idx * 8;
+            let used_elem_addr = dma(RX_USED) + 4 + elem_idx * 8;
             let id = mmio_read_u32(used_elem_addr + 0) as u16;
             let len = mmio_read_u32(used_elem_addr + 4) as usize;
 
             self.rx_last_used = used_idx;
 
             let n = core::cmp::min(len, out.len());
-            let buf_addr = RX_BUFS + (id as u64) * (RX_BUF_SZ as u64);
+            let buf_addr = dma(RX_BUFS) + (id as u64) * (RX_BUF_SZ as u64);
             for i in 0..n {
                 out[i] = read_volatile((buf_addr + i as u64) as *const u8);
             }
 
             // Repost the same descriptor id to avail ring
-            let avail_idx_ptr = (RX_AVAIL + 2) as *mut u16;
+            let avail_idx_ptr = (dma(RX_AVAIL) + 2) as *mut u16;
             let avail_idx = read_volatile(avail_idx_ptr as *const u16);
-            let ring_entry = (RX_AVAIL + 4 + ((avail_idx % self.qsize) as u64) * 2) as *mut u16;
+            let ring_entry = (dma(RX_AVAIL) + 4 + ((avail_idx % self.qsize) as u64) * 2) as *mut u16;
             write_volatile(ring_entry, id);
             fence(Ordering::SeqCst);
             write_volatile(avail_idx_ptr, avail_idx.wrapping_add(1));
@@ -653,44 +689,126 @@ impl core::fmt::Write for VirtioConsole {
 
 // -------------------------- Global console instance --------------------------
 
-static mut CONSOLE: Option<VirtioConsole> = None;
+static CONSOLE: IrqSpinLock<Option<VirtioConsole>> = IrqSpinLock::new(None);
+static CONSOLE_READY: AtomicBool = AtomicBool::new(false);
+
+// Woken when input arrives / a transmit completes
+static RX_EVENT: IoEvent = IoEvent::new();
+static TX_EVENT: IoEvent = IoEvent::new();
 
 /// Initialize the virtio console. Call early in boot.
 pub fn console_init() {
-    unsafe {
-        CONSOLE = find_virtio_console();
-    }
+    let console = find_virtio_console();
+    let found = console.is_some();
+    *CONSOLE.lock() = console;
+    CONSOLE_READY.store(found, Ordering::Release);
 }
 
 /// Check if console is available
 pub fn console_available() -> bool {
-    unsafe { CONSOLE.is_some() }
+    CONSOLE_READY.load(Ordering::Acquire)
 }
 
 /// Write a single byte
 pub fn putc(b: u8) {
-    unsafe {
-        if let Some(c) = CONSOLE.as_mut() {
-            c.putc(b);
-        }
+    if let Some(c) = CONSOLE.lock().as_mut() {
+        c.putc(b);
     }
 }
 
 /// Write a string
 pub fn puts(s: &str) {
-    unsafe {
-        if let Some(c) = CONSOLE.as_mut() {
-            c.write_str(s);
-        }
+    if let Some(c) = CONSOLE.lock().as_mut() {
+        c.write_str(s);
     }
 }
 
 /// Write bytes
 pub fn write_bytes(bytes: &[u8]) {
-    unsafe {
-        if let Some(c) = CONSOLE.as_mut() {
-            c.write(bytes);
+    if let Some(c) = CONSOLE.lock().as_mut() {
+        c.write(bytes);
+    }
+}
+
+/// write_bytes(), unless the console is locked (by code that crashed
+/// holding it, say); false if nothing was written
+pub fn try_write_bytes(bytes: &[u8]) -> bool {
+    match CONSOLE.try_lock() {
+        Some(mut console) => {
+            if let Some(c) = console.as_mut() {
+                c.write(bytes);
+            }
+            true
         }
+        None => false,
+    }
+}
+
+/// Read available input bytes (non-blocking, returns 0 if none)
+pub fn read_bytes(out: &mut [u8]) -> usize {
+    match CONSOLE.lock().as_mut() {
+        Some(c) => c.poll_read(out),
+        None => 0,
+    }
+}
+
+/// Write bytes without blocking the executor
+pub async fn write_bytes_async(bytes: &[u8]) {
+    for chunk in bytes.chunks(RX_BUF_SZ) {
+        let queued = executor::wait_for(&TX_EVENT, || {
+            let mut console = CONSOLE.lock();
+            let Some(c) = console.as_mut() else {
+                return Some(false);
+            };
+            if !c.tx_reap() {
+                return None;
+            }
+            c.tx_submit(chunk);
+            Some(true)
+        })
+        .await;
+        if !queued {
+            return;
+        }
+    }
+
+    executor::wait_for(&TX_EVENT, || match CONSOLE.lock().as_mut() {
+        Some(c) => c.tx_reap().then_some(()),
+        None => Some(()),
+    })
+    .await;
+    // Let the next writer have the buffer
+    TX_EVENT.wake();
+}
+
+/// Wait for input and return the number of bytes read (0 if there is no console)
+pub async fn read_bytes_async(out: &mut [u8]) -> usize {
+    executor::wait_for(&RX_EVENT, || match CONSOLE.lock().as_mut() {
+        Some(c) => match c.poll_read(out) {
+            0 => None,
+            n => Some(n),
+        },
+        None => Some(0),
+    })
+    .await
+}
+
+/// Wake tasks waiting on console input or a finished transmit
+pub fn poll_io() {
+    let Some(console) = CONSOLE.try_lock() else {
+        return;
+    };
+    let Some(c) = console.as_ref() else {
+        return;
+    };
+    let (rx, tx) = (c.rx_has_used(), c.tx_has_used());
+    drop(console);
+
+    if rx {
+        RX_EVENT.wake();
+    }
+    if tx {
+        TX_EVENT.wake();
     }
 }
 
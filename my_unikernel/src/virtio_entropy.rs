@@ -3,9 +3,17 @@
 //! The simplest VirtIO device - just one queue, device fills buffers with random bytes.
 //! Device IDs: 0x1004 (transitional), 0x1044 (modern)
 
+use core::fmt::Write;
 use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::{fence, Ordering};
 
+use kernel_macros::kernel_test;
+
+use crate::ConsoleWriter;
+use crate::executor::{self, IoEvent};
+use crate::ktest::Outcome;
+use crate::sync::{Once, SpinLock};
+
 // Virtio vendor ID
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 
@@ -85,20 +93,153 @@ struct VringUsed {
     ring: [VringUsedElem; QUEUE_SIZE as usize],
 }
 
-// Static buffers for the entropy queue
-static mut ENTROPY_QUEUE_DESCS: [VringDesc; QUEUE_SIZE as usize] =
-    [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize];
-static mut ENTROPY_QUEUE_AVAIL: VringAvail = VringAvail {
-    flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize]
-};
-static mut ENTROPY_QUEUE_USED: VringUsed = VringUsed {
-    flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize]
-};
-
-// Buffer to receive random bytes
-static mut ENTROPY_BUFFER: [u8; 64] = [0; 64];
-static mut ENTROPY_IDX: u16 = 0;
-static mut ENTROPY_LAST_USED: u16 = 0;
+/// Virtqueue memory, receive buffer and ring indices, shared with the device
+#[repr(C, align(16))]
+struct EntropyQueue {
+    descs: [VringDesc; QUEUE_SIZE as usize],
+    avail: VringAvail,
+    used: VringUsed,
+    buffer: [u8; 64],
+    idx: u16,
+    last_used: u16,
+    // One receive buffer, so at most one request is outstanding
+    in_flight: bool,
+}
+
+static ENTROPY_QUEUE: SpinLock<EntropyQueue> = SpinLock::new(EntropyQueue {
+    descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
+    avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
+    used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
+    buffer: [0; 64],
+    idx: 0,
+    last_used: 0,
+    in_flight: false,
+});
+
+// Woken when a request completes or the queue becomes free
+static ENTROPY_EVENT: IoEvent = IoEvent::new();
+
+impl EntropyQueue {
+    /// Physical addresses of the descriptor table, available and used rings
+    fn ring_addrs(&self) -> (u64, u64, u64) {
+        (
+            self.descs.as_ptr() as u64,
+            &self.avail as *const VringAvail as u64,
+            &self.used as *const VringUsed as u64,
+        )
+    }
+
+    /// Mark the queue busy; None if a request is already outstanding
+    fn claim(&mut self) -> Option<()> {
+        if self.in_flight {
+            return None;
+        }
+        self.in_flight = true;
+        Some(())
+    }
+
+    /// Ask the device to fill the first `len` bytes of the buffer
+    fn submit(&mut self, notify_addr: u64, len: usize) {
+        unsafe {
+            // Clear buffer
+            for i in 0..len {
+                self.buffer[i] = 0;
+            }
+
+            let idx = self.idx % QUEUE_SIZE;
+
+            // Set up descriptor - device-writable buffer
+            self.descs[idx as usize] = VringDesc {
+                addr: self.buffer.as_ptr() as u64,
+                len: len as u32,
+                flags: VRING_DESC_F_WRITE,
+                next: 0,
+            };
+
+            fence(Ordering::SeqCst);
+
+            // Add to available ring
+            let avail_idx = read_volatile(&self.avail.idx);
+            write_volatile(
+                &mut self.avail.ring[(avail_idx % QUEUE_SIZE) as usize],
+                idx,
+            );
+            fence(Ordering::SeqCst);
+            write_volatile(&mut self.avail.idx, avail_idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+
+            // Notify device
+            write_volatile(notify_addr as *mut u16, 0);
+            fence(Ordering::SeqCst);
+
+            self.idx = self.idx.wrapping_add(1);
+        }
+    }
+
+    /// True once the device has returned the outstanding request
+    fn has_completion(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.in_flight && unsafe { read_volatile(&self.used.idx) } != self.last_used
+    }
+
+    /// Copy the device's bytes into `buf`; None while the request is outstanding
+    fn complete(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if !self.has_completion() {
+            return None;
+        }
+        unsafe {
+            // Get length from used ring
+            let used_elem = read_volatile(
+                &self.used.ring[(self.last_used % QUEUE_SIZE) as usize]
+            );
+            self.last_used = read_volatile(&self.used.idx);
+            self.in_flight = false;
+
+            // Copy received bytes
+            let copy_len = (used_elem.len as usize).min(buf.len());
+            for i in 0..copy_len {
+                buf[i] = read_volatile(&self.buffer[i]);
+            }
+
+            Some(copy_len)
+        }
+    }
+}
+
+// The device, set up on first use (it owns the one static queue)
+static DEVICE: Once<Option<VirtioEntropy>> = Once::new();
+
+/// The virtio-rng device, if the VM has one
+pub fn device() -> Option<&'static VirtioEntropy> {
+    DEVICE
+        .call_once(|| {
+            let (dev, modern) = crate::find_virtio_device(VIRTIO_ENTROPY_MODERN)?;
+            unsafe { VirtioEntropy::from_modern(&modern, dev.ecam_addr) }
+        })
+        .as_ref()
+}
+
+/// Fill `buf` from the device; false if there is none or it stops answering
+pub fn fill(buf: &mut [u8]) -> bool {
+    let Some(rng) = device() else {
+        return false;
+    };
+    let mut filled = 0;
+    while filled < buf.len() {
+        match rng.read(&mut buf[filled..]) {
+            0 => return false,
+            n => filled += n,
+        }
+    }
+    true
+}
+
+/// Wake tasks waiting on an entropy request that has completed
+pub fn poll_io() {
+    if ENTROPY_QUEUE.try_lock().is_some_and(|q| q.has_completion()) {
+        ENTROPY_EVENT.wake();
+    }
+}
 
 /// VirtIO Entropy device capability
 #[derive(Clone, Copy)]
@@ -162,9 +303,7 @@ impl VirtioEntropy {
         let actual_size = queue_size_max.min(QUEUE_SIZE);
         write_volatile((common_base + 24) as *mut u16, actual_size);
 
-        let desc_addr = &raw const ENTROPY_QUEUE_DESCS as u64;
-        let avail_addr = &raw const ENTROPY_QUEUE_AVAIL as u64;
-        let used_addr = &raw const ENTROPY_QUEUE_USED as u64;
+        let (desc_addr, avail_addr, used_addr) = ENTROPY_QUEUE.lock().ring_addrs();
 
         write_volatile((common_base + 32) as *mut u32, desc_addr as u32);
         write_volatile((common_base + 36) as *mut u32, (desc_addr >> 32) as u32);
@@ -342,9 +481,7 @@ impl VirtioEntropy {
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
             // Set queue addresses
-            let desc_addr = &raw const ENTROPY_QUEUE_DESCS as u64;
-            let avail_addr = &raw const ENTROPY_QUEUE_AVAIL as u64;
-            let used_addr = &raw const ENTROPY_QUEUE_USED as u64;
+            let (desc_addr, avail_addr, used_addr) = ENTROPY_QUEUE.lock().ring_addrs();
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -377,75 +514,56 @@ impl VirtioEntropy {
     }
 
     /// Read random bytes from the device
+    /// Returns 0 if an async read currently owns the queue
     pub fn read(&self, buf: &mut [u8]) -> usize {
         if buf.is_empty() {
             return 0;
         }
 
-        unsafe {
-            let len = buf.len().min(ENTROPY_BUFFER.len());
+        let mut q = ENTROPY_QUEUE.lock();
+        if q.claim().is_none() {
+            return 0;
+        }
+        let len = buf.len().min(q.buffer.len());
+        q.submit(self.notify_addr(), len);
 
-            // Clear buffer
-            for i in 0..len {
-                ENTROPY_BUFFER[i] = 0;
+        // Wait for completion
+        for _ in 0..100_000 {
+            if let Some(n) = q.complete(buf) {
+                return n;
             }
+            core::hint::spin_loop();
+        }
 
-            let idx = ENTROPY_IDX % QUEUE_SIZE;
-
-            // Set up descriptor - device-writable buffer
-            ENTROPY_QUEUE_DESCS[idx as usize] = VringDesc {
-                addr: ENTROPY_BUFFER.as_ptr() as u64,
-                len: len as u32,
-                flags: VRING_DESC_F_WRITE,
-                next: 0,
-            };
-
-            fence(Ordering::SeqCst);
-
-            // Add to available ring
-            let avail_idx = read_volatile(&ENTROPY_QUEUE_AVAIL.idx);
-            write_volatile(
-                &raw mut ENTROPY_QUEUE_AVAIL.ring[(avail_idx % QUEUE_SIZE) as usize],
-                idx,
-            );
-            fence(Ordering::SeqCst);
-            write_volatile(&raw mut ENTROPY_QUEUE_AVAIL.idx, avail_idx.wrapping_add(1));
-            fence(Ordering::SeqCst);
+        q.in_flight = false;
+        0
+    }
 
-            // Notify device
-            let notify_addr = self.bar0
-                + self.notify_offset as u64
-                + (self.queue_notify_off as u64 * self.notify_multiplier as u64);
-            write_volatile(notify_addr as *mut u16, 0);
-            fence(Ordering::SeqCst);
+    /// Read random bytes without blocking the executor
+    pub async fn read_async(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
 
-            ENTROPY_IDX = ENTROPY_IDX.wrapping_add(1);
-
-            // Wait for completion
-            let mut bytes_received = 0usize;
-            for _ in 0..100_000 {
-                fence(Ordering::SeqCst);
-                let used_idx = read_volatile(&ENTROPY_QUEUE_USED.idx);
-                if used_idx != ENTROPY_LAST_USED {
-                    // Get length from used ring
-                    let used_elem = read_volatile(
-                        &ENTROPY_QUEUE_USED.ring[(ENTROPY_LAST_USED % QUEUE_SIZE) as usize]
-                    );
-                    bytes_received = used_elem.len as usize;
-                    ENTROPY_LAST_USED = used_idx;
-                    break;
-                }
-                core::hint::spin_loop();
-            }
+        executor::wait_for(&ENTROPY_EVENT, || {
+            let mut q = ENTROPY_QUEUE.lock();
+            q.claim()?;
+            let len = buf.len().min(q.buffer.len());
+            q.submit(self.notify_addr(), len);
+            Some(())
+        })
+        .await;
 
-            // Copy received bytes
-            let copy_len = bytes_received.min(buf.len());
-            for i in 0..copy_len {
-                buf[i] = read_volatile(&ENTROPY_BUFFER[i]);
-            }
+        let n = executor::wait_for(&ENTROPY_EVENT, || ENTROPY_QUEUE.lock().complete(buf)).await;
+        // Let the next waiter claim the queue
+        ENTROPY_EVENT.wake();
+        n
+    }
 
-            copy_len
-        }
+    fn notify_addr(&self) -> u64 {
+        self.bar0
+            + self.notify_offset as u64
+            + (self.queue_notify_off as u64 * self.notify_multiplier as u64)
     }
 
     /// Test entropy quality - returns stats about randomness
@@ -524,3 +642,16 @@ pub struct EntropyStats {
     pub unique_bytes: u32,
     pub looks_random: bool,
 }
+
+#[kernel_test]
+fn entropy() -> Outcome {
+    if crate::find_virtio_device(VIRTIO_ENTROPY_MODERN).is_none() {
+        return Outcome::Skip("no virtio-rng device");
+    }
+    let Some(entropy) = device() else {
+        return Outcome::Fail("device init failed");
+    };
+    let stats = entropy.test_entropy();
+    let _ = writeln!(ConsoleWriter, "# bytes={} unique={}", stats.bytes_read, stats.unique_bytes);
+    Outcome::check(stats.looks_random, "output does not look random")
+}
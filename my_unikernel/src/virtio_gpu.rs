@@ -3,8 +3,18 @@
 //! Implements basic virtio-gpu protocol to display graphics
 
 use core::ptr::{read_volatile, write_volatile};
+use core::mem::size_of;
 use core::sync::atomic::{fence, Ordering};
 
+use driver_core::gpu::{
+    self, Command, CtrlHdr, Rect, ResourceAttachBacking, ResourceCreate2d, ResourceFlush, RespDisplayInfo,
+    SetScanout, TransferToHost2d, VIRTIO_GPU_CMD_GET_DISPLAY_INFO, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+    VIRTIO_GPU_RESP_OK_DISPLAY_INFO, VIRTIO_GPU_RESP_OK_NODATA,
+};
+
+use crate::memory;
+use crate::sync::SpinLock;
+
 // PCI config space offsets
 const PCI_VENDOR_ID: usize = 0x00;
 const PCI_DEVICE_ID: usize = 0x02;
@@ -46,30 +56,20 @@ const VIRTIO_PCI_COMMON_Q_AVAILHI: usize = 0x2c;
 const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
 const VIRTIO_PCI_COMMON_Q_USEDHI: usize = 0x34;
 
-// GPU command types
-const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
-const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
-const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
-const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
-const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
-const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
-
-// GPU response types
-const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
-const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
-// Error responses start at 0x1200
-const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+// Control queue layout inside memory::Region::GpuQueue
+const DESC_OFFSET: u64 = 0;
+const AVAIL_OFFSET: u64 = 0x1000;  // 4KB aligned
+const USED_OFFSET: u64 = 0x2000;   // 4KB aligned
+const CMD_OFFSET: u64 = 0x3000;
+const RESP_OFFSET: u64 = 0x4000;
 
-// Response buffer address (must match send_cmd)
-const RESP_ADDR: u64 = 0x8000_4000;
-
-// GPU formats
-const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 1;  // Try this for Apple Silicon
-const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
-const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 3;
+fn queue_ram_base() -> u64 {
+    memory::region(memory::Region::GpuQueue)
+}
 
-// Hardcoded framebuffer address in VZ RAM (0x70000000 + 32MB offset)
-const HARDCODED_FB_ADDR: u64 = 0x7200_0000;
+fn fb_addr() -> u64 {
+    memory::region(memory::Region::Framebuffer)
+}
 
 const QUEUE_SIZE: u16 = 16;
 
@@ -107,128 +107,22 @@ struct VringUsed {
     ring: [VringUsedElem; QUEUE_SIZE as usize],
 }
 
-#[repr(C, align(4096))]
-struct Virtqueue {
-    descs: [VringDesc; QUEUE_SIZE as usize],
-    avail: VringAvail,
-    _pad: [u8; 2048],
-    used: VringUsed,
-}
-
-// GPU command/response headers
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct VirtioGpuCtrlHdr {
-    cmd_type: u32,
-    flags: u32,
-    fence_id: u64,
-    ctx_id: u32,
-    padding: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuRect {
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuDisplayOne {
-    r: VirtioGpuRect,
-    enabled: u32,
-    flags: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuRespDisplayInfo {
-    hdr: VirtioGpuCtrlHdr,
-    pmodes: [VirtioGpuDisplayOne; 16],
-}
-
-#[repr(C)]
-struct VirtioGpuResourceCreate2d {
-    hdr: VirtioGpuCtrlHdr,
-    resource_id: u32,
-    format: u32,
-    width: u32,
-    height: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuSetScanout {
-    hdr: VirtioGpuCtrlHdr,
-    r: VirtioGpuRect,
-    scanout_id: u32,
-    resource_id: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuMemEntry {
-    addr: u64,
-    length: u32,
-    padding: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuResourceAttachBacking {
-    hdr: VirtioGpuCtrlHdr,
-    resource_id: u32,
-    nr_entries: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuTransferToHost2d {
-    hdr: VirtioGpuCtrlHdr,
-    r: VirtioGpuRect,
-    offset: u64,
-    resource_id: u32,
-    padding: u32,
-}
-
-#[repr(C)]
-struct VirtioGpuResourceFlush {
-    hdr: VirtioGpuCtrlHdr,
-    r: VirtioGpuRect,
-    resource_id: u32,
-    padding: u32,
-}
-
-// Static buffers
-static mut GPU_QUEUE: Virtqueue = Virtqueue {
-    descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
-    avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
-    _pad: [0; 2048],
-    used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
-};
-
-// Command/response buffers
-#[repr(C, align(64))]
-struct CmdBuffer {
-    data: [u8; 4096],
-}
-
-static mut CMD_BUF: CmdBuffer = CmdBuffer { data: [0; 4096] };
-static mut RESP_BUF: CmdBuffer = CmdBuffer { data: [0; 4096] };
-
 // Framebuffer - 1280x720 @ 32bpp = ~3.5MB
 const FB_WIDTH: u32 = 1280;
 const FB_HEIGHT: u32 = 720;
 const FB_BPP: u32 = 4;
 const FB_SIZE: usize = (FB_WIDTH * FB_HEIGHT * FB_BPP) as usize;
 
-#[repr(C, align(4096))]
-struct Framebuffer {
-    pixels: [u32; (FB_WIDTH * FB_HEIGHT) as usize],
+/// Control queue ring indices
+///
+/// The rings and command/response buffers themselves sit in the GpuQueue
+/// area (see send_cmd); holding this lock serialises use of all of them.
+struct ControlQueue {
+    next_desc: u16,
+    last_used: u16,
 }
 
-static mut FRAMEBUFFER: Framebuffer = Framebuffer {
-    pixels: [0; (FB_WIDTH * FB_HEIGHT) as usize],
-};
-
-static mut QUEUE_IDX: u16 = 0;
-static mut LAST_USED_IDX: u16 = 0;
+static CONTROLQ: SpinLock<ControlQueue> = SpinLock::new(ControlQueue { next_desc: 0, last_used: 0 });
 
 pub struct VirtioGpu {
     notify_base: u64,           // Pre-computed: BAR address + notify_cfg offset
@@ -236,6 +130,7 @@ pub struct VirtioGpu {
     queue_notify_off: u16,
     width: u32,
     height: u32,
+    mode_override: bool,        // Resolution forced by set_resolution(), ignore display info
 }
 
 impl VirtioGpu {
@@ -460,20 +355,16 @@ impl VirtioGpu {
             let actual_size = if queue_size_max < QUEUE_SIZE { queue_size_max } else { QUEUE_SIZE };
             write_volatile((common_cfg + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
-            // Use fixed RAM addresses for queue structures (0x80000000 region)
-            // This ensures the device can access them via DMA
-            const QUEUE_RAM_BASE: u64 = 0x8000_0000;
-            const DESC_OFFSET: u64 = 0;
-            const AVAIL_OFFSET: u64 = 0x1000;  // 4KB aligned
-            const USED_OFFSET: u64 = 0x2000;   // 4KB aligned
-
-            let desc_addr = QUEUE_RAM_BASE + DESC_OFFSET;
-            let avail_addr = QUEUE_RAM_BASE + AVAIL_OFFSET;
-            let used_addr = QUEUE_RAM_BASE + USED_OFFSET;
+            // Queue structures live in a RAM area set aside at boot, so the
+            // device can reach them via DMA
+            let queue_ram = queue_ram_base();
+            let desc_addr = queue_ram + DESC_OFFSET;
+            let avail_addr = queue_ram + AVAIL_OFFSET;
+            let used_addr = queue_ram + USED_OFFSET;
 
             // Zero initialize the queue memory
-            for i in 0..0x3000u64 {
-                write_volatile((QUEUE_RAM_BASE + i) as *mut u8, 0);
+            for i in 0..CMD_OFFSET {
+                write_volatile((queue_ram + i) as *mut u8, 0);
             }
 
             write_volatile((common_cfg + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
@@ -503,52 +394,53 @@ impl VirtioGpu {
                 queue_notify_off,
                 width: FB_WIDTH,
                 height: FB_HEIGHT,
+                mode_override: false,
             })
         }
     }
 
     /// Returns the response type from the command, or 0 on timeout
     fn send_cmd(&self, cmd: &[u8], resp_len: usize) -> u32 {
+        let mut q = CONTROLQ.lock();
         unsafe {
             fence(Ordering::SeqCst);
 
-            // Fixed RAM addresses for queue structures
-            const QUEUE_RAM_BASE: u64 = 0x8000_0000;
-            const DESC_BASE: u64 = QUEUE_RAM_BASE;
-            const AVAIL_BASE: u64 = QUEUE_RAM_BASE + 0x1000;
-            const USED_BASE: u64 = QUEUE_RAM_BASE + 0x2000;
-            // Command/response buffers in fixed RAM
-            const CMD_BASE: u64 = QUEUE_RAM_BASE + 0x3000;
-            const RESP_BASE: u64 = QUEUE_RAM_BASE + 0x4000;
+            // Queue structures and command/response buffers (see new())
+            let queue_ram = queue_ram_base();
+            let desc_base = queue_ram + DESC_OFFSET;
+            let avail_base = queue_ram + AVAIL_OFFSET;
+            let used_base = queue_ram + USED_OFFSET;
+            let cmd_base = queue_ram + CMD_OFFSET;
+            let resp_base = queue_ram + RESP_OFFSET;
 
             // Copy command to fixed RAM buffer
             for (i, &b) in cmd.iter().enumerate() {
-                write_volatile((CMD_BASE + i as u64) as *mut u8, b);
+                write_volatile((cmd_base + i as u64) as *mut u8, b);
             }
             fence(Ordering::SeqCst);
 
-            let idx = QUEUE_IDX % QUEUE_SIZE;
+            let idx = q.next_desc % QUEUE_SIZE;
             let next_idx = (idx + 1) % QUEUE_SIZE;
 
             // Write descriptor 0: command (device reads)
-            let desc0_addr = DESC_BASE + (idx as u64) * 16;
-            write_volatile((desc0_addr + 0) as *mut u64, CMD_BASE);         // addr
+            let desc0_addr = desc_base + (idx as u64) * 16;
+            write_volatile((desc0_addr + 0) as *mut u64, cmd_base);         // addr
             write_volatile((desc0_addr + 8) as *mut u32, cmd.len() as u32); // len
             write_volatile((desc0_addr + 12) as *mut u16, VRING_DESC_F_NEXT); // flags
             write_volatile((desc0_addr + 14) as *mut u16, next_idx);        // next
 
             // Write descriptor 1: response (device writes)
-            let desc1_addr = DESC_BASE + (next_idx as u64) * 16;
-            write_volatile((desc1_addr + 0) as *mut u64, RESP_BASE);        // addr
+            let desc1_addr = desc_base + (next_idx as u64) * 16;
+            write_volatile((desc1_addr + 0) as *mut u64, resp_base);        // addr
             write_volatile((desc1_addr + 8) as *mut u32, resp_len as u32);  // len
             write_volatile((desc1_addr + 12) as *mut u16, VRING_DESC_F_WRITE); // flags
             write_volatile((desc1_addr + 14) as *mut u16, 0);               // next
 
             // Add to available ring
             // avail ring: flags(2) + idx(2) + ring[N](2*N)
-            let avail_idx_ptr = (AVAIL_BASE + 2) as *mut u16;
+            let avail_idx_ptr = (avail_base + 2) as *mut u16;
             let avail_idx = read_volatile(avail_idx_ptr as *const u16);
-            let ring_entry_ptr = (AVAIL_BASE + 4 + ((avail_idx % QUEUE_SIZE) as u64) * 2) as *mut u16;
+            let ring_entry_ptr = (avail_base + 4 + ((avail_idx % QUEUE_SIZE) as u64) * 2) as *mut u16;
             write_volatile(ring_entry_ptr, idx);
             fence(Ordering::SeqCst);
             write_volatile(avail_idx_ptr, avail_idx.wrapping_add(1));
@@ -564,24 +456,24 @@ impl VirtioGpu {
             write_volatile(notify_addr as *mut u16, 0);
             fence(Ordering::SeqCst);
 
-            QUEUE_IDX = QUEUE_IDX.wrapping_add(2);
+            q.next_desc = q.next_desc.wrapping_add(2);
 
             // Wait for response - read from fixed USED ring address
             // used ring: flags(2) + idx(2) + ring[N](8*N)
-            let used_idx_ptr = (USED_BASE + 2) as *const u16;
+            let used_idx_ptr = (used_base + 2) as *const u16;
             for _ in 0..50_000_000u64 {
                 fence(Ordering::SeqCst);
                 let used_idx = read_volatile(used_idx_ptr);
-                if used_idx != LAST_USED_IDX {
-                    LAST_USED_IDX = used_idx;
+                if used_idx != q.last_used {
+                    q.last_used = used_idx;
                     // Read and return response type from response buffer
-                    let resp_hdr = RESP_BASE as *const VirtioGpuCtrlHdr;
+                    let resp_hdr = resp_base as *const CtrlHdr;
                     return read_volatile(&(*resp_hdr).cmd_type);
                 }
                 core::hint::spin_loop();
             }
             // TIMEOUT
-            LAST_USED_IDX = LAST_USED_IDX.wrapping_add(1);
+            q.last_used = q.last_used.wrapping_add(1);
             0  // Return 0 to indicate timeout
         }
     }
@@ -598,7 +490,7 @@ impl VirtioGpu {
                     if r == 0 {
                         // Timeout - brk #0xD0
                         core::arch::asm!("mov x1, {0}", "brk #0xD0", in(reg) id);
-                    } else if r >= VIRTIO_GPU_RESP_ERR_UNSPEC {
+                    } else if gpu::is_error(r) {
                         // Error response - brk #0xE0, x0=resp, x1=cmd_id
                         core::arch::asm!("mov x0, {0}", "mov x1, {1}", "brk #0xE0",
                             in(reg) r as u64, in(reg) id);
@@ -612,110 +504,58 @@ impl VirtioGpu {
         }
 
         // 1. Get display info
-        let hdr = VirtioGpuCtrlHdr {
-            cmd_type: VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
-            flags: 0, fence_id: 0, ctx_id: 0, padding: 0,
-        };
-        let cmd_bytes = unsafe {
-            core::slice::from_raw_parts(&hdr as *const _ as *const u8, core::mem::size_of::<VirtioGpuCtrlHdr>())
-        };
-        let resp = self.send_cmd(cmd_bytes, core::mem::size_of::<VirtioGpuRespDisplayInfo>());
+        let cmd = CtrlHdr::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO);
+        let resp = self.send_cmd(cmd.as_bytes(), size_of::<RespDisplayInfo>());
         check_resp!(resp, VIRTIO_GPU_RESP_OK_DISPLAY_INFO, 1);
 
-        // Check dimensions from response (but use hardcoded anyway)
-        unsafe {
-            let disp_resp = &*(RESP_ADDR as *const VirtioGpuRespDisplayInfo);
-            if disp_resp.pmodes[0].enabled != 0 {
-                self.width = disp_resp.pmodes[0].r.width;
-                self.height = disp_resp.pmodes[0].r.height;
+        // Use the scanout's preferred mode unless one was requested,
+        // as long as it fits in the framebuffer
+        let disp_resp = unsafe { &*((queue_ram_base() + RESP_OFFSET) as *const RespDisplayInfo) };
+        if let Some((w, h)) = disp_resp.preferred_mode(0) {
+            if !self.mode_override && Self::mode_fits(w, h) {
+                self.width = w;
+                self.height = h;
             }
         }
+        let screen = Rect::sized(self.width, self.height);
 
         // 2. Create 2D resource
-        let create = VirtioGpuResourceCreate2d {
-            hdr: VirtioGpuCtrlHdr {
-                cmd_type: VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
-                flags: 0, fence_id: 0, ctx_id: 0, padding: 0,
-            },
-            resource_id: 1,
-            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
-            width: 1280,
-            height: 720,
-        };
-        let cmd_bytes = unsafe {
-            core::slice::from_raw_parts(&create as *const _ as *const u8, core::mem::size_of::<VirtioGpuResourceCreate2d>())
-        };
-        let resp = self.send_cmd(cmd_bytes, core::mem::size_of::<VirtioGpuCtrlHdr>());
+        let create = ResourceCreate2d::new(1, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, self.width, self.height);
+        let resp = self.send_cmd(create.as_bytes(), size_of::<CtrlHdr>());
         check_resp!(resp, VIRTIO_GPU_RESP_OK_NODATA, 2);
 
         // 3. Attach backing (framebuffer memory)
-        #[repr(C)]
-        struct AttachCmd {
-            hdr: VirtioGpuResourceAttachBacking,
-            entry: VirtioGpuMemEntry,
-        }
-
-        let attach = AttachCmd {
-            hdr: VirtioGpuResourceAttachBacking {
-                hdr: VirtioGpuCtrlHdr {
-                    cmd_type: VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
-                    flags: 0, fence_id: 0, ctx_id: 0, padding: 0,
-                },
-                resource_id: 1,
-                nr_entries: 1,
-            },
-            entry: VirtioGpuMemEntry {
-                addr: HARDCODED_FB_ADDR,
-                length: 1280 * 720 * 4,
-                padding: 0,
-            },
-        };
-        let cmd_bytes = unsafe {
-            core::slice::from_raw_parts(&attach as *const _ as *const u8, core::mem::size_of::<AttachCmd>())
-        };
-        let resp = self.send_cmd(cmd_bytes, core::mem::size_of::<VirtioGpuCtrlHdr>());
+        let attach = ResourceAttachBacking::new(1, fb_addr(), self.width * self.height * FB_BPP);
+        let resp = self.send_cmd(attach.as_bytes(), size_of::<CtrlHdr>());
         check_resp!(resp, VIRTIO_GPU_RESP_OK_NODATA, 3);
 
         // 4. Set scanout
-        let scanout = VirtioGpuSetScanout {
-            hdr: VirtioGpuCtrlHdr {
-                cmd_type: VIRTIO_GPU_CMD_SET_SCANOUT,
-                flags: 0, fence_id: 0, ctx_id: 0, padding: 0,
-            },
-            r: VirtioGpuRect { x: 0, y: 0, width: 1280, height: 720 },
-            scanout_id: 0,
-            resource_id: 1,
-        };
-        let cmd_bytes = unsafe {
-            core::slice::from_raw_parts(&scanout as *const _ as *const u8, core::mem::size_of::<VirtioGpuSetScanout>())
-        };
-        let resp = self.send_cmd(cmd_bytes, core::mem::size_of::<VirtioGpuCtrlHdr>());
+        let scanout = SetScanout::new(0, 1, screen);
+        let resp = self.send_cmd(scanout.as_bytes(), size_of::<CtrlHdr>());
         check_resp!(resp, VIRTIO_GPU_RESP_OK_NODATA, 4);
 
         true  // All commands succeeded
     }
 
     pub fn fill(&self, color: u32) {
-        const PIXELS: usize = 1280 * 720;
+        let pixels = (self.width * self.height) as usize;
         unsafe {
-            let ptr = HARDCODED_FB_ADDR as *mut u32;
-            for i in 0..PIXELS {
+            let ptr = fb_addr() as *mut u32;
+            for i in 0..pixels {
                 ptr.add(i).write_volatile(color);
             }
         }
     }
 
     pub fn draw_rect(&self, x: u32, y: u32, w: u32, h: u32, color: u32) {
-        const WIDTH: u32 = 1280;
-        const HEIGHT: u32 = 720;
         unsafe {
-            let ptr = HARDCODED_FB_ADDR as *mut u32;
+            let ptr = fb_addr() as *mut u32;
             for dy in 0..h {
                 for dx in 0..w {
                     let px = x + dx;
                     let py = y + dy;
-                    if px < WIDTH && py < HEIGHT {
-                        let idx = (py * WIDTH + px) as usize;
+                    if px < self.width && py < self.height {
+                        let idx = (py * self.width + px) as usize;
                         ptr.add(idx).write_volatile(color);
                     }
                 }
@@ -724,61 +564,46 @@ impl VirtioGpu {
     }
 
     pub fn flush(&self) {
-        // Transfer to host - hardcoded dimensions
-        let transfer = VirtioGpuTransferToHost2d {
-            hdr: VirtioGpuCtrlHdr {
-                cmd_type: VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
-                flags: 0, fence_id: 0, ctx_id: 0, padding: 0,
-            },
-            r: VirtioGpuRect { x: 0, y: 0, width: 1280, height: 720 },
-            offset: 0,
-            resource_id: 1,
-            padding: 0,
-        };
-
-        let cmd_bytes = unsafe {
-            core::slice::from_raw_parts(
-                &transfer as *const _ as *const u8,
-                core::mem::size_of::<VirtioGpuTransferToHost2d>()
-            )
-        };
-
-        self.send_cmd(cmd_bytes, core::mem::size_of::<VirtioGpuCtrlHdr>());
-
-        // Flush - hardcoded dimensions
-        let flush = VirtioGpuResourceFlush {
-            hdr: VirtioGpuCtrlHdr {
-                cmd_type: VIRTIO_GPU_CMD_RESOURCE_FLUSH,
-                flags: 0, fence_id: 0, ctx_id: 0, padding: 0,
-            },
-            r: VirtioGpuRect { x: 0, y: 0, width: 1280, height: 720 },
-            resource_id: 1,
-            padding: 0,
-        };
-
-        let cmd_bytes = unsafe {
-            core::slice::from_raw_parts(
-                &flush as *const _ as *const u8,
-                core::mem::size_of::<VirtioGpuResourceFlush>()
-            )
-        };
-
-        self.send_cmd(cmd_bytes, core::mem::size_of::<VirtioGpuCtrlHdr>());
+        let screen = Rect::sized(self.width, self.height);
+
+        // Transfer to host - whole framebuffer
+        let transfer = TransferToHost2d::new(1, screen, 0);
+        self.send_cmd(transfer.as_bytes(), size_of::<CtrlHdr>());
+
+        // Flush - whole scanout
+        let flush = ResourceFlush::new(1, screen);
+        self.send_cmd(flush.as_bytes(), size_of::<CtrlHdr>());
     }
 
     pub fn width(&self) -> u32 { self.width }
     pub fn height(&self) -> u32 { self.height }
 
+    /// Force a resolution instead of the scanout's preferred mode
+    /// Must be called before init_display(); fails if it does not fit the framebuffer
+    pub fn set_resolution(&mut self, width: u32, height: u32) -> bool {
+        if !Self::mode_fits(width, height) {
+            return false;
+        }
+        self.width = width;
+        self.height = height;
+        self.mode_override = true;
+        true
+    }
+
+    fn mode_fits(width: u32, height: u32) -> bool {
+        width > 0 && height > 0 && (width as u64) * (height as u64) <= (FB_WIDTH * FB_HEIGHT) as u64
+    }
+
     /// Compute a simple checksum of the framebuffer for testing
     /// Returns (checksum, non_zero_pixels) for verification
     pub fn framebuffer_checksum(&self) -> (u32, u32) {
-        const PIXELS: usize = 1280 * 720;
+        let pixels = (self.width * self.height) as usize;
         let mut checksum: u32 = 0;
         let mut non_zero: u32 = 0;
 
         unsafe {
-            let ptr = HARDCODED_FB_ADDR as *const u32;
-            for i in 0..PIXELS {
+            let ptr = fb_addr() as *const u32;
+            for i in 0..pixels {
                 let pixel = ptr.add(i).read_volatile();
                 // Simple checksum: XOR with position-mixed value
                 checksum = checksum.wrapping_add(pixel ^ (i as u32).wrapping_mul(0x9e3779b9));
@@ -793,7 +618,7 @@ impl VirtioGpu {
     /// Sample specific pixels for test verification
     /// Returns array of pixel values at test coordinates
     pub fn sample_test_pixels(&self) -> [u32; 5] {
-        const WIDTH: usize = 1280;
+        let width = self.width as usize;
         let test_coords = [
             (100, 150),   // First colored box area
             (330, 150),   // Second colored box area
@@ -804,10 +629,11 @@ impl VirtioGpu {
 
         let mut samples = [0u32; 5];
         unsafe {
-            let ptr = HARDCODED_FB_ADDR as *const u32;
+            let ptr = fb_addr() as *const u32;
             for (i, (x, y)) in test_coords.iter().enumerate() {
-                let idx = y * WIDTH + x;
-                samples[i] = ptr.add(idx).read_volatile();
+                if *x < width && *y < self.height as usize {
+                    samples[i] = ptr.add(y * width + x).read_volatile();
+                }
             }
         }
         samples
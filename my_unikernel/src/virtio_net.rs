@@ -3,9 +3,19 @@
 //! Basic network driver with TX/RX queues.
 //! Device IDs: 0x1000 (transitional), 0x1041 (modern)
 
+use core::fmt::Write;
 use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::{fence, Ordering};
 
+use driver_core::net::{self, MTU, PACKET_BUF_SIZE, VIRTIO_NET_F_MAC};
+use driver_core::virtqueue::{Buffer, Virtqueue};
+use kernel_macros::kernel_test;
+
+use crate::executor::{self, IoEvent};
+use crate::ktest::Outcome;
+use crate::sync::SpinLock;
+use crate::{cmdline, ConsoleWriter};
+
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_NET_TRANSITIONAL: u16 = 0x1000;
 const VIRTIO_NET_MODERN: u16 = 0x1041;
@@ -30,9 +40,6 @@ const VIRTIO_STATUS_DRIVER: u8 = 2;
 const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
 const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
 
-// Network feature bits
-const VIRTIO_NET_F_MAC: u32 = 1 << 5;
-
 // Common configuration offsets
 const VIRTIO_PCI_COMMON_DFSELECT: usize = 0x00;
 const VIRTIO_PCI_COMMON_DF: usize = 0x04;
@@ -50,86 +57,37 @@ const VIRTIO_PCI_COMMON_Q_AVAILHI: usize = 0x2c;
 const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
 const VIRTIO_PCI_COMMON_Q_USEDHI: usize = 0x34;
 
-// Descriptor flags
-const VRING_DESC_F_NEXT: u16 = 1;
-const VRING_DESC_F_WRITE: u16 = 2;
-
-const QUEUE_SIZE: u16 = 8;
-const MTU: usize = 1514; // Ethernet MTU
-
-/// VirtIO net header (prepended to every packet)
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct VirtioNetHeader {
-    flags: u8,
-    gso_type: u8,
-    hdr_len: u16,
-    gso_size: u16,
-    csum_start: u16,
-    csum_offset: u16,
-    // num_buffers only present with VIRTIO_NET_F_MRG_RXBUF
-}
-
-const NET_HDR_SIZE: usize = core::mem::size_of::<VirtioNetHeader>();
+const QUEUE_SIZE: usize = 8;
 
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct VringDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+/// One virtqueue with its packet buffer, shared with the device
+struct NetQueue {
+    ring: Virtqueue<QUEUE_SIZE>,
+    buffer: [u8; PACKET_BUF_SIZE],
 }
 
-#[repr(C)]
-struct VringAvail {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE as usize],
-}
+impl NetQueue {
+    const fn new() -> Self {
+        NetQueue { ring: Virtqueue::new(), buffer: [0; PACKET_BUF_SIZE] }
+    }
 
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct VringUsedElem {
-    id: u32,
-    len: u32,
-}
+    /// True if the device has returned buffers we have not looked at yet
+    fn has_used(&self) -> bool {
+        self.ring.has_used()
+    }
 
-#[repr(C)]
-struct VringUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VringUsedElem; QUEUE_SIZE as usize],
+    /// Catch up with the used ring; true once every posted buffer is back
+    fn reap(&mut self) -> bool {
+        while self.ring.pop_used().is_some() {}
+        self.ring.is_idle()
+    }
 }
 
-// RX queue buffers
-static mut RX_QUEUE_DESCS: [VringDesc; QUEUE_SIZE as usize] =
-    [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize];
-static mut RX_QUEUE_AVAIL: VringAvail = VringAvail {
-    flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize]
-};
-static mut RX_QUEUE_USED: VringUsed = VringUsed {
-    flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize]
-};
-
-// TX queue buffers
-static mut TX_QUEUE_DESCS: [VringDesc; QUEUE_SIZE as usize] =
-    [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize];
-static mut TX_QUEUE_AVAIL: VringAvail = VringAvail {
-    flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize]
-};
-static mut TX_QUEUE_USED: VringUsed = VringUsed {
-    flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize]
-};
-
-// Packet buffers
-static mut RX_BUFFER: [u8; NET_HDR_SIZE + MTU] = [0; NET_HDR_SIZE + MTU];
-static mut TX_BUFFER: [u8; NET_HDR_SIZE + MTU] = [0; NET_HDR_SIZE + MTU];
-
-static mut RX_IDX: u16 = 0;
-static mut TX_IDX: u16 = 0;
-static mut RX_LAST_USED: u16 = 0;
-static mut TX_LAST_USED: u16 = 0;
+static RX_QUEUE: SpinLock<NetQueue> = SpinLock::new(NetQueue::new());
+static TX_QUEUE: SpinLock<NetQueue> = SpinLock::new(NetQueue::new());
+
+// Woken when a packet arrives / a transmit completes
+static RX_EVENT: IoEvent = IoEvent::new();
+static TX_EVENT: IoEvent = IoEvent::new();
 
 #[derive(Clone, Copy)]
 struct VirtioCap {
@@ -204,12 +162,10 @@ impl VirtioNet {
         let queue_size_max = read_volatile((common_base + 24) as *const u16);
         if queue_size_max == 0 { return None; }
 
-        let actual_size = queue_size_max.min(QUEUE_SIZE);
+        let actual_size = queue_size_max.min(QUEUE_SIZE as u16);
         write_volatile((common_base + 24) as *mut u16, actual_size);
 
-        let desc_addr = &raw const RX_QUEUE_DESCS as u64;
-        let avail_addr = &raw const RX_QUEUE_AVAIL as u64;
-        let used_addr = &raw const RX_QUEUE_USED as u64;
+        let (desc_addr, avail_addr, used_addr) = RX_QUEUE.lock().ring.addrs();
 
         write_volatile((common_base + 32) as *mut u32, desc_addr as u32);
         write_volatile((common_base + 36) as *mut u32, (desc_addr >> 32) as u32);
@@ -229,12 +185,10 @@ impl VirtioNet {
         let queue_size_max = read_volatile((common_base + 24) as *const u16);
         if queue_size_max == 0 { return None; }
 
-        let actual_size = queue_size_max.min(QUEUE_SIZE);
+        let actual_size = queue_size_max.min(QUEUE_SIZE as u16);
         write_volatile((common_base + 24) as *mut u16, actual_size);
 
-        let desc_addr = &raw const TX_QUEUE_DESCS as u64;
-        let avail_addr = &raw const TX_QUEUE_AVAIL as u64;
-        let used_addr = &raw const TX_QUEUE_USED as u64;
+        let (desc_addr, avail_addr, used_addr) = TX_QUEUE.lock().ring.addrs();
 
         write_volatile((common_base + 32) as *mut u32, desc_addr as u32);
         write_volatile((common_base + 36) as *mut u32, (desc_addr >> 32) as u32);
@@ -252,7 +206,7 @@ impl VirtioNet {
         fence(Ordering::SeqCst);
 
         // Post initial RX buffer
-        Self::post_rx_buffer(notify_base, 0, modern.notify_mult, rx_notify_off);
+        Self::post_rx_buffer(&mut RX_QUEUE.lock(), notify_base, 0, modern.notify_mult, rx_notify_off);
 
         Some(VirtioNet {
             bar0: notify_base,
@@ -421,12 +375,10 @@ impl VirtioNet {
                 return None;
             }
 
-            let actual_size = queue_size_max.min(QUEUE_SIZE);
+            let actual_size = queue_size_max.min(QUEUE_SIZE as u16);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
-            let desc_addr = &raw const RX_QUEUE_DESCS as u64;
-            let avail_addr = &raw const RX_QUEUE_AVAIL as u64;
-            let used_addr = &raw const RX_QUEUE_USED as u64;
+            let (desc_addr, avail_addr, used_addr) = RX_QUEUE.lock().ring.addrs();
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -449,12 +401,10 @@ impl VirtioNet {
                 return None;
             }
 
-            let actual_size = queue_size_max.min(QUEUE_SIZE);
+            let actual_size = queue_size_max.min(QUEUE_SIZE as u16);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
-            let desc_addr = &raw const TX_QUEUE_DESCS as u64;
-            let avail_addr = &raw const TX_QUEUE_AVAIL as u64;
-            let used_addr = &raw const TX_QUEUE_USED as u64;
+            let (desc_addr, avail_addr, used_addr) = TX_QUEUE.lock().ring.addrs();
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -476,7 +426,7 @@ impl VirtioNet {
             fence(Ordering::SeqCst);
 
             // Post initial RX buffer
-            Self::post_rx_buffer(bar0, notify.offset, notify.notify_off_multiplier, rx_notify_off);
+            Self::post_rx_buffer(&mut RX_QUEUE.lock(), bar0, notify.offset, notify.notify_off_multiplier, rx_notify_off);
 
             Some(VirtioNet {
                 bar0,
@@ -489,38 +439,17 @@ impl VirtioNet {
         }
     }
 
-    fn post_rx_buffer(bar0: u64, notify_offset: u32, notify_multiplier: u32, rx_notify_off: u16) {
-        unsafe {
-            let idx = RX_IDX % QUEUE_SIZE;
-
-            // RX buffer: device writes header + packet
-            RX_QUEUE_DESCS[idx as usize] = VringDesc {
-                addr: RX_BUFFER.as_ptr() as u64,
-                len: (NET_HDR_SIZE + MTU) as u32,
-                flags: VRING_DESC_F_WRITE,
-                next: 0,
-            };
-
-            fence(Ordering::SeqCst);
-
-            let avail_idx = read_volatile(&RX_QUEUE_AVAIL.idx);
-            write_volatile(
-                &raw mut RX_QUEUE_AVAIL.ring[(avail_idx % QUEUE_SIZE) as usize],
-                idx,
-            );
-            fence(Ordering::SeqCst);
-            write_volatile(&raw mut RX_QUEUE_AVAIL.idx, avail_idx.wrapping_add(1));
-            fence(Ordering::SeqCst);
-
-            // Notify device
-            let notify_addr = bar0
-                + notify_offset as u64
-                + (rx_notify_off as u64 * notify_multiplier as u64);
-            write_volatile(notify_addr as *mut u16, 0);
-            fence(Ordering::SeqCst);
+    fn post_rx_buffer(rx: &mut NetQueue, bar0: u64, notify_offset: u32, notify_multiplier: u32, rx_notify_off: u16) {
+        // RX buffer: device writes header + packet
+        // Only one buffer is ever posted, so the ring has room
+        rx.ring.push(&[Buffer::writable(&mut rx.buffer)]);
 
-            RX_IDX = RX_IDX.wrapping_add(1);
-        }
+        // Notify device
+        let notify_addr = bar0
+            + notify_offset as u64
+            + (rx_notify_off as u64 * notify_multiplier as u64);
+        unsafe { write_volatile(notify_addr as *mut u16, 0) };
+        fence(Ordering::SeqCst);
     }
 
     /// Get MAC address
@@ -534,137 +463,103 @@ impl VirtioNet {
             return false;
         }
 
-        unsafe {
-            // Setup header (all zeros for basic transmit)
-            let hdr = VirtioNetHeader::default();
-            let hdr_bytes = core::slice::from_raw_parts(
-                &hdr as *const VirtioNetHeader as *const u8,
-                NET_HDR_SIZE
-            );
+        let mut tx = TX_QUEUE.lock();
 
-            // Copy header
-            for i in 0..NET_HDR_SIZE {
-                TX_BUFFER[i] = hdr_bytes[i];
-            }
-
-            // Copy data
-            for i in 0..data.len() {
-                TX_BUFFER[NET_HDR_SIZE + i] = data[i];
-            }
-
-            let total_len = NET_HDR_SIZE + data.len();
-            let idx = TX_IDX % QUEUE_SIZE;
-
-            TX_QUEUE_DESCS[idx as usize] = VringDesc {
-                addr: TX_BUFFER.as_ptr() as u64,
-                len: total_len as u32,
-                flags: 0,
-                next: 0,
-            };
-
-            fence(Ordering::SeqCst);
+        // An async send may still be on the ring
+        if !Self::wait_tx_idle(&mut tx) {
+            return false;
+        }
+        self.tx_submit(&mut tx, data);
+        Self::wait_tx_idle(&mut tx)
+    }
 
-            let avail_idx = read_volatile(&TX_QUEUE_AVAIL.idx);
-            write_volatile(
-                &raw mut TX_QUEUE_AVAIL.ring[(avail_idx % QUEUE_SIZE) as usize],
-                idx,
-            );
-            fence(Ordering::SeqCst);
-            write_volatile(&raw mut TX_QUEUE_AVAIL.idx, avail_idx.wrapping_add(1));
-            fence(Ordering::SeqCst);
+    /// Send a packet without blocking the executor
+    pub async fn send_async(&self, data: &[u8]) -> bool {
+        if data.len() > MTU {
+            return false;
+        }
 
-            // Notify device
-            let notify_addr = self.bar0
-                + self.notify_offset as u64
-                + (self.tx_notify_off as u64 * self.notify_multiplier as u64);
-            write_volatile(notify_addr as *mut u16, 1);
-            fence(Ordering::SeqCst);
+        executor::wait_for(&TX_EVENT, || {
+            let mut tx = TX_QUEUE.lock();
+            if !tx.reap() {
+                return None;
+            }
+            self.tx_submit(&mut tx, data);
+            Some(())
+        })
+        .await;
 
-            TX_IDX = TX_IDX.wrapping_add(1);
+        executor::wait_for(&TX_EVENT, || TX_QUEUE.lock().reap().then_some(())).await;
+        // Let the next sender have the buffer
+        TX_EVENT.wake();
+        true
+    }
 
-            // Wait for completion
-            for _ in 0..100_000 {
-                fence(Ordering::SeqCst);
-                let used_idx = read_volatile(&TX_QUEUE_USED.idx);
-                if used_idx != TX_LAST_USED {
-                    TX_LAST_USED = used_idx;
-                    return true;
-                }
-                core::hint::spin_loop();
+    fn wait_tx_idle(tx: &mut NetQueue) -> bool {
+        for _ in 0..100_000 {
+            if tx.reap() {
+                return true;
             }
-
-            false
+            core::hint::spin_loop();
         }
+        false
+    }
+
+    /// Copy a packet into the TX buffer and hand it to the device
+    /// The caller has checked the length and that the previous send is done
+    fn tx_submit(&self, tx: &mut NetQueue, data: &[u8]) {
+        let Some(len) = net::encode_tx(data, &mut tx.buffer) else {
+            return;
+        };
+        tx.ring.push(&[Buffer { len: len as u32, ..Buffer::readable(&tx.buffer) }]);
+
+        // Notify device
+        let notify_addr = self.bar0
+            + self.notify_offset as u64
+            + (self.tx_notify_off as u64 * self.notify_multiplier as u64);
+        unsafe { write_volatile(notify_addr as *mut u16, 1) };
+        fence(Ordering::SeqCst);
     }
 
     /// Try to receive a packet (returns length or 0 if no packet)
     pub fn recv(&self, buf: &mut [u8]) -> usize {
-        unsafe {
-            fence(Ordering::SeqCst);
-            let used_idx = read_volatile(&RX_QUEUE_USED.idx);
-            if used_idx == RX_LAST_USED {
-                return 0;
-            }
+        self.take_packet(&mut RX_QUEUE.lock(), buf).unwrap_or(0)
+    }
 
-            let used_elem = read_volatile(
-                &RX_QUEUE_USED.ring[(RX_LAST_USED % QUEUE_SIZE) as usize]
-            );
-            RX_LAST_USED = used_idx;
-
-            let total_len = used_elem.len as usize;
-            if total_len <= NET_HDR_SIZE {
-                // Re-post buffer
-                Self::post_rx_buffer(
-                    self.bar0, self.notify_offset, self.notify_multiplier, self.rx_notify_off
-                );
-                return 0;
+    /// Wait for a packet with a non-empty payload and return its length
+    pub async fn recv_async(&self, buf: &mut [u8]) -> usize {
+        executor::wait_for(&RX_EVENT, || {
+            match self.take_packet(&mut RX_QUEUE.lock(), buf) {
+                Some(0) | None => None,
+                len => len,
             }
+        })
+        .await
+    }
 
-            let data_len = total_len - NET_HDR_SIZE;
-            let copy_len = data_len.min(buf.len());
+    /// Copy out the next received packet, if any, and re-post its buffer
+    /// Runt frames are dropped and reported as Some(0)
+    fn take_packet(&self, rx: &mut NetQueue, buf: &mut [u8]) -> Option<usize> {
+        let used = rx.ring.pop_used()?;
 
-            for i in 0..copy_len {
-                buf[i] = read_volatile(&RX_BUFFER[NET_HDR_SIZE + i]);
-            }
+        let frame = net::rx_frame(&rx.buffer, used.len as usize);
+        let copy_len = frame.len().min(buf.len());
+        for i in 0..copy_len {
+            buf[i] = unsafe { read_volatile(&frame[i]) };
+        }
 
-            // Re-post buffer
-            Self::post_rx_buffer(
-                self.bar0, self.notify_offset, self.notify_multiplier, self.rx_notify_off
-            );
+        // Re-post buffer
+        Self::post_rx_buffer(
+            rx, self.bar0, self.notify_offset, self.notify_multiplier, self.rx_notify_off
+        );
 
-            copy_len
-        }
+        Some(copy_len)
     }
 
     /// Test network by sending a broadcast frame and checking for any response
     pub fn test_network(&self) -> NetTestResult {
-        // Create a simple ARP request (broadcast)
-        let mut arp_packet = [0u8; 42];
-
-        // Ethernet header
-        // Destination: broadcast FF:FF:FF:FF:FF:FF
-        for i in 0..6 { arp_packet[i] = 0xFF; }
-        // Source: our MAC
-        for i in 0..6 { arp_packet[6 + i] = self.mac[i]; }
-        // EtherType: ARP (0x0806)
-        arp_packet[12] = 0x08;
-        arp_packet[13] = 0x06;
-
-        // ARP header
-        arp_packet[14] = 0x00; arp_packet[15] = 0x01; // Hardware type: Ethernet
-        arp_packet[16] = 0x08; arp_packet[17] = 0x00; // Protocol type: IPv4
-        arp_packet[18] = 6;    // Hardware size
-        arp_packet[19] = 4;    // Protocol size
-        arp_packet[20] = 0x00; arp_packet[21] = 0x01; // Opcode: request
-
-        // Sender MAC
-        for i in 0..6 { arp_packet[22 + i] = self.mac[i]; }
-        // Sender IP: 10.0.0.2
-        arp_packet[28] = 10; arp_packet[29] = 0; arp_packet[30] = 0; arp_packet[31] = 2;
-
-        // Target MAC: zeros (unknown)
-        // Target IP: 10.0.0.1 (gateway)
-        arp_packet[38] = 10; arp_packet[39] = 0; arp_packet[40] = 0; arp_packet[41] = 1;
+        // ARP who-has for the gateway, from 10.0.0.2
+        let arp_packet = net::arp_request(self.mac, [10, 0, 0, 2], [10, 0, 0, 1]);
 
         // Send the ARP request
         let send_ok = self.send(&arp_packet);
@@ -697,3 +592,45 @@ pub struct NetTestResult {
     pub received_response: bool,
     pub init_ok: bool,
 }
+
+/// Wake tasks waiting on a received packet or a finished transmit
+pub fn poll_io() {
+    if RX_QUEUE.try_lock().is_some_and(|rx| rx.has_used()) {
+        RX_EVENT.wake();
+    }
+    if TX_QUEUE.try_lock().is_some_and(|tx| tx.has_used()) {
+        TX_EVENT.wake();
+    }
+}
+
+#[kernel_test]
+fn net() -> Outcome {
+    let Some((dev, modern)) = crate::find_virtio_device(0x1041) else {
+        return Outcome::Skip("no virtio-net device");
+    };
+    let Some(net) = (unsafe { VirtioNet::from_modern(&modern, dev.ecam_addr) }) else {
+        return Outcome::Fail("device init failed");
+    };
+    let m = net.mac();
+    let _ = writeln!(
+        ConsoleWriter,
+        "# mac={:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        m[0], m[1], m[2], m[3], m[4], m[5]
+    );
+    match cmdline::ip_config() {
+        Some(cmdline::IpConfig::Dhcp) => {
+            let _ = writeln!(ConsoleWriter, "# ip=dhcp");
+        }
+        Some(cmdline::IpConfig::Static { addr, .. }) => {
+            let _ = writeln!(ConsoleWriter, "# ip={}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]);
+        }
+        None => {}
+    }
+
+    let result = net.test_network();
+    let _ = writeln!(ConsoleWriter, "# send={}", result.send_ok);
+    if !result.init_ok {
+        return Outcome::Fail("queue setup failed");
+    }
+    Outcome::check(result.send_ok, "transmit failed")
+}
@@ -8,6 +8,8 @@
 use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::{fence, Ordering};
 
+use crate::sync::IrqSpinLock;
+
 // PCI config space offsets
 const PCI_VENDOR_ID: usize = 0x00;
 const PCI_DEVICE_ID: usize = 0x02;
@@ -106,17 +108,28 @@ struct TxBuffer {
     data: [u8; 256],
 }
 
-// Static buffers
-static mut PCI_TX_QUEUE: VirtqueueBuffers = VirtqueueBuffers {
-    descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
-    avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
-    _pad: [0; 4096 - core::mem::size_of::<[VringDesc; QUEUE_SIZE as usize]>() - core::mem::size_of::<VringAvail>()],
-    used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
-};
+/// Transmit virtqueue, its buffer and ring indices, shared with the device
+///
+/// Console output may come from interrupt context, so IRQs stay masked
+/// while it is held.
+struct TxQueue {
+    ring: VirtqueueBuffers,
+    buffer: TxBuffer,
+    idx: u16,
+    last_used: u16,
+}
 
-static mut PCI_TX_BUFFER: TxBuffer = TxBuffer { data: [0; 256] };
-static mut PCI_TX_IDX: u16 = 0;
-static mut PCI_LAST_USED_IDX: u16 = 0;
+static TX_QUEUE: IrqSpinLock<TxQueue> = IrqSpinLock::new(TxQueue {
+    ring: VirtqueueBuffers {
+        descs: [VringDesc { addr: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE as usize],
+        avail: VringAvail { flags: 0, idx: 0, ring: [0; QUEUE_SIZE as usize] },
+        _pad: [0; 4096 - core::mem::size_of::<[VringDesc; QUEUE_SIZE as usize]>() - core::mem::size_of::<VringAvail>()],
+        used: VringUsed { flags: 0, idx: 0, ring: [VringUsedElem { id: 0, len: 0 }; QUEUE_SIZE as usize] },
+    },
+    buffer: TxBuffer { data: [0; 256] },
+    idx: 0,
+    last_used: 0,
+});
 
 /// Virtio PCI capability structure
 #[derive(Debug, Clone, Copy)]
@@ -294,9 +307,14 @@ impl VirtioPciConsole {
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_SIZE as u64) as *mut u16, actual_size);
 
             // Set queue addresses
-            let desc_addr = &raw const PCI_TX_QUEUE.descs as u64;
-            let avail_addr = &raw const PCI_TX_QUEUE.avail as u64;
-            let used_addr = &raw const PCI_TX_QUEUE.used as u64;
+            let (desc_addr, avail_addr, used_addr) = {
+                let tx = TX_QUEUE.lock();
+                (
+                    &tx.ring.descs as *const _ as u64,
+                    &tx.ring.avail as *const _ as u64,
+                    &tx.ring.used as *const _ as u64,
+                )
+            };
 
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCLO as u64) as *mut u32, desc_addr as u32);
             write_volatile((common_base + VIRTIO_PCI_COMMON_Q_DESCHI as u64) as *mut u32, (desc_addr >> 32) as u32);
@@ -344,31 +362,46 @@ impl VirtioPciConsole {
         if data.is_empty() {
             return;
         }
+        self.send(&mut TX_QUEUE.lock(), data);
+    }
+
+    /// write(), unless the transmit queue is locked (by code that crashed
+    /// holding it, say); false if nothing was written
+    pub fn try_write(&self, data: &[u8]) -> bool {
+        let Some(mut tx) = TX_QUEUE.try_lock() else {
+            return false;
+        };
+        if !data.is_empty() {
+            self.send(&mut tx, data);
+        }
+        true
+    }
 
+    fn send(&self, tx: &mut TxQueue, data: &[u8]) {
         unsafe {
             fence(Ordering::SeqCst);
 
             // Copy data to buffer (truncate if too large)
-            let len = data.len().min(PCI_TX_BUFFER.data.len());
+            let len = data.len().min(tx.buffer.data.len());
             for i in 0..len {
-                PCI_TX_BUFFER.data[i] = data[i];
+                tx.buffer.data[i] = data[i];
             }
 
-            let idx = PCI_TX_IDX % QUEUE_SIZE;
+            let idx = tx.idx % QUEUE_SIZE;
 
             // Set up descriptor
-            PCI_TX_QUEUE.descs[idx as usize] = VringDesc {
-                addr: PCI_TX_BUFFER.data.as_ptr() as u64,
+            tx.ring.descs[idx as usize] = VringDesc {
+                addr: tx.buffer.data.as_ptr() as u64,
                 len: len as u32,
                 flags: 0,
                 next: 0,
             };
 
             // Add to available ring
-            let avail_idx = PCI_TX_QUEUE.avail.idx;
-            PCI_TX_QUEUE.avail.ring[(avail_idx % QUEUE_SIZE) as usize] = idx;
+            let avail_idx = tx.ring.avail.idx;
+            tx.ring.avail.ring[(avail_idx % QUEUE_SIZE) as usize] = idx;
             fence(Ordering::SeqCst);
-            PCI_TX_QUEUE.avail.idx = avail_idx.wrapping_add(1);
+            tx.ring.avail.idx = avail_idx.wrapping_add(1);
             fence(Ordering::SeqCst);
 
             // Notify device
@@ -378,13 +411,14 @@ impl VirtioPciConsole {
             write_volatile(notify_addr as *mut u16, 1); // queue index 1
             fence(Ordering::SeqCst);
 
-            PCI_TX_IDX = PCI_TX_IDX.wrapping_add(1);
+            tx.idx = tx.idx.wrapping_add(1);
 
             // Brief wait for completion
             for _ in 0..10000 {
                 fence(Ordering::SeqCst);
-                if PCI_TX_QUEUE.used.idx != PCI_LAST_USED_IDX {
-                    PCI_LAST_USED_IDX = PCI_TX_QUEUE.used.idx;
+                let used_idx = read_volatile(&tx.ring.used.idx);
+                if used_idx != tx.last_used {
+                    tx.last_used = used_idx;
                     break;
                 }
                 core::hint::spin_loop();
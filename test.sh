@@ -1,13 +1,13 @@
 #!/bin/bash
 # Automated test runner for the aarch64 unikernel
 # Usage: ./test.sh [--skip-build] [--verbose]
+# macOS only (Virtualization.framework); on Linux use `cargo xtask qemu`
 
 set -e
 
 SCRIPT_DIR="$(cd "$(dirname "$0")" && pwd)"
 KERNEL_DIR="$SCRIPT_DIR/my_unikernel"
 VMM_DIR="$SCRIPT_DIR/vmm"
-LLVM_BIN=~/.rustup/toolchains/nightly-aarch64-apple-darwin/lib/rustlib/aarch64-apple-darwin/bin
 
 # Colors
 RED='\033[0;31m'
@@ -62,13 +62,9 @@ if [ "$SKIP_BUILD" = false ]; then
     echo -e "${YELLOW}[2/4] Creating boot image...${NC}"
     cd "$KERNEL_DIR"
 
-    $LLVM_BIN/llvm-objcopy -O binary \
-        target/aarch64-unknown-none/release/kernel \
-        target/aarch64-unknown-none/release/kernel.bin
-
-    python3 "$SCRIPT_DIR/make_image.py" \
-        target/aarch64-unknown-none/release/kernel.bin \
-        target/aarch64-unknown-none/release/Image
+    # Symbol table, then kernel.bin and Image next to the ELF (see
+    # xtask/src/symtab.rs and image.rs)
+    (cd "$SCRIPT_DIR" && cargo xtask symtab && cargo xtask image)
 
     echo -e "${GREEN}Image created${NC}"
     echo ""
@@ -0,0 +1,224 @@
+//! virtio-blk backed by a file (or anything seekable)
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use driver_core::blk::{
+    ID_BYTES, SECTOR_SIZE, VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX,
+    VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH,
+    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+};
+
+use crate::device::VIRTIO_F_VERSION_1;
+use crate::{Chain, Device, Error, Queues};
+
+/// Discard and write-zeroes limits: sectors per segment, segments per request
+const MAX_RANGE_SECTORS: u32 = 64;
+const MAX_RANGE_SEG: u32 = 4;
+
+pub struct BlkDevice<S> {
+    storage: S,
+    sectors: u64,
+    serial: [u8; ID_BYTES],
+    /// size_max and seg_max, if the device advertises them
+    limits: Option<(u32, u32)>,
+    read_only: bool,
+    /// Logical block size, if the device advertises one
+    blk_size: Option<u32>,
+    /// Request queues; more than one advertises VIRTIO_BLK_F_MQ
+    num_queues: u16,
+    requests: usize,
+}
+
+impl BlkDevice<File> {
+    /// Disk image at `path`, opened read-write
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+}
+
+impl<S: Read + Write + Seek> BlkDevice<S> {
+    /// Capacity is the storage's length, rounded down to whole sectors
+    pub fn new(mut storage: S) -> io::Result<Self> {
+        let len = storage.seek(SeekFrom::End(0))?;
+        let mut serial = [0; ID_BYTES];
+        serial[..8].copy_from_slice(b"SIMBLK01");
+        Ok(BlkDevice { storage, sectors: len / SECTOR_SIZE as u64, serial, limits: None, read_only: false, blk_size: None, num_queues: 1, requests: 0 })
+    }
+
+    /// Advertise (and enforce) at most `seg_max` data segments of at most
+    /// `size_max` bytes per request
+    pub fn with_limits(mut self, size_max: u32, seg_max: u32) -> Self {
+        self.limits = Some((size_max, seg_max));
+        self
+    }
+
+    /// Advertise VIRTIO_BLK_F_RO and refuse writes
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Advertise `size`-byte logical blocks (and a matching topology), and
+    /// refuse reads and writes that are not whole, aligned blocks
+    pub fn with_block_size(mut self, size: u32) -> Self {
+        self.blk_size = Some(size);
+        self
+    }
+
+    /// Advertise VIRTIO_BLK_F_MQ with `n` request queues
+    pub fn with_queues(mut self, n: u16) -> Self {
+        self.num_queues = n.max(1);
+        self
+    }
+
+    /// Requests handled so far
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Carry out one request; returns the status and the data to return
+    fn execute(&mut self, req_type: u32, sector: u64, data: &[u8], read_len: usize) -> (u8, Vec<u8>) {
+        let block = self.blk_size.unwrap_or(SECTOR_SIZE as u32) as usize;
+        let in_range = |len: usize| {
+            len.is_multiple_of(block)
+                && sector.is_multiple_of((block / SECTOR_SIZE) as u64)
+                && sector.checked_add((len / SECTOR_SIZE) as u64).is_some_and(|end| end <= self.sectors)
+        };
+        let writes = matches!(req_type, VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES);
+        let result = match req_type {
+            _ if writes && self.read_only => return (VIRTIO_BLK_S_IOERR, Vec::new()),
+            VIRTIO_BLK_T_IN if in_range(read_len) => self.read_at(sector, read_len),
+            VIRTIO_BLK_T_OUT if in_range(data.len()) => self.write_at(sector, data).map(|_| Vec::new()),
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => return (VIRTIO_BLK_S_IOERR, Vec::new()),
+            // Discarded sectors read back as zeroes here
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => match self.zero_ranges(data) {
+                Some(result) => result.map(|_| Vec::new()),
+                None => return (VIRTIO_BLK_S_IOERR, Vec::new()),
+            },
+            VIRTIO_BLK_T_FLUSH => self.storage.flush().map(|_| Vec::new()),
+            VIRTIO_BLK_T_GET_ID => Ok(self.serial.to_vec()),
+            _ => return (VIRTIO_BLK_S_UNSUPP, Vec::new()),
+        };
+        match result {
+            Ok(out) => (VIRTIO_BLK_S_OK, out),
+            Err(_) => (VIRTIO_BLK_S_IOERR, Vec::new()),
+        }
+    }
+
+    /// Zero every range in a discard or write-zeroes request
+    /// None if the list is malformed or over the limits.
+    fn zero_ranges(&mut self, data: &[u8]) -> Option<io::Result<()>> {
+        if data.is_empty() || !data.len().is_multiple_of(16) || data.len() / 16 > MAX_RANGE_SEG as usize {
+            return None;
+        }
+        let mut ranges = Vec::new();
+        for seg in data.chunks_exact(16) {
+            let sector = u64::from_le_bytes(seg[..8].try_into().unwrap());
+            let count = u32::from_le_bytes(seg[8..12].try_into().unwrap());
+            let end = sector.checked_add(count as u64)?;
+            if count > MAX_RANGE_SECTORS || end > self.sectors {
+                return None;
+            }
+            ranges.push((sector, count as usize));
+        }
+        Some(ranges.into_iter().try_for_each(|(sector, count)| self.write_at(sector, &vec![0; count * SECTOR_SIZE])))
+    }
+
+    fn read_at(&mut self, sector: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.storage.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.storage.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_at(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.storage.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.storage.write_all(data)
+    }
+
+    fn handle(&mut self, chain: &Chain, queues: &mut Queues) -> Result<u32, Error> {
+        let mem = queues.mem();
+        let req = chain.read(mem)?;
+        let writable = chain.writable_len();
+        // Status byte is the last writable byte; no room for it means there
+        // is nothing we can even report
+        if writable == 0 {
+            return Ok(0);
+        }
+        self.requests += 1;
+        // Data segments sit between the header and the status descriptor
+        let segments = chain.readable.iter().skip(1).chain(chain.writable.iter().rev().skip(1));
+        let (count, longest) = segments.fold((0, 0), |(n, max), &(_, len)| (n + 1, max.max(len)));
+        let too_big = self.limits.is_some_and(|(size_max, seg_max)| count > seg_max || longest > size_max);
+        let (status, out) = match req.get(..16) {
+            _ if queues.inject_failure() || too_big => (VIRTIO_BLK_S_IOERR, Vec::new()),
+            Some(hdr) => {
+                let req_type = u32::from_le_bytes(hdr[..4].try_into().unwrap());
+                let sector = u64::from_le_bytes(hdr[8..16].try_into().unwrap());
+                self.execute(req_type, sector, &req[16..], writable - 1)
+            }
+            None => (VIRTIO_BLK_S_IOERR, Vec::new()),
+        };
+        let mem = queues.mem();
+        let written = chain.write(mem, 0, &out[..out.len().min(writable - 1)])?;
+        chain.write(mem, writable - 1, &[status])?;
+        Ok(written as u32 + 1)
+    }
+}
+
+impl<S: Read + Write + Seek + 'static> Device for BlkDevice<S> {
+    fn device_type(&self) -> u16 {
+        2
+    }
+
+    fn features(&self) -> u64 {
+        let limits = if self.limits.is_some() { VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX } else { 0 };
+        let read_only = if self.read_only { VIRTIO_BLK_F_RO } else { 0 };
+        let blk_size = if self.blk_size.is_some() { VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_TOPOLOGY } else { 0 };
+        let mq = if self.num_queues > 1 { VIRTIO_BLK_F_MQ } else { 0 };
+        VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES | limits | read_only | blk_size | mq
+    }
+
+    fn num_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let (size_max, seg_max) = self.limits.unwrap_or((0, 0));
+        let mut config = self.sectors.to_le_bytes().to_vec();
+        config.extend_from_slice(&size_max.to_le_bytes());
+        config.extend_from_slice(&seg_max.to_le_bytes());
+        // No geometry
+        config.resize(0x14, 0);
+        config.extend_from_slice(&self.blk_size.unwrap_or(0).to_le_bytes());
+        // Topology: one logical block per physical block, 16-block optimal I/O
+        config.extend_from_slice(&[0, 0, 1, 0, 16, 0, 0, 0]);
+        // No writeback toggle
+        config.resize(0x22, 0);
+        config.extend_from_slice(&self.num_queues.to_le_bytes());
+        for field in [MAX_RANGE_SECTORS, MAX_RANGE_SEG, 1, MAX_RANGE_SECTORS, MAX_RANGE_SEG] {
+            config.extend_from_slice(&field.to_le_bytes());
+        }
+        // write_zeroes_may_unmap
+        config.extend_from_slice(&[1, 0, 0, 0]);
+        config
+    }
+
+    fn notify(&mut self, queue: u16, queues: &mut Queues) -> Result<(), Error> {
+        while let Some(chain) = queues.pop(queue)? {
+            let len = self.handle(&chain, queues)?;
+            queues.complete(queue, &chain, len)?;
+        }
+        Ok(())
+    }
+}
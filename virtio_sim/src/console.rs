@@ -0,0 +1,73 @@
+//! virtio-console, single port: captures output, feeds queued input
+
+use std::collections::VecDeque;
+
+use crate::{Device, Error, Queues};
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+#[derive(Default)]
+pub struct ConsoleDevice {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl ConsoleDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes for the driver to read; deliver them with `SimPci::kick`
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Everything the driver wrote so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Device for ConsoleDevice {
+    fn device_type(&self) -> u16 {
+        3
+    }
+
+    fn num_queues(&self) -> u16 {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // cols, rows, max_nr_ports
+        let mut cfg = 80u16.to_le_bytes().to_vec();
+        cfg.extend_from_slice(&25u16.to_le_bytes());
+        cfg.extend_from_slice(&1u32.to_le_bytes());
+        cfg
+    }
+
+    fn notify(&mut self, _queue: u16, queues: &mut Queues) -> Result<(), Error> {
+        while let Some(chain) = queues.pop(TX_QUEUE)? {
+            let data = chain.read(queues.mem())?;
+            if !queues.inject_failure() {
+                self.output.extend_from_slice(&data);
+            }
+            queues.complete(TX_QUEUE, &chain, 0)?;
+        }
+
+        while !self.input.is_empty() {
+            let Some(chain) = queues.pop(RX_QUEUE)? else {
+                break;
+            };
+            let n = chain.writable_len().min(self.input.len());
+            let data: Vec<u8> = self.input.drain(..n).collect();
+            chain.write(queues.mem(), 0, &data)?;
+            queues.complete(RX_QUEUE, &chain, n as u32)?;
+        }
+        Ok(())
+    }
+}
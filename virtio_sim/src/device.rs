@@ -0,0 +1,99 @@
+//! What a device model implements, and what it gets to work with
+
+use std::any::Any;
+
+use crate::{Chain, DeviceQueue, Error, GuestMemory};
+
+/// Feature bit every modern device offers
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A virtio device model behind the simulated transport
+pub trait Device: Any {
+    /// virtio device type: 1 net, 2 block, 3 console, 4 entropy, 16 gpu
+    fn device_type(&self) -> u16;
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn num_queues(&self) -> u16;
+
+    fn queue_max_size(&self) -> u16 {
+        256
+    }
+
+    /// Device-specific configuration space, little-endian
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// The driver notified `queue` (or the test asked the device to make
+    /// progress): consume and complete whatever is ready
+    fn notify(&mut self, queue: u16, queues: &mut Queues) -> Result<(), Error>;
+
+    /// The driver reset the device; drop in-flight state
+    fn reset(&mut self) {}
+}
+
+/// Faults a test can inject into one device
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Requests still to complete with a device error status
+    pub fail_requests: usize,
+    /// Keep completions off the used ring until `SimPci::release`
+    pub hold_completions: bool,
+}
+
+/// A completion waiting for the test to release it
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Held {
+    pub queue: u16,
+    pub head: u16,
+    pub len: u32,
+}
+
+/// A device's view of its queues while it handles a notification
+pub struct Queues<'a> {
+    pub(crate) mem: &'a GuestMemory,
+    pub(crate) queues: &'a mut [DeviceQueue],
+    pub(crate) faults: &'a mut Faults,
+    pub(crate) held: &'a mut Vec<Held>,
+    pub(crate) interrupt: bool,
+}
+
+impl Queues<'_> {
+    pub fn mem(&self) -> &GuestMemory {
+        self.mem
+    }
+
+    /// Next available chain on `queue`
+    pub fn pop(&mut self, queue: u16) -> Result<Option<Chain>, Error> {
+        match self.queues.get_mut(queue as usize) {
+            Some(q) => q.pop(self.mem),
+            None => Ok(None),
+        }
+    }
+
+    /// Hand a chain back with `len` bytes written, unless completions are
+    /// being held
+    pub fn complete(&mut self, queue: u16, chain: &Chain, len: u32) -> Result<(), Error> {
+        if self.faults.hold_completions {
+            self.held.push(Held { queue, head: chain.head, len });
+            return Ok(());
+        }
+        self.queues[queue as usize].push_used(self.mem, chain.head, len)?;
+        self.interrupt = true;
+        Ok(())
+    }
+
+    /// True if the current request should fail; uses up one injected failure
+    pub fn inject_failure(&mut self) -> bool {
+        if self.faults.fail_requests == 0 {
+            return false;
+        }
+        self.faults.fail_requests -= 1;
+        true
+    }
+}
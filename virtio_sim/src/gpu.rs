@@ -0,0 +1,256 @@
+//! virtio-gpu, 2D only, one scanout
+//!
+//! Resources live on the host side; TRANSFER_TO_HOST_2D copies from their
+//! guest backing as QEMU does, and RESOURCE_FLUSH of the scanout resource
+//! captures a `Frame` the test can check pixel by pixel.
+
+use std::collections::HashMap;
+
+use driver_core::gpu::{
+    Command, CtrlHdr, DisplayOne, Rect, RespDisplayInfo, ResourceCreate2d, ResourceFlush, SetScanout,
+    TransferToHost2d, VIRTIO_GPU_CMD_GET_DISPLAY_INFO, VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+    VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, VIRTIO_GPU_CMD_RESOURCE_FLUSH, VIRTIO_GPU_CMD_SET_SCANOUT,
+    VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM, VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+    VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM, VIRTIO_GPU_RESP_ERR_UNSPEC,
+    VIRTIO_GPU_RESP_OK_DISPLAY_INFO, VIRTIO_GPU_RESP_OK_NODATA,
+};
+
+use crate::{Device, Error, GuestMemory, Queues};
+
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1;
+
+const CONTROL_QUEUE: u16 = 0;
+const CURSOR_QUEUE: u16 = 1;
+
+/// What was on the scanout at the last flush
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// One little-endian word per pixel, in the resource's format
+    pub pixels: Vec<u32>,
+}
+
+impl Frame {
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
+
+struct Resource {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+    backing: Vec<(u64, u32)>,
+}
+
+impl Resource {
+    /// Copy `len` bytes from `offset` into the backing, across entries
+    fn read_backing(&self, mem: &GuestMemory, offset: u64, buf: &mut [u8]) -> Result<bool, Error> {
+        let mut skip = offset;
+        let mut done = 0;
+        for &(addr, len) in &self.backing {
+            let len = len as u64;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let n = ((len - skip) as usize).min(buf.len() - done);
+            mem.read(addr + skip, &mut buf[done..done + n])?;
+            done += n;
+            skip = 0;
+            if done == buf.len() {
+                break;
+            }
+        }
+        Ok(done == buf.len())
+    }
+}
+
+pub struct GpuDevice {
+    width: u32,
+    height: u32,
+    resources: HashMap<u32, Resource>,
+    scanout: Option<u32>,
+    frame: Option<Frame>,
+    flushes: usize,
+}
+
+/// Read a command struct from the start of a request
+fn parse<T: Command + Copy>(req: &[u8]) -> Option<T> {
+    (req.len() >= size_of::<T>()).then(|| unsafe { std::ptr::read_unaligned(req.as_ptr() as *const T) })
+}
+
+impl GpuDevice {
+    /// One display with a preferred mode of `width` x `height`
+    pub fn new(width: u32, height: u32) -> Self {
+        GpuDevice { width, height, resources: HashMap::new(), scanout: None, frame: None, flushes: 0 }
+    }
+
+    /// Scanout contents at the last flush
+    pub fn frame(&self) -> Option<&Frame> {
+        self.frame.as_ref()
+    }
+
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+
+    /// Carry out one control command; returns the response
+    fn execute(&mut self, mem: &GuestMemory, req: &[u8]) -> Result<Vec<u8>, Error> {
+        let Some(hdr) = parse::<CtrlHdr>(req) else {
+            return Ok(CtrlHdr::new(VIRTIO_GPU_RESP_ERR_UNSPEC).as_bytes().to_vec());
+        };
+        let resp_type = match hdr.cmd_type {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                let mut info = RespDisplayInfo { hdr: CtrlHdr::new(VIRTIO_GPU_RESP_OK_DISPLAY_INFO), ..Default::default() };
+                info.pmodes[0] = DisplayOne { r: Rect::sized(self.width, self.height), enabled: 1, flags: 0 };
+                return Ok(info.as_bytes().to_vec());
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => parse::<ResourceCreate2d>(req).map_or(VIRTIO_GPU_RESP_ERR_UNSPEC, |c| self.create(c)),
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => self.attach_backing(req),
+            VIRTIO_GPU_CMD_SET_SCANOUT => parse::<SetScanout>(req).map_or(VIRTIO_GPU_RESP_ERR_UNSPEC, |c| self.set_scanout(c)),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => match parse::<TransferToHost2d>(req) {
+                Some(c) => self.transfer(mem, c)?,
+                None => VIRTIO_GPU_RESP_ERR_UNSPEC,
+            },
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => parse::<ResourceFlush>(req).map_or(VIRTIO_GPU_RESP_ERR_UNSPEC, |c| self.flush(c)),
+            _ => VIRTIO_GPU_RESP_ERR_UNSPEC,
+        };
+        let mut resp = CtrlHdr::new(resp_type);
+        if hdr.flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+            resp.flags = VIRTIO_GPU_FLAG_FENCE;
+            resp.fence_id = hdr.fence_id;
+        }
+        Ok(resp.as_bytes().to_vec())
+    }
+
+    fn create(&mut self, cmd: ResourceCreate2d) -> u32 {
+        let formats = [
+            VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+            VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM,
+            VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM,
+        ];
+        if cmd.resource_id == 0 || self.resources.contains_key(&cmd.resource_id) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        }
+        if !formats.contains(&cmd.format) || cmd.width == 0 || cmd.height == 0 {
+            return VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER;
+        }
+        let data = vec![0; cmd.width as usize * cmd.height as usize * 4];
+        self.resources.insert(cmd.resource_id, Resource { width: cmd.width, height: cmd.height, data, backing: Vec::new() });
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    /// Header, resource_id, nr_entries, then nr_entries mem entries
+    fn attach_backing(&mut self, req: &[u8]) -> u32 {
+        let word = |off: usize| req.get(off..off + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        let (Some(id), Some(nr)) = (word(24), word(28)) else {
+            return VIRTIO_GPU_RESP_ERR_UNSPEC;
+        };
+        let Some(res) = self.resources.get_mut(&id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        let mut backing = Vec::new();
+        for i in 0..nr as usize {
+            let off = 32 + i * 16;
+            let (Some(lo), Some(hi), Some(len)) = (word(off), word(off + 4), word(off + 8)) else {
+                return VIRTIO_GPU_RESP_ERR_UNSPEC;
+            };
+            backing.push(((hi as u64) << 32 | lo as u64, len));
+        }
+        res.backing = backing;
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn set_scanout(&mut self, cmd: SetScanout) -> u32 {
+        if cmd.scanout_id != 0 {
+            return VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID;
+        }
+        if cmd.resource_id == 0 {
+            self.scanout = None;
+            return VIRTIO_GPU_RESP_OK_NODATA;
+        }
+        if !self.resources.contains_key(&cmd.resource_id) {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        }
+        self.scanout = Some(cmd.resource_id);
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+
+    fn transfer(&mut self, mem: &GuestMemory, cmd: TransferToHost2d) -> Result<u32, Error> {
+        let Some(res) = self.resources.get_mut(&cmd.resource_id) else {
+            return Ok(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        };
+        let r = cmd.r;
+        if res.backing.is_empty() || r.x.saturating_add(r.width) > res.width || r.y.saturating_add(r.height) > res.height {
+            return Ok(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        // Row h of the rectangle comes from offset + h * stride
+        let stride = res.width as u64 * 4;
+        let mut row = vec![0; r.width as usize * 4];
+        for h in 0..r.height {
+            if !res.read_backing(mem, cmd.offset + h as u64 * stride, &mut row)? {
+                return Ok(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+            }
+            let dst = ((r.y + h) as u64 * stride + r.x as u64 * 4) as usize;
+            res.data[dst..dst + row.len()].copy_from_slice(&row);
+        }
+        Ok(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    fn flush(&mut self, cmd: ResourceFlush) -> u32 {
+        let Some(res) = self.resources.get(&cmd.resource_id) else {
+            return VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID;
+        };
+        if self.scanout == Some(cmd.resource_id) {
+            let pixels = res.data.chunks_exact(4).map(|p| u32::from_le_bytes(p.try_into().unwrap())).collect();
+            self.frame = Some(Frame { width: res.width, height: res.height, pixels });
+            self.flushes += 1;
+        }
+        VIRTIO_GPU_RESP_OK_NODATA
+    }
+}
+
+impl Device for GpuDevice {
+    fn device_type(&self) -> u16 {
+        16
+    }
+
+    fn num_queues(&self) -> u16 {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // events_read, events_clear, num_scanouts, num_capsets
+        [0u32, 0, 1, 0].iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn notify(&mut self, _queue: u16, queues: &mut Queues) -> Result<(), Error> {
+        while let Some(chain) = queues.pop(CONTROL_QUEUE)? {
+            let req = chain.read(queues.mem())?;
+            let resp = if queues.inject_failure() {
+                CtrlHdr::new(VIRTIO_GPU_RESP_ERR_UNSPEC).as_bytes().to_vec()
+            } else {
+                self.execute(queues.mem(), &req)?
+            };
+            let written = chain.write(queues.mem(), 0, &resp)?;
+            queues.complete(CONTROL_QUEUE, &chain, written as u32)?;
+        }
+        // No cursor support: hand cursor commands straight back
+        while let Some(chain) = queues.pop(CURSOR_QUEUE)? {
+            queues.complete(CURSOR_QUEUE, &chain, 0)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.resources.clear();
+        self.scanout = None;
+    }
+}
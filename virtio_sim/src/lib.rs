@@ -0,0 +1,55 @@
+//! Software virtio devices for host-side driver tests
+//!
+//! The device end of the split virtqueue over a fake guest memory, a fake
+//! PCI ECAM with virtio capabilities, and device models for virtio-blk,
+//! net, rng, console and gpu (2D). `SimPci` implements `driver_core`'s `Mmio`
+//! trait, so the same driver code the kernel runs can probe, initialize and
+//! drive these devices under `cargo test`.
+//!
+//! Guest physical addresses are host addresses inside a `GuestMemory`
+//! arena, the way the kernel runs identity mapped. Register accesses go
+//! through `SimPci`; a notify write runs the device synchronously unless
+//! completions are being held back (see `Faults`).
+
+pub mod blk;
+pub mod console;
+pub mod device;
+pub mod gpu;
+pub mod memory;
+pub mod net;
+pub mod pci;
+pub mod queue;
+pub mod rng;
+
+pub use device::{Device, Faults, Queues};
+pub use memory::GuestMemory;
+pub use pci::SimPci;
+pub use queue::{Chain, DeviceQueue};
+
+use std::fmt;
+
+/// Something the driver handed the device that a real device would choke on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// DMA outside guest memory
+    OutOfBounds { addr: u64, len: usize },
+    /// Descriptor index past the end of the table
+    BadDescriptor(u16),
+    /// Chain longer than the queue, so it must loop
+    ChainLoop,
+    /// Device-readable descriptor after a device-writable one
+    ReadableAfterWritable,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfBounds { addr, len } => write!(f, "DMA of {} bytes at {:#x} outside guest memory", len, addr),
+            Error::BadDescriptor(idx) => write!(f, "descriptor index {} out of range", idx),
+            Error::ChainLoop => write!(f, "descriptor chain loops"),
+            Error::ReadableAfterWritable => write!(f, "readable descriptor after a writable one"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,140 @@
+//! Fake guest RAM
+//!
+//! One zeroed, page-aligned allocation. The test (playing the driver) carves
+//! queues and buffers out of it with `alloc_*` and hands the device their
+//! host addresses; the device side accesses it only through `read`/`write`,
+//! which refuse anything outside the arena instead of touching host memory.
+
+use std::alloc::{self, Layout};
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+use crate::Error;
+
+pub struct GuestMemory {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    next: Cell<usize>,
+}
+
+impl GuestMemory {
+    pub fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size.max(1), 4096).unwrap();
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        GuestMemory { ptr, layout, next: Cell::new(0) }
+    }
+
+    /// Guest physical address of the first byte
+    pub fn base(&self) -> u64 {
+        self.ptr.as_ptr() as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Bump-allocate `size` zeroed bytes; panics when the arena is full
+    pub fn alloc(&self, size: usize, align: usize) -> u64 {
+        let start = self.next.get().next_multiple_of(align.max(1));
+        assert!(start + size <= self.size(), "guest memory exhausted");
+        self.next.set(start + size);
+        self.base() + start as u64
+    }
+
+    /// Move `value` into guest memory, e.g. a `Virtqueue` the device will see
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_value<T>(&self, value: T) -> &mut T {
+        let addr = self.alloc(size_of::<T>(), align_of::<T>()) as *mut T;
+        unsafe {
+            addr.write(value);
+            &mut *addr
+        }
+    }
+
+    /// Zeroed byte buffer in guest memory
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_bytes(&self, len: usize) -> &mut [u8] {
+        let addr = self.alloc(len, 16) as *mut u8;
+        unsafe { std::slice::from_raw_parts_mut(addr, len) }
+    }
+
+    fn check(&self, addr: u64, len: usize) -> Result<*mut u8, Error> {
+        let offset = addr.wrapping_sub(self.base());
+        if addr < self.base() || offset.checked_add(len as u64).is_none_or(|end| end > self.size() as u64) {
+            return Err(Error::OutOfBounds { addr, len });
+        }
+        Ok(unsafe { self.ptr.as_ptr().add(offset as usize) })
+    }
+
+    /// Device-side DMA read
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let src = self.check(addr, buf.len())?;
+        unsafe { std::ptr::copy(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Device-side DMA write
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<(), Error> {
+        let dst = self.check(addr, data.len())?;
+        unsafe { std::ptr::copy(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    pub fn read16(&self, addr: u64) -> Result<u16, Error> {
+        let mut b = [0; 2];
+        self.read(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn read32(&self, addr: u64) -> Result<u32, Error> {
+        let mut b = [0; 4];
+        self.read(addr, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn read64(&self, addr: u64) -> Result<u64, Error> {
+        let mut b = [0; 8];
+        self.read(addr, &mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn write16(&self, addr: u64, val: u16) -> Result<(), Error> {
+        self.write(addr, &val.to_le_bytes())
+    }
+
+    pub fn write32(&self, addr: u64, val: u32) -> Result<(), Error> {
+        self.write(addr, &val.to_le_bytes())
+    }
+}
+
+impl Drop for GuestMemory {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_aligned_and_in_bounds() {
+        let mem = GuestMemory::new(8192);
+        let a = mem.alloc(3, 1);
+        let b = mem.alloc(16, 16);
+        assert_eq!(a, mem.base());
+        assert_eq!(b, mem.base() + 16);
+        let v = mem.alloc_value(0x1234u32);
+        assert_eq!(mem.read32(v as *mut u32 as u64).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn dma_outside_arena_is_refused() {
+        let mem = GuestMemory::new(4096);
+        mem.write32(mem.base() + 4092, 7).unwrap();
+        assert_eq!(mem.read32(mem.base() + 4092), Ok(7));
+        assert_eq!(mem.read32(mem.base() + 4093), Err(Error::OutOfBounds { addr: mem.base() + 4093, len: 4 }));
+        assert!(mem.write(0, &[1]).is_err());
+        assert!(mem.read(u64::MAX, &mut [0; 2]).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! virtio-net with a loopback wire and optional pcap capture
+//!
+//! Frames carry the `NET_HDR_SIZE` header the driver uses. Transmitted frames
+//! are recorded (and written to the capture, if any); in loopback mode they
+//! also come straight back on the receive queue. Tests can inject received
+//! frames with `inject` followed by `SimPci::kick`.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use driver_core::net::{NET_HDR_SIZE, VIRTIO_NET_F_MAC};
+
+use crate::device::VIRTIO_F_VERSION_1;
+use crate::{Device, Error, Queues};
+
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+pub struct NetDevice {
+    mac: [u8; 6],
+    loopback: bool,
+    rx_backlog: VecDeque<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+    capture: Option<Box<dyn Write>>,
+}
+
+impl NetDevice {
+    /// Transmitted frames go nowhere (but are recorded)
+    pub fn new(mac: [u8; 6]) -> Self {
+        NetDevice { mac, loopback: false, rx_backlog: VecDeque::new(), sent: Vec::new(), capture: None }
+    }
+
+    /// Transmitted frames are received again
+    pub fn loopback(mac: [u8; 6]) -> Self {
+        NetDevice { loopback: true, ..Self::new(mac) }
+    }
+
+    /// Also write every transmitted frame to `out` in pcap format
+    pub fn capture_to(mut self, mut out: impl Write + 'static) -> io::Result<Self> {
+        // Global header: magic, v2.4, UTC, no sigfigs, snaplen, Ethernet
+        out.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&[0; 8])?;
+        out.write_all(&65535u32.to_le_bytes())?;
+        out.write_all(&1u32.to_le_bytes())?;
+        self.capture = Some(Box::new(out));
+        Ok(self)
+    }
+
+    /// Queue a frame for the driver to receive
+    pub fn inject(&mut self, frame: &[u8]) {
+        self.rx_backlog.push_back(frame.to_vec());
+    }
+
+    /// Frames the driver transmitted, oldest first
+    pub fn sent(&self) -> &[Vec<u8>] {
+        &self.sent
+    }
+
+    /// Frames waiting for a receive buffer
+    pub fn rx_pending(&self) -> usize {
+        self.rx_backlog.len()
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        if let Some(out) = &mut self.capture {
+            // Timestamps are the frame number, so captures are reproducible
+            let mut rec = Vec::with_capacity(16 + frame.len());
+            rec.extend_from_slice(&(self.sent.len() as u32).to_le_bytes());
+            rec.extend_from_slice(&0u32.to_le_bytes());
+            rec.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            rec.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            rec.extend_from_slice(frame);
+            // A broken capture file should not take the device down
+            let _ = out.write_all(&rec).and_then(|_| out.flush());
+        }
+        self.sent.push(frame.to_vec());
+    }
+
+    fn transmit(&mut self, queues: &mut Queues) -> Result<(), Error> {
+        while let Some(chain) = queues.pop(TX_QUEUE)? {
+            let data = chain.read(queues.mem())?;
+            // Failed sends are dropped on the floor, like a lossy wire
+            if !queues.inject_failure() && data.len() > NET_HDR_SIZE {
+                let frame = &data[NET_HDR_SIZE..];
+                self.record(frame);
+                if self.loopback {
+                    self.rx_backlog.push_back(frame.to_vec());
+                }
+            }
+            queues.complete(TX_QUEUE, &chain, 0)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, queues: &mut Queues) -> Result<(), Error> {
+        while !self.rx_backlog.is_empty() {
+            let Some(chain) = queues.pop(RX_QUEUE)? else {
+                break;
+            };
+            let frame = self.rx_backlog.pop_front().unwrap();
+            let mut packet = vec![0; NET_HDR_SIZE];
+            packet.extend_from_slice(&frame);
+            let written = chain.write(queues.mem(), 0, &packet)?;
+            queues.complete(RX_QUEUE, &chain, written as u32)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for NetDevice {
+    fn device_type(&self) -> u16 {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC as u64 | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> u16 {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut cfg = self.mac.to_vec();
+        cfg.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        cfg
+    }
+
+    fn notify(&mut self, _queue: u16, queues: &mut Queues) -> Result<(), Error> {
+        self.transmit(queues)?;
+        self.receive(queues)
+    }
+
+    fn reset(&mut self) {
+        self.rx_backlog.clear();
+    }
+}
@@ -0,0 +1,448 @@
+//! Fake PCI ECAM with virtio-pci modern devices behind it
+//!
+//! Bus 0 only, one function per slot. Each device has a 64-bit BAR4 of
+//! 16 KiB, as QEMU lays out virtio-pci, holding the common configuration
+//! (0x0000), ISR (0x1000), device configuration (0x2000) and notify
+//! (0x3000, multiplier 4) regions, and the four vendor capabilities pointing
+//! at them. BAR4 starts unprogrammed, like under VZ, so drivers have to size
+//! and place it.
+
+use std::any::Any;
+use std::cell::RefCell;
+
+use driver_core::mmio::Mmio;
+use driver_core::pci::{PciDevice, VIRTIO_VENDOR_ID};
+
+use crate::device::{Held, VIRTIO_F_VERSION_1};
+use crate::{Device, DeviceQueue, Error, Faults, GuestMemory, Queues};
+
+/// ECAM base, as on QEMU's virt machine (highmem)
+pub const ECAM_BASE: u64 = 0x40_1000_0000;
+/// 32-bit PCI MMIO window to allocate BARs from (base, size)
+pub const MMIO_WINDOW: (u64, u64) = (0x1000_0000, 0x2eff_0000);
+
+const BAR_INDEX: usize = 4;
+const BAR_SIZE: u64 = 0x4000;
+/// 64-bit, prefetchable memory BAR
+const BAR_TYPE: u32 = 0xC;
+
+const COMMON: u64 = 0x0000;
+const ISR: u64 = 0x1000;
+const DEVICE_CFG: u64 = 0x2000;
+const NOTIFY: u64 = 0x3000;
+const NOTIFY_MULT: u32 = 4;
+
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_CAP_PTR: usize = 0x34;
+const PCI_COMMAND_MEMORY: u16 = 0x02;
+
+const STATUS_DRIVER_OK: u8 = 0x04;
+const STATUS_FEATURES_OK: u8 = 0x08;
+const STATUS_NEEDS_RESET: u8 = 0x40;
+
+const ISR_QUEUE: u8 = 0x1;
+const ISR_CONFIG: u8 = 0x2;
+
+struct Slot {
+    config: [u8; 256],
+    device: Box<dyn Device>,
+    status: u8,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    queue_select: u16,
+    queues: Vec<DeviceQueue>,
+    isr: u8,
+    faults: Faults,
+    held: Vec<Held>,
+    last_error: Option<Error>,
+}
+
+impl Slot {
+    fn new(device: Box<dyn Device>) -> Self {
+        let mut config = [0u8; 256];
+        let mut put = |off: usize, data: &[u8]| config[off..off + data.len()].copy_from_slice(data);
+        put(0x00, &VIRTIO_VENDOR_ID.to_le_bytes());
+        put(0x02, &(0x1040 + device.device_type()).to_le_bytes());
+        put(PCI_STATUS, &0x10u16.to_le_bytes());
+        put(0x08, &[1]);
+        put(0x10 + BAR_INDEX * 4, &BAR_TYPE.to_le_bytes());
+        put(0x2c, &VIRTIO_VENDOR_ID.to_le_bytes());
+        put(0x2e, &0x1100u16.to_le_bytes());
+        put(PCI_CAP_PTR, &[0x40]);
+
+        // Vendor capabilities: id, next, len, cfg_type, bar, ..., offset, length
+        let caps = [(0x40, 0x50, 1, COMMON, 0x38), (0x50, 0x64, 2, NOTIFY, 0x1000), (0x64, 0x74, 3, ISR, 1), (0x74, 0, 4, DEVICE_CFG, 0x1000)];
+        for (off, next, cfg_type, bar_off, len) in caps {
+            let cap_len = if cfg_type == 2 { 20 } else { 16 };
+            put(off, &[0x09, next, cap_len, cfg_type, BAR_INDEX as u8]);
+            put(off + 8, &(bar_off as u32).to_le_bytes());
+            put(off + 12, &(len as u32).to_le_bytes());
+        }
+        put(0x50 + 16, &NOTIFY_MULT.to_le_bytes());
+
+        let queues = (0..device.num_queues()).map(|_| DeviceQueue::new(device.queue_max_size())).collect();
+        Slot {
+            config,
+            device,
+            status: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            queue_select: 0,
+            queues,
+            isr: 0,
+            faults: Faults::default(),
+            held: Vec::new(),
+            last_error: None,
+        }
+    }
+
+    fn config16(&self, off: usize) -> u16 {
+        u16::from_le_bytes([self.config[off], self.config[off + 1]])
+    }
+
+    fn config32(&self, off: usize) -> u32 {
+        u32::from_le_bytes(self.config[off..off + 4].try_into().unwrap())
+    }
+
+    /// BAR4 address while memory decode is on
+    fn bar(&self) -> Option<u64> {
+        if self.config16(PCI_COMMAND) & PCI_COMMAND_MEMORY == 0 {
+            return None;
+        }
+        let reg = 0x10 + BAR_INDEX * 4;
+        let addr = (self.config32(reg) & !0xF) as u64 | (self.config32(reg + 4) as u64) << 32;
+        (addr != 0).then_some(addr)
+    }
+
+    fn write_config_space(&mut self, off: usize, width: usize, val: u32) {
+        let bar_lo = 0x10 + BAR_INDEX * 4;
+        match (off, width) {
+            (PCI_COMMAND, 2) => {
+                self.config[off..off + 2].copy_from_slice(&((val as u16) & 0x0407).to_le_bytes());
+            }
+            (o, 4) if o == bar_lo => {
+                let val = (val & !(BAR_SIZE as u32 - 1) & !0xF) | BAR_TYPE;
+                self.config[o..o + 4].copy_from_slice(&val.to_le_bytes());
+            }
+            (o, 4) if o == bar_lo + 4 => self.config[o..o + 4].copy_from_slice(&val.to_le_bytes()),
+            // Everything else, including the other BARs, is read-only
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.queue_select = 0;
+        self.isr = 0;
+        self.held.clear();
+        self.queues.iter_mut().for_each(DeviceQueue::reset);
+        self.device.reset();
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut DeviceQueue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    fn read_common(&mut self, off: u64, width: usize) -> u32 {
+        let features = self.device.features();
+        let q = self.queues.get(self.queue_select as usize).cloned();
+        let mut regs = [0u8; 0x38];
+        let mut put = |off: usize, data: &[u8]| regs[off..off + data.len()].copy_from_slice(data);
+        put(0x00, &self.device_feature_select.to_le_bytes());
+        let half = |v: u64, sel: u32| if sel < 2 { (v >> (32 * sel)) as u32 } else { 0 };
+        put(0x04, &half(features, self.device_feature_select).to_le_bytes());
+        put(0x08, &self.driver_feature_select.to_le_bytes());
+        put(0x0c, &half(self.driver_features, self.driver_feature_select).to_le_bytes());
+        put(0x10, &0xffffu16.to_le_bytes());
+        put(0x12, &(self.queues.len() as u16).to_le_bytes());
+        put(0x14, &[self.status]);
+        put(0x16, &self.queue_select.to_le_bytes());
+        if let Some(q) = q {
+            put(0x18, &q.size.to_le_bytes());
+            put(0x1a, &0xffffu16.to_le_bytes());
+            put(0x1c, &(q.enabled as u16).to_le_bytes());
+            put(0x1e, &self.queue_select.to_le_bytes());
+            put(0x20, &q.desc.to_le_bytes());
+            put(0x28, &q.avail.to_le_bytes());
+            put(0x30, &q.used.to_le_bytes());
+        }
+        load(&regs, off as usize, width)
+    }
+
+    fn write_common(&mut self, off: u64, val: u32) {
+        let set_half = |reg: &mut u64, high: bool, val: u32| {
+            let shift = if high { 32 } else { 0 };
+            *reg = (*reg & !(0xffff_ffff << shift)) | (val as u64) << shift;
+        };
+        match off {
+            0x00 => self.device_feature_select = val,
+            0x08 => self.driver_feature_select = val,
+            0x0c => match self.driver_feature_select {
+                0 => set_half(&mut self.driver_features, false, val),
+                1 => set_half(&mut self.driver_features, true, val),
+                _ => {}
+            },
+            0x14 => self.write_status(val as u8),
+            0x16 => self.queue_select = val as u16,
+            0x18 => {
+                if let Some(q) = self.selected_queue() {
+                    if val as u16 <= q.max_size {
+                        q.size = val as u16;
+                    }
+                }
+            }
+            0x1c => {
+                if let Some(q) = self.selected_queue() {
+                    q.enabled = val & 1 != 0;
+                }
+            }
+            0x20..=0x37 => {
+                if let Some(q) = self.selected_queue() {
+                    let high = off & 4 != 0;
+                    match off & !7 {
+                        0x20 => set_half(&mut q.desc, high, val),
+                        0x28 => set_half(&mut q.avail, high, val),
+                        _ => set_half(&mut q.used, high, val),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_status(&mut self, val: u8) {
+        if val == 0 {
+            self.reset();
+            return;
+        }
+        let mut val = val;
+        if val & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0 {
+            // Accept only features we offered, and only modern drivers
+            let offered = self.device.features();
+            if self.driver_features & !offered != 0 || self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+                val &= !STATUS_FEATURES_OK;
+            }
+        }
+        self.status = val | (self.status & STATUS_NEEDS_RESET);
+    }
+
+    /// Let the device work on `queue` if the driver has it running
+    fn run(&mut self, mem: &GuestMemory, queue: u16) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+        let mut queues = Queues {
+            mem,
+            queues: &mut self.queues,
+            faults: &mut self.faults,
+            held: &mut self.held,
+            interrupt: false,
+        };
+        let result = self.device.notify(queue, &mut queues);
+        if queues.interrupt {
+            self.isr |= ISR_QUEUE;
+        }
+        if let Err(err) = result {
+            self.fail(err);
+        }
+    }
+
+    /// The device gives up until the driver resets it
+    fn fail(&mut self, err: Error) {
+        self.last_error = Some(err);
+        self.status |= STATUS_NEEDS_RESET;
+        self.isr |= ISR_CONFIG;
+        self.held.clear();
+    }
+}
+
+fn load(bytes: &[u8], off: usize, width: usize) -> u32 {
+    (0..width).fold(0, |acc, i| acc | (*bytes.get(off + i).unwrap_or(&0) as u32) << (8 * i))
+}
+
+enum Target {
+    Config(usize, usize),
+    Bar(usize, u64),
+    None,
+}
+
+/// The simulated PCI bus; implements `Mmio` for the driver under test
+pub struct SimPci<'m> {
+    mem: &'m GuestMemory,
+    slots: RefCell<Vec<Slot>>,
+}
+
+impl<'m> SimPci<'m> {
+    pub fn new(mem: &'m GuestMemory) -> Self {
+        SimPci { mem, slots: RefCell::new(Vec::new()) }
+    }
+
+    pub fn ecam_base(&self) -> u64 {
+        ECAM_BASE
+    }
+
+    /// Plug a device into the next free slot and return the slot number
+    pub fn add(&mut self, device: impl Device) -> u8 {
+        let mut slots = self.slots.borrow_mut();
+        assert!(slots.len() < 32, "bus 0 is full");
+        slots.push(Slot::new(Box::new(device)));
+        (slots.len() - 1) as u8
+    }
+
+    /// Config space address of a slot, for `PciDevice::new`
+    pub fn config_addr(&self, slot: u8) -> u64 {
+        PciDevice::config_addr(ECAM_BASE, 0, slot, 0)
+    }
+
+    /// Look at or poke the device model in `slot`
+    pub fn with_device<D: Device, R>(&self, slot: u8, f: impl FnOnce(&mut D) -> R) -> R {
+        let mut slots = self.slots.borrow_mut();
+        let device = &mut *slots[slot as usize].device as &mut dyn Any;
+        f(device.downcast_mut::<D>().expect("device type mismatch"))
+    }
+
+    /// Let the device make progress on `queue` without a driver notify,
+    /// e.g. to deliver input that was just queued on the model
+    pub fn kick(&self, slot: u8, queue: u16) {
+        self.slots.borrow_mut()[slot as usize].run(self.mem, queue);
+    }
+
+    /// Complete the next `n` requests on `slot` with a device error
+    pub fn fail_next(&self, slot: u8, n: usize) {
+        self.slots.borrow_mut()[slot as usize].faults.fail_requests = n;
+    }
+
+    /// While on, completions are kept back until `release`
+    pub fn hold_completions(&self, slot: u8, hold: bool) {
+        self.slots.borrow_mut()[slot as usize].faults.hold_completions = hold;
+    }
+
+    /// Publish held completions in order; returns how many
+    pub fn release(&self, slot: u8) -> usize {
+        self.release_held(slot, false)
+    }
+
+    /// Publish held completions newest first, as a device that finishes
+    /// requests out of order would; returns how many
+    pub fn release_reversed(&self, slot: u8) -> usize {
+        self.release_held(slot, true)
+    }
+
+    fn release_held(&self, slot: u8, reversed: bool) -> usize {
+        let mut slots = self.slots.borrow_mut();
+        let s = &mut slots[slot as usize];
+        let mut held = std::mem::take(&mut s.held);
+        if reversed {
+            held.reverse();
+        }
+        for h in &held {
+            if let Err(err) = s.queues[h.queue as usize].push_used(self.mem, h.head, h.len) {
+                s.fail(err);
+                return 0;
+            }
+            s.isr |= ISR_QUEUE;
+        }
+        held.len()
+    }
+
+    /// Device-initiated failure: set DEVICE_NEEDS_RESET, raise a config
+    /// interrupt and drop anything in flight
+    pub fn trigger_reset(&self, slot: u8) {
+        let mut slots = self.slots.borrow_mut();
+        let s = &mut slots[slot as usize];
+        s.status |= STATUS_NEEDS_RESET;
+        s.isr |= ISR_CONFIG;
+        s.held.clear();
+    }
+
+    pub fn status(&self, slot: u8) -> u8 {
+        self.slots.borrow()[slot as usize].status
+    }
+
+    /// Why the device last went to NEEDS_RESET on its own
+    pub fn last_error(&self, slot: u8) -> Option<Error> {
+        self.slots.borrow()[slot as usize].last_error
+    }
+
+    fn decode(&self, slots: &[Slot], addr: u64) -> Target {
+        if (ECAM_BASE..ECAM_BASE + (1 << 20)).contains(&addr) {
+            let off = addr - ECAM_BASE;
+            let (slot, func, reg) = ((off >> 15) as usize, (off >> 12) & 7, (off & 0xfff) as usize);
+            return if func == 0 && slot < slots.len() { Target::Config(slot, reg) } else { Target::None };
+        }
+        for (i, slot) in slots.iter().enumerate() {
+            if let Some(bar) = slot.bar() {
+                if (bar..bar + BAR_SIZE).contains(&addr) {
+                    return Target::Bar(i, addr - bar);
+                }
+            }
+        }
+        Target::None
+    }
+
+    fn read(&self, addr: u64, width: usize) -> u32 {
+        let mut slots = self.slots.borrow_mut();
+        match self.decode(&slots, addr) {
+            Target::Config(slot, reg) => load(&slots[slot].config, reg, width),
+            Target::Bar(slot, off) => {
+                let s = &mut slots[slot];
+                match off {
+                    o if o < ISR => s.read_common(o - COMMON, width),
+                    o if o < DEVICE_CFG => std::mem::take(&mut s.isr) as u32,
+                    o if o < NOTIFY => load(&s.device.config(), (o - DEVICE_CFG) as usize, width),
+                    _ => 0,
+                }
+            }
+            Target::None => u32::MAX >> (32 - 8 * width),
+        }
+    }
+
+    fn write(&self, addr: u64, width: usize, val: u32) {
+        let mut slots = self.slots.borrow_mut();
+        match self.decode(&slots, addr) {
+            Target::Config(slot, reg) => slots[slot].write_config_space(reg, width, val),
+            Target::Bar(slot, off) => {
+                let s = &mut slots[slot];
+                match off {
+                    o if o < ISR => s.write_common(o - COMMON, val),
+                    o if o < DEVICE_CFG => {}
+                    o if o < NOTIFY => s.device.write_config(o - DEVICE_CFG, &val.to_le_bytes()[..width]),
+                    o => s.run(self.mem, ((o - NOTIFY) / NOTIFY_MULT as u64) as u16),
+                }
+            }
+            Target::None => {}
+        }
+    }
+}
+
+impl Mmio for SimPci<'_> {
+    fn read8(&self, addr: u64) -> u8 {
+        self.read(addr, 1) as u8
+    }
+
+    fn read16(&self, addr: u64) -> u16 {
+        self.read(addr, 2) as u16
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        self.read(addr, 4)
+    }
+
+    fn write8(&self, addr: u64, val: u8) {
+        self.write(addr, 1, val as u32);
+    }
+
+    fn write16(&self, addr: u64, val: u16) {
+        self.write(addr, 2, val as u32);
+    }
+
+    fn write32(&self, addr: u64, val: u32) {
+        self.write(addr, 4, val);
+    }
+}
@@ -0,0 +1,194 @@
+//! Device end of a split virtqueue (virtio 1.x, section 2.7)
+//!
+//! Reads the rings the driver set up in guest memory and returns chains
+//! through the used ring. Everything the driver wrote is validated, so a
+//! broken chain turns into an `Error` (and the device into NEEDS_RESET)
+//! rather than a wild access.
+
+use std::sync::atomic::{fence, Ordering};
+
+use driver_core::virtqueue::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+
+use crate::{Error, GuestMemory};
+
+/// Queue registers as the driver programmed them, plus the device's indices
+#[derive(Clone, Debug)]
+pub struct DeviceQueue {
+    pub max_size: u16,
+    pub size: u16,
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    pub enabled: bool,
+    last_avail: u16,
+    used_idx: u16,
+}
+
+impl DeviceQueue {
+    pub fn new(max_size: u16) -> Self {
+        DeviceQueue { max_size, size: max_size, desc: 0, avail: 0, used: 0, enabled: false, last_avail: 0, used_idx: 0 }
+    }
+
+    /// Back to the state after a device reset
+    pub fn reset(&mut self) {
+        *self = DeviceQueue::new(self.max_size);
+    }
+
+    /// Take the next chain the driver made available, if any
+    pub fn pop(&mut self, mem: &GuestMemory) -> Result<Option<Chain>, Error> {
+        if !self.enabled || self.size == 0 {
+            return Ok(None);
+        }
+        fence(Ordering::SeqCst);
+        let avail_idx = mem.read16(self.avail + 2)?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        let slot = (self.last_avail % self.size) as u64;
+        let head = mem.read16(self.avail + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut idx = head;
+        for _ in 0..self.size {
+            if idx >= self.size {
+                return Err(Error::BadDescriptor(idx));
+            }
+            let desc = self.desc + idx as u64 * 16;
+            let addr = mem.read64(desc)?;
+            let len = mem.read32(desc + 8)?;
+            let flags = mem.read16(desc + 12)?;
+            if flags & VRING_DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else if chain.writable.is_empty() {
+                chain.readable.push((addr, len));
+            } else {
+                return Err(Error::ReadableAfterWritable);
+            }
+            if flags & VRING_DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            idx = mem.read16(desc + 14)?;
+        }
+        Err(Error::ChainLoop)
+    }
+
+    /// Return a chain to the driver with `len` bytes written into it
+    pub fn push_used(&mut self, mem: &GuestMemory, head: u16, len: u32) -> Result<(), Error> {
+        let slot = (self.used_idx % self.size) as u64;
+        let elem = self.used + 4 + slot * 8;
+        mem.write32(elem, head as u32)?;
+        mem.write32(elem + 4, len)?;
+        fence(Ordering::SeqCst);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        mem.write16(self.used + 2, self.used_idx)
+    }
+}
+
+/// One request: device-readable buffers followed by device-writable ones
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chain {
+    pub head: u16,
+    pub readable: Vec<(u64, u32)>,
+    pub writable: Vec<(u64, u32)>,
+}
+
+impl Chain {
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// Everything the driver gave the device to read, concatenated
+    pub fn read(&self, mem: &GuestMemory) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0; self.readable_len()];
+        let mut pos = 0;
+        for &(addr, len) in &self.readable {
+            mem.read(addr, &mut out[pos..pos + len as usize])?;
+            pos += len as usize;
+        }
+        Ok(out)
+    }
+
+    /// Scatter `data` into the writable buffers starting `offset` bytes in
+    /// Returns how much fit.
+    pub fn write(&self, mem: &GuestMemory, offset: usize, data: &[u8]) -> Result<usize, Error> {
+        let mut skip = offset;
+        let mut done = 0;
+        for &(addr, len) in &self.writable {
+            let len = len as usize;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let n = (len - skip).min(data.len() - done);
+            mem.write(addr + skip as u64, &data[done..done + n])?;
+            done += n;
+            skip = 0;
+            if done == data.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use driver_core::virtqueue::{Buffer, Virtqueue};
+
+    use super::*;
+
+    fn device_queue<const N: usize>(q: &Virtqueue<N>) -> DeviceQueue {
+        let (desc, avail, used) = q.addrs();
+        DeviceQueue { desc, avail, used, enabled: true, ..DeviceQueue::new(N as u16) }
+    }
+
+    #[test]
+    fn round_trips_a_chain_with_the_driver_queue() {
+        let mem = GuestMemory::new(0x4000);
+        let q = mem.alloc_value(Virtqueue::<4>::new());
+        let header = mem.alloc_bytes(4);
+        header.copy_from_slice(b"ping");
+        let reply = mem.alloc_bytes(3);
+        let status = mem.alloc_bytes(2);
+        let head = q.push(&[Buffer::readable(&*header), Buffer::writable(&mut *reply), Buffer::writable(&mut *status)]).unwrap();
+
+        let mut dq = device_queue(q);
+        let chain = dq.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head, head);
+        assert_eq!(chain.read(&mem).unwrap(), b"ping");
+        assert_eq!(chain.writable_len(), 5);
+        assert_eq!(chain.write(&mem, 1, b"ongX!!").unwrap(), 4);
+        assert_eq!(dq.pop(&mem).unwrap(), None);
+
+        dq.push_used(&mem, chain.head, 5).unwrap();
+        assert_eq!(&reply[1..], b"on");
+        assert_eq!(status, b"gX");
+        assert_eq!(q.pop_used().map(|u| (u.id, u.len)), Some((head as u32, 5)));
+        assert!(q.is_idle());
+    }
+
+    #[test]
+    fn rejects_malformed_chains() {
+        let mem = GuestMemory::new(0x4000);
+        let q = mem.alloc_value(Virtqueue::<4>::new());
+        let buf = mem.alloc_bytes(8);
+        q.push(&[Buffer::writable(&mut *buf), Buffer::readable(&*buf)]).unwrap();
+        let mut dq = device_queue(q);
+        assert_eq!(dq.pop(&mem), Err(Error::ReadableAfterWritable));
+
+        // Point the next chain's descriptor outside guest memory
+        q.push(&[Buffer { addr: 0x10, len: 4, device_writes: false }]).unwrap();
+        let chain = dq.pop(&mem).unwrap().unwrap();
+        assert!(matches!(chain.read(&mem), Err(Error::OutOfBounds { .. })));
+
+        // A disabled queue has nothing to offer
+        dq.enabled = false;
+        q.push(&[Buffer::readable(&*buf)]).unwrap();
+        assert_eq!(dq.pop(&mem), Ok(None));
+    }
+}
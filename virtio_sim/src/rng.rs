@@ -0,0 +1,49 @@
+//! virtio-rng with a seeded generator, so tests are reproducible
+
+use crate::{Device, Error, Queues};
+
+pub struct RngDevice {
+    state: u64,
+    served: usize,
+}
+
+impl RngDevice {
+    pub fn new(seed: u64) -> Self {
+        RngDevice { state: seed | 1, served: 0 }
+    }
+
+    /// Bytes handed to the driver so far
+    pub fn served(&self) -> usize {
+        self.served
+    }
+
+    // xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Device for RngDevice {
+    fn device_type(&self) -> u16 {
+        4
+    }
+
+    fn num_queues(&self) -> u16 {
+        1
+    }
+
+    fn notify(&mut self, _queue: u16, queues: &mut Queues) -> Result<(), Error> {
+        while let Some(chain) = queues.pop(0)? {
+            // A failing source returns the buffer empty
+            let len = if queues.inject_failure() { 0 } else { chain.writable_len() };
+            let bytes: Vec<u8> = (0..len.div_ceil(8)).flat_map(|_| self.next().to_le_bytes()).take(len).collect();
+            let written = chain.write(queues.mem(), 0, &bytes)?;
+            self.served += written;
+            queues.complete(0, &chain, written as u32)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,598 @@
+//! driver_core's driver code against the simulated devices, the way the
+//! kernel brings them up: scan the ECAM, size and place BARs, probe the
+//! virtio capabilities, negotiate, set up queues and exchange requests.
+
+use std::cell::RefCell;
+use std::io::{Cursor, Write};
+use std::rc::Rc;
+
+use driver_core::blk::{
+    self, Config, Data, Limits, RangeLimits, Topology, RangeSegment, ReqHeader, Requests, ID_BYTES, SECTOR_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK,
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+    VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+};
+use driver_core::gpu::{
+    self, Command, CtrlHdr, Rect, RespDisplayInfo, ResourceAttachBacking, ResourceCreate2d, ResourceFlush, SetScanout,
+    TransferToHost2d, VIRTIO_GPU_CMD_GET_DISPLAY_INFO, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, VIRTIO_GPU_RESP_ERR_UNSPEC,
+    VIRTIO_GPU_RESP_OK_NODATA,
+};
+use driver_core::mmio::Mmio;
+use driver_core::net::{self, PACKET_BUF_SIZE};
+use driver_core::pci::{MmioAllocator, PciDevice, VirtioModern, VIRTIO_VENDOR_ID};
+use driver_core::virtqueue::{Buffer, UsedElem, Virtqueue};
+use virtio_sim::blk::BlkDevice;
+use virtio_sim::console::ConsoleDevice;
+use virtio_sim::gpu::GpuDevice;
+use virtio_sim::net::NetDevice;
+use virtio_sim::pci::MMIO_WINDOW;
+use virtio_sim::rng::RngDevice;
+use virtio_sim::{Error, GuestMemory, SimPci};
+
+const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Size and place the device's BARs, then find its virtio capabilities
+fn probe(sim: &SimPci, alloc: &mut MmioAllocator, slot: u8) -> VirtioModern {
+    let mut dev = PciDevice::new(sim, sim.ecam_base(), 0, slot, 0).unwrap();
+    for i in 0..6 {
+        let (size, _) = dev.get_bar_size(sim, i);
+        if size > 0 {
+            assert!(dev.program_bar(sim, i, alloc.allocate(size).unwrap()));
+        }
+    }
+    dev.read_bars(sim);
+    VirtioModern::probe(sim, &dev).unwrap()
+}
+
+/// Negotiate, hand over the queues and go; returns their notify addresses
+fn start<const N: usize>(sim: &SimPci, modern: &VirtioModern, queues: &[&Virtqueue<N>]) -> Vec<u64> {
+    assert!(modern.negotiate(sim));
+    let notify = queues.iter().enumerate().map(|(i, q)| modern.setup_queue(sim, i as u16, q).unwrap()).collect();
+    modern.driver_ok(sim);
+    notify
+}
+
+/// Push a chain, notify and take the completion
+fn submit<const N: usize>(sim: &SimPci, modern: &VirtioModern, notify: u64, index: u16, q: &mut Virtqueue<N>, bufs: &[Buffer]) -> Option<UsedElem> {
+    q.push(bufs).unwrap();
+    modern.notify(sim, notify, index);
+    q.pop_used()
+}
+
+struct Disk<'m> {
+    sim: SimPci<'m>,
+    modern: VirtioModern,
+    notify: u64,
+    queue: &'m mut Virtqueue<8>,
+    header: &'m mut ReqHeader,
+    data: &'m mut [u8],
+    status: &'m mut u8,
+}
+
+impl<'m> Disk<'m> {
+    fn new<S: std::io::Read + std::io::Write + std::io::Seek + 'static>(mem: &'m GuestMemory, storage: S) -> Self {
+        let mut sim = SimPci::new(mem);
+        let slot = sim.add(BlkDevice::new(storage).unwrap());
+        let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+        let queue = mem.alloc_value(Virtqueue::new());
+        let notify = start(&sim, &modern, &[queue])[0];
+        Disk {
+            sim,
+            modern,
+            notify,
+            queue,
+            header: mem.alloc_value(ReqHeader::default()),
+            data: mem.alloc_bytes(2 * SECTOR_SIZE),
+            status: mem.alloc_value(0xff),
+        }
+    }
+
+    fn request(&mut self, req_type: u32, sector: u64, len: usize) -> Option<UsedElem> {
+        *self.header = ReqHeader::new(req_type, sector);
+        *self.status = 0xff;
+        // Guest memory is mutable, so any request's data can go as In
+        let chain = blk::request_chain(self.header, Data::In(&mut self.data[..len]), self.status);
+        submit(&self.sim, &self.modern, self.notify, 0, self.queue, &chain)
+    }
+}
+
+#[test]
+fn scan_finds_every_device_and_negotiates() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    sim.add(NetDevice::new(MAC));
+    sim.add(BlkDevice::new(Cursor::new(vec![0; 4096])).unwrap());
+    sim.add(ConsoleDevice::new());
+    sim.add(RngDevice::new(1));
+    sim.add(GpuDevice::new(640, 480));
+
+    let found: Vec<_> = (0..32).filter_map(|slot| PciDevice::new(&sim, sim.ecam_base(), 0, slot, 0)).collect();
+    assert_eq!(found.iter().map(|d| d.device_id).collect::<Vec<_>>(), [0x1041, 0x1042, 0x1043, 0x1044, 0x1050]);
+    assert!(found.iter().all(|d| d.vendor_id == VIRTIO_VENDOR_ID));
+
+    let mut alloc = MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1);
+    for slot in 0..5 {
+        let modern = probe(&sim, &mut alloc, slot);
+        assert_eq!(modern.notify_mult, 4);
+        assert!(modern.init_device(&sim));
+        assert_eq!(sim.status(slot), 0x0F);
+    }
+    // Only BAR4 (64-bit, 16 KiB) is implemented
+    let dev = PciDevice::new(&sim, sim.ecam_base(), 0, 0, 0).unwrap();
+    assert_eq!(dev.get_bar_size(&sim, 0), (0, false));
+    assert_eq!(dev.get_bar_size(&sim, 4), (0x4000, true));
+}
+
+#[test]
+fn blk_reads_and_writes_a_disk_image() {
+    let path = std::env::temp_dir().join(format!("virtio_sim_blk_{}.img", std::process::id()));
+    let image: Vec<u8> = (0..8 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+    std::fs::write(&path, &image).unwrap();
+
+    let mem = GuestMemory::new(1 << 16);
+    let mut disk = Disk::new(&mem, std::fs::File::options().read(true).write(true).open(&path).unwrap());
+    assert_eq!(disk.sim.read64(disk.modern.device + blk::CONFIG_CAPACITY), 8);
+
+    let used = disk.request(VIRTIO_BLK_T_IN, 3, 2 * SECTOR_SIZE).unwrap();
+    assert_eq!((*disk.status, used.len), (VIRTIO_BLK_S_OK, 2 * SECTOR_SIZE as u32 + 1));
+    assert!(disk.data[..SECTOR_SIZE].iter().all(|&b| b == 3));
+    assert!(disk.data[SECTOR_SIZE..].iter().all(|&b| b == 4));
+
+    disk.data[..SECTOR_SIZE].fill(0xab);
+    disk.request(VIRTIO_BLK_T_OUT, 7, SECTOR_SIZE).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_OK);
+
+    // Past the end of the disk
+    disk.request(VIRTIO_BLK_T_IN, 7, 2 * SECTOR_SIZE).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_IOERR);
+    assert!(disk.queue.is_idle());
+
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(written[7 * SECTOR_SIZE..].iter().all(|&b| b == 0xab));
+    assert_eq!(written[..7 * SECTOR_SIZE], image[..7 * SECTOR_SIZE]);
+}
+
+#[test]
+fn blk_scatter_gather_within_device_limits() {
+    let image: Vec<u8> = (0..16 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE * 3 + i % 7) as u8).collect();
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let slot = sim.add(BlkDevice::new(Cursor::new(image.clone())).unwrap().with_limits(1024, 2));
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+
+    let wanted = VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX;
+    assert_eq!(modern.negotiate_features(&sim, wanted), Some(wanted | 1 << 32));
+    let queue = mem.alloc_value(Virtqueue::<8>::new());
+    let notify = modern.setup_queue(&sim, 0, queue).unwrap();
+    modern.driver_ok(&sim);
+    let size_max = sim.read32(modern.device + blk::CONFIG_SIZE_MAX);
+    let seg_max = sim.read32(modern.device + blk::CONFIG_SEG_MAX);
+    let limits = Limits::new(queue.size(), Some(size_max), Some(seg_max));
+    assert_eq!(limits.max_request(), 2048);
+
+    // The whole disk in requests of two 1 KiB segments each
+    let header = mem.alloc_value(ReqHeader::default());
+    let status = mem.alloc_value(0xffu8);
+    let data = mem.alloc_bytes(image.len());
+    let mut chain = [Buffer { addr: 0, len: 0, device_writes: false }; 8];
+    let mut done = 0;
+    while done < data.len() {
+        *header = ReqHeader::new(VIRTIO_BLK_T_IN, (done / SECTOR_SIZE) as u64);
+        *status = 0xff;
+        let (n, len) = blk::request_chain_sg(header, Data::In(&mut data[done..]), status, &limits, &mut chain);
+        assert_eq!((n, len), (4, 2048));
+        submit(&sim, &modern, notify, 0, queue, &chain[..n]).unwrap();
+        assert_eq!(*status, VIRTIO_BLK_S_OK);
+        done += len;
+    }
+    assert_eq!(*data, *image);
+    assert_eq!(sim.with_device(slot, |dev: &mut BlkDevice<Cursor<Vec<u8>>>| dev.requests()), 4);
+
+    // A request over the limits is refused
+    *header = ReqHeader::new(VIRTIO_BLK_T_IN, 0);
+    let (n, _) = blk::request_chain_sg(header, Data::In(data), status, &Limits::new(8, Some(2048), None), &mut chain);
+    submit(&sim, &modern, notify, 0, queue, &chain[..n]).unwrap();
+    assert_eq!(*status, VIRTIO_BLK_S_IOERR);
+}
+
+#[test]
+fn blk_config_and_4k_logical_blocks() {
+    let image: Vec<u8> = (0..16 * 4096).map(|i| (i / 4096) as u8).collect();
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let slot = sim.add(BlkDevice::new(Cursor::new(image)).unwrap().with_block_size(4096));
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+    let features = modern.negotiate_features(&sim, VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_TOPOLOGY).unwrap();
+    let queue = mem.alloc_value(Virtqueue::<8>::new());
+    let notify = modern.setup_queue(&sim, 0, queue).unwrap();
+    modern.driver_ok(&sim);
+
+    let config = Config::read(&sim, modern.device, features);
+    assert_eq!((config.capacity, config.blk_size, config.blocks(), config.num_queues), (128, 4096, 16, 1));
+    assert_eq!(config.topology, Some(Topology { physical_block_exp: 0, alignment_offset: 0, min_io_size: 1, opt_io_size: 16 }));
+    // Offered but not negotiated
+    assert_eq!(config.discard, None);
+    let limits = Limits::for_config(queue.size(), &config).unwrap();
+
+    // Block 3 is sectors 24-31
+    let header = mem.alloc_value(ReqHeader::new(VIRTIO_BLK_T_IN, 3 * config.sectors_per_block()));
+    let status = mem.alloc_value(0xffu8);
+    let data = mem.alloc_bytes(2 * 4096);
+    let mut chain = [Buffer { addr: 0, len: 0, device_writes: false }; 8];
+    let (n, len) = blk::request_chain_sg(header, Data::In(data), status, &limits, &mut chain);
+    assert_eq!(len, 2 * 4096);
+    submit(&sim, &modern, notify, 0, queue, &chain[..n]).unwrap();
+    assert_eq!(*status, VIRTIO_BLK_S_OK);
+    assert!(data[..4096].iter().all(|&b| b == 3) && data[4096..].iter().all(|&b| b == 4));
+
+    // Part of a block, or not on a block boundary
+    submit(&sim, &modern, notify, 0, queue, &blk::request_chain(header, Data::In(&mut data[..SECTOR_SIZE]), status)).unwrap();
+    assert_eq!(*status, VIRTIO_BLK_S_IOERR);
+    *header = ReqHeader::new(VIRTIO_BLK_T_IN, 1);
+    submit(&sim, &modern, notify, 0, queue, &blk::request_chain(header, Data::In(&mut data[..4096]), status)).unwrap();
+    assert_eq!(*status, VIRTIO_BLK_S_IOERR);
+}
+
+#[test]
+fn blk_multiqueue_requests_complete_out_of_order() {
+    let image: Vec<u8> = (0..8 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let slot = sim.add(BlkDevice::new(Cursor::new(image)).unwrap().with_queues(2));
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+    let features = modern.negotiate_features(&sim, VIRTIO_BLK_F_MQ).unwrap();
+    let config = Config::read(&sim, modern.device, features);
+    assert_eq!(config.num_queues, 2);
+    let queues = [mem.alloc_value(Virtqueue::<16>::new()), mem.alloc_value(Virtqueue::<16>::new())];
+    let notify: Vec<u64> = queues.iter().enumerate().map(|(i, q)| modern.setup_queue(&sim, i as u16, &**q).unwrap()).collect();
+    modern.driver_ok(&sim);
+    let limits = Limits::for_config(16, &config).unwrap();
+
+    // Three reads outstanding on queue 1 at once, each with its own tag
+    let reqs = mem.alloc_value(Requests::<4>::new());
+    let data = mem.alloc_bytes(3 * SECTOR_SIZE);
+    sim.hold_completions(slot, true);
+    let mut tags = Vec::new();
+    let mut heads = Vec::new();
+    for (i, buf) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+        let tag = reqs.claim(VIRTIO_BLK_T_IN, 2 * i as u64 + 1).unwrap();
+        let (header, status) = reqs.parts(tag);
+        let mut chain = [Buffer { addr: 0, len: 0, device_writes: false }; 4];
+        let (n, _) = blk::request_chain_sg(header, Data::In(buf), status, &limits, &mut chain);
+        let head = queues[1].push(&chain[..n]).unwrap();
+        reqs.submitted(tag, head);
+        modern.notify(&sim, notify[1], 1);
+        tags.push(tag);
+        heads.push(head);
+    }
+    assert!(!queues[1].has_used());
+    assert_eq!(reqs.outstanding(), 3);
+
+    // The device finishes them newest first; the tags sort that out
+    assert_eq!(sim.release_reversed(slot), 3);
+    let mut order = Vec::new();
+    while let Some(used) = queues[1].pop_used() {
+        order.push(reqs.complete(used.id as u16).unwrap());
+    }
+    assert_eq!(order, [tags[2], tags[1], tags[0]]);
+    for &tag in &tags {
+        assert_eq!(reqs.take(tag), Some(Ok(())));
+    }
+    for (i, sector) in data.chunks(SECTOR_SIZE).enumerate() {
+        assert!(sector.iter().all(|&b| b == 2 * i as u8 + 1));
+    }
+    assert!(queues[1].is_idle() && reqs.outstanding() == 0);
+
+    // Queue 0 is served on its own
+    sim.hold_completions(slot, false);
+    let tag = reqs.claim(VIRTIO_BLK_T_IN, 7).unwrap();
+    let (header, status) = reqs.parts(tag);
+    let chain = blk::request_chain(header, Data::In(&mut data[..SECTOR_SIZE]), status);
+    reqs.submitted(tag, queues[0].push(&chain).unwrap());
+    modern.notify(&sim, notify[0], 0);
+    let used = queues[0].pop_used().unwrap();
+    assert_eq!(reqs.complete(used.id as u16), Some(tag));
+    assert_eq!(reqs.take(tag), Some(Ok(())));
+    assert!(data[..SECTOR_SIZE].iter().all(|&b| b == 7));
+}
+
+#[test]
+fn blk_flush_get_id_and_zeroing_ranges() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut disk = Disk::new(&mem, Cursor::new(vec![0x5a; 512 * SECTOR_SIZE]));
+    let config = |off| disk.sim.read32(disk.modern.device + off);
+    let limits = RangeLimits::new(
+        config(blk::CONFIG_MAX_WRITE_ZEROES_SECTORS),
+        config(blk::CONFIG_MAX_WRITE_ZEROES_SEG),
+        1,
+    );
+    assert_eq!(limits, RangeLimits::new(64, 4, 1));
+    assert_eq!(disk.sim.read8(disk.modern.device + blk::CONFIG_WRITE_ZEROES_MAY_UNMAP), 1);
+
+    disk.request(VIRTIO_BLK_T_FLUSH, 0, 0).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_OK);
+    let used = disk.request(VIRTIO_BLK_T_GET_ID, 0, ID_BYTES).unwrap();
+    assert_eq!((*disk.status, used.len), (VIRTIO_BLK_S_OK, ID_BYTES as u32 + 1));
+    assert_eq!(&disk.data[..8], b"SIMBLK01");
+
+    // 300 sectors from 1 need two requests of up to four 64-sector segments
+    let mut segments = [RangeSegment::default(); 4];
+    let (mut sector, mut left, mut requests) = (1, 300, 0);
+    while left > 0 {
+        let (n, done) = blk::range_segments(sector, left, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP, &limits, &mut segments);
+        let bytes = unsafe { std::slice::from_raw_parts(segments.as_ptr() as *const u8, n * 16) };
+        disk.data[..bytes.len()].copy_from_slice(bytes);
+        disk.request(VIRTIO_BLK_T_WRITE_ZEROES, 0, bytes.len()).unwrap();
+        assert_eq!(*disk.status, VIRTIO_BLK_S_OK);
+        sector += done;
+        left -= done;
+        requests += 1;
+    }
+    assert_eq!(requests, 2);
+    disk.sim.with_device(0, |dev: &mut BlkDevice<Cursor<Vec<u8>>>| {
+        let image = dev.storage().get_ref();
+        assert!(image[..SECTOR_SIZE].iter().all(|&b| b == 0x5a));
+        assert!(image[SECTOR_SIZE..301 * SECTOR_SIZE].iter().all(|&b| b == 0));
+        assert!(image[301 * SECTOR_SIZE..].iter().all(|&b| b == 0x5a));
+    });
+
+    // Past the end of the disk
+    let segment = RangeSegment { sector: 510, num_sectors: 8, flags: 0 };
+    let bytes = unsafe { std::slice::from_raw_parts(&segment as *const RangeSegment as *const u8, 16) };
+    disk.data[..16].copy_from_slice(bytes);
+    disk.request(VIRTIO_BLK_T_DISCARD, 0, 16).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_IOERR);
+}
+
+#[test]
+fn blk_read_only_disk_refuses_writes() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let slot = sim.add(BlkDevice::new(Cursor::new(vec![7; 4 * SECTOR_SIZE])).unwrap().read_only());
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+    let wanted = VIRTIO_BLK_F_RO | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES;
+    assert_eq!(modern.negotiate_features(&sim, wanted), Some(wanted | 1 << 32));
+    let queue = mem.alloc_value(Virtqueue::<8>::new());
+    let notify = modern.setup_queue(&sim, 0, queue).unwrap();
+    modern.driver_ok(&sim);
+
+    let header = mem.alloc_value(ReqHeader::new(VIRTIO_BLK_T_OUT, 0));
+    let data = mem.alloc_bytes(SECTOR_SIZE);
+    let status = mem.alloc_value(0xffu8);
+    submit(&sim, &modern, notify, 0, queue, &blk::request_chain(header, Data::Out(data), status)).unwrap();
+    assert_eq!(*status, VIRTIO_BLK_S_IOERR);
+
+    *header = ReqHeader::new(VIRTIO_BLK_T_IN, 0);
+    submit(&sim, &modern, notify, 0, queue, &blk::request_chain(header, Data::In(data), status)).unwrap();
+    assert_eq!(*status, VIRTIO_BLK_S_OK);
+    assert!(data.iter().all(|&b| b == 7));
+}
+
+#[test]
+fn blk_injected_errors_and_delayed_completions() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut disk = Disk::new(&mem, Cursor::new(vec![0x5a; 4 * SECTOR_SIZE]));
+
+    disk.sim.fail_next(0, 1);
+    disk.request(VIRTIO_BLK_T_IN, 0, SECTOR_SIZE).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_IOERR);
+    disk.request(VIRTIO_BLK_T_IN, 0, SECTOR_SIZE).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_OK);
+
+    // Held completions only show up on release, with an interrupt
+    assert_eq!(disk.modern.read_isr(&disk.sim), 1);
+    disk.sim.hold_completions(0, true);
+    assert_eq!(disk.request(VIRTIO_BLK_T_IN, 1, SECTOR_SIZE), None);
+    assert!(!disk.queue.has_used());
+    assert_eq!(disk.modern.read_isr(&disk.sim), 0);
+    assert_eq!(disk.sim.release(0), 1);
+    assert_eq!(disk.modern.read_isr(&disk.sim), 1);
+    assert_eq!(disk.queue.pop_used().map(|u| u.len), Some(SECTOR_SIZE as u32 + 1));
+    assert_eq!(*disk.status, VIRTIO_BLK_S_OK);
+}
+
+#[test]
+fn device_reset_needs_driver_reinit() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut disk = Disk::new(&mem, Cursor::new(vec![0; 4 * SECTOR_SIZE]));
+
+    disk.sim.hold_completions(0, true);
+    assert_eq!(disk.request(VIRTIO_BLK_T_IN, 0, SECTOR_SIZE), None);
+    disk.sim.trigger_reset(0);
+    assert_eq!(disk.modern.status(&disk.sim) & 0x40, 0x40);
+    assert_eq!(disk.modern.read_isr(&disk.sim), 2);
+    // The in-flight request was dropped and the device ignores new ones
+    assert_eq!(disk.sim.release(0), 0);
+    disk.sim.hold_completions(0, false);
+    assert_eq!(disk.request(VIRTIO_BLK_T_IN, 0, SECTOR_SIZE), None);
+
+    // The driver starts over with a fresh queue
+    let queue = mem.alloc_value(Virtqueue::new());
+    disk.notify = start(&disk.sim, &disk.modern, &[queue])[0];
+    disk.queue = queue;
+    assert_eq!(disk.modern.status(&disk.sim), 0x0F);
+    disk.request(VIRTIO_BLK_T_IN, 0, SECTOR_SIZE).unwrap();
+    assert_eq!(*disk.status, VIRTIO_BLK_S_OK);
+}
+
+#[test]
+fn wild_descriptor_puts_device_in_needs_reset() {
+    let mem = GuestMemory::new(1 << 16);
+    let disk = Disk::new(&mem, Cursor::new(vec![0; SECTOR_SIZE]));
+    let header = ReqHeader::new(VIRTIO_BLK_T_IN, 0);
+    let bufs = [Buffer::readable(&header), Buffer { addr: 0x1000, len: 512, device_writes: true }, Buffer::writable(&mut *disk.status)];
+
+    assert_eq!(submit(&disk.sim, &disk.modern, disk.notify, 0, disk.queue, &bufs), None);
+    assert!(matches!(disk.sim.last_error(0), Some(Error::OutOfBounds { .. })));
+    assert_eq!(disk.modern.status(&disk.sim) & 0x40, 0x40);
+}
+
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn net_loopback_with_pcap_capture() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let capture = Capture::default();
+    let slot = sim.add(NetDevice::loopback(MAC).capture_to(capture.clone()).unwrap());
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+    let rx = mem.alloc_value(Virtqueue::<8>::new());
+    let tx = mem.alloc_value(Virtqueue::<8>::new());
+    let notify = start(&sim, &modern, &[rx, tx]);
+
+    let mac: Vec<u8> = (0..6).map(|i| sim.read8(modern.device + net::CONFIG_MAC + i)).collect();
+    assert_eq!(mac, MAC);
+
+    let rx_buf = mem.alloc_bytes(PACKET_BUF_SIZE);
+    assert_eq!(submit(&sim, &modern, notify[0], 0, rx, &[Buffer::writable(&mut *rx_buf)]), None);
+
+    let frame = net::arp_request(MAC, [10, 0, 0, 2], [10, 0, 0, 1]);
+    let tx_buf = mem.alloc_bytes(PACKET_BUF_SIZE);
+    let len = net::encode_tx(&frame, tx_buf).unwrap();
+    let bufs = [Buffer { len: len as u32, ..Buffer::readable(&*tx_buf) }];
+    assert!(submit(&sim, &modern, notify[1], 1, tx, &bufs).is_some());
+
+    let used = rx.pop_used().unwrap();
+    assert_eq!(net::rx_frame(rx_buf, used.len as usize), frame);
+    sim.with_device(slot, |dev: &mut NetDevice| assert_eq!(dev.sent(), [frame.to_vec()]));
+
+    let pcap = capture.0.borrow();
+    assert_eq!(pcap.len(), 24 + 16 + frame.len());
+    assert_eq!(pcap[..4], 0xa1b2_c3d4u32.to_le_bytes());
+    assert_eq!(pcap[24 + 16..], frame);
+}
+
+#[test]
+fn net_injected_frames_wait_for_rx_buffers() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let slot = sim.add(NetDevice::new(MAC));
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+    let rx = mem.alloc_value(Virtqueue::<8>::new());
+    let tx = mem.alloc_value(Virtqueue::<8>::new());
+    let notify = start(&sim, &modern, &[rx, tx]);
+
+    sim.with_device(slot, |dev: &mut NetDevice| dev.inject(b"hello, wire"));
+    sim.kick(slot, 0);
+    assert!(!rx.has_used());
+
+    let rx_buf = mem.alloc_bytes(PACKET_BUF_SIZE);
+    let used = submit(&sim, &modern, notify[0], 0, rx, &[Buffer::writable(&mut *rx_buf)]).unwrap();
+    assert_eq!(net::rx_frame(rx_buf, used.len as usize), b"hello, wire");
+    assert_eq!(sim.with_device(slot, |dev: &mut NetDevice| dev.rx_pending()), 0);
+}
+
+#[test]
+fn rng_and_console_exchange_data() {
+    let mem = GuestMemory::new(1 << 16);
+    let mut sim = SimPci::new(&mem);
+    let mut alloc = MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1);
+    let rng_slot = sim.add(RngDevice::new(42));
+    let con_slot = sim.add(ConsoleDevice::new());
+
+    let rng = probe(&sim, &mut alloc, rng_slot);
+    let rq = mem.alloc_value(Virtqueue::<4>::new());
+    let rng_notify = start(&sim, &rng, &[rq])[0];
+    let a = mem.alloc_bytes(32);
+    assert_eq!(submit(&sim, &rng, rng_notify, 0, rq, &[Buffer::writable(&mut *a)]).unwrap().len, 32);
+    let b = mem.alloc_bytes(32);
+    submit(&sim, &rng, rng_notify, 0, rq, &[Buffer::writable(&mut *b)]).unwrap();
+    assert_ne!(a, b);
+    sim.fail_next(rng_slot, 1);
+    assert_eq!(submit(&sim, &rng, rng_notify, 0, rq, &[Buffer::writable(&mut *a)]).unwrap().len, 0);
+    assert_eq!(sim.with_device(rng_slot, |d: &mut RngDevice| d.served()), 64);
+
+    let con = probe(&sim, &mut alloc, con_slot);
+    let crx = mem.alloc_value(Virtqueue::<4>::new());
+    let ctx = mem.alloc_value(Virtqueue::<4>::new());
+    let notify = start(&sim, &con, &[crx, ctx]);
+    let msg = mem.alloc_bytes(6);
+    msg.copy_from_slice(b"hello\n");
+    submit(&sim, &con, notify[1], 1, ctx, &[Buffer::readable(&*msg)]).unwrap();
+    assert_eq!(sim.with_device(con_slot, |d: &mut ConsoleDevice| d.take_output()), b"hello\n");
+
+    let input = mem.alloc_bytes(4);
+    assert_eq!(submit(&sim, &con, notify[0], 0, crx, &[Buffer::writable(&mut *input)]), None);
+    sim.with_device(con_slot, |d: &mut ConsoleDevice| d.push_input(b"ls\r"));
+    sim.kick(con_slot, 0);
+    assert_eq!(crx.pop_used().unwrap().len, 3);
+    assert_eq!(&input[..3], b"ls\r");
+}
+
+struct Display<'m> {
+    sim: SimPci<'m>,
+    modern: VirtioModern,
+    notify: u64,
+    queue: &'m mut Virtqueue<16>,
+    cmd: &'m mut [u8],
+    resp: &'m mut [u8],
+}
+
+impl Display<'_> {
+    fn send(&mut self, cmd: &impl Command) -> u32 {
+        let bytes = cmd.as_bytes();
+        self.cmd[..bytes.len()].copy_from_slice(bytes);
+        let bufs = [Buffer::readable(&self.cmd[..bytes.len()]), Buffer::writable(&mut *self.resp)];
+        submit(&self.sim, &self.modern, self.notify, 0, self.queue, &bufs).unwrap();
+        gpu::response_type(self.resp).unwrap()
+    }
+}
+
+#[test]
+fn gpu_renders_framebuffer_to_scanout() {
+    let mem = GuestMemory::new(1 << 21);
+    let mut sim = SimPci::new(&mem);
+    let slot = sim.add(GpuDevice::new(320, 200));
+    let modern = probe(&sim, &mut MmioAllocator::new(MMIO_WINDOW.0, MMIO_WINDOW.1), slot);
+    let queue = mem.alloc_value(Virtqueue::new());
+    let cursor = mem.alloc_value(Virtqueue::new());
+    let notify = start(&sim, &modern, &[&*queue, &*cursor])[0];
+    let mut gpu = Display { sim, modern, notify, queue, cmd: mem.alloc_bytes(64), resp: mem.alloc_bytes(size_of::<RespDisplayInfo>()) };
+
+    gpu.send(&CtrlHdr::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO));
+    let (w, h) = RespDisplayInfo::parse(gpu.resp).unwrap().preferred_mode(0).unwrap();
+    assert_eq!((w, h), (320, 200));
+
+    let fb = mem.alloc_bytes((w * h * 4) as usize);
+    let screen = Rect::sized(w, h);
+    assert_eq!(gpu.send(&ResourceCreate2d::new(1, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, w, h)), VIRTIO_GPU_RESP_OK_NODATA);
+    assert_eq!(gpu.send(&ResourceAttachBacking::new(1, fb.as_ptr() as u64, w * h * 4)), VIRTIO_GPU_RESP_OK_NODATA);
+    assert_eq!(gpu.send(&SetScanout::new(0, 1, screen)), VIRTIO_GPU_RESP_OK_NODATA);
+
+    // Blue background with one red pixel at (10, 5)
+    for px in fb.chunks_exact_mut(4) {
+        px.copy_from_slice(&0x0000_00ffu32.to_le_bytes());
+    }
+    let red = ((5 * w + 10) * 4) as usize;
+    fb[red..red + 4].copy_from_slice(&0x00ff_0000u32.to_le_bytes());
+    assert_eq!(gpu.send(&TransferToHost2d::new(1, screen, 0)), VIRTIO_GPU_RESP_OK_NODATA);
+    assert_eq!(gpu.send(&ResourceFlush::new(1, screen)), VIRTIO_GPU_RESP_OK_NODATA);
+
+    gpu.sim.with_device(slot, |dev: &mut GpuDevice| {
+        let frame = dev.frame().unwrap();
+        assert_eq!((frame.width, frame.height), (320, 200));
+        assert_eq!(frame.pixel(10, 5), 0x00ff_0000);
+        assert_eq!(frame.pixel(11, 5), 0x0000_00ff);
+        assert_eq!(dev.flushes(), 1);
+    });
+
+    // Bad requests get error responses, not a broken device
+    assert!(gpu::is_error(gpu.send(&ResourceCreate2d::new(1, VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, w, h))));
+    assert!(gpu::is_error(gpu.send(&TransferToHost2d::new(1, Rect::sized(w + 1, h), 0))));
+    assert!(gpu::is_error(gpu.send(&ResourceFlush::new(9, screen))));
+    gpu.sim.fail_next(slot, 1);
+    assert_eq!(gpu.send(&ResourceFlush::new(1, screen)), VIRTIO_GPU_RESP_ERR_UNSPEC);
+    assert_eq!(gpu.modern.status(&gpu.sim), 0x0F);
+}
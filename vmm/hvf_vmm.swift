@@ -745,6 +745,15 @@ while running && exitCount < maxExits {
             continue
 
         } else if ec == 0x16 {
+            // PSCI over HVC: x0 = function ID
+            var fid: UInt64 = 0
+            hv_vcpu_get_reg(vcpu, HV_REG_X0, &fid)
+            if fid == 0x8400_0008 || fid == 0x8400_0009 {
+                log("\n-----------------------------------")
+                log("PSCI \(fid == 0x8400_0008 ? "SYSTEM_OFF" : "SYSTEM_RESET") at PC=0x\(String(pc, radix: 16))")
+                running = false
+                continue
+            }
             log("\nHVC at PC=0x\(String(pc, radix: 16))")
             hv_vcpu_set_reg(vcpu, HV_REG_PC, pc + 4)
             continue
@@ -32,9 +32,14 @@ var vmStarted = false
 // Paths
 let kernelPath = ProcessInfo.processInfo.environment["KERNEL_PATH"]
     ?? "/Users/kevin/Desktop/uni/my_unikernel/target/aarch64-unknown-none/release/Image"
+// Optional CPIO newc archive for the kernel's initrd module
+let initrdPath = ProcessInfo.processInfo.environment["INITRD_PATH"]
 
 print("=== Unikernel Automated Test ===")
 print("Kernel: \(kernelPath)")
+if let initrd = initrdPath {
+    print("Initrd: \(initrd)")
+}
 print("Timeout: \(Int(TIMEOUT_SECONDS))s")
 print("Expected strings: \(EXPECTED_STRINGS.count)")
 print("")
@@ -53,6 +58,9 @@ config.platform = VZGenericPlatformConfiguration()
 
 // Boot loader
 let bootLoader = VZLinuxBootLoader(kernelURL: URL(fileURLWithPath: kernelPath))
+if let initrd = initrdPath {
+    bootLoader.initialRamdiskURL = URL(fileURLWithPath: initrd)
+}
 bootLoader.commandLine = "console=hvc0"
 config.bootLoader = bootLoader
 
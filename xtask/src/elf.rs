@@ -0,0 +1,321 @@
+//! Just enough ELF64 (little-endian) reading to turn the kernel into an Image
+//! and fill in its symbol table
+
+/// One PT_LOAD segment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub paddr: u64,
+    pub offset: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// One section header, named from the section header string table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub kind: u32,
+    pub flags: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+}
+
+/// One .symtab entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub info: u8,
+    /// Index of the section the symbol is defined in (0: undefined)
+    pub shndx: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SYM_SIZE: usize = 24;
+
+fn u16_at(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?))
+}
+
+/// NUL-terminated string at `off`; names that are not UTF-8 read as empty
+fn str_at(data: &[u8], off: usize) -> Option<&str> {
+    let rest = data.get(off..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    Some(std::str::from_utf8(&rest[..len]).unwrap_or(""))
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        (data.get(..6)? == b"\x7fELF\x02\x01").then_some(Elf { data })
+    }
+
+    /// ET_DYN: a (static) PIE, which relocates itself
+    pub fn is_pie(&self) -> bool {
+        u16_at(self.data, 0x10) == Some(ET_DYN)
+    }
+
+    /// Segment contents as stored in the file
+    pub fn contents(&self, s: &Segment) -> Option<&'a [u8]> {
+        self.data.get(s.offset as usize..s.offset.checked_add(s.filesz)? as usize)
+    }
+
+    /// PT_LOAD segments
+    pub fn load_segments(&self) -> Option<Vec<Segment>> {
+        let phoff = u64_at(self.data, 0x20)? as usize;
+        let phentsize = u16_at(self.data, 0x36)? as usize;
+        let phnum = u16_at(self.data, 0x38)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if u32_at(self.data, ph)? != PT_LOAD {
+                continue;
+            }
+            segments.push(Segment {
+                offset: u64_at(self.data, ph + 0x08)?,
+                paddr: u64_at(self.data, ph + 0x18)?,
+                filesz: u64_at(self.data, ph + 0x20)?,
+                memsz: u64_at(self.data, ph + 0x28)?,
+            });
+        }
+        Some(segments)
+    }
+
+    /// Section headers in index order; unnamed if the file has no section
+    /// name table
+    pub fn sections(&self) -> Option<Vec<Section<'a>>> {
+        let shoff = u64_at(self.data, 0x28)? as usize;
+        let shentsize = u16_at(self.data, 0x3a)? as usize;
+        let shnum = u16_at(self.data, 0x3c)? as usize;
+        let shstrndx = u16_at(self.data, 0x3e)? as usize;
+        let names = match shstrndx {
+            0 => None,
+            i => Some(u64_at(self.data, shoff + i * shentsize + 0x18)? as usize),
+        };
+
+        (0..shnum)
+            .map(|i| {
+                let sh = shoff + i * shentsize;
+                let name = match names {
+                    Some(names) => str_at(self.data, names + u32_at(self.data, sh)? as usize)?,
+                    None => "",
+                };
+                Some(Section {
+                    name,
+                    kind: u32_at(self.data, sh + 4)?,
+                    flags: u64_at(self.data, sh + 0x08)?,
+                    offset: u64_at(self.data, sh + 0x18)?,
+                    size: u64_at(self.data, sh + 0x20)?,
+                    link: u32_at(self.data, sh + 0x28)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Section contents as stored in the file
+    pub fn section_data(&self, s: &Section) -> Option<&'a [u8]> {
+        self.data.get(s.offset as usize..s.offset.checked_add(s.size)? as usize)
+    }
+
+    /// Entries of .symtab, the null symbol included; None if there is none
+    pub fn symbols(&self) -> Option<Vec<Symbol<'a>>> {
+        let sections = self.sections()?;
+        let symtab = sections.iter().find(|s| s.kind == SHT_SYMTAB)?;
+        let strtab = sections.get(symtab.link as usize)?;
+        let (sym_off, str_off) = (symtab.offset as usize, strtab.offset as usize);
+
+        (0..symtab.size as usize / SYM_SIZE)
+            .map(|i| {
+                let sym = sym_off + i * SYM_SIZE;
+                Some(Symbol {
+                    name: str_at(self.data, str_off + u32_at(self.data, sym)? as usize)?,
+                    info: *self.data.get(sym + 4)?,
+                    shndx: u16_at(self.data, sym + 6)?,
+                    value: u64_at(self.data, sym + 8)?,
+                    size: u64_at(self.data, sym + 16)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Value of the symbol `name` in .symtab (linker script symbols included)
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols()?.iter().find(|s| s.name == name).map(|s| s.value)
+    }
+}
+
+/// Minimal ELF files for tests
+#[cfg(test)]
+pub mod build {
+    use super::*;
+
+    /// Header, program headers for `segments`, then `payload`, then a
+    /// symbol table holding `symbols`
+    pub fn elf(segments: &[Segment], payload: &[u8], symbols: &[(&str, u64)]) -> Vec<u8> {
+        let mut elf = vec![0; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        // ET_EXEC
+        elf[0x10] = 2;
+        elf[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for s in segments {
+            let mut ph = vec![0; 56];
+            ph[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            ph[0x08..0x10].copy_from_slice(&s.offset.to_le_bytes());
+            ph[0x18..0x20].copy_from_slice(&s.paddr.to_le_bytes());
+            ph[0x20..0x28].copy_from_slice(&s.filesz.to_le_bytes());
+            ph[0x28..0x30].copy_from_slice(&s.memsz.to_le_bytes());
+            elf.extend(ph);
+        }
+        elf.extend_from_slice(payload);
+
+        // Null symbol first, as in real files
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; SYM_SIZE];
+        for (name, value) in symbols {
+            let mut sym = vec![0; SYM_SIZE];
+            sym[0..4].copy_from_slice(&(strtab.len() as u32).to_le_bytes());
+            sym[8..16].copy_from_slice(&value.to_le_bytes());
+            symtab.extend(sym);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let symtab_off = elf.len() as u64;
+        elf.extend(&symtab);
+        let strtab_off = elf.len() as u64;
+        elf.extend(&strtab);
+
+        // Section headers: null, .symtab (linked to 2), .strtab
+        let shoff = elf.len() as u64;
+        elf.extend(vec![0; 64]);
+        let mut sh = vec![0; 64];
+        sh[4..8].copy_from_slice(&SHT_SYMTAB.to_le_bytes());
+        sh[0x18..0x20].copy_from_slice(&symtab_off.to_le_bytes());
+        sh[0x20..0x28].copy_from_slice(&(symtab.len() as u64).to_le_bytes());
+        sh[0x28..0x2c].copy_from_slice(&2u32.to_le_bytes());
+        elf.extend(sh);
+        let mut sh = vec![0; 64];
+        sh[4..8].copy_from_slice(&3u32.to_le_bytes());
+        sh[0x18..0x20].copy_from_slice(&strtab_off.to_le_bytes());
+        sh[0x20..0x28].copy_from_slice(&(strtab.len() as u64).to_le_bytes());
+        elf.extend(sh);
+
+        elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&3u16.to_le_bytes());
+        elf
+    }
+
+    /// Header, the named `sections` (flags, contents) at indexes 1.., then
+    /// .symtab holding `symbols`, .strtab and .shstrtab
+    pub fn with_sections(sections: &[(&str, u64, &[u8])], symbols: &[Symbol]) -> Vec<u8> {
+        let mut elf = vec![0; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x10] = 2;
+
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; SYM_SIZE];
+        for s in symbols {
+            let mut sym = vec![0; SYM_SIZE];
+            sym[0..4].copy_from_slice(&(strtab.len() as u32).to_le_bytes());
+            sym[4] = s.info;
+            sym[6..8].copy_from_slice(&s.shndx.to_le_bytes());
+            sym[8..16].copy_from_slice(&s.value.to_le_bytes());
+            sym[16..24].copy_from_slice(&s.size.to_le_bytes());
+            symtab.extend(sym);
+            strtab.extend_from_slice(s.name.as_bytes());
+            strtab.push(0);
+        }
+
+        // (name, type, flags, contents, link)
+        let symtab_link = sections.len() as u32 + 2;
+        let mut all: Vec<(&str, u32, u64, &[u8], u32)> =
+            sections.iter().map(|&(name, flags, data)| (name, 1, flags, data, 0)).collect();
+        all.push((".symtab", SHT_SYMTAB, 0, &symtab, symtab_link));
+        all.push((".strtab", 3, 0, &strtab, 0));
+        let mut shstrtab = vec![0u8];
+        for (name, ..) in &all {
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+        shstrtab.extend_from_slice(b".shstrtab\0");
+        all.push((".shstrtab", 3, 0, &shstrtab, 0));
+
+        let mut headers = vec![0; 64];
+        let mut name_off = 1;
+        for (name, kind, flags, data, link) in all {
+            let mut sh = vec![0; 64];
+            sh[0..4].copy_from_slice(&(name_off as u32).to_le_bytes());
+            sh[4..8].copy_from_slice(&kind.to_le_bytes());
+            sh[0x08..0x10].copy_from_slice(&flags.to_le_bytes());
+            sh[0x18..0x20].copy_from_slice(&(elf.len() as u64).to_le_bytes());
+            sh[0x20..0x28].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            sh[0x28..0x2c].copy_from_slice(&link.to_le_bytes());
+            headers.extend(sh);
+            elf.extend_from_slice(data);
+            name_off += name.len() + 1;
+        }
+
+        let shnum = headers.len() / 64;
+        let shoff = elf.len() as u64;
+        elf.extend(headers);
+        elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&(shnum as u16).to_le_bytes());
+        elf[0x3e..0x40].copy_from_slice(&(shnum as u16 - 1).to_le_bytes());
+        elf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_segments_and_symbols() {
+        let text = Segment { paddr: 0x7000_0000, offset: 0x78, filesz: 4, memsz: 0x1000 };
+        let elf = build::elf(&[text], &[1, 2, 3, 4], &[("_start", 0x7000_0000), ("_end", 0x7000_1000)]);
+        let elf = Elf::parse(&elf).unwrap();
+
+        assert_eq!(elf.load_segments(), Some(vec![text]));
+        assert_eq!(elf.contents(&text), Some(&[1, 2, 3, 4][..]));
+        assert_eq!(elf.symbol("_end"), Some(0x7000_1000));
+        assert_eq!(elf.symbol("_start"), Some(0x7000_0000));
+        assert_eq!(elf.symbol("_en"), None);
+        assert!(!elf.is_pie());
+        assert!(Elf::parse(b"\x7fELF\x01\x01").is_none());
+    }
+
+    #[test]
+    fn reads_named_sections() {
+        let text = [0xd5, 0x03, 0x20, 0x1f];
+        let main = Symbol { name: "main", info: 0x12, shndx: 1, value: 0x1000, size: 4 };
+        let elf = build::with_sections(&[(".text", 0x6, &text), (".ksymtab", 0x2, &[0; 8])], &[main]);
+        let elf = Elf::parse(&elf).unwrap();
+
+        let sections = elf.sections().unwrap();
+        let names: Vec<_> = sections.iter().map(|s| s.name).collect();
+        assert_eq!(names, ["", ".text", ".ksymtab", ".symtab", ".strtab", ".shstrtab"]);
+        assert_eq!(elf.section_data(&sections[1]), Some(&text[..]));
+        assert_eq!(sections[2].size, 8);
+        assert_eq!(elf.symbols().unwrap()[1], main);
+        assert_eq!(elf.symbol("main"), Some(0x1000));
+    }
+}
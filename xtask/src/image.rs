@@ -0,0 +1,224 @@
+//! Kernel ELF -> flat binary and Linux arm64 Image
+//!
+//! The 64-byte Image header is the first thing in .text.boot (see
+//! asm/entry.s), so the flat binary and the Image are the same bytes; only
+//! the header fields differ. image_size runs to `_end`, covering BSS as the
+//! boot protocol requires, so nothing a loader places after the kernel gets
+//! cleared.
+//!
+//! The kernel is a static PIE that relocates itself, so its Image says it
+//! can go anywhere (text_offset 0, placement flag set). An ELF linked for a
+//! fixed address still works: loaders put the Image at a 2 MiB aligned RAM
+//! base + text_offset, so text_offset becomes the link address minus the
+//! RAM base of the VMM it is built for.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::elf::Elf;
+use crate::Result;
+
+/// Start of RAM under Virtualization.framework (and hvf_vmm)
+pub const VZ_RAM_BASE: u64 = 0x7000_0000;
+/// Start of RAM on QEMU's virt machine
+pub const QEMU_RAM_BASE: u64 = 0x4000_0000;
+
+const HEADER_SIZE: usize = 64;
+/// "ARM\x64"
+const MAGIC: u32 = 0x644d_5241;
+/// Loaders align the RAM base the kernel is placed relative to
+const BASE_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// Header `flags`: little-endian (bit 0 clear) and 4K pages (bits 1-2 = 1)
+const FLAGS: u64 = 1 << 1;
+/// Placement bit: the 2 MiB aligned base may be anywhere in RAM, rather than
+/// text_offset counting from the base of DRAM
+const FLAG_ANYWHERE: u64 = 1 << 3;
+
+/// Flat binary plus the header values that go with it
+pub struct Kernel {
+    /// Link address of the first byte
+    pub base: u64,
+    /// Memory image, from `base` to the end of the last initialized byte
+    pub binary: Vec<u8>,
+    /// `_end` - `base`: loaded size including BSS
+    pub image_size: u64,
+    /// Position-independent, runs at any load address
+    pub relocatable: bool,
+}
+
+impl Kernel {
+    /// Lay out the loadable segments of `elf` as llvm-objcopy -O binary does
+    pub fn from_elf(data: &[u8]) -> Result<Self> {
+        let elf = Elf::parse(data).ok_or("kernel is not a little-endian ELF64 file")?;
+        let segments = elf.load_segments().ok_or("kernel has malformed program headers")?;
+        let loaded: Vec<_> = segments.iter().filter(|s| s.filesz > 0).collect();
+        let base = loaded.iter().map(|s| s.paddr).min().ok_or("kernel has no loadable segments")?;
+        let file_end = loaded.iter().map(|s| s.paddr + s.filesz).max().unwrap_or(base);
+
+        let mut binary = vec![0; (file_end - base) as usize];
+        for s in loaded {
+            let src = elf.contents(s).ok_or("segment extends past the end of the file")?;
+            let dst = (s.paddr - base) as usize;
+            binary[dst..dst + src.len()].copy_from_slice(src);
+        }
+
+        let end = elf.symbol("_end").ok_or("kernel has no _end symbol (stripped?)")?;
+        if end < file_end {
+            return Err(format!("_end ({end:#x}) is before the end of the loaded data ({file_end:#x})"));
+        }
+        Ok(Kernel { base, binary, image_size: end - base, relocatable: elf.is_pie() })
+    }
+
+    /// The binary with its Image header filled in; a fixed-address kernel
+    /// is laid out for a VMM whose RAM starts at `ram_base`
+    pub fn image(&self, ram_base: u64) -> Result<Vec<u8>> {
+        let header = self.binary.get(..HEADER_SIZE).ok_or("kernel is smaller than an Image header")?;
+        if header[56..60] != MAGIC.to_le_bytes() {
+            return Err("kernel does not start with an Image header (see asm/entry.s)".into());
+        }
+        let mut image = self.binary.clone();
+        image[8..16].copy_from_slice(&self.text_offset(ram_base)?.to_le_bytes());
+        image[16..24].copy_from_slice(&self.image_size.to_le_bytes());
+        let flags = if self.relocatable { FLAGS | FLAG_ANYWHERE } else { FLAGS };
+        image[24..32].copy_from_slice(&flags.to_le_bytes());
+        Ok(image)
+    }
+
+    fn text_offset(&self, ram_base: u64) -> Result<u64> {
+        if self.relocatable {
+            return Ok(0);
+        }
+        if !ram_base.is_multiple_of(BASE_ALIGN) {
+            return Err(format!("RAM base {ram_base:#x} is not 2 MiB aligned"));
+        }
+        self.base
+            .checked_sub(ram_base)
+            .ok_or_else(|| format!("kernel linked at {:#x}, below RAM at {ram_base:#x}", self.base))
+    }
+}
+
+/// gzip at the best compression level (QEMU and Linux loaders accept this)
+pub fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).and_then(|_| encoder.finish()).map_err(|e| format!("gzip: {e}"))
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<PathBuf> {
+    fs::write(path, data).map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(path.to_path_buf())
+}
+
+/// Write `kernel.bin`, `Image` and, with `compress`, `Image.gz` for the ELF
+/// at `elf` into `out_dir`
+pub fn write_images(elf: &Path, out_dir: &Path, ram_base: u64, compress: bool) -> Result<Vec<PathBuf>> {
+    let data = fs::read(elf).map_err(|e| format!("{}: {e}", elf.display()))?;
+    let kernel = Kernel::from_elf(&data)?;
+    let image = kernel.image(ram_base)?;
+
+    let mut written = vec![write(&out_dir.join("kernel.bin"), &kernel.binary)?, write(&out_dir.join("Image"), &image)?];
+    if compress {
+        written.push(write(&out_dir.join("Image.gz"), &gzip(&image)?)?);
+    }
+    let placement = if kernel.relocatable {
+        "anywhere in RAM (position-independent)".to_string()
+    } else {
+        format!("at {:#x}", kernel.base)
+    };
+    println!("xtask: Image loads {placement}, {} bytes in the file, image_size {:#x}", image.len(), kernel.image_size);
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::elf::{build, Segment};
+
+    fn u64_at(data: &[u8], off: usize) -> u64 {
+        u64::from_le_bytes(data[off..off + 8].try_into().unwrap())
+    }
+
+    /// Header + 4 bytes of text at 0x7000_0000, 4 bytes of data a page up,
+    /// BSS to _end
+    fn kernel_elf(end: u64) -> Vec<u8> {
+        let mut text = vec![0; HEADER_SIZE];
+        text[0..4].copy_from_slice(&0x1400_0010u32.to_le_bytes());
+        text[56..60].copy_from_slice(&MAGIC.to_le_bytes());
+        text.extend_from_slice(&[1, 2, 3, 4]);
+
+        // ELF header + two program headers come first
+        let off = 64 + 2 * 56;
+        let segments = [
+            Segment { paddr: 0x7000_0000, offset: off, filesz: text.len() as u64, memsz: text.len() as u64 },
+            Segment { paddr: 0x7000_1000, offset: off + text.len() as u64, filesz: 4, memsz: 0x2000 },
+        ];
+        let mut payload = text;
+        payload.extend_from_slice(&[5, 6, 7, 8]);
+        build::elf(&segments, &payload, &[("_end", end)])
+    }
+
+    #[test]
+    fn header_covers_bss_and_places_kernel_at_link_address() {
+        let kernel = Kernel::from_elf(&kernel_elf(0x7000_3000)).unwrap();
+        assert_eq!(kernel.base, 0x7000_0000);
+        assert_eq!(kernel.binary.len(), 0x1004);
+        assert_eq!(kernel.binary[HEADER_SIZE..HEADER_SIZE + 4], [1, 2, 3, 4]);
+        assert_eq!(kernel.binary[0x1000..], [5, 6, 7, 8]);
+
+        let vz = kernel.image(VZ_RAM_BASE).unwrap();
+        assert_eq!(u64_at(&vz, 8), 0);
+        assert_eq!(u64_at(&vz, 16), 0x3000);
+        assert_eq!(u64_at(&vz, 24), 0b0010);
+        assert_eq!(vz[HEADER_SIZE..], kernel.binary[HEADER_SIZE..]);
+
+        let qemu = kernel.image(QEMU_RAM_BASE).unwrap();
+        assert_eq!(u64_at(&qemu, 8), 0x3000_0000);
+    }
+
+    #[test]
+    fn pie_kernel_goes_anywhere() {
+        let mut elf = kernel_elf(0x7000_3000);
+        elf[0x10] = 3;
+        let kernel = Kernel::from_elf(&elf).unwrap();
+        assert!(kernel.relocatable);
+        for ram_base in [VZ_RAM_BASE, QEMU_RAM_BASE, 0x8000_0000] {
+            let image = kernel.image(ram_base).unwrap();
+            assert_eq!(u64_at(&image, 8), 0);
+            assert_eq!(u64_at(&image, 16), 0x3000);
+            assert_eq!(u64_at(&image, 24), 0b1010);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_layouts() {
+        assert!(Kernel::from_elf(b"not an elf").is_err());
+        // _end inside the loaded data
+        assert!(Kernel::from_elf(&kernel_elf(0x7000_0010)).is_err());
+
+        let mut kernel = Kernel::from_elf(&kernel_elf(0x7000_3000)).unwrap();
+        assert!(kernel.image(0x8000_0000).is_err());
+        assert!(kernel.image(0x4000_1000).is_err());
+        kernel.binary[56] = 0;
+        assert!(kernel.image(VZ_RAM_BASE).is_err());
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        let image = Kernel::from_elf(&kernel_elf(0x7000_3000)).unwrap().image(VZ_RAM_BASE).unwrap();
+        let packed = gzip(&image).unwrap();
+        assert_eq!(packed[..2], [0x1f, 0x8b]);
+        assert!(packed.len() < image.len());
+
+        let mut unpacked = Vec::new();
+        GzDecoder::new(&packed[..]).read_to_end(&mut unpacked).unwrap();
+        assert_eq!(unpacked, image);
+    }
+}
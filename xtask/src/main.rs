@@ -0,0 +1,285 @@
+//! Build and test automation: `cargo xtask <command>` from anywhere in the repo
+//!
+//! `build`, `symtab` and `image` package the kernel straight from its ELF,
+//! with no LLVM binutils or Python needed.
+//! `qemu` is the Linux counterpart of test.sh + vz_test: it builds the kernel,
+//! boots it on QEMU's virt machine with virtio-pci devices attached and
+//! reports the self-tests, exiting non-zero if any failed or hung.
+
+mod elf;
+mod image;
+mod qemu;
+mod symtab;
+mod tap;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::Duration;
+
+use qemu::{Config, Device, NetBackend};
+use tap::Status;
+
+type Result<T> = std::result::Result<T, String>;
+
+const USAGE: &str = "\
+usage: cargo xtask <command> [options]
+
+commands:
+  build                 build the kernel and embed its symbol table, then `image`
+                        (takes its options)
+  symtab [--elf PATH]   embed the backtrace symbol table in a kernel ELF
+  image                 write kernel.bin and Image next to the kernel ELF
+  qemu                  build, then run the kernel self-tests under QEMU
+
+image options:
+  --elf PATH            kernel ELF (default: the release build)
+  --out-dir DIR         where to write (default: the ELF's directory)
+  --ram-base ADDR       RAM base of the target VMM (default: 0x70000000, VZ)
+  --qemu                target QEMU virt (RAM base 0x40000000)
+  --gzip                also write Image.gz
+
+qemu options:
+  --no-build            use the kernel from the last build
+  --devices LIST        virtio devices to attach: blk,net,rng,console,gpu,balloon
+                        (default: all of them)
+  --tests LIST          value for the kernel's tests= (default: all)
+  --disk PATH           raw image for virtio-blk (default: a blank 1 MiB disk)
+  --initrd PATH         CPIO newc archive to load as the initrd
+  --net BACKEND         user, or socket:<netdev options> (default: user)
+  --append ARGS         extra kernel command line options
+  --boot-timeout SECS   time allowed to reach the first test (default: 120)
+  --timeout SECS        time allowed per test (default: 60)
+  --qemu PATH           QEMU binary (default: qemu-system-aarch64)
+  --machine NAME        QEMU machine (default: virt)
+  --cpu NAME            QEMU CPU model (default: cortex-a72)
+  --verbose             echo the serial console while running
+";
+
+/// Repository root (this crate lives in xtask/)
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().expect("xtask is inside the repo").to_path_buf()
+}
+
+/// Scratch directory for images, disks and logs
+fn out_dir() -> Result<PathBuf> {
+    let dir = root().join("target/xtask");
+    std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Where the kernel build puts its output, and where the VMMs look for it
+fn release_dir() -> PathBuf {
+    root().join("my_unikernel/target/aarch64-unknown-none/release")
+}
+
+fn kernel_elf() -> PathBuf {
+    release_dir().join("kernel")
+}
+
+fn run_command(cmd: &mut Command) -> Result<()> {
+    let status = cmd.status().map_err(|e| format!("failed to run {:?}: {e}", cmd.get_program()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?} failed ({status})", cmd.get_program()))
+    }
+}
+
+/// Build the kernel and embed its symbol table
+fn build() -> Result<()> {
+    let kernel_dir = root().join("my_unikernel");
+    println!("xtask: building kernel");
+    // The kernel picks its own toolchain and target
+    // (my_unikernel/rust-toolchain.toml and .cargo/config.toml), not the
+    // ones running xtask: go through the rustup proxy rather than $CARGO,
+    // which is the host toolchain's own cargo
+    run_command(
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .current_dir(&kernel_dir)
+            .env_remove("RUSTUP_TOOLCHAIN")
+            .env_remove("CARGO_TARGET_DIR"),
+    )?;
+
+    symtab::embed(&kernel_elf())
+}
+
+/// Package the kernel ELF, printing what was written
+fn write_images(elf: &Path, out_dir: &Path, ram_base: u64, compress: bool) -> Result<()> {
+    for path in image::write_images(elf, out_dir, ram_base, compress)? {
+        println!("xtask: wrote {}", path.display());
+    }
+    Ok(())
+}
+
+fn parse_addr(value: &str) -> Result<u64> {
+    let digits = value.trim_start_matches("0x").replace('_', "");
+    u64::from_str_radix(&digits, 16).map_err(|_| format!("not a hex address: {value}"))
+}
+
+fn image(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut elf = kernel_elf();
+    let mut out = None;
+    let mut ram_base = image::VZ_RAM_BASE;
+    let mut compress = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--elf" => elf = PathBuf::from(value()?),
+            "--out-dir" => out = Some(PathBuf::from(value()?)),
+            "--ram-base" => ram_base = parse_addr(&value()?)?,
+            "--qemu" => ram_base = image::QEMU_RAM_BASE,
+            "--gzip" => compress = true,
+            _ => return Err(format!("unknown option: {arg}\n\n{USAGE}")),
+        }
+    }
+    let out = out.unwrap_or_else(|| elf.parent().unwrap_or(Path::new(".")).to_path_buf());
+    write_images(&elf, &out, ram_base, compress)
+}
+
+fn symtab(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut elf = kernel_elf();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--elf" => elf = PathBuf::from(args.next().ok_or("--elf needs a value")?),
+            _ => return Err(format!("unknown option: {arg}\n\n{USAGE}")),
+        }
+    }
+    symtab::embed(&elf)
+}
+
+fn parse_secs(value: &str) -> Result<Duration> {
+    value.parse().map(Duration::from_secs).map_err(|_| format!("not a number of seconds: {value}"))
+}
+
+/// Parse the qemu options into a config
+fn qemu_config(mut args: impl Iterator<Item = String>) -> Result<(Config, bool)> {
+    let out = out_dir()?;
+    let mut config = Config {
+        qemu: "qemu-system-aarch64".into(),
+        machine: "virt".into(),
+        cpu: "cortex-a72".into(),
+        image: out.join("Image"),
+        initrd: None,
+        devices: Device::ALL.to_vec(),
+        disk: out.join("disk.img"),
+        net: NetBackend::User,
+        console_log: out.join("hvc0.log"),
+        tests: "all".into(),
+        append: String::new(),
+        boot_timeout: Duration::from_secs(120),
+        test_timeout: Duration::from_secs(60),
+        verbose: false,
+        serial_log: out.join("serial.log"),
+    };
+    let mut build = true;
+    let mut disk = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--no-build" => build = false,
+            "--verbose" => config.verbose = true,
+            "--devices" => {
+                let list = value()?;
+                config.devices = list
+                    .split(',')
+                    .filter(|name| !name.is_empty())
+                    .map(|name| Device::parse(name).ok_or_else(|| format!("unknown device: {name}")))
+                    .collect::<Result<_>>()?;
+            }
+            "--tests" => config.tests = value()?,
+            "--disk" => disk = Some(PathBuf::from(value()?)),
+            "--initrd" => config.initrd = Some(PathBuf::from(value()?)),
+            "--net" => {
+                let spec = value()?;
+                config.net = NetBackend::parse(&spec).ok_or_else(|| format!("unknown net backend: {spec}"))?;
+            }
+            "--append" => config.append = value()?,
+            "--boot-timeout" => config.boot_timeout = parse_secs(&value()?)?,
+            "--timeout" => config.test_timeout = parse_secs(&value()?)?,
+            "--qemu" => config.qemu = value()?,
+            "--machine" => config.machine = value()?,
+            "--cpu" => config.cpu = value()?,
+            _ => return Err(format!("unknown option: {arg}\n\n{USAGE}")),
+        }
+    }
+
+    match disk {
+        Some(path) => config.disk = path,
+        None if config.devices.contains(&Device::Blk) => qemu::create_disk(&config.disk)?,
+        None => {}
+    }
+    Ok((config, build))
+}
+
+/// Print the results; true if the run passed
+fn summarize(report: &tap::Report) -> bool {
+    println!();
+    for result in &report.results {
+        match &result.reason {
+            Some(reason) => println!("{:<8} {} ({reason})", result.status, result.name),
+            None => println!("{:<8} {}", result.status, result.name),
+        }
+    }
+    let reported = report.results.len();
+    if let Some(planned) = report.planned.filter(|&n| n != reported) {
+        println!("{} of {planned} tests did not report", planned.saturating_sub(reported));
+    }
+    if !report.started {
+        println!("kernel never reached its tests");
+    }
+    println!(
+        "\n{} passed, {} failed, {} skipped, {} timed out; kernel exit status {}",
+        report.count(Status::Pass),
+        report.count(Status::Fail),
+        report.count(Status::Skip),
+        report.count(Status::Timeout),
+        report.exit_code.map_or("missing".into(), |code| code.to_string()),
+    );
+    report.passed()
+}
+
+fn qemu(args: impl Iterator<Item = String>) -> Result<bool> {
+    let (config, build_first) = qemu_config(args)?;
+    if build_first {
+        build()?;
+    } else if !kernel_elf().exists() {
+        return Err(format!("{} not found, run without --no-build", kernel_elf().display()));
+    }
+    let dir = config.image.parent().expect("image is in target/xtask");
+    image::write_images(&kernel_elf(), dir, image::QEMU_RAM_BASE, false)?;
+
+    println!("xtask: {} {}", config.qemu, config.args().join(" "));
+    let report = qemu::run(&config)?;
+    let passed = summarize(&report);
+    if !passed && !config.verbose {
+        println!("serial log: {}", config.serial_log.display());
+    }
+    Ok(passed)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("build") => build().and_then(|_| image(args)).map(|_| true),
+        Some("symtab") => symtab(args).map(|_| true),
+        Some("image") => image(args).map(|_| true),
+        Some("qemu") => qemu(args),
+        Some("help" | "--help" | "-h") => {
+            print!("{USAGE}");
+            Ok(true)
+        }
+        _ => Err(USAGE.trim_end().to_string()),
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("xtask: {e}");
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! Boot the kernel under `qemu-system-aarch64 -M virt` and follow its tests
+//!
+//! Devices are modern-only virtio-pci, like VZ's. Output is taken from the
+//! PL011 on QEMU's stdio; the virtio-console (hvc0) goes to a file so its
+//! driver is still exercised. The boot has to reach the TAP header within
+//! the boot timeout, after which every test gets its own timeout.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::tap::{Event, Report, Status};
+use crate::Result;
+
+/// Guest RAM; the kernel finds it through the DTB's /memory node
+const MEMORY: &str = "2G";
+/// Disk created when none is given
+const DEFAULT_DISK_SIZE: u64 = 1024 * 1024;
+/// After the summary line, time allowed to print EXIT: and power off
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Device {
+    Blk,
+    Net,
+    Rng,
+    Console,
+    Gpu,
+    Balloon,
+}
+
+impl Device {
+    pub const ALL: [Device; 6] = [Device::Blk, Device::Net, Device::Rng, Device::Console, Device::Gpu, Device::Balloon];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "blk" => Device::Blk,
+            "net" => Device::Net,
+            "rng" => Device::Rng,
+            "console" => Device::Console,
+            "gpu" => Device::Gpu,
+            "balloon" => Device::Balloon,
+            _ => return None,
+        })
+    }
+}
+
+/// Backend for the virtio-net device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetBackend {
+    /// Slirp, the guest sees 10.0.2.0/24
+    User,
+    /// `-netdev socket,<opts>`, e.g. `listen=:1234` or `connect=127.0.0.1:1234`
+    Socket(String),
+}
+
+impl NetBackend {
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec.split_once(':') {
+            _ if spec == "user" => Some(NetBackend::User),
+            Some(("socket", opts)) if !opts.is_empty() => Some(NetBackend::Socket(opts.to_string())),
+            _ => None,
+        }
+    }
+}
+
+pub struct Config {
+    pub qemu: String,
+    pub machine: String,
+    pub cpu: String,
+    pub image: PathBuf,
+    /// CPIO archive passed with -initrd
+    pub initrd: Option<PathBuf>,
+    pub devices: Vec<Device>,
+    /// Raw disk image for virtio-blk
+    pub disk: PathBuf,
+    pub net: NetBackend,
+    /// Where the virtio-console output is written
+    pub console_log: PathBuf,
+    /// Value of `tests=`
+    pub tests: String,
+    /// Extra kernel command line options
+    pub append: String,
+    pub boot_timeout: Duration,
+    pub test_timeout: Duration,
+    /// Echo the serial output as it arrives
+    pub verbose: bool,
+    /// Full serial output is kept here
+    pub serial_log: PathBuf,
+}
+
+impl Config {
+    /// Kernel command line
+    pub fn cmdline(&self) -> String {
+        let mut cmdline = format!("console=ttyAMA0 tests={}", self.tests);
+        if !self.append.is_empty() {
+            cmdline.push(' ');
+            cmdline.push_str(&self.append);
+        }
+        cmdline
+    }
+
+    /// QEMU command line, without the program name
+    pub fn args(&self) -> Vec<String> {
+        let mut args: Vec<String> = [
+            "-M", &self.machine, "-cpu", &self.cpu, "-m", MEMORY,
+            "-display", "none", "-monitor", "none", "-serial", "stdio", "-no-reboot",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        args.extend(["-kernel".into(), self.image.display().to_string(), "-append".into(), self.cmdline()]);
+        if let Some(initrd) = &self.initrd {
+            args.extend(["-initrd".into(), initrd.display().to_string()]);
+        }
+
+        let modern = ",disable-legacy=on,disable-modern=off";
+        for device in &self.devices {
+            let extra: &[String] = match device {
+                Device::Blk => &[
+                    "-drive".into(),
+                    format!("file={},if=none,id=disk0,format=raw", self.disk.display()),
+                    "-device".into(),
+                    // More than one request queue, so the self-test covers multiqueue
+                    format!("virtio-blk-pci,drive=disk0,num-queues=2{modern}"),
+                ],
+                Device::Net => &[
+                    "-netdev".into(),
+                    match &self.net {
+                        NetBackend::User => "user,id=net0".into(),
+                        NetBackend::Socket(opts) => format!("socket,id=net0,{opts}"),
+                    },
+                    "-device".into(),
+                    format!("virtio-net-pci,netdev=net0{modern}"),
+                ],
+                Device::Rng => &["-device".into(), format!("virtio-rng-pci{modern}")],
+                Device::Console => &[
+                    "-chardev".into(),
+                    format!("file,id=hvc0,path={}", self.console_log.display()),
+                    "-device".into(),
+                    format!("virtio-serial-pci{modern}"),
+                    "-device".into(),
+                    "virtconsole,chardev=hvc0".into(),
+                ],
+                // virtio-gpu-pci is modern-only already
+                Device::Gpu => &["-device".into(), "virtio-gpu-pci,xres=1280,yres=720".into()],
+                Device::Balloon => &["-device".into(), format!("virtio-balloon-pci{modern}")],
+            };
+            args.extend_from_slice(extra);
+        }
+        args
+    }
+}
+
+/// Zeroed disk at `path`, unless one is there already
+pub fn create_disk(path: &Path) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    file.set_len(DEFAULT_DISK_SIZE).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Which deadline the run is up against
+fn phase(report: &Report) -> String {
+    match &report.running {
+        _ if !report.started => "boot".into(),
+        Some(name) => format!("test {name}"),
+        None if report.done => "shutdown".into(),
+        None => "waiting for the next test".into(),
+    }
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Boot the kernel and collect its report
+///
+/// Returns Err only if QEMU could not be run; a hung or crashed kernel shows
+/// up in the report as TIMEOUT, FAIL or missing results.
+pub fn run(config: &Config) -> Result<Report> {
+    if config.devices.contains(&Device::Console) {
+        // QEMU appends to an existing chardev file
+        let _ = fs::remove_file(&config.console_log);
+    }
+    let mut child = Command::new(&config.qemu)
+        .args(config.args())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {e}", config.qemu))?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf).trim_end_matches(['\r', '\n']).to_string();
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut log = File::create(&config.serial_log).map_err(|e| format!("{}: {e}", config.serial_log.display()))?;
+    let mut report = Report::new();
+    let mut deadline = Instant::now() + config.boot_timeout;
+
+    loop {
+        let line = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                eprintln!("xtask: timed out in {}", phase(&report));
+                let limit = if report.started { config.test_timeout } else { config.boot_timeout };
+                report.interrupt(Status::Timeout, format!("no result after {}s", limit.as_secs()));
+                kill(&mut child);
+                break;
+            }
+            // QEMU exited
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let _ = writeln!(log, "{line}");
+        if config.verbose {
+            println!("{line}");
+        }
+        match report.feed(&line) {
+            Some(Event::Started | Event::TestStarted(_) | Event::Finished(_)) => {
+                deadline = Instant::now() + config.test_timeout;
+            }
+            Some(Event::Exit(_)) => deadline = Instant::now() + SHUTDOWN_TIMEOUT,
+            None if report.done && report.exit_code.is_none() => {
+                deadline = deadline.min(Instant::now() + SHUTDOWN_TIMEOUT);
+            }
+            None => {}
+        }
+    }
+
+    // PSCI SYSTEM_OFF ends QEMU by itself; anything still running is hung
+    match child.try_wait() {
+        Ok(Some(_)) => {}
+        _ => kill(&mut child),
+    }
+    // Powered off (e.g. by the panic handler) in the middle of a test
+    let reason = match report.exit_code {
+        Some(code) => format!("kernel exited with status {code} during the test"),
+        None => "QEMU exited during the test".into(),
+    };
+    report.interrupt(Status::Fail, reason);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(devices: Vec<Device>) -> Config {
+        Config {
+            qemu: "qemu-system-aarch64".into(),
+            machine: "virt".into(),
+            cpu: "cortex-a72".into(),
+            image: "Image".into(),
+            initrd: None,
+            devices,
+            disk: "disk.img".into(),
+            net: NetBackend::User,
+            console_log: "hvc0.log".into(),
+            tests: "all".into(),
+            append: String::new(),
+            boot_timeout: Duration::from_secs(1),
+            test_timeout: Duration::from_secs(1),
+            verbose: false,
+            serial_log: "serial.log".into(),
+        }
+    }
+
+    fn has_pair(args: &[String], flag: &str, value: &str) -> bool {
+        args.windows(2).any(|w| w[0] == flag && w[1] == value)
+    }
+
+    #[test]
+    fn args_add_one_backend_and_device_per_entry() {
+        let mut cfg = config(vec![Device::Blk, Device::Net, Device::Console]);
+        cfg.net = NetBackend::parse("socket:listen=:1234").unwrap();
+        cfg.append = "loglevel=8".into();
+        cfg.initrd = Some("initrd.cpio".into());
+        let args = cfg.args();
+
+        assert!(has_pair(&args, "-append", "console=ttyAMA0 tests=all loglevel=8"));
+        assert!(has_pair(&args, "-initrd", "initrd.cpio"));
+        assert!(has_pair(&args, "-drive", "file=disk.img,if=none,id=disk0,format=raw"));
+        assert!(has_pair(&args, "-device", "virtio-blk-pci,drive=disk0,num-queues=2,disable-legacy=on,disable-modern=off"));
+        assert!(has_pair(&args, "-netdev", "socket,id=net0,listen=:1234"));
+        assert!(has_pair(&args, "-chardev", "file,id=hvc0,path=hvc0.log"));
+        assert!(!args.iter().any(|a| a.starts_with("virtio-gpu") || a.starts_with("virtio-rng")));
+    }
+
+    #[test]
+    fn parses_device_and_net_names() {
+        assert!(Device::ALL.iter().all(|d| {
+            let name = format!("{d:?}").to_ascii_lowercase();
+            Device::parse(&name) == Some(*d)
+        }));
+        assert_eq!(Device::parse("sound"), None);
+        assert_eq!(NetBackend::parse("user"), Some(NetBackend::User));
+        assert_eq!(NetBackend::parse("socket:"), None);
+        assert_eq!(NetBackend::parse("tap"), None);
+    }
+}
@@ -0,0 +1,288 @@
+//! The backtrace symbol table in .ksymtab
+//!
+//! The kernel reserves a fixed-size .ksymtab section (see
+//! my_unikernel/src/symbols.rs). `embed` fills it in place from the ELF's
+//! .symtab, with demangled function names, so no second link is needed.
+//!
+//! Layout: a 16-byte header (magic, count, offset of the names, reserved),
+//! `count` entries of (address - `_start`, size, name offset), sorted by
+//! address, then the NUL-terminated names.
+
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fs;
+use std::path::Path;
+
+use crate::elf::Elf;
+use crate::Result;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 12;
+
+const SHF_EXECINSTR: u64 = 0x4;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+/// One code symbol, demangled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub addr: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// Legacy Rust mangling (`_ZN...E`): the path without its trailing hash
+fn demangle_legacy(body: &str) -> String {
+    let mut parts = Vec::new();
+    let mut rest = body;
+    while rest.starts_with(|c: char| c.is_ascii_digit()) {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Ok(len) = rest[..digits].parse::<usize>() else { break };
+        let Some(ident) = rest.get(digits..digits + len) else { break };
+        // Identifiers that start with an escape get a leading '_'
+        parts.push(ident.strip_prefix("_$").map_or(ident, |_| &ident[1..]));
+        rest = &rest[digits + len..];
+    }
+    let is_hash = |s: &str| s.len() == 17 && s.starts_with('h') && s[1..].bytes().all(|b| b.is_ascii_hexdigit());
+    if parts.last().is_some_and(|s| is_hash(s)) {
+        parts.pop();
+    }
+
+    let mut name = parts.join("::");
+    for (esc, c) in [
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$RF$", "&"),
+        ("$BP$", "*"),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("..", "::"),
+    ] {
+        name = name.replace(esc, c);
+    }
+    name
+}
+
+/// v0 mangling (`_R...`), best effort: the path's identifiers joined by
+/// `::`. Generic arguments and impl paths are not reconstructed; this only
+/// has to make backtraces readable.
+fn demangle_v0(sym: &str) -> String {
+    let bytes = sym.as_bytes();
+    let mut idents = Vec::new();
+    let mut i = 2;
+    while i < bytes.len() {
+        match bytes[i] {
+            // Disambiguator s<base62>_
+            b's' if i + 1 < bytes.len() => {
+                i = sym[i + 1..].find('_').map_or(bytes.len(), |end| i + 1 + end + 1);
+            }
+            b'0'..=b'9' => {
+                let digits = bytes[i..].iter().take_while(|b| b.is_ascii_digit()).count();
+                let Ok(len) = sym[i..i + digits].parse::<usize>() else { break };
+                i += digits;
+                if bytes.get(i) == Some(&b'_') {
+                    i += 1;
+                }
+                let Some(ident) = sym.get(i..(i + len).min(bytes.len())) else { break };
+                idents.push(ident);
+                i += len;
+            }
+            _ => i += 1,
+        }
+    }
+    if idents.is_empty() {
+        sym.to_string()
+    } else {
+        idents.join("::")
+    }
+}
+
+/// Readable name for a symbol; anything not Rust-mangled is left alone
+pub fn demangle(sym: &str) -> String {
+    if let Some(body) = sym.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+        demangle_legacy(body)
+    } else if sym.starts_with("_R") {
+        demangle_v0(sym)
+    } else {
+        sym.to_string()
+    }
+}
+
+/// Code symbols of `elf` sorted by address, one per address
+pub fn functions(elf: &Elf) -> Result<Vec<Function>> {
+    let sections = elf.sections().ok_or("kernel has malformed section headers")?;
+    let symbols = elf.symbols().ok_or("kernel has no .symtab (stripped?)")?;
+
+    // address -> (size, name, type)
+    let mut by_addr = BTreeMap::new();
+    for sym in symbols {
+        let kind = sym.info & 0xf;
+        if sym.shndx == 0 || sym.name.is_empty() || (kind != STT_FUNC && kind != STT_NOTYPE) {
+            continue;
+        }
+        if sections.get(sym.shndx as usize).is_none_or(|s| s.flags & SHF_EXECINSTR == 0) {
+            continue;
+        }
+        // Mapping symbols ($x, $d) and local labels
+        if sym.name.starts_with('$') || sym.name.starts_with(".L") {
+            continue;
+        }
+        // Real functions win over bare labels at the same address
+        match by_addr.entry(sym.value) {
+            Entry::Vacant(e) => {
+                e.insert((sym.size, sym.name, kind));
+            }
+            Entry::Occupied(mut e) if kind == STT_FUNC && e.get().2 != STT_FUNC => {
+                e.insert((sym.size, sym.name, kind));
+            }
+            Entry::Occupied(_) => {}
+        }
+    }
+
+    let addrs: Vec<u64> = by_addr.keys().copied().collect();
+    Ok(by_addr
+        .into_iter()
+        .zip(addrs.iter().skip(1).map(Some).chain([None]))
+        .map(|((addr, (size, name, _)), next)| {
+            // Labels without a size extend to the next symbol
+            let size = match next {
+                Some(&next) if size == 0 => next - addr,
+                _ => size,
+            };
+            Function { addr, size, name: demangle(name) }
+        })
+        .collect())
+}
+
+/// The .ksymtab contents for the functions at or above `base`
+pub fn table(functions: &[Function], base: u64) -> Vec<u8> {
+    let functions: Vec<_> = functions.iter().filter(|f| f.addr >= base).collect();
+    let mut entries = Vec::with_capacity(functions.len() * ENTRY_SIZE);
+    let mut names = Vec::new();
+    for f in &functions {
+        entries.extend_from_slice(&((f.addr - base) as u32).to_le_bytes());
+        entries.extend_from_slice(&(f.size as u32).to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(f.name.as_bytes());
+        names.push(0);
+    }
+
+    let mut table = Vec::with_capacity(HEADER_SIZE + entries.len() + names.len());
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+    table.extend_from_slice(&((HEADER_SIZE + entries.len()) as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend(entries);
+    table.extend(names);
+    table
+}
+
+/// Fill .ksymtab of the ELF in `data`, zero-padding the rest of the section;
+/// returns (symbols, bytes used, section size)
+pub fn patch(data: &mut [u8]) -> Result<(usize, usize, usize)> {
+    let elf = Elf::parse(data).ok_or("kernel is not a little-endian ELF64 file")?;
+    let sections = elf.sections().ok_or("kernel has malformed section headers")?;
+    let ksymtab = sections.iter().find(|s| s.name == ".ksymtab").ok_or("kernel has no .ksymtab section")?;
+    let capacity = elf.section_data(ksymtab).ok_or(".ksymtab extends past the end of the file")?.len();
+
+    let functions = functions(&elf)?;
+    let start = functions.iter().find(|f| f.name == "_start").ok_or("_start not found in symbol table")?.addr;
+    let table = table(&functions, start);
+    if table.len() > capacity {
+        return Err(format!("symbol table is {} bytes, .ksymtab holds {capacity}", table.len()));
+    }
+    let count = functions.iter().filter(|f| f.addr >= start).count();
+
+    let offset = ksymtab.offset as usize;
+    let section = &mut data[offset..offset + capacity];
+    section[..table.len()].copy_from_slice(&table);
+    section[table.len()..].fill(0);
+    Ok((count, table.len(), capacity))
+}
+
+/// Embed the symbol table into the kernel ELF at `path`, in place
+pub fn embed(path: &Path) -> Result<()> {
+    let mut data = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let (count, used, capacity) = patch(&mut data)?;
+    fs::write(path, &data).map_err(|e| format!("{}: {e}", path.display()))?;
+    println!("xtask: embedded {count} symbols in .ksymtab, {used} of {capacity} bytes");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{build, Symbol};
+
+    fn u32_at(data: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+    }
+
+    fn func(name: &str, value: u64, size: u64) -> Symbol<'_> {
+        Symbol { name, info: STT_FUNC, shndx: 1, value, size }
+    }
+
+    #[test]
+    fn demangles_rust_symbols() {
+        assert_eq!(demangle("_ZN4core9panicking5panic17h0123456789abcdefE"), "core::panicking::panic");
+        assert_eq!(
+            demangle("_ZN47_$LT$my_unikernel..Foo$u20$as$u20$core..Bar$GT$3baz17h00000000000000ffE"),
+            "<my_unikernel::Foo as core::Bar>::baz"
+        );
+        assert_eq!(demangle("_RNvNtCs1234_12my_unikernel6memory4init"), "my_unikernel::memory::init");
+        assert_eq!(demangle("_start"), "_start");
+        assert_eq!(demangle("memcpy"), "memcpy");
+    }
+
+    #[test]
+    fn picks_code_symbols_and_sizes_labels() {
+        let data_sym = Symbol { name: "DATA", info: 1, shndx: 2, value: 0x2000, size: 8 };
+        let symbols = [
+            Symbol { name: "_start", info: STT_NOTYPE, shndx: 1, value: 0x1000, size: 0 },
+            Symbol { name: "$x", info: STT_NOTYPE, shndx: 1, value: 0x1000, size: 0 },
+            Symbol { name: "label", info: STT_NOTYPE, shndx: 1, value: 0x1010, size: 0 },
+            func("kmain", 0x1010, 0x20),
+            func("_ZN3foo3bar17h0123456789abcdefE", 0x1030, 0x10),
+            Symbol { name: "undefined", info: STT_FUNC, shndx: 0, value: 0, size: 0 },
+            data_sym,
+        ];
+        let elf = build::with_sections(&[(".text", 0x6, &[0; 0x40]), (".data", 0x3, &[0; 8])], &symbols);
+        let functions = functions(&Elf::parse(&elf).unwrap()).unwrap();
+        assert_eq!(
+            functions,
+            [
+                Function { addr: 0x1000, size: 0x10, name: "_start".into() },
+                Function { addr: 0x1010, size: 0x20, name: "kmain".into() },
+                Function { addr: 0x1030, size: 0x10, name: "foo::bar".into() },
+            ]
+        );
+    }
+
+    #[test]
+    fn patches_ksymtab_in_place() {
+        let symbols = [func("early", 0x800, 4), func("_start", 0x1000, 8), func("kmain", 0x1008, 4)];
+        let mut elf = build::with_sections(&[(".text", 0x6, &[0; 16]), (".ksymtab", 0x2, &[0xff; 64])], &symbols);
+        assert_eq!(patch(&mut elf).unwrap(), (2, 16 + 2 * ENTRY_SIZE + 13, 64));
+
+        let parsed = Elf::parse(&elf).unwrap();
+        let sections = parsed.sections().unwrap();
+        let ksymtab = parsed.section_data(&sections[2]).unwrap();
+        assert_eq!(&ksymtab[..4], MAGIC);
+        assert_eq!(u32_at(ksymtab, 4), 2);
+        assert_eq!(u32_at(ksymtab, 8), (HEADER_SIZE + 2 * ENTRY_SIZE) as u32);
+        // kmain: 8 bytes past _start, 4 long, name after "_start\0"
+        assert_eq!([u32_at(ksymtab, 28), u32_at(ksymtab, 32), u32_at(ksymtab, 36)], [8, 4, 7]);
+        assert_eq!(&ksymtab[40..53], b"_start\0kmain\0");
+        assert!(ksymtab[53..].iter().all(|&b| b == 0));
+
+        let mut small = build::with_sections(&[(".text", 0x6, &[0; 16]), (".ksymtab", 0x2, &[0; 16])], &symbols);
+        assert!(patch(&mut small).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Parser for the kernel's self-test report (see my_unikernel/src/ktest.rs)
+//!
+//! The serial output is fed in line by line. Each test is announced with a
+//! `# <name>` diagnostic, ends with `TEST:<NAME>=PASS|FAIL|SKIP` and the
+//! matching TAP `ok`/`not ok` line, and the run finishes with the
+//! `EXIT:<code>` line power::shutdown() prints.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Fail,
+    Skip,
+    /// No result before the per-test timeout
+    Timeout,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Status::Pass => "PASS",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+            Status::Timeout => "TIMEOUT",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub status: Status,
+    /// From the TAP line's `# ...` directive
+    pub reason: Option<String>,
+}
+
+/// What a line changed, for the runner's timers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// `TAP version` seen: boot is over
+    Started,
+    /// A test announced itself
+    TestStarted(String),
+    /// TEST: line for results[index]
+    Finished(usize),
+    /// power::shutdown() status
+    Exit(u32),
+}
+
+/// Accumulated state of one run
+#[derive(Debug, Default)]
+pub struct Report {
+    pub started: bool,
+    pub planned: Option<usize>,
+    pub results: Vec<TestResult>,
+    /// Test announced but not finished yet
+    pub running: Option<String>,
+    /// Summary line seen: only shutdown is left
+    pub done: bool,
+    pub exit_code: Option<u32>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take in one line of serial output
+    pub fn feed(&mut self, line: &str) -> Option<Event> {
+        let line = line.trim_end();
+        if let Some(hex) = line.strip_prefix("EXIT:0x") {
+            let code = u32::from_str_radix(hex, 16).ok()?;
+            self.exit_code = Some(code);
+            return Some(Event::Exit(code));
+        }
+        if line.starts_with("TAP version") {
+            self.started = true;
+            return Some(Event::Started);
+        }
+        if !self.started {
+            return None;
+        }
+        if let Some(rest) = line.strip_prefix("TEST:") {
+            let (name, status) = rest.split_once('=')?;
+            let status = match status {
+                "PASS" => Status::Pass,
+                "FAIL" => Status::Fail,
+                "SKIP" => Status::Skip,
+                _ => return None,
+            };
+            // The TAP line that follows has the name as registered
+            let name = self.running.take().unwrap_or_else(|| name.to_ascii_lowercase());
+            self.results.push(TestResult { name, status, reason: None });
+            return Some(Event::Finished(self.results.len() - 1));
+        }
+        if let Some(plan) = line.strip_prefix("1..") {
+            self.planned = plan.parse().ok();
+            return None;
+        }
+        if let Some(rest) = line.strip_prefix("ok ").or_else(|| line.strip_prefix("not ok ")) {
+            self.tap_line(rest);
+            return None;
+        }
+        if let Some(rest) = line.strip_prefix("# passed ") {
+            if self.running.is_none() && rest.contains("failed") {
+                self.done = true;
+                return None;
+            }
+        }
+        // Announcement: the first diagnostic after the previous result,
+        // the test's own diagnostics follow it
+        if let Some(name) = line.strip_prefix("# ") {
+            if self.running.is_none() && !self.done && !name.is_empty() && !name.contains(char::is_whitespace) {
+                self.running = Some(name.to_string());
+                return Some(Event::TestStarted(name.to_string()));
+            }
+        }
+        None
+    }
+
+    /// `N - name [# reason]` after `ok`/`not ok`: fills in the last result
+    fn tap_line(&mut self, rest: &str) {
+        let Some(last) = self.results.last_mut() else {
+            return;
+        };
+        let Some((_, desc)) = rest.split_once(" - ") else {
+            return;
+        };
+        let (name, directive) = match desc.split_once(" # ") {
+            Some((name, directive)) => (name, Some(directive)),
+            None => (desc, None),
+        };
+        if name.eq_ignore_ascii_case(&last.name) {
+            last.name = name.to_string();
+            last.reason = directive.map(|d| d.strip_prefix("SKIP ").unwrap_or(d).to_string());
+        }
+    }
+
+    /// End the running test (if any) without a result from the kernel
+    pub fn interrupt(&mut self, status: Status, reason: String) {
+        if let Some(name) = self.running.take() {
+            self.results.push(TestResult { name, status, reason: Some(reason) });
+        }
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+
+    /// Every planned test reported, none failed and the kernel exited cleanly
+    pub fn passed(&self) -> bool {
+        self.exit_code == Some(0)
+            && self.planned.is_some_and(|n| n == self.results.len())
+            && self.results.iter().all(|r| matches!(r.status, Status::Pass | Status::Skip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(report: &mut Report, text: &str) -> Vec<Event> {
+        text.lines().filter_map(|line| report.feed(line)).collect()
+    }
+
+    #[test]
+    fn parses_a_full_run() {
+        let mut report = Report::new();
+        let events = feed_all(
+            &mut report,
+            "Booting\r\n# not a test yet\nTAP version 13\n1..3\n# entropy\n# read 64 bytes\nTEST:ENTROPY=PASS\n\
+             ok 1 - entropy\n# block\nTEST:BLOCK=FAIL\nnot ok 2 - block # readback mismatch\nTEST:NET=SKIP\n\
+             ok 3 - net # SKIP disabled on command line\n# passed 1, failed 1, skipped 1\nEXIT:0x0000000000000001\n",
+        );
+
+        assert_eq!(
+            events,
+            [
+                Event::Started,
+                Event::TestStarted("entropy".into()),
+                Event::Finished(0),
+                Event::TestStarted("block".into()),
+                Event::Finished(1),
+                Event::Finished(2),
+                Event::Exit(1),
+            ]
+        );
+        assert_eq!(report.planned, Some(3));
+        assert_eq!(report.results[0], TestResult { name: "entropy".into(), status: Status::Pass, reason: None });
+        assert_eq!(report.results[1].reason.as_deref(), Some("readback mismatch"));
+        assert_eq!(report.results[2].name, "net");
+        assert_eq!(report.results[2].reason.as_deref(), Some("disabled on command line"));
+        assert!(report.done);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn timeout_and_missing_results_fail_the_run() {
+        let mut report = Report::new();
+        feed_all(&mut report, "TAP version 13\n1..2\n# threads\n# spawned 4\n");
+        assert_eq!(report.running.as_deref(), Some("threads"));
+        report.interrupt(Status::Timeout, "no result after 60s".into());
+        assert_eq!(report.results[0].status, Status::Timeout);
+        assert!(report.running.is_none());
+        assert_eq!(report.count(Status::Timeout), 1);
+        assert!(!report.passed());
+
+        let mut report = Report::new();
+        feed_all(&mut report, "TAP version 13\n1..1\nTEST:NET=PASS\nok 1 - net\nEXIT:0x0000000000000000\n");
+        assert!(report.passed());
+        report.planned = Some(2);
+        assert!(!report.passed());
+    }
+}